@@ -1,43 +1,259 @@
 mod services;
 
-use std::env;
+use std::path::PathBuf;
 
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+
+use crate::services::bot_common::{self, BotOptions};
 use crate::services::claude;
+use crate::services::export::ExportFormat;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-fn print_help() {
-    println!("aemi {} - Agent Mirror", VERSION);
-    println!();
-    println!("USAGE:");
-    println!("    aemi [OPTIONS]");
-    println!("    aemi --agent <AGENT> --routing <PLATFORM> --token <TOKEN>... --chat-id|--channel-id <ID>");
-    println!();
-    println!("OPTIONS:");
-    println!("    -h, --help              Print help information");
-    println!("    -v, --version           Print version information");
-    println!("    --prompt <TEXT>         Send prompt to AI and print response");
-    println!();
-    println!("SERVER MODE:");
-    println!("    --agent <AGENT>         AI agent to use (claude, gemini, codex, opencode, oh-my-pi)");
-    println!("    --routing <PLATFORM>    Messaging platform (telegram, discord)");
-    println!("    --token <TOKEN>...      Bot token(s). Telegram supports multiple tokens");
-    println!("    --chat-id <ID>          Telegram chat ID (required for telegram routing)");
-    println!("    --channel-id <ID>       Discord channel ID (required for discord routing)");
-    println!();
-    println!("EXAMPLES:");
-    println!("    aemi --agent claude --routing telegram --token <TOKEN> --chat-id <ID>");
-    println!("    aemi --agent claude --routing telegram --token <T1> <T2> --chat-id <ID>");
-    println!("    aemi --agent claude --routing discord --token <TOKEN> --channel-id <ID>");
-    println!();
-    println!("INTERNAL:");
-    println!("    --base64 <TEXT>         Decode base64 and print");
-    println!("    --sendfile <PATH> --chat <ID> --key <HASH>");
-    println!("                            Send file via Telegram bot (HASH = token hash)");
+/// Agent Mirror - relay AI agents through Telegram and Discord bots
+#[derive(Parser)]
+#[command(
+    name = "aemi",
+    version,
+    disable_version_flag = true,
+    arg_required_else_help = true,
+    after_help = "Older flag forms still work: --prompt <TEXT>, --sendfile <PATH> --chat <ID> --key <HASH>,\n\
+                  --base64 <TEXT> and --agent <AGENT> --routing <PLATFORM> ... (same as `aemi serve`)."
+)]
+struct Cli {
+    /// Print version information
+    #[arg(short = 'v', long, action = clap::ArgAction::Version)]
+    version: Option<bool>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the bots in the config file, or a single bot given with --routing
+    Serve(ServeArgs),
+    /// Send a prompt to Claude and print the response
+    Prompt {
+        /// Prompt text (several words are joined with spaces)
+        #[arg(required = true)]
+        text: Vec<String>,
+    },
+    /// List, show, export, search, delete and prune saved sessions
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+    /// Show or change saved bot settings (~/.aemi/bot_settings.json)
+    #[command(subcommand)]
+    Settings(SettingsCommand),
+    /// Send a file or a message to a Telegram chat through a running bot
+    #[command(subcommand)]
+    Send(SendCommand),
+    /// Encrypt existing sessions, archives and bot settings in place
+    #[command(after_help = ENCRYPTION_KEY_HELP)]
+    Encrypt(DryRun),
+    /// Decrypt sessions, archives and bot settings back to plaintext
+    #[command(after_help = ENCRYPTION_KEY_HELP)]
+    Decrypt(DryRun),
+    /// Print a shell completion script (e.g. `aemi completions bash > ~/.local/share/bash-completion/completions/aemi`)
+    Completions {
+        shell: clap_complete::Shell,
+    },
+    /// Decode base64 and print it
+    #[command(hide = true)]
+    Base64 {
+        encoded: String,
+    },
+}
+
+const ENCRYPTION_KEY_HELP: &str = "Key: AEMI_ENCRYPTION_KEY, AEMI_ENCRYPTION_KEYFILE, AEMI_ENCRYPTION_KEYRING=1 or ~/.aemi/encryption.key";
+
+#[derive(Args)]
+#[command(
+    group = clap::ArgGroup::new("token_source").multiple(false),
+    after_help = "\
+Tokens for --routing are read from AEMI_TELEGRAM_TOKEN or AEMI_DISCORD_TOKEN unless a token
+option is given. Environment variables, files and helper output may hold several
+whitespace-separated tokens (Telegram only).
+
+Examples:
+    aemi serve                                   Run every [[bot]] in ~/.aemi/config.toml
+    aemi serve --config ./bots.toml
+    AEMI_TELEGRAM_TOKEN=... aemi serve --routing telegram --chat-id <ID>
+    aemi serve --routing telegram --agent claude --token-file ~/.aemi/telegram.token --chat-id <ID>
+    aemi serve --routing discord --agent codex --token-command 'pass show aemi/discord' --channel-id <ID>",
+)]
+struct ServeArgs {
+    /// Config file with [[bot]] entries (default ~/.aemi/config.toml)
+    #[arg(long, value_name = "PATH", conflicts_with = "routing")]
+    config: Option<String>,
+    /// Run a single bot on this platform instead of using the config file
+    #[arg(long, value_enum, value_name = "PLATFORM")]
+    routing: Option<Routing>,
+    /// AI agent for the single bot
+    #[arg(
+        long,
+        default_value = "claude",
+        requires = "routing",
+        value_parser = clap::builder::PossibleValuesParser::new(bot_common::AVAILABLE_AGENTS.iter().map(|(name, _)| *name)),
+    )]
+    agent: String,
+    /// Bot token(s) on the command line. Visible to other local users in `ps`; prefer the options below
+    #[arg(long, num_args = 1.., value_name = "TOKEN", requires = "routing", group = "token_source")]
+    token: Vec<String>,
+    /// Read the token(s) from this environment variable
+    #[arg(long, value_name = "VAR", requires = "routing", group = "token_source")]
+    token_env: Option<String>,
+    /// Read the token(s) from a file
+    #[arg(long, value_name = "PATH", requires = "routing", group = "token_source")]
+    token_file: Option<String>,
+    /// Run a credential helper (with `sh -c`) and read the token(s) from its output
+    #[arg(long, value_name = "CMD", requires = "routing", group = "token_source")]
+    token_command: Option<String>,
+    /// Allowed Telegram chat ID
+    #[arg(
+        long,
+        value_name = "ID",
+        allow_negative_numbers = true,
+        requires = "routing",
+        required_if_eq("routing", "telegram"),
+        conflicts_with = "channel_id",
+    )]
+    chat_id: Option<i64>,
+    /// Allowed Discord channel ID
+    #[arg(long, value_name = "ID", requires = "routing", required_if_eq("routing", "discord"))]
+    channel_id: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Routing {
+    Telegram,
+    Discord,
+}
+
+#[derive(Subcommand)]
+enum SessionsCommand {
+    /// List saved sessions, pinned first, then most recent
+    List {
+        /// Maximum number of sessions to list (0 = all)
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Print a session transcript, including archived history
+    Show {
+        id: String,
+        #[arg(long, value_enum, default_value = "md")]
+        format: Format,
+    },
+    /// Export a session transcript to ~/.aemi/exports
+    Export {
+        id: String,
+        #[arg(long, value_enum, default_value = "md")]
+        format: Format,
+    },
+    /// Delete a saved session and its history archive
+    Delete {
+        id: String,
+    },
+    /// Full-text search across saved sessions
+    Search {
+        #[arg(required = true)]
+        terms: Vec<String>,
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Apply retention limits to sessions, workspaces and debug logs
+    Prune(DryRun),
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Md,
+    Html,
+    Json,
+}
+
+impl From<Format> for ExportFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Md => ExportFormat::Markdown,
+            Format::Html => ExportFormat::Html,
+            Format::Json => ExportFormat::Json,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum SettingsCommand {
+    /// Print saved settings per bot (tokens are hidden)
+    Show {
+        /// Bot key as shown by `aemi settings show`; all bots if omitted
+        bot: Option<String>,
+    },
+    /// Change one setting of a bot. Running bots apply it when sent SIGHUP or on /reload
+    #[command(after_help = "\
+Examples:
+    aemi settings set <BOT> allowed_tools Read,Grep,Glob
+    aemi settings set <BOT> owner_user_id 123456789
+    aemi settings set <BOT> owner_user_id none")]
+    Set {
+        /// Bot key as shown by `aemi settings show`
+        bot: String,
+        #[arg(value_parser = clap::builder::PossibleValuesParser::new(bot_common::SETTABLE_KEYS.iter().copied()))]
+        key: String,
+        value: String,
+    },
 }
 
-fn print_version() {
-    println!("aemi {}", VERSION);
+#[derive(Subcommand)]
+enum SendCommand {
+    /// Send a file as a document
+    File {
+        path: PathBuf,
+        #[command(flatten)]
+        target: SendTarget,
+    },
+    /// Send a text message
+    Text {
+        text: String,
+        #[command(flatten)]
+        target: SendTarget,
+    },
+}
+
+#[derive(Args)]
+struct SendTarget {
+    /// Telegram chat ID
+    #[arg(long, value_name = "ID", allow_negative_numbers = true)]
+    chat: i64,
+    /// Key (token hash) of the running bot, as given in its system prompt
+    #[arg(long, value_name = "HASH")]
+    key: String,
+}
+
+#[derive(Args)]
+struct DryRun {
+    /// Only print what would change
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Map the flags used before subcommands existed onto their subcommands, so
+/// `--prompt`, `--base64`, `--sendfile` (which agents are told to run) and the
+/// `--agent ... --routing ...` server flags keep working.
+fn rewrite_legacy_args(mut args: Vec<String>) -> Vec<String> {
+    let replacement: &[&str] = match args.get(1).map(String::as_str) {
+        Some("--prompt") => &["prompt"],
+        Some("--base64") => &["base64"],
+        Some("--sendfile") => &["send", "file"],
+        Some("--agent" | "--routing" | "--token" | "--token-env" | "--token-file" | "--token-command"
+            | "--chat-id" | "--channel-id") => {
+            args.insert(1, "serve".to_string());
+            return args;
+        }
+        _ => return args,
+    };
+    args.splice(1..2, replacement.iter().map(|s| s.to_string()));
+    args
 }
 
 fn handle_base64(encoded: &str) {
@@ -56,35 +272,214 @@ fn handle_base64(encoded: &str) {
     }
 }
 
-fn handle_sendfile(path: &str, chat_id: i64, hash_key: &str) {
-    use teloxide::prelude::*;
-    use crate::services::telegram::resolve_token_by_hash;
-    let token = match resolve_token_by_hash(hash_key) {
-        Some(t) => t,
-        None => {
-            eprintln!("Error: no bot token found for hash key: {}", hash_key);
-            std::process::exit(1);
+fn handle_send(command: SendCommand) {
+    use crate::services::ipc::{self, Request};
+
+    let (key, request, done) = match command {
+        SendCommand::File { path, target } => {
+            // The server may run in another directory
+            let path = std::path::absolute(&path).unwrap_or(path);
+            if !path.is_file() {
+                eprintln!("Error: file not found: {}", path.display());
+                std::process::exit(1);
+            }
+            let done = format!("File sent: {}", path.display());
+            (target.key, Request::SendFile { chat: target.chat, path }, done)
+        }
+        SendCommand::Text { text, target } => {
+            (target.key, Request::SendText { chat: target.chat, text }, "Message sent".to_string())
         }
     };
-    let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
-    rt.block_on(async {
-        let bot = Bot::new(&token);
-        let file_path = std::path::Path::new(path);
-        if !file_path.exists() {
-            eprintln!("Error: file not found: {}", path);
+    match ipc::request(&key, &request) {
+        Ok(()) => println!("{}", done),
+        Err(e) => {
+            eprintln!("Failed to send: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn handle_sessions(command: SessionsCommand) {
+    use crate::services::export;
+    use crate::services::search;
+    use crate::services::retention;
+
+    let load = |session_id: &str| {
+        bot_common::load_session_by_id(session_id).unwrap_or_else(|| {
+            eprintln!("Error: session not found: {}", session_id);
             std::process::exit(1);
+        })
+    };
+
+    match command {
+        SessionsCommand::List { limit } => {
+            let sessions = bot_common::list_all_sessions();
+            if sessions.is_empty() {
+                println!("No saved sessions.");
+                return;
+            }
+            let shown = if limit == 0 { sessions.len() } else { limit.min(sessions.len()) };
+            for s in &sessions[..shown] {
+                let modified: chrono::DateTime<chrono::Local> = s.modified.into();
+                println!("{}{}  {}  {:>4} items  {}",
+                    if s.pinned { "📌 " } else { "" },
+                    s.session_id, modified.format("%Y-%m-%d %H:%M"), s.history_count, s.current_path);
+                println!("    {}", s.title);
+            }
+            if shown < sessions.len() {
+                println!("({} more; use --limit 0 to list all)", sessions.len() - shown);
+            }
+        }
+        SessionsCommand::Show { id, format } => {
+            let data = export::with_archived_history(&load(&id));
+            let rendered = export::render_session(&data, format.into(), None);
+            print!("{}", rendered);
+            if !rendered.ends_with('\n') {
+                println!();
+            }
+        }
+        SessionsCommand::Export { id, format } => {
+            match export::export_session_to_file(&load(&id), format.into(), None) {
+                Ok(path) => println!("Exported: {}", path.display()),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SessionsCommand::Delete { id } => {
+            match retention::delete_session(&id) {
+                Ok(true) => println!("Deleted: {}", id),
+                Ok(false) => {
+                    eprintln!("Error: session not found: {}", id);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        SessionsCommand::Search { terms, limit } => {
+            let hits = search::search_sessions(&terms.join(" "), limit, None);
+            if hits.is_empty() {
+                println!("No sessions match.");
+                return;
+            }
+            for hit in &hits {
+                println!("{}  {}  {}", hit.session_id, hit.created_at, hit.current_path);
+                if let Some(name) = &hit.name {
+                    println!("    name: {}", name);
+                }
+                for snippet in &hit.snippets {
+                    println!("    » {}", snippet);
+                }
+            }
+        }
+        SessionsCommand::Prune(DryRun { dry_run }) => {
+            // Bots may be running: leave sessions they could still write back alone
+            let protected = retention::Protected { changed_within: Some(retention::RECENT_CHANGE), ..Default::default() };
+            let report = retention::prune(retention::policy(), &protected, dry_run);
+            let verb = if dry_run { "Would remove" } else { "Removed" };
+            for path in report.sessions_removed.iter()
+                .chain(&report.workspaces_removed)
+                .chain(&report.logs_removed)
+            {
+                println!("{}: {}", verb, path.display());
+            }
+            for (session_id, count) in &report.sessions_trimmed {
+                let verb = if dry_run { "Would archive" } else { "Archived" };
+                println!("{}: {} items from {}", verb, count, session_id);
+            }
+            for path in &report.logs_rotated {
+                let verb = if dry_run { "Would rotate" } else { "Rotated" };
+                println!("{}: {}", verb, path.display());
+            }
+            println!("{}{}", if dry_run { "[dry run] " } else { "" }, report.summary());
+        }
+    }
+}
+
+fn handle_settings(command: SettingsCommand) {
+    use crate::services::settings_file::{self, ReadError};
+
+    let fail = |msg: String| -> ! {
+        eprintln!("Error: {}", msg);
+        std::process::exit(1);
+    };
+    let Some(path) = bot_common::bot_settings_path() else {
+        fail("cannot determine home directory".to_string());
+    };
+    if !path.exists() {
+        fail(format!("{} does not exist yet; it is created when a bot first runs", path.display()));
+    }
+
+    match command {
+        SettingsCommand::Show { bot } => {
+            let mut json = match settings_file::read(&path) {
+                Ok(json) => json,
+                Err(ReadError::Unreadable(e) | ReadError::Corrupt(e)) => fail(e),
+            };
+            let Some(entries) = json.as_object_mut() else {
+                fail(format!("{} is not a JSON object", path.display()));
+            };
+            if let Some(bot) = &bot {
+                entries.retain(|key, _| key == bot);
+                if entries.is_empty() {
+                    fail(format!("no bot '{}' in {}", bot, path.display()));
+                }
+            }
+            for entry in entries.values_mut() {
+                if let Some(token) = entry.get_mut("token") {
+                    *token = serde_json::json!("<hidden>");
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&json).unwrap_or_default());
+        }
+        SettingsCommand::Set { bot, key, value } => {
+            if let Err(e) = settings_file::update(&path, |json| bot_common::apply_setting(json, &bot, &key, &value)) {
+                fail(e);
+            }
+            println!("Updated {} for {}. Send SIGHUP to running bots or use /reload to apply it.", key, bot);
         }
-        match bot.send_document(
-            ChatId(chat_id),
-            teloxide::types::InputFile::file(file_path),
-        ).await {
-            Ok(_) => println!("File sent: {}", path),
+    }
+}
+
+fn handle_encryption(dry_run: bool, encrypt: bool) {
+    use crate::services::crypto::{self, Conversion};
+
+    if !crypto::is_enabled() {
+        eprintln!("Error: no encryption key configured.");
+        eprintln!("Set AEMI_ENCRYPTION_KEY, AEMI_ENCRYPTION_KEYFILE or AEMI_ENCRYPTION_KEYRING=1,");
+        eprintln!("or create ~/.aemi/encryption.key");
+        std::process::exit(1);
+    }
+
+    let verb = match (encrypt, dry_run) {
+        (true, false) => "Encrypted",
+        (true, true) => "Would encrypt",
+        (false, false) => "Decrypted",
+        (false, true) => "Would decrypt",
+    };
+    let (mut converted, mut failed) = (0, 0);
+    for path in crypto::data_files() {
+        match crypto::convert_file(&path, encrypt, dry_run) {
+            Ok(Conversion::Converted) => {
+                converted += 1;
+                println!("{}: {}", verb, path.display());
+            }
+            Ok(Conversion::Unchanged) => {}
             Err(e) => {
-                eprintln!("Failed to send file: {}", e);
-                std::process::exit(1);
+                failed += 1;
+                eprintln!("Failed: {}: {}", path.display(), e);
             }
         }
-    });
+    }
+    println!("{}{} {} file(s), {} failed",
+        if dry_run { "[dry run] " } else { "" }, verb, converted, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
 }
 
 fn handle_prompt(prompt: &str) {
@@ -126,12 +521,13 @@ fn handle_telegram_server(tokens: Vec<String>, allowed_chat_id: i64, agent: &str
 
     println!("  ▸ Agent        : {}", agent);
     println!("  ▸ Chat ID filter : {}", allowed_chat_id);
+    println!("  ▸ Encryption   : {}", if services::crypto::is_enabled() { "on" } else { "off" });
 
     if tokens.len() == 1 {
         println!("  ▸ Bot instance : 1");
         println!("  ▸ Status       : Connecting...");
         println!();
-        rt.block_on(services::telegram::run_bot(&tokens[0], Some(allowed_chat_id), agent));
+        rt.block_on(services::telegram::run_bot(&tokens[0], vec![allowed_chat_id], agent, BotOptions::default()));
     } else {
         println!("  ▸ Bot instances : {}", tokens.len());
         println!("  ▸ Status        : Connecting...");
@@ -144,7 +540,7 @@ fn handle_telegram_server(tokens: Vec<String>, allowed_chat_id: i64, agent: &str
                 let agent = agent_owned.clone();
                 handles.push(tokio::spawn(async move {
                     println!("  ✓ Bot #{} connected", i + 1);
-                    services::telegram::run_bot(&token, Some(chat_id), &agent).await;
+                    services::telegram::run_bot(&token, vec![chat_id], &agent, BotOptions::default()).await;
                 }));
             }
             for handle in handles {
@@ -167,219 +563,200 @@ fn handle_discord_server(token: String, allowed_channel_id: u64, agent: &str) {
 
     println!("  ▸ Agent          : {}", agent);
     println!("  ▸ Channel ID filter : {}", allowed_channel_id);
+    println!("  ▸ Encryption     : {}", if services::crypto::is_enabled() { "on" } else { "off" });
 
     println!("  ▸ Bot instance : 1");
     println!("  ▸ Status       : Connecting...");
     println!();
-    rt.block_on(services::discord::run_bot(&token, Some(allowed_channel_id), agent));
+    rt.block_on(services::discord::run_bot(&token, vec![allowed_channel_id], agent, BotOptions::default()));
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+/// Exit with a usage error for `aemi serve`
+fn serve_error(kind: clap::error::ErrorKind, message: &str) -> ! {
+    let mut cli = Cli::command();
+    let serve = cli.find_subcommand_mut("serve").map(std::mem::take).unwrap_or_default();
+    serve.bin_name("aemi serve").error(kind, message).exit()
+}
 
-    if args.len() <= 1 {
-        print_help();
-        return;
-    }
+/// Tokens for single-bot mode: `--token`, one of the token options, or the platform's env var
+fn serve_tokens(args: &ServeArgs, routing: Routing) -> Vec<String> {
+    use crate::services::credentials::{self, TokenSource};
 
-    // Handle standalone commands first
-    match args[1].as_str() {
-        "-h" | "--help" => {
-            print_help();
-            return;
-        }
-        "-v" | "--version" => {
-            print_version();
-            return;
-        }
-        "--prompt" => {
-            if args.len() < 3 {
-                eprintln!("Error: --prompt requires a text argument");
-                eprintln!("Usage: aemi --prompt \"your question\"");
-                return;
-            }
-            handle_prompt(&args[2]);
-            return;
-        }
-        "--base64" => {
-            if args.len() < 3 {
-                std::process::exit(1);
-            }
-            handle_base64(&args[2]);
-            return;
-        }
-        "--sendfile" => {
-            // Parse: --sendfile <PATH> --chat <ID> --key <TOKEN>
-            let mut file_path: Option<String> = None;
-            let mut chat_id: Option<i64> = None;
-            let mut key: Option<String> = None;
-            let mut j = 2;
-            while j < args.len() {
-                match args[j].as_str() {
-                    "--chat" => {
-                        if j + 1 < args.len() {
-                            chat_id = args[j + 1].parse().ok();
-                            j += 2;
-                        } else {
-                            j += 1;
-                        }
-                    }
-                    "--key" => {
-                        if j + 1 < args.len() {
-                            key = Some(args[j + 1].clone());
-                            j += 2;
-                        } else {
-                            j += 1;
-                        }
-                    }
-                    _ if file_path.is_none() && !args[j].starts_with("--") => {
-                        file_path = Some(args[j].clone());
-                        j += 1;
-                    }
-                    _ => { j += 1; }
-                }
-            }
-            match (file_path, chat_id, key) {
-                (Some(fp), Some(cid), Some(k)) => {
-                    handle_sendfile(&fp, cid, &k);
-                }
-                _ => {
-                    eprintln!("Error: --sendfile requires <PATH>, --chat <ID>, and --key <HASH>");
-                    eprintln!("Usage: aemi --sendfile <PATH> --chat <ID> --key <HASH>");
-                }
-            }
-            return;
-        }
-        _ => {}
+    if !args.token.is_empty() {
+        eprintln!("  ⚠ --token is visible to other local users; prefer --token-file, --token-env or --token-command");
+        return args.token.clone();
     }
+    let default_env = match routing {
+        Routing::Telegram => credentials::TELEGRAM_TOKEN_ENV,
+        Routing::Discord => credentials::DISCORD_TOKEN_ENV,
+    };
+    let source = if let Some(var) = &args.token_env {
+        TokenSource::Env(var.clone())
+    } else if let Some(file) = &args.token_file {
+        TokenSource::File(file.clone())
+    } else if let Some(cmd) = &args.token_command {
+        TokenSource::Command(cmd.clone())
+    } else if std::env::var_os(default_env).is_some() {
+        TokenSource::Env(default_env.to_string())
+    } else {
+        serve_error(clap::error::ErrorKind::MissingRequiredArgument, &format!(
+            "no bot token: set {} or pass --token-file, --token-env or --token-command", default_env));
+    };
+    credentials::read_tokens(&source)
+        .unwrap_or_else(|e| serve_error(clap::error::ErrorKind::InvalidValue, &e))
+}
 
-    // Parse server mode: --agent <AGENT> --routing <PLATFORM> --token <TOKEN>... [OPTIONS]
-    let mut agent: Option<String> = None;
-    let mut routing: Option<String> = None;
-    let mut tokens: Vec<String> = Vec::new();
-    let mut chat_id: Option<i64> = None;
-    let mut channel_id: Option<u64> = None;
-
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--agent" => {
-                if i + 1 >= args.len() {
-                    eprintln!("Error: --agent requires a value (e.g., claude)");
-                    return;
-                }
-                agent = Some(args[i + 1].clone());
-                i += 2;
-            }
-            "--routing" => {
-                if i + 1 >= args.len() {
-                    eprintln!("Error: --routing requires a value (e.g., telegram, discord)");
-                    return;
-                }
-                routing = Some(args[i + 1].clone());
-                i += 2;
-            }
-            "--token" => {
-                i += 1;
-                while i < args.len() && !args[i].starts_with('-') {
-                    tokens.push(args[i].clone());
-                    i += 1;
-                }
-                if tokens.is_empty() {
-                    eprintln!("Error: --token requires at least one value");
-                    return;
-                }
-            }
-            "--chat-id" => {
-                if i + 1 >= args.len() {
-                    eprintln!("Error: --chat-id requires a value");
-                    return;
-                }
-                chat_id = args[i + 1].parse().ok();
-                if chat_id.is_none() {
-                    eprintln!("Error: --chat-id value must be a valid integer");
-                    return;
-                }
-                i += 2;
+fn handle_serve(args: ServeArgs) {
+    // Before any runtime thread starts, since it takes the key out of the environment
+    services::crypto::init();
+    if let Some(routing) = args.routing {
+        let mut tokens = serve_tokens(&args, routing);
+        services::credentials::take_default_env();
+        match (routing, args.chat_id, args.channel_id) {
+            (Routing::Telegram, Some(chat_id), _) => handle_telegram_server(tokens, chat_id, &args.agent),
+            (Routing::Discord, _, Some(channel_id)) if tokens.len() == 1 => {
+                handle_discord_server(tokens.remove(0), channel_id, &args.agent);
             }
-            "--channel-id" => {
-                if i + 1 >= args.len() {
-                    eprintln!("Error: --channel-id requires a value");
-                    return;
-                }
-                channel_id = args[i + 1].parse().ok();
-                if channel_id.is_none() {
-                    eprintln!("Error: --channel-id value must be a valid integer");
-                    return;
-                }
-                i += 2;
+            (Routing::Discord, _, Some(_)) => {
+                serve_error(clap::error::ErrorKind::TooManyValues, "Discord supports only one token");
             }
-            arg => {
-                eprintln!("Unknown option: {}", arg);
-                eprintln!("Use --help for usage information");
-                return;
+            _ => {
+                serve_error(clap::error::ErrorKind::MissingRequiredArgument,
+                    "--chat-id (telegram) or --channel-id (discord) is required (security)");
             }
         }
+        return;
     }
 
-    // Validate required server mode flags
-    let agent = match agent {
-        Some(a) => a,
-        None => {
-            eprintln!("Error: --agent is required");
-            eprintln!("Usage: aemi --agent claude --routing telegram --token <TOKEN>");
-            return;
+    let config_path = args.config
+        .map(|value| services::access::expand_tilde(&value, dirs::home_dir().as_deref()));
+    let Some(config_path) = config_path.or_else(services::config::default_path) else {
+        eprintln!("Error: cannot determine home directory; pass --config <PATH>");
+        return;
+    };
+    let bots = match services::config::load(&config_path) {
+        Ok(bots) => bots,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
         }
     };
-    let routing = match routing {
-        Some(r) => r,
-        None => {
-            eprintln!("Error: --routing is required");
-            eprintln!("Usage: aemi --agent claude --routing telegram --token <TOKEN>");
-            return;
+    services::credentials::take_default_env();
+
+    let rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            eprintln!("Error: failed to create Tokio runtime: {}", e);
+            std::process::exit(1);
         }
     };
-    if tokens.is_empty() {
-        eprintln!("Error: --token is required");
-        eprintln!("Usage: aemi --agent {} --routing {} --token <TOKEN>", agent, routing);
-        return;
+
+    let title = format!("  aemi v{}  |  Bot Server  ", VERSION);
+    let width = title.chars().count();
+    println!();
+    println!("  ┌{}┐", "─".repeat(width));
+    println!("  │{}│", title);
+    println!("  └{}┘", "─".repeat(width));
+    println!();
+
+    println!("  ▸ Config        : {}", config_path.display());
+    println!("  ▸ Encryption    : {}", if services::crypto::is_enabled() { "on" } else { "off" });
+    println!("  ▸ Bot instances : {}", bots.len());
+    for bot in &bots {
+        let chats: Vec<String> = bot.chats.iter().map(|id| id.to_string()).collect();
+        println!("      {} — {}, {}, chats {}", bot.name, bot.platform.name(), bot.agent, chats.join(", "));
     }
+    println!("  ▸ Status        : Connecting...");
+    println!();
 
-    // Dispatch based on agent and routing
-    match agent.as_str() {
-        "claude" | "gemini" | "codex" | "opencode" | "oh-my-pi" => match routing.as_str() {
-            "telegram" => {
-                let chat_id = match chat_id {
-                    Some(id) => id,
-                    None => {
-                        eprintln!("Error: --chat-id is required for Telegram routing (security)");
-                        eprintln!("Usage: aemi --agent {} --routing telegram --token <TOKEN> --chat-id <ID>", agent);
-                        return;
+    rt.block_on(async {
+        let mut handles = Vec::new();
+        for bot in bots {
+            handles.push(tokio::spawn(async move {
+                match bot.platform {
+                    services::config::Platform::Telegram => {
+                        services::telegram::run_bot(&bot.token, bot.chats, &bot.agent, bot.options).await;
                     }
-                };
-                handle_telegram_server(tokens, chat_id, &agent);
-            }
-            "discord" => {
-                if tokens.len() > 1 {
-                    eprintln!("Error: Discord supports only one token");
-                    return;
-                }
-                let channel_id = match channel_id {
-                    Some(id) => id,
-                    None => {
-                        eprintln!("Error: --channel-id is required for Discord routing (security)");
-                        eprintln!("Usage: aemi --agent {} --routing discord --token <TOKEN> --channel-id <ID>", agent);
-                        return;
+                    services::config::Platform::Discord => {
+                        let channels = bot.chats.iter().map(|id| *id as u64).collect();
+                        services::discord::run_bot(&bot.token, channels, &bot.agent, bot.options).await;
                     }
-                };
-                handle_discord_server(tokens.into_iter().next().unwrap(), channel_id, &agent);
-            }
-            other => {
-                eprintln!("Error: unsupported routing '{}'. Supported: telegram, discord", other);
-            }
-        },
-        other => {
-            eprintln!("Error: unsupported agent '{}'. Supported: claude, gemini, codex, opencode, oh-my-pi", other);
+                }
+                println!("  ✗ Bot '{}' stopped", bot.name);
+            }));
         }
+        for handle in handles {
+            let _ = handle.await;
+        }
+    });
+}
+
+fn main() {
+    let cli = Cli::parse_from(rewrite_legacy_args(std::env::args().collect()));
+
+    match cli.command {
+        Command::Serve(args) => handle_serve(args),
+        Command::Prompt { text } => handle_prompt(&text.join(" ")),
+        Command::Sessions(command) => handle_sessions(command),
+        Command::Settings(command) => handle_settings(command),
+        Command::Send(command) => handle_send(command),
+        Command::Encrypt(DryRun { dry_run }) => handle_encryption(dry_run, true),
+        Command::Decrypt(DryRun { dry_run }) => handle_encryption(dry_run, false),
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "aemi", &mut std::io::stdout());
+        }
+        Command::Base64 { encoded } => handle_base64(&encoded),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        let args = std::iter::once("aemi").chain(args.iter().copied()).map(String::from).collect();
+        Cli::try_parse_from(rewrite_legacy_args(args))
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_legacy_flags() {
+        assert!(matches!(parse(&["--prompt", "hi"]).unwrap().command, Command::Prompt { text } if text == ["hi"]));
+        assert!(matches!(parse(&["--base64", "aGk="]).unwrap().command, Command::Base64 { .. }));
+
+        let Command::Send(SendCommand::File { path, target }) =
+            parse(&["--sendfile", "/tmp/a.txt", "--chat", "-100123", "--key", "abc"]).unwrap().command
+        else {
+            panic!("expected send file");
+        };
+        assert_eq!(path, PathBuf::from("/tmp/a.txt"));
+        assert_eq!((target.chat, target.key.as_str()), (-100123, "abc"));
+
+        let Command::Serve(args) =
+            parse(&["--agent", "codex", "--routing", "telegram", "--token", "t1", "t2", "--chat-id", "-5"]).unwrap().command
+        else {
+            panic!("expected serve");
+        };
+        assert!(args.routing == Some(Routing::Telegram));
+        assert_eq!(args.agent, "codex");
+        assert_eq!(args.token, ["t1", "t2"]);
+        assert_eq!(args.chat_id, Some(-5));
+    }
+
+    #[test]
+    fn test_serve_validation() {
+        assert!(parse(&["serve"]).is_ok());
+        assert!(parse(&["serve", "--routing", "telegram", "--token", "t"]).is_err());
+        assert!(parse(&["serve", "--routing", "telegram", "--token-file", "f", "--chat-id", "1"]).is_ok());
+        assert!(parse(&["serve", "--routing", "telegram", "--token", "t", "--token-env", "V", "--chat-id", "1"]).is_err());
+        assert!(parse(&["serve", "--token-command", "pass show x"]).is_err());
+        assert!(parse(&["serve", "--routing", "discord", "--token", "t", "--chat-id", "1"]).is_err());
+        assert!(parse(&["serve", "--config", "x.toml", "--routing", "telegram", "--token", "t", "--chat-id", "1"]).is_err());
+        assert!(parse(&["serve", "--chat-id", "1"]).is_err());
+        assert!(parse(&["serve", "--routing", "telegram", "--agent", "gpt", "--token", "t", "--chat-id", "1"]).is_err());
     }
 }
@@ -0,0 +1,185 @@
+//! Filesystem access limits for chat users.
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Expand a leading `~` to the home directory
+pub fn expand_tilde(path: &str, home: Option<&Path>) -> PathBuf {
+    match (path, home) {
+        ("~", Some(home)) => home.to_path_buf(),
+        (p, Some(home)) if p.starts_with("~/") => home.join(&p[2..]),
+        (p, _) => PathBuf::from(p),
+    }
+}
+
+/// Parse a colon-separated root list. Entries that do not exist are dropped with a warning.
+pub fn parse_roots(value: &str, home: Option<&Path>) -> Vec<PathBuf> {
+    value.split(':')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| {
+            let path = expand_tilde(s, home);
+            match path.canonicalize() {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    eprintln!("  ⚠ AEMI_ALLOWED_ROOTS: ignoring {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Build a root list from configured entries, always including `~/.aemi/workspace`
+pub fn roots_from(entries: &[String]) -> Vec<PathBuf> {
+    let home = dirs::home_dir();
+    let mut roots = parse_roots(&entries.join(":"), home.as_deref());
+    if let Some(workspace) = home.map(|h| h.join(".aemi").join("workspace")) {
+        let _ = std::fs::create_dir_all(&workspace);
+        if let Ok(workspace) = workspace.canonicalize() {
+            roots.push(workspace);
+        }
+    }
+    roots
+}
+
+/// Allowed roots from `AEMI_ALLOWED_ROOTS` (colon-separated, `~` expanded), or None when
+/// access is unrestricted. When set, `/start`, `/resume`, `/down`, `!` shell working
+/// directories and upload destinations must resolve inside one of them. Bots defined in a
+/// config file can set their own `workspace_roots` instead (see [`roots_from`]).
+pub fn allowed_roots() -> Option<&'static [PathBuf]> {
+    static ROOTS: OnceLock<Option<Vec<PathBuf>>> = OnceLock::new();
+    ROOTS.get_or_init(|| {
+        let value = std::env::var("AEMI_ALLOWED_ROOTS").ok().filter(|v| !v.trim().is_empty())?;
+        Some(roots_from(&[value]))
+    }).as_deref()
+}
+
+/// Whether `/down` is confined to the session directory (`AEMI_DOWN_SESSION_ONLY=1`)
+pub fn down_session_only() -> bool {
+    static FLAG: OnceLock<bool> = OnceLock::new();
+    *FLAG.get_or_init(|| std::env::var("AEMI_DOWN_SESSION_ONLY").map(|v| v == "1").unwrap_or(false))
+}
+
+/// Resolve a path the way the OS will: canonicalize the longest existing prefix
+/// (following symlinks) and append the rest, rejecting `..` in the non-existent part.
+/// A dangling symlink is not a non-existent part: writing to it would create its target,
+/// so it is rejected. Callers write to the returned path, not the one they passed in.
+pub fn resolve(path: &Path) -> Option<PathBuf> {
+    if let Ok(p) = path.canonicalize() {
+        return Some(p);
+    }
+    let mut existing = path.to_path_buf();
+    let mut rest = Vec::new();
+    // symlink_metadata sees a dangling link as existing; its canonicalize below fails
+    while std::fs::symlink_metadata(&existing).is_err() {
+        let name = existing.file_name()?.to_os_string();
+        rest.push(name);
+        existing = existing.parent()?.to_path_buf();
+    }
+    let mut resolved = existing.canonicalize().ok()?;
+    for name in rest.iter().rev() {
+        let component = Path::new(name);
+        if component.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        resolved.push(component);
+    }
+    Some(resolved)
+}
+
+/// Whether a resolved path lies inside any of the roots
+pub fn is_within(path: &Path, roots: &[PathBuf]) -> bool {
+    roots.iter().any(|root| path.starts_with(root))
+}
+
+/// Log a denied access attempt
+fn log_denied(action: &str, path: &Path, who: &str) {
+    let ts = chrono::Local::now().format("%H:%M:%S");
+    println!("  [{ts}] ⛔ [{who}] Denied {action}: {}", path.display());
+}
+
+/// Check that `path` is inside `roots` (None means unrestricted). Returns the resolved path.
+/// Paths are resolved first, so `..` and symlinks cannot escape a root.
+/// `action` and `who` are only used for the denial log line.
+pub fn check(roots: Option<&[PathBuf]>, path: &Path, action: &str, who: &str) -> Result<PathBuf, String> {
+    let Some(resolved) = resolve(path) else {
+        log_denied(action, path, who);
+        return Err(format!("Access denied: {}", path.display()));
+    };
+    match roots {
+        Some(roots) if !is_within(&resolved, roots) => {
+            log_denied(action, &resolved, who);
+            Err(format!("Access denied: {} is outside the allowed directories.", resolved.display()))
+        }
+        _ => Ok(resolved),
+    }
+}
+
+/// Check a `/down` target: allowed roots, plus the session directory when confined
+pub fn check_download(roots: Option<&[PathBuf]>, path: &Path, session_dir: Option<&str>, who: &str) -> Result<PathBuf, String> {
+    let resolved = check(roots, path, "download", who)?;
+    if down_session_only() {
+        let session_root = session_dir.and_then(|d| Path::new(d).canonicalize().ok());
+        let inside = session_root.map(|root| resolved.starts_with(root)).unwrap_or(false);
+        if !inside {
+            log_denied("download", &resolved, who);
+            return Err("Access denied: /down is limited to the session directory.".to_string());
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_tilde() {
+        let home = Path::new("/home/me");
+        assert_eq!(expand_tilde("~", Some(home)), PathBuf::from("/home/me"));
+        assert_eq!(expand_tilde("~/src", Some(home)), PathBuf::from("/home/me/src"));
+        assert_eq!(expand_tilde("/srv", Some(home)), PathBuf::from("/srv"));
+        assert_eq!(expand_tilde("~other", Some(home)), PathBuf::from("~other"));
+    }
+
+    #[test]
+    fn test_parse_roots_skips_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let value = format!("{}: /definitely/not/here ", dir.path().display());
+        let roots = parse_roots(&value, None);
+        assert_eq!(roots, vec![dir.path().canonicalize().unwrap()]);
+    }
+
+    #[test]
+    fn test_is_within_after_canonicalization() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("sub")).unwrap();
+        let roots = vec![root.path().canonicalize().unwrap()];
+
+        let inside = resolve(&root.path().join("sub")).unwrap();
+        assert!(is_within(&inside, &roots));
+
+        // `..` escapes are resolved before the check
+        let escaped = resolve(&root.path().join("sub/../..")).unwrap();
+        assert!(!is_within(&escaped, &roots));
+
+        // Symlinks pointing outside are resolved too
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        let linked = resolve(&root.path().join("link/file.txt")).unwrap();
+        assert!(!is_within(&linked, &roots));
+
+        // A dangling symlink is not treated as a new file inside the root
+        std::os::unix::fs::symlink(outside.path().join("missing"), root.path().join("dangling")).unwrap();
+        assert_eq!(resolve(&root.path().join("dangling")), None);
+        assert_eq!(resolve(&root.path().join("dangling/file.txt")), None);
+    }
+
+    #[test]
+    fn test_resolve_nonexistent_tail() {
+        let root = tempfile::tempdir().unwrap();
+        let resolved = resolve(&root.path().join("new/file.txt")).unwrap();
+        assert_eq!(resolved, root.path().canonicalize().unwrap().join("new/file.txt"));
+    }
+}
@@ -1,5 +1,7 @@
 /// Shared types for all agent backends (Claude, Gemini, etc.)
 
+use crate::services::session::Usage;
+
 /// Streaming message types for real-time agent responses.
 /// All agent backends convert their native stream events into this common enum.
 #[derive(Debug, Clone)]
@@ -14,6 +16,8 @@ pub enum StreamMessage {
     ToolResult { content: String, is_error: bool },
     /// Background task notification
     TaskNotification { _task_id: String, _status: String, summary: String },
+    /// Model and token usage for the turn (sent before Done by agents that report them)
+    Usage { model: Option<String>, usage: Option<Usage> },
     /// Completion
     Done { result: String, session_id: Option<String> },
     /// Error
@@ -34,6 +38,24 @@ impl CancelToken {
             child_pid: std::sync::Mutex::new(None),
         }
     }
+
+    /// Send SIGTERM to the child's process group: the agent CLI and everything it started
+    pub fn kill_child(&self) {
+        if let Ok(guard) = self.child_pid.lock() {
+            if let Some(pid) = *guard {
+                kill_process_group(pid);
+            }
+        }
+    }
+}
+
+/// Send SIGTERM to a process group; agent CLIs are spawned as the leader of their own group
+pub fn kill_process_group(pid: u32) {
+    #[cfg(unix)]
+    #[allow(unsafe_code)]
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+    }
 }
 
 /// Common response type for non-streaming agent execution
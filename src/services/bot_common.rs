@@ -1,13 +1,23 @@
 /// Shared types and functions for Telegram and Discord bot implementations.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::hash::Hash;
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 use sha2::{Sha256, Digest};
 
+use crate::services::agent::CancelToken;
+use crate::services::chat_settings::{ChatSettings, Verbosity};
+use crate::services::chat_state::ChatState;
 use crate::services::claude::DEFAULT_ALLOWED_TOOLS;
-use crate::services::session::{self, HistoryItem, HistoryType, SessionData};
+use crate::services::crypto;
+use crate::services::roles::{self, Role};
+use crate::services::settings_file;
+use crate::services::system_prompt;
+use crate::services::session::{self, HistoryItem, HistoryType, SessionData, SessionOrigin};
 
 /// Bot-level settings persisted to disk
 #[derive(Clone)]
@@ -17,6 +27,20 @@ pub struct BotSettings {
     pub last_sessions: HashMap<String, String>,
     /// User ID of the registered owner (imprinting auth)
     pub owner_user_id: Option<u64>,
+    /// Allowed users and their roles (managed with /users)
+    pub users: BTreeMap<u64, Role>,
+    /// Bot-wide model; None uses the agent's default
+    pub model: Option<String>,
+    /// Bot-wide system prompt template with placeholders; None uses the built-in one
+    pub prompt_template: Option<String>,
+    /// Bot-wide system prompt additions
+    pub system_prompt: Option<String>,
+    /// Bot-wide verbosity; None means full
+    pub verbosity: Option<Verbosity>,
+    /// channel/chat id (string) → settings overriding the bot-wide ones (/settings)
+    pub chats: HashMap<String, ChatSettings>,
+    /// channel/chat id (string) → agent sessions and turn status, kept across restarts
+    pub chat_state: HashMap<String, ChatState>,
 }
 
 impl Default for BotSettings {
@@ -25,10 +49,32 @@ impl Default for BotSettings {
             allowed_tools: DEFAULT_ALLOWED_TOOLS.iter().map(|s| s.to_string()).collect(),
             last_sessions: HashMap::new(),
             owner_user_id: None,
+            users: BTreeMap::new(),
+            model: None,
+            prompt_template: None,
+            system_prompt: None,
+            verbosity: None,
+            chats: HashMap::new(),
+            chat_state: HashMap::new(),
         }
     }
 }
 
+/// Startup options for one bot instance, from the command line or the config file
+#[derive(Debug, Clone, Default)]
+pub struct BotOptions {
+    /// Replaces the saved allowed tools at startup (`/allowed` still works afterwards)
+    pub allowed_tools: Option<Vec<String>>,
+    /// Workspace roots for this bot; None falls back to `AEMI_ALLOWED_ROOTS`
+    pub workspace_roots: Option<Vec<PathBuf>>,
+    /// Role of allowed-chat members who are not listed users; None means `user`
+    pub chat_role: Option<Role>,
+    /// Users added to the saved user list at startup
+    pub users: BTreeMap<u64, Role>,
+    /// Config file the bot was started from; a reload re-reads the bot's entry in it
+    pub config_path: Option<PathBuf>,
+}
+
 /// All supported AI agent types
 pub const AVAILABLE_AGENTS: &[(&str, &str)] = &[
     ("claude",    "Claude Code (Anthropic)"),
@@ -110,71 +156,201 @@ pub fn bot_settings_path() -> Option<PathBuf> {
 
 /// Load bot settings from bot_settings.json using a pre-computed hash key.
 pub fn load_bot_settings(hash_key: &str) -> BotSettings {
-    let Some(path) = bot_settings_path() else {
-        return BotSettings::default();
-    };
-    let Ok(content) = fs::read_to_string(&path) else {
-        return BotSettings::default();
-    };
-    let Ok(json) = serde_json::from_str::<serde_json::Value>(&content) else {
-        return BotSettings::default();
-    };
-    let Some(entry) = json.get(hash_key) else {
-        return BotSettings::default();
-    };
-    let owner_user_id = entry.get("owner_user_id").and_then(|v| v.as_u64());
-    let Some(tools_arr) = entry.get("allowed_tools").and_then(|v| v.as_array()) else {
-        return BotSettings { owner_user_id, ..BotSettings::default() };
-    };
-    let tools: Vec<String> = tools_arr
-        .iter()
-        .filter_map(|v| v.as_str().map(String::from))
-        .collect();
-    if tools.is_empty() {
-        return BotSettings { owner_user_id, ..BotSettings::default() };
-    }
-    let last_sessions = entry.get("last_sessions")
+    // Corrupt or unreadable files are reported by settings_file
+    read_bot_settings(hash_key).unwrap_or_default()
+}
+
+/// Read bot settings, failing instead of falling back to defaults when the file is
+/// unreadable or corrupt (used by reloads, which must not reset a running bot)
+pub fn read_bot_settings(hash_key: &str) -> Result<BotSettings, String> {
+    let path = bot_settings_path().ok_or("cannot determine home directory")?;
+    let json = settings_file::read(&path).map_err(|e| match e {
+        settings_file::ReadError::Unreadable(e) | settings_file::ReadError::Corrupt(e) => e,
+    })?;
+    Ok(json.get(hash_key).map(settings_from_entry).unwrap_or_default())
+}
+
+/// Per-chat objects of a bot entry; entries that cannot be parsed are skipped
+fn object_map<T: serde::de::DeserializeOwned>(entry: &serde_json::Value, key: &str) -> HashMap<String, T> {
+    entry.get(key)
         .and_then(|v| v.as_object())
         .map(|obj| {
             obj.iter()
-                .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
+                .filter_map(|(k, v)| Some((k.clone(), serde_json::from_value(v.clone()).ok()?)))
                 .collect()
         })
+        .unwrap_or_default()
+}
+
+/// Parse one bot's entry of bot_settings.json
+fn settings_from_entry(entry: &serde_json::Value) -> BotSettings {
+    let text = |key: &str| entry.get(key).and_then(|v| v.as_str()).map(String::from);
+    let defaults = BotSettings::default();
+    let allowed_tools: Vec<String> = entry.get("allowed_tools")
+        .and_then(|v| v.as_array())
+        .map(|arr| arr.iter().filter_map(|v| v.as_str().map(String::from)).collect())
         .unwrap_or_default();
-    BotSettings { allowed_tools: tools, last_sessions, owner_user_id }
+    let string_map = |key: &str| -> HashMap<String, String> {
+        entry.get(key)
+            .and_then(|v| v.as_object())
+            .map(|obj| {
+                obj.iter()
+                    .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    BotSettings {
+        allowed_tools: if allowed_tools.is_empty() { defaults.allowed_tools } else { allowed_tools },
+        last_sessions: string_map("last_sessions"),
+        owner_user_id: entry.get("owner_user_id").and_then(|v| v.as_u64()),
+        users: roles::users_from_json(entry.get("users")),
+        model: text("model"),
+        prompt_template: text("prompt_template"),
+        system_prompt: text("system_prompt"),
+        verbosity: text("verbosity").and_then(|v| Verbosity::parse(&v)),
+        // Chats with unreadable settings fall back to the bot-wide ones
+        chats: object_map(entry, "chats"),
+        chat_state: object_map(entry, "chat_state"),
+    }
+}
+
+/// Serialize settings as one bot's entry of bot_settings.json
+fn settings_to_entry(settings: &BotSettings) -> serde_json::Value {
+    let mut entry = serde_json::json!({
+        "allowed_tools": settings.allowed_tools,
+        "last_sessions": settings.last_sessions,
+    });
+    if let Some(owner_id) = settings.owner_user_id {
+        entry["owner_user_id"] = serde_json::json!(owner_id);
+    }
+    if !settings.users.is_empty() {
+        entry["users"] = roles::users_to_json(&settings.users);
+    }
+    if let Some(model) = &settings.model {
+        entry["model"] = serde_json::json!(model);
+    }
+    if let Some(template) = &settings.prompt_template {
+        entry["prompt_template"] = serde_json::json!(template);
+    }
+    if let Some(prompt) = &settings.system_prompt {
+        entry["system_prompt"] = serde_json::json!(prompt);
+    }
+    if let Some(verbosity) = settings.verbosity {
+        entry["verbosity"] = serde_json::json!(verbosity.name());
+    }
+    let chats: HashMap<&String, &ChatSettings> = settings.chats.iter()
+        .filter(|(_, chat)| !chat.is_empty())
+        .collect();
+    if !chats.is_empty() {
+        entry["chats"] = serde_json::json!(chats);
+    }
+    let chat_state: HashMap<&String, &ChatState> = settings.chat_state.iter()
+        .filter(|(_, state)| !state.is_empty())
+        .collect();
+    if !chat_state.is_empty() {
+        entry["chat_state"] = serde_json::json!(chat_state);
+    }
+    entry
 }
 
 /// Save bot settings to bot_settings.json.
 /// `hash_key`: the computed hash key for this bot instance.
-/// `platform_fields`: optional extra key-value pairs to store (e.g., `("token", token_value)` or `("platform", "discord")`).
+/// `platform_fields`: optional extra key-value pairs to store (e.g., `("platform", "discord")`).
+/// Bot tokens are never stored; tokens written by older versions are removed from every entry.
 pub fn save_bot_settings(
     hash_key: &str,
     settings: &BotSettings,
     platform_fields: &[(&str, &str)],
 ) {
     let Some(path) = bot_settings_path() else { return };
-    if let Some(parent) = path.parent() {
-        let _ = fs::create_dir_all(parent);
-    }
-    let mut json: serde_json::Value = if let Ok(content) = fs::read_to_string(&path) {
-        serde_json::from_str(&content).unwrap_or_else(|_| serde_json::json!({}))
-    } else {
-        serde_json::json!({})
-    };
-    let mut entry = serde_json::json!({
-        "allowed_tools": settings.allowed_tools,
-        "last_sessions": settings.last_sessions,
-    });
+    let mut entry = settings_to_entry(settings);
     for &(key, value) in platform_fields {
         entry[key] = serde_json::json!(value);
     }
-    if let Some(owner_id) = settings.owner_user_id {
-        entry["owner_user_id"] = serde_json::json!(owner_id);
+    let result = settings_file::update(&path, |json| {
+        json[hash_key] = entry;
+        if let Some(entries) = json.as_object_mut() {
+            for entry in entries.values_mut().filter_map(|e| e.as_object_mut()) {
+                entry.remove("token");
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = result {
+        eprintln!("  ⚠ Bot settings not saved: {}", e);
     }
-    json[hash_key] = entry;
-    if let Ok(s) = serde_json::to_string_pretty(&json) {
-        let _ = fs::write(&path, s);
+}
+
+/// Parse a comma-separated tool list, matching known tools case-insensitively
+pub fn parse_tool_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            ALL_TOOLS.iter()
+                .find(|(tool, _, _)| tool.eq_ignore_ascii_case(name))
+                .map(|(tool, _, _)| tool.to_string())
+                .unwrap_or_else(|| normalize_tool_name(name))
+        })
+        .collect()
+}
+
+/// Keys that `aemi settings set` can change
+pub const SETTABLE_KEYS: &[&str] = &["allowed_tools", "owner_user_id", "model", "prompt_template", "system_prompt", "verbosity"];
+
+/// Set one key of a bot's entry in parsed bot_settings.json (`aemi settings set`).
+/// `allowed_tools` takes a comma-separated list; `owner_user_id` takes a user ID or `none`;
+/// `model`, `prompt_template`, `system_prompt` and `verbosity` are the bot-wide defaults
+/// for every chat (`none` removes them).
+pub fn apply_setting(json: &mut serde_json::Value, hash_key: &str, key: &str, value: &str) -> Result<(), String> {
+    let entry = json.get_mut(hash_key)
+        .and_then(|e| e.as_object_mut())
+        .ok_or_else(|| format!("no bot '{}' in bot_settings.json", hash_key))?;
+    match key {
+        "allowed_tools" => {
+            let tools = parse_tool_list(value);
+            if tools.is_empty() {
+                return Err("allowed_tools needs at least one tool".to_string());
+            }
+            entry.insert(key.to_string(), serde_json::json!(tools));
+        }
+        "owner_user_id" => {
+            if value.eq_ignore_ascii_case("none") {
+                entry.remove(key);
+            } else {
+                let id: u64 = value.parse()
+                    .map_err(|_| format!("owner_user_id must be a user ID or 'none', got '{}'", value))?;
+                entry.insert(key.to_string(), serde_json::json!(id));
+            }
+        }
+        "model" | "prompt_template" | "system_prompt" | "verbosity" if value.eq_ignore_ascii_case("none") => {
+            entry.remove(key);
+        }
+        "prompt_template" => {
+            let unknown = system_prompt::unknown_placeholders(value);
+            if !unknown.is_empty() {
+                return Err(format!(
+                    "unknown placeholder(s) in prompt_template: {}. Known: {}",
+                    unknown.iter().map(|p| format!("{{{}}}", p)).collect::<Vec<_>>().join(", "),
+                    system_prompt::PLACEHOLDERS.iter().map(|p| format!("{{{}}}", p)).collect::<Vec<_>>().join(", ")
+                ));
+            }
+            entry.insert(key.to_string(), serde_json::json!(value));
+        }
+        "model" | "system_prompt" => {
+            entry.insert(key.to_string(), serde_json::json!(value));
+        }
+        "verbosity" => {
+            let verbosity = Verbosity::parse(value)
+                .ok_or_else(|| format!("verbosity must be quiet, tools, full or 'none', got '{}'", value))?;
+            entry.insert(key.to_string(), serde_json::json!(verbosity.name()));
+        }
+        other => {
+            return Err(format!("unknown key '{}'. Settable: {}", other, SETTABLE_KEYS.join(", ")));
+        }
     }
+    Ok(())
 }
 
 /// Summary of a saved session for listing purposes.
@@ -184,6 +360,23 @@ pub struct SessionSummary {
     pub created_at: String,
     pub history_count: usize,
     pub modified: std::time::SystemTime,
+    pub name: Option<String>,
+    pub parent_session_id: Option<String>,
+    pub tags: Vec<String>,
+    pub pinned: bool,
+    /// Session name, or a title taken from the first user message
+    pub title: String,
+    pub platform: Option<String>,
+    pub chat_id: Option<String>,
+    pub bot_hash: Option<String>,
+}
+
+impl SessionSummary {
+    /// Whether the session belongs to the given bot and chat
+    pub fn in_scope(&self, scope: &SessionOrigin) -> bool {
+        self.bot_hash.as_deref() == Some(scope.bot_hash.as_str())
+            && self.chat_id.as_deref() == Some(scope.chat_id.as_str())
+    }
 }
 
 /// List all saved sessions from the ai_sessions directory, sorted by modification time (most recent first).
@@ -201,17 +394,27 @@ pub fn list_all_sessions() -> Vec<SessionSummary> {
         for entry in entries.filter_map(|e| e.ok()) {
             let path = entry.path();
             if path.extension().map(|e| e == "json").unwrap_or(false) {
-                if let Ok(content) = fs::read_to_string(&path) {
-                    if let Ok(data) = serde_json::from_str::<SessionData>(&content) {
+                if let Ok(content) = crypto::read_to_string(&path) {
+                    if let Some(data) = session::parse_session(&content) {
                         let modified = path.metadata()
                             .and_then(|m| m.modified())
                             .unwrap_or(std::time::UNIX_EPOCH);
+                        let title = data.name.clone()
+                            .unwrap_or_else(|| session_title(&data.history));
                         sessions.push(SessionSummary {
                             session_id: data.session_id,
                             current_path: data.current_path,
                             created_at: data.created_at,
                             history_count: data.history.len(),
                             modified,
+                            name: data.name,
+                            parent_session_id: data.parent_session_id,
+                            tags: data.tags,
+                            pinned: data.pinned,
+                            title,
+                            platform: data.platform,
+                            chat_id: data.chat_id,
+                            bot_hash: data.bot_hash,
                         });
                     }
                 }
@@ -219,11 +422,105 @@ pub fn list_all_sessions() -> Vec<SessionSummary> {
         }
     }
 
-    // Sort by modification time, most recent first
-    sessions.sort_by(|a, b| b.modified.cmp(&a.modified));
+    // Pinned sessions first, then by modification time, most recent first
+    sessions.sort_by(|a, b| b.pinned.cmp(&a.pinned).then(b.modified.cmp(&a.modified)));
     sessions
 }
 
+/// List saved sessions owned by the given bot and chat (same order as [`list_all_sessions`]).
+/// Sessions saved before ownership was recorded only appear in the unscoped listing.
+pub fn list_sessions_in_scope(scope: &SessionOrigin) -> Vec<SessionSummary> {
+    list_all_sessions().into_iter()
+        .filter(|s| s.in_scope(scope))
+        .collect()
+}
+
+/// A session saved before ownership was recorded, by exact id. Only admins may claim one
+/// (`/resume <id>`, listed by `/resume all`); the next save records the chat as its owner.
+pub fn find_unowned_session(session_id: &str) -> Option<SessionSummary> {
+    list_all_sessions().into_iter()
+        .find(|s| s.session_id == session_id.trim() && s.bot_hash.is_none() && s.chat_id.is_none())
+}
+
+/// Auto-generated session title: the first line of the first user message
+pub fn session_title(history: &[HistoryItem]) -> String {
+    const MAX_TITLE_CHARS: usize = 40;
+
+    let Some(first) = history.iter().find(|item| item.item_type == HistoryType::User) else {
+        return "(untitled)".to_string();
+    };
+    let line = first.content.lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or("");
+    if line.chars().count() > MAX_TITLE_CHARS {
+        let truncated: String = line.chars().take(MAX_TITLE_CHARS).collect();
+        format!("{}…", truncated.trim_end())
+    } else if line.is_empty() {
+        "(untitled)".to_string()
+    } else {
+        line.to_string()
+    }
+}
+
+/// Normalize a session tag: lowercase, leading `#` stripped, no whitespace.
+/// Returns None for empty or over-long labels.
+pub fn normalize_tag(label: &str) -> Option<String> {
+    const MAX_TAG_CHARS: usize = 32;
+
+    let tag = label.trim().trim_start_matches('#').to_lowercase();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_CHARS || tag.chars().any(char::is_whitespace) {
+        return None;
+    }
+    Some(tag)
+}
+
+/// Resolve a /resume argument that is not a list number.
+/// Matches, in order: exact session id, exact name (case-insensitive), tag, then name/title substring.
+/// Returns indices into `sessions`.
+pub fn find_sessions(sessions: &[SessionSummary], query: &str) -> Vec<usize> {
+    let query = query.trim();
+    if let Some(idx) = sessions.iter().position(|s| s.session_id == query) {
+        return vec![idx];
+    }
+
+    let lower = query.to_lowercase();
+    let matching = |pred: &dyn Fn(&SessionSummary) -> bool| -> Vec<usize> {
+        sessions.iter().enumerate()
+            .filter(|(_, s)| pred(s))
+            .map(|(i, _)| i)
+            .collect()
+    };
+
+    let by_name = matching(&|s| s.name.as_deref().map(|n| n.to_lowercase() == lower).unwrap_or(false));
+    if !by_name.is_empty() {
+        return by_name;
+    }
+    if let Some(tag) = normalize_tag(query) {
+        let by_tag = matching(&|s| s.tags.contains(&tag));
+        if !by_tag.is_empty() {
+            return by_tag;
+        }
+    }
+    matching(&|s| s.title.to_lowercase().contains(&lower))
+}
+
+/// Load a saved session, apply `update` to it and write it back.
+/// Returns the updated session, or None if the session has not been saved yet.
+pub fn update_session_meta(session_id: &str, update: impl FnOnce(&mut SessionData)) -> Option<SessionData> {
+    let sessions_dir = session::ai_sessions_dir()?;
+    let file_path = sessions_dir.join(format!("{}.json", session_id));
+    if file_path.parent() != Some(sessions_dir.as_path()) {
+        return None;
+    }
+
+    let mut data = load_session_by_id(session_id)?;
+    update(&mut data);
+    let json = serde_json::to_string_pretty(&data).ok()?;
+    crypto::write(file_path, json).ok()?;
+    Some(data)
+}
+
 /// Load a specific session by session_id from the ai_sessions directory.
 pub fn load_session_by_id(session_id: &str) -> Option<SessionData> {
     let sessions_dir = session::ai_sessions_dir()?;
@@ -236,12 +533,20 @@ pub fn load_session_by_id(session_id: &str) -> Option<SessionData> {
         }
     }
 
-    let content = fs::read_to_string(&file_path).ok()?;
-    serde_json::from_str::<SessionData>(&content).ok()
+    let content = crypto::read_to_string(&file_path).ok()?;
+    session::parse_session(&content)
+}
+
+/// Whether saved session data belongs to the given bot and chat.
+/// Sessions without a recorded owner are never picked up by path; see [`find_unowned_session`].
+fn owned_by(data: &SessionData, scope: &SessionOrigin) -> bool {
+    data.bot_hash.as_deref() == Some(scope.bot_hash.as_str())
+        && data.chat_id.as_deref() == Some(scope.chat_id.as_str())
 }
 
-/// Load the most recently modified session matching the given working directory path.
-pub fn load_existing_session(current_path: &str) -> Option<(SessionData, std::time::SystemTime)> {
+/// Load the most recently modified session matching the given working directory path
+/// that belongs to the given bot and chat.
+pub fn load_existing_session(current_path: &str, scope: &SessionOrigin) -> Option<(SessionData, std::time::SystemTime)> {
     let sessions_dir = session::ai_sessions_dir()?;
 
     if !sessions_dir.exists() {
@@ -254,9 +559,9 @@ pub fn load_existing_session(current_path: &str) -> Option<(SessionData, std::ti
         for entry in entries.filter_map(|e| e.ok()) {
             let path = entry.path();
             if path.extension().map(|e| e == "json").unwrap_or(false) {
-                if let Ok(content) = fs::read_to_string(&path) {
-                    if let Ok(session_data) = serde_json::from_str::<SessionData>(&content) {
-                        if session_data.current_path == current_path {
+                if let Ok(content) = crypto::read_to_string(&path) {
+                    if let Some(session_data) = session::parse_session(&content) {
+                        if session_data.current_path == current_path && owned_by(&session_data, scope) {
                             if let Ok(metadata) = path.metadata() {
                                 if let Ok(modified) = metadata.modified() {
                                     match &matching_session {
@@ -278,12 +583,23 @@ pub fn load_existing_session(current_path: &str) -> Option<(SessionData, std::ti
     matching_session
 }
 
+/// Session to restore for a chat after a restart: the last session of the chat's agent if it
+/// was saved in `current_path`, else the most recent session there (see [`load_existing_session`]).
+pub fn restore_session(current_path: &str, agent_session: Option<&str>, scope: &SessionOrigin) -> Option<SessionData> {
+    agent_session.and_then(load_session_by_id)
+        .filter(|data| data.current_path == current_path)
+        .or_else(|| load_existing_session(current_path, scope).map(|(data, _)| data))
+}
+
 /// Save session to file in the ai_sessions directory.
 /// Takes session fields directly to avoid coupling with platform-specific session structs.
+/// `created_at`, user metadata and the owner (platform, chat, bot) are kept from the existing
+/// file; `updated_at` is refreshed and `origin` becomes the owner of a session that has none.
 pub fn save_session_to_file(
     session_id: Option<&str>,
     history: &[HistoryItem],
     current_path: &str,
+    origin: &SessionOrigin,
 ) {
     let Some(session_id) = session_id else {
         return;
@@ -311,15 +627,30 @@ pub fn save_session_to_file(
         return;
     }
 
+    let file_path = sessions_dir.join(format!("{}.json", session_id));
+
+    // Carry over created_at and metadata (fork parent, name, tags, pin) from the previously saved file
+    let previous = crypto::read_to_string(&file_path).ok()
+        .and_then(|content| session::parse_session(&content));
+
+    let now = session::now_timestamp();
+    let previous = previous.unwrap_or_default();
+    let created_at = if previous.created_at.is_empty() { now.clone() } else { previous.created_at.clone() };
+
+    let owned = previous.bot_hash.is_some() && previous.chat_id.is_some();
     let session_data = SessionData {
+        version: session::SESSION_SCHEMA_VERSION,
         session_id: session_id.to_string(),
         history: saveable_history,
         current_path: current_path.to_string(),
-        created_at: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        created_at,
+        updated_at: now,
+        platform: if owned { previous.platform.clone() } else { Some(origin.platform.clone()) },
+        chat_id: if owned { previous.chat_id.clone() } else { Some(origin.chat_id.clone()) },
+        bot_hash: if owned { previous.bot_hash.clone() } else { Some(origin.bot_hash.clone()) },
+        ..previous
     };
 
-    let file_path = sessions_dir.join(format!("{}.json", session_id));
-
     // Security: Verify the path is within sessions directory
     if let Some(parent) = file_path.parent() {
         if parent != sessions_dir {
@@ -328,7 +659,165 @@ pub fn save_session_to_file(
     }
 
     if let Ok(json) = serde_json::to_string_pretty(&session_data) {
-        let _ = fs::write(file_path, json);
+        let _ = crypto::write(file_path, json);
+    }
+}
+
+/// Agents whose CLI can fork a provider-side session natively
+/// (Claude `--fork-session`, oh-my-pi `--fork`).
+pub fn supports_native_fork(agent: &str) -> bool {
+    matches!(agent, "claude" | "oh-my-pi")
+}
+
+/// Create a new saved session that copies `history` and links back to `parent_session_id`.
+/// The new session gets a local `fork-<random>` id until the provider assigns its own
+/// on the next turn (see [`rename_session`]).
+pub fn fork_session(
+    parent_session_id: &str,
+    history: &[HistoryItem],
+    current_path: &str,
+    name: Option<&str>,
+) -> Option<SessionData> {
+    use rand::Rng;
+
+    let sessions_dir = session::ai_sessions_dir()?;
+    fs::create_dir_all(&sessions_dir).ok()?;
+
+    let suffix: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(12)
+        .map(|b| (b as char).to_ascii_lowercase())
+        .collect();
+    let fork_data = SessionData {
+        session_id: format!("fork-{}", suffix),
+        history: history.iter()
+            .filter(|item| !matches!(item.item_type, HistoryType::System))
+            .cloned()
+            .collect(),
+        current_path: current_path.to_string(),
+        created_at: session::now_timestamp(),
+        updated_at: session::now_timestamp(),
+        version: session::SESSION_SCHEMA_VERSION,
+        parent_session_id: Some(parent_session_id.to_string()),
+        name: name.map(String::from),
+        ..Default::default()
+    };
+
+    let json = serde_json::to_string_pretty(&fork_data).ok()?;
+    crypto::write(sessions_dir.join(format!("{}.json", fork_data.session_id)), json).ok()?;
+    Some(fork_data)
+}
+
+/// Move a saved session file to a new session id, keeping its metadata.
+/// Used when a forked session receives its real provider session id.
+pub fn rename_session(old_id: &str, new_id: &str) {
+    if old_id == new_id {
+        return;
+    }
+    let Some(mut data) = load_session_by_id(old_id) else {
+        return;
+    };
+    let Some(sessions_dir) = session::ai_sessions_dir() else {
+        return;
+    };
+    let new_path = sessions_dir.join(format!("{}.json", new_id));
+    if new_path.parent() != Some(sessions_dir.as_path()) {
+        return;
+    }
+    data.session_id = new_id.to_string();
+    if let Ok(json) = serde_json::to_string_pretty(&data) {
+        if crypto::write(&new_path, json).is_ok() {
+            let _ = fs::remove_file(sessions_dir.join(format!("{}.json", old_id)));
+        }
+    }
+}
+
+/// Build a transcript preamble for agents that cannot fork natively, so the
+/// fresh provider session starts with the copied conversation as context.
+pub fn fork_context_prompt(history: &[HistoryItem]) -> String {
+    const MAX_CONTEXT_LEN: usize = 6000;
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut total = 0;
+    for item in history.iter().rev() {
+        let prefix = match item.item_type {
+            HistoryType::User => "User",
+            HistoryType::Assistant => "Assistant",
+            _ => continue,
+        };
+        let line = format!("{}: {}", prefix, item.content);
+        total += line.len();
+        if total > MAX_CONTEXT_LEN {
+            break;
+        }
+        lines.push(line);
+    }
+    lines.reverse();
+
+    format!(
+        "[Forked conversation — earlier transcript for context]\n{}\n[End of earlier transcript]",
+        lines.join("\n\n")
+    )
+}
+
+/// Max messages queued per chat while a turn is running
+pub const MAX_QUEUED_MESSAGES: usize = 20;
+
+/// Merge messages queued during a turn into one follow-up prompt
+pub fn merge_queued_messages(texts: &[String]) -> String {
+    match texts {
+        [only] => only.clone(),
+        _ => {
+            let items: Vec<String> = texts.iter().enumerate()
+                .map(|(i, text)| format!("{}. {}", i + 1, text))
+                .collect();
+            format!("I sent these messages while you were working:\n\n{}", items.join("\n\n"))
+        }
+    }
+}
+
+/// Take a chat's queued messages, oldest first, and reserve the chat for the turn that runs
+/// them with a placeholder cancel token, so new messages keep queueing and `/stop` has
+/// something to stop until the turn starts. None if a turn is running or nothing is queued.
+pub fn reserve_queued_turn<K: Eq + Hash + Copy, M>(
+    cancel_tokens: &mut HashMap<K, Arc<CancelToken>>,
+    queued_messages: &mut HashMap<K, Vec<M>>,
+    chat: K,
+) -> Option<(Arc<CancelToken>, Vec<M>)> {
+    if cancel_tokens.contains_key(&chat) {
+        return None;
+    }
+    let queued = queued_messages.remove(&chat).filter(|q| !q.is_empty())?;
+    let reserved = Arc::new(CancelToken::new());
+    cancel_tokens.insert(chat, reserved.clone());
+    Some((reserved, queued))
+}
+
+/// Install the cancel token of a starting turn in place of its reservation, if any.
+/// If the reservation was stopped in the meantime it is dropped and None is returned:
+/// the turn must not start.
+pub fn start_turn<K: Eq + Hash + Copy>(
+    cancel_tokens: &mut HashMap<K, Arc<CancelToken>>,
+    chat: K,
+    reserved: Option<&Arc<CancelToken>>,
+) -> Option<Arc<CancelToken>> {
+    if let Some(reserved) = reserved.filter(|r| r.cancelled.load(Ordering::Relaxed)) {
+        release_reservation(cancel_tokens, chat, reserved);
+        return None;
+    }
+    let token = Arc::new(CancelToken::new());
+    cancel_tokens.insert(chat, token.clone());
+    Some(token)
+}
+
+/// Drop a reservation whose turn never started (no session, stopped, failed to send)
+pub fn release_reservation<K: Eq + Hash + Copy>(
+    cancel_tokens: &mut HashMap<K, Arc<CancelToken>>,
+    chat: K,
+    reserved: &Arc<CancelToken>,
+) {
+    if cancel_tokens.get(&chat).is_some_and(|t| Arc::ptr_eq(t, reserved)) {
+        cancel_tokens.remove(&chat);
     }
 }
 
@@ -430,6 +919,133 @@ mod tests {
         assert_eq!(h_dc.strip_prefix("dc_").unwrap(), h_none);
     }
 
+    // --- fork helpers ---
+
+    #[test]
+    fn test_supports_native_fork() {
+        assert!(supports_native_fork("claude"));
+        assert!(supports_native_fork("oh-my-pi"));
+        assert!(!supports_native_fork("gemini"));
+        assert!(!supports_native_fork("codex"));
+    }
+
+    #[test]
+    fn test_fork_context_prompt_keeps_conversation_order() {
+        let history = vec![
+            HistoryItem::new(HistoryType::User, "first".to_string()),
+            HistoryItem::new(HistoryType::ToolUse, "Bash".to_string()),
+            HistoryItem::new(HistoryType::Assistant, "second".to_string()),
+        ];
+        let ctx = fork_context_prompt(&history);
+        let first = ctx.find("User: first").unwrap();
+        let second = ctx.find("Assistant: second").unwrap();
+        assert!(first < second);
+        assert!(!ctx.contains("Bash"));
+    }
+
+    #[test]
+    fn test_fork_context_prompt_truncates_oldest() {
+        let history = vec![
+            HistoryItem::new(HistoryType::User, "old".repeat(3000)),
+            HistoryItem::new(HistoryType::User, "recent".to_string()),
+        ];
+        let ctx = fork_context_prompt(&history);
+        assert!(ctx.contains("User: recent"));
+        assert!(!ctx.contains("oldold"));
+    }
+
+    // --- session titles, tags, lookup ---
+
+    fn summary(id: &str, name: Option<&str>, tags: &[&str], first_message: &str) -> SessionSummary {
+        SessionSummary {
+            session_id: id.to_string(),
+            current_path: "/tmp".to_string(),
+            created_at: String::new(),
+            history_count: 1,
+            modified: std::time::UNIX_EPOCH,
+            name: name.map(String::from),
+            parent_session_id: None,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            pinned: false,
+            title: name.map(String::from).unwrap_or_else(|| first_message.to_string()),
+            platform: None,
+            chat_id: None,
+            bot_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_session_title_first_user_line() {
+        let history = vec![
+            HistoryItem::new(HistoryType::System, "started".to_string()),
+            HistoryItem::new(HistoryType::User, "\n  Fix the login bug\nmore".to_string()),
+        ];
+        assert_eq!(session_title(&history), "Fix the login bug");
+        assert_eq!(session_title(&[]), "(untitled)");
+    }
+
+    #[test]
+    fn test_session_title_truncates() {
+        let history = vec![HistoryItem::new(HistoryType::User, "a".repeat(100))];
+        let title = session_title(&history);
+        assert_eq!(title.chars().count(), 41);
+        assert!(title.ends_with('…'));
+    }
+
+    #[test]
+    fn test_normalize_tag() {
+        assert_eq!(normalize_tag("#Bugfix"), Some("bugfix".to_string()));
+        assert_eq!(normalize_tag("two words"), None);
+        assert_eq!(normalize_tag("#"), None);
+    }
+
+    #[test]
+    fn test_session_summary_in_scope() {
+        let scope = SessionOrigin {
+            platform: "telegram".to_string(),
+            chat_id: "42".to_string(),
+            bot_hash: "abc".to_string(),
+        };
+        let mut s = summary("id-1", None, &[], "");
+        assert!(!s.in_scope(&scope)); // no recorded owner
+        s.bot_hash = Some("abc".to_string());
+        s.chat_id = Some("42".to_string());
+        assert!(s.in_scope(&scope));
+        s.chat_id = Some("43".to_string());
+        assert!(!s.in_scope(&scope));
+    }
+
+    #[test]
+    fn test_owned_by() {
+        let scope = SessionOrigin {
+            platform: "discord".to_string(),
+            chat_id: "7".to_string(),
+            bot_hash: "dc_x".to_string(),
+        };
+        let mut data = SessionData::default();
+        assert!(!owned_by(&data, &scope)); // no recorded owner
+        data.bot_hash = Some("dc_y".to_string());
+        data.chat_id = Some("7".to_string());
+        assert!(!owned_by(&data, &scope));
+        data.bot_hash = Some("dc_x".to_string());
+        assert!(owned_by(&data, &scope));
+    }
+
+    #[test]
+    fn test_find_sessions_order() {
+        let sessions = vec![
+            summary("id-1", Some("Parser"), &["compiler"], ""),
+            summary("id-2", None, &["compiler", "urgent"], "parser crash on input"),
+            summary("id-3", None, &[], "deploy script"),
+        ];
+        assert_eq!(find_sessions(&sessions, "id-3"), vec![2]);
+        assert_eq!(find_sessions(&sessions, "parser"), vec![0]);
+        assert_eq!(find_sessions(&sessions, "#compiler"), vec![0, 1]);
+        assert_eq!(find_sessions(&sessions, "urgent"), vec![1]);
+        assert_eq!(find_sessions(&sessions, "deploy"), vec![2]);
+        assert!(find_sessions(&sessions, "nothing").is_empty());
+    }
+
     // --- bot_settings_path ---
 
     #[test]
@@ -449,4 +1065,102 @@ mod tests {
         assert!(settings.last_sessions.is_empty());
         assert!(settings.owner_user_id.is_none());
     }
+
+    // --- merge_queued_messages ---
+
+    #[test]
+    fn test_merge_queued_messages() {
+        assert_eq!(merge_queued_messages(&["run the tests".to_string()]), "run the tests");
+        let merged = merge_queued_messages(&["first".to_string(), "second".to_string()]);
+        assert!(merged.ends_with("1. first\n\n2. second"));
+    }
+
+    // --- apply_setting ---
+
+    #[test]
+    fn test_apply_setting() {
+        let mut json = serde_json::json!({
+            "abc": { "token": "t", "allowed_tools": ["Bash"], "owner_user_id": 7 }
+        });
+        apply_setting(&mut json, "abc", "allowed_tools", "read, GREP,mytool").unwrap();
+        assert_eq!(json["abc"]["allowed_tools"], serde_json::json!(["Read", "Grep", "Mytool"]));
+        apply_setting(&mut json, "abc", "owner_user_id", "42").unwrap();
+        assert_eq!(json["abc"]["owner_user_id"], 42);
+        apply_setting(&mut json, "abc", "owner_user_id", "none").unwrap();
+        assert!(json["abc"].get("owner_user_id").is_none());
+        assert_eq!(json["abc"]["token"], "t");
+
+        assert!(apply_setting(&mut json, "nope", "allowed_tools", "Read").unwrap_err().contains("no bot"));
+        assert!(apply_setting(&mut json, "abc", "allowed_tools", " , ").is_err());
+        assert!(apply_setting(&mut json, "abc", "owner_user_id", "me").is_err());
+        assert!(apply_setting(&mut json, "abc", "token", "x").unwrap_err().contains("unknown key"));
+
+        apply_setting(&mut json, "abc", "verbosity", "Quiet").unwrap();
+        assert_eq!(json["abc"]["verbosity"], "quiet");
+        apply_setting(&mut json, "abc", "model", "opus").unwrap();
+        apply_setting(&mut json, "abc", "model", "none").unwrap();
+        assert!(json["abc"].get("model").is_none());
+        assert!(apply_setting(&mut json, "abc", "verbosity", "loud").is_err());
+
+        apply_setting(&mut json, "abc", "prompt_template", "You are Ada, on {platform} in {cwd}.").unwrap();
+        assert_eq!(json["abc"]["prompt_template"], "You are Ada, on {platform} in {cwd}.");
+        assert!(apply_setting(&mut json, "abc", "prompt_template", "In {dir}").unwrap_err().contains("{dir}"));
+    }
+
+    #[test]
+    fn test_settings_entry_roundtrip() {
+        let mut settings = BotSettings {
+            owner_user_id: Some(1),
+            model: Some("sonnet".to_string()),
+            prompt_template: Some("You are Ada, on {platform}.".to_string()),
+            verbosity: Some(Verbosity::Tools),
+            ..BotSettings::default()
+        };
+        settings.last_sessions.insert("5".to_string(), "/tmp".to_string());
+        settings.chats.insert("5".to_string(), ChatSettings { agent: Some("codex".to_string()), ..ChatSettings::default() });
+        settings.chats.insert("6".to_string(), ChatSettings::default());
+        settings.chat_state.entry("5".to_string()).or_default().set_session("claude", Some("abc"));
+        settings.chat_state.insert("6".to_string(), ChatState::default());
+
+        let entry = settings_to_entry(&settings);
+        assert_eq!(entry["chats"], serde_json::json!({"5": {"agent": "codex"}}));
+        let loaded = settings_from_entry(&entry);
+        assert_eq!(loaded.model.as_deref(), Some("sonnet"));
+        assert_eq!(loaded.prompt_template, settings.prompt_template);
+        assert_eq!(loaded.verbosity, Some(Verbosity::Tools));
+        assert_eq!(loaded.chats.len(), 1);
+        assert_eq!(loaded.chats["5"].agent.as_deref(), Some("codex"));
+        assert_eq!(loaded.last_sessions["5"], "/tmp");
+        assert_eq!(entry["chat_state"], serde_json::json!({"5": {"sessions": {"claude": "abc"}}}));
+        assert_eq!(loaded.chat_state["5"].sessions["claude"], "abc");
+        assert_eq!(loaded.allowed_tools, settings.allowed_tools);
+        assert!(loaded.system_prompt.is_none());
+    }
+
+    #[test]
+    fn test_queued_turn_reservation() {
+        let mut tokens: HashMap<u8, Arc<CancelToken>> = HashMap::new();
+        let mut queued: HashMap<u8, Vec<&str>> = HashMap::from([(1, vec!["a", "b"])]);
+        assert!(reserve_queued_turn(&mut tokens, &mut queued, 2).is_none());
+
+        let (reserved, messages) = reserve_queued_turn(&mut tokens, &mut queued, 1).unwrap();
+        assert_eq!(messages, vec!["a", "b"]);
+        // Messages arriving now wait for the next turn
+        queued.entry(1).or_default().push("c");
+        assert!(reserve_queued_turn(&mut tokens, &mut queued, 1).is_none());
+
+        // The turn replaces the reservation; releasing it afterwards leaves the turn alone
+        let token = start_turn(&mut tokens, 1, Some(&reserved)).unwrap();
+        release_reservation(&mut tokens, 1, &reserved);
+        assert!(Arc::ptr_eq(&tokens[&1], &token));
+        tokens.remove(&1);
+
+        let (reserved, messages) = reserve_queued_turn(&mut tokens, &mut queued, 1).unwrap();
+        assert_eq!(messages, vec!["c"]);
+        // /stop before the turn starts: it never starts and the chat is free again
+        reserved.cancelled.store(true, Ordering::Relaxed);
+        assert!(start_turn(&mut tokens, 1, Some(&reserved)).is_none());
+        assert!(tokens.is_empty());
+        assert!(start_turn(&mut tokens, 1, None).is_some());
+    }
 }
@@ -0,0 +1,242 @@
+//! Per-chat settings layered over the bot-wide defaults.
+
+use serde::{Deserialize, Serialize};
+
+use crate::services::bot_common::{self, BotSettings};
+
+/// How much of a turn's tool activity is shown in the chat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    /// Only the agent's answer
+    Quiet,
+    /// Tool calls, without their output
+    Tools,
+    /// Tool calls and their output
+    #[default]
+    Full,
+}
+
+impl Verbosity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Verbosity::Quiet => "quiet",
+            Verbosity::Tools => "tools",
+            Verbosity::Full => "full",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Verbosity> {
+        [Verbosity::Quiet, Verbosity::Tools, Verbosity::Full].into_iter()
+            .find(|v| v.name().eq_ignore_ascii_case(name.trim()))
+    }
+
+    pub fn shows_tool_calls(self) -> bool {
+        self != Verbosity::Quiet
+    }
+
+    pub fn shows_tool_output(self) -> bool {
+        self == Verbosity::Full
+    }
+}
+
+/// Settings of one chat, stored in `bot_settings.json` under the bot's `chats` object keyed
+/// by chat or channel id. None means "use the bot-wide default".
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_tools: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verbosity: Option<Verbosity>,
+}
+
+impl ChatSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == ChatSettings::default()
+    }
+}
+
+/// Where an effective value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// This chat's settings
+    Chat,
+    /// The bot-wide settings
+    Bot,
+    /// Built-in default
+    Default,
+}
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Chat => "chat",
+            Source::Bot => "bot",
+            Source::Default => "default",
+        }
+    }
+}
+
+/// A value together with the layer it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layered<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+/// Settings in effect for a chat
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effective {
+    pub agent: Layered<String>,
+    /// None uses the agent's own default model
+    pub model: Layered<Option<String>>,
+    pub allowed_tools: Layered<Vec<String>>,
+    pub system_prompt: Layered<Option<String>>,
+    pub verbosity: Layered<Verbosity>,
+}
+
+/// The chat's value if set, else the bot's, else `default`
+fn layer<T: Clone>(chat: Option<&T>, bot: Option<&T>, default: T) -> Layered<T> {
+    match (chat, bot) {
+        (Some(v), _) => Layered { value: v.clone(), source: Source::Chat },
+        (None, Some(v)) => Layered { value: v.clone(), source: Source::Bot },
+        (None, None) => Layered { value: default, source: Source::Default },
+    }
+}
+
+/// Merge a chat's settings over the bot-wide ones. `bot_agent` is the bot's agent
+/// from `--agent` or the config file.
+pub fn effective(bot: &BotSettings, bot_agent: &str, chat_key: &str) -> Effective {
+    let chat = bot.chats.get(chat_key).cloned().unwrap_or_default();
+    // Optional values keep None as "not set" in every layer
+    let optional = |chat: &Option<String>, bot: &Option<String>| {
+        let set = |v: &Option<String>| v.is_some().then(|| v.clone());
+        layer(set(chat).as_ref(), set(bot).as_ref(), None)
+    };
+    Effective {
+        agent: layer(chat.agent.as_ref(), Some(&bot_agent.to_string()), String::new()),
+        model: optional(&chat.model, &bot.model),
+        allowed_tools: layer(chat.allowed_tools.as_ref(), Some(&bot.allowed_tools), Vec::new()),
+        system_prompt: optional(&chat.system_prompt, &bot.system_prompt),
+        verbosity: layer(chat.verbosity.as_ref(), bot.verbosity.as_ref(), Verbosity::default()),
+    }
+}
+
+/// Keys accepted by `/settings <key> <value>`
+pub const KEYS: &[&str] = &["agent", "model", "tools", "system_prompt", "verbosity"];
+
+/// Set one key of a chat's settings; `reset` returns it to the bot-wide default.
+/// Returns a confirmation message or an error for the user.
+pub fn apply(chat: &mut ChatSettings, key: &str, value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(format!("Usage: /settings {} <value|reset>", key));
+    }
+    let reset = value.eq_ignore_ascii_case("reset");
+    match key {
+        "agent" if reset => chat.agent = None,
+        "agent" => {
+            let agent = value.to_lowercase();
+            if !bot_common::is_valid_agent(&agent) {
+                let valid: Vec<&str> = bot_common::AVAILABLE_AGENTS.iter().map(|(n, _)| *n).collect();
+                return Err(format!("Unknown agent: {}. Available: {}", agent, valid.join(", ")));
+            }
+            chat.agent = Some(agent);
+        }
+        "model" => chat.model = (!reset).then(|| value.to_string()),
+        "tools" if reset => chat.allowed_tools = None,
+        "tools" => {
+            let tools = bot_common::parse_tool_list(value);
+            if tools.is_empty() {
+                return Err("tools needs at least one tool".to_string());
+            }
+            chat.allowed_tools = Some(tools);
+        }
+        "system_prompt" => chat.system_prompt = (!reset).then(|| value.to_string()),
+        "verbosity" if reset => chat.verbosity = None,
+        "verbosity" => {
+            chat.verbosity = Some(Verbosity::parse(value)
+                .ok_or_else(|| format!("Unknown verbosity: {}. Use quiet, tools or full", value))?);
+        }
+        other => return Err(format!("Unknown setting: {}. Settings: {}", other, KEYS.join(", "))),
+    }
+    Ok(if reset {
+        format!("{} reset to the bot default.", key)
+    } else {
+        format!("{} set for this chat.", key)
+    })
+}
+
+/// Effective settings as (key, display value, source) rows for `/settings`
+pub fn describe(effective: &Effective) -> Vec<(&'static str, String, Source)> {
+    let or_default = |v: &Option<String>, default: &str| v.clone().unwrap_or_else(|| default.to_string());
+    vec![
+        ("agent", effective.agent.value.clone(), effective.agent.source),
+        ("model", or_default(&effective.model.value, "(agent default)"), effective.model.source),
+        ("tools", effective.allowed_tools.value.join(", "), effective.allowed_tools.source),
+        ("system_prompt", or_default(&effective.system_prompt.value, "(none)"), effective.system_prompt.source),
+        ("verbosity", effective.verbosity.value.name().to_string(), effective.verbosity.source),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective_layers() {
+        let mut bot = BotSettings { model: Some("sonnet".to_string()), ..BotSettings::default() };
+        let plain = effective(&bot, "claude", "1");
+        assert_eq!(plain.agent, Layered { value: "claude".to_string(), source: Source::Bot });
+        assert_eq!(plain.model.source, Source::Bot);
+        assert_eq!(plain.allowed_tools.value, bot.allowed_tools);
+        assert_eq!(plain.system_prompt, Layered { value: None, source: Source::Default });
+        assert_eq!(plain.verbosity, Layered { value: Verbosity::Full, source: Source::Default });
+
+        bot.chats.insert("1".to_string(), ChatSettings {
+            agent: Some("codex".to_string()),
+            allowed_tools: Some(vec!["Read".to_string()]),
+            verbosity: Some(Verbosity::Quiet),
+            ..ChatSettings::default()
+        });
+        let chat = effective(&bot, "claude", "1");
+        assert_eq!(chat.agent, Layered { value: "codex".to_string(), source: Source::Chat });
+        assert_eq!(chat.model, Layered { value: Some("sonnet".to_string()), source: Source::Bot });
+        assert_eq!(chat.allowed_tools.value, vec!["Read"]);
+        assert_eq!(chat.verbosity.value, Verbosity::Quiet);
+        // Other chats keep the bot-wide values
+        assert_eq!(effective(&bot, "claude", "2"), plain);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut chat = ChatSettings::default();
+        assert!(apply(&mut chat, "agent", "Gemini").is_ok());
+        assert_eq!(chat.agent.as_deref(), Some("gemini"));
+        assert!(apply(&mut chat, "agent", "nope").unwrap_err().contains("Unknown agent"));
+        assert!(apply(&mut chat, "tools", "read, grep").is_ok());
+        assert_eq!(chat.allowed_tools, Some(vec!["Read".to_string(), "Grep".to_string()]));
+        assert!(apply(&mut chat, "system_prompt", "Answer in French.").is_ok());
+        assert!(apply(&mut chat, "verbosity", "loud").unwrap_err().contains("quiet, tools or full"));
+        assert!(apply(&mut chat, "colour", "red").unwrap_err().contains("Unknown setting"));
+
+        for key in KEYS {
+            assert!(apply(&mut chat, key, "reset").is_ok());
+        }
+        assert!(chat.is_empty());
+    }
+
+    #[test]
+    fn test_serialized_form() {
+        let chat = ChatSettings { verbosity: Some(Verbosity::Tools), ..ChatSettings::default() };
+        assert_eq!(serde_json::to_value(&chat).unwrap(), serde_json::json!({"verbosity": "tools"}));
+        let parsed: ChatSettings = serde_json::from_value(serde_json::json!({"model": "o3", "extra": 1})).unwrap();
+        assert_eq!(parsed.model.as_deref(), Some("o3"));
+    }
+}
@@ -0,0 +1,138 @@
+//! Chat state that outlives a restart.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::services::session;
+use crate::services::utils::truncate_str;
+
+/// A user request sent to the agent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Turn {
+    pub prompt: String,
+    pub agent: String,
+    pub started_at: String,
+}
+
+impl Turn {
+    pub fn new(prompt: &str, agent: &str) -> Self {
+        Turn {
+            prompt: prompt.to_string(),
+            agent: agent.to_string(),
+            started_at: session::now_timestamp(),
+        }
+    }
+}
+
+/// Persisted state of one chat, kept in `bot_settings.json` under the bot's `chat_state`
+/// object keyed by chat or channel id
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatState {
+    /// Agent name → provider session id
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sessions: BTreeMap<String, String>,
+    /// Turn in progress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub running: Option<Turn>,
+    /// Turn cut off by the last restart, kept until it is retried or a new turn starts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interrupted: Option<Turn>,
+}
+
+impl ChatState {
+    pub fn is_empty(&self) -> bool {
+        *self == ChatState::default()
+    }
+
+    /// Record the provider session of `agent`; None forgets it
+    pub fn set_session(&mut self, agent: &str, session_id: Option<&str>) {
+        match session_id {
+            Some(id) => self.sessions.insert(agent.to_string(), id.to_string()),
+            None => self.sessions.remove(agent),
+        };
+    }
+
+    /// Mark a turn as running; it replaces an interrupted turn that was never retried
+    pub fn start_turn(&mut self, turn: Turn) {
+        self.running = Some(turn);
+        self.interrupted = None;
+    }
+}
+
+/// At startup: turns still marked running were interrupted. Moves them to `interrupted`
+/// and returns them with the keys of their chats, to notify.
+pub fn mark_interrupted(states: &mut HashMap<String, ChatState>) -> Vec<(String, Turn)> {
+    let mut turns: Vec<(String, Turn)> = states.iter_mut()
+        .filter_map(|(key, state)| {
+            let turn = state.running.take()?;
+            state.interrupted = Some(turn.clone());
+            Some((key.clone(), turn))
+        })
+        .collect();
+    turns.sort_by(|a, b| a.0.cmp(&b.0));
+    turns
+}
+
+/// Message sent to a chat whose turn was interrupted
+pub fn interrupted_notice(turn: &Turn) -> String {
+    format!(
+        "⚠ The bot restarted before your last request finished ({}, started {}):\n\n{}\n\nSend /retry to run it again.",
+        turn.agent,
+        turn.started_at,
+        truncate_str(&turn.prompt, 300)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions_per_agent() {
+        let mut state = ChatState::default();
+        state.set_session("claude", Some("c1"));
+        state.set_session("codex", Some("x1"));
+        state.set_session("claude", Some("c2"));
+        assert_eq!(state.sessions.get("claude").map(String::as_str), Some("c2"));
+        state.set_session("codex", None);
+        assert!(!state.sessions.contains_key("codex"));
+        state.set_session("claude", None);
+        assert!(state.is_empty());
+    }
+
+    #[test]
+    fn test_mark_interrupted() {
+        let mut states = HashMap::new();
+        let mut busy = ChatState::default();
+        busy.start_turn(Turn::new("fix the build", "claude"));
+        states.insert("1".to_string(), busy);
+        states.insert("2".to_string(), ChatState::default());
+
+        let turns = mark_interrupted(&mut states);
+        assert_eq!(turns.len(), 1);
+        assert_eq!(turns[0].0, "1");
+        assert!(interrupted_notice(&turns[0].1).contains("fix the build"));
+        let state = &states["1"];
+        assert!(state.running.is_none());
+        assert_eq!(state.interrupted.as_ref(), Some(&turns[0].1));
+        // Already handled: a second startup does not notify again
+        assert!(mark_interrupted(&mut states).is_empty());
+
+        // A new turn drops the interrupted one
+        let state = states.get_mut("1").unwrap();
+        state.start_turn(Turn::new("something else", "codex"));
+        assert!(state.interrupted.is_none());
+    }
+
+    #[test]
+    fn test_serialized_form() {
+        let mut state = ChatState::default();
+        state.set_session("gemini", Some("g1"));
+        assert_eq!(serde_json::to_value(&state).unwrap(), serde_json::json!({"sessions": {"gemini": "g1"}}));
+        let parsed: ChatState = serde_json::from_value(serde_json::json!({
+            "running": {"prompt": "hi", "agent": "claude", "started_at": "2026-01-01 10:00:00"}
+        })).unwrap();
+        assert_eq!(parsed.running.map(|t| t.agent), Some("claude".to_string()));
+    }
+}
@@ -0,0 +1,625 @@
+//! Per-turn workspace checkpoints for `/undo` and `/checkpoints`.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::UNIX_EPOCH;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::services::git;
+
+const DEFAULT_MAX_CHECKPOINTS: usize = 20;
+
+/// Files larger than this are left out of file snapshots
+const MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Directories with more files than this are not snapshotted
+const MAX_FILES: usize = 20_000;
+
+/// Directory names skipped by file snapshots (VCS and aemi data, dependencies, build output)
+const SKIP_DIRS: &[&str] = &[
+    ".git", ".aemi", "node_modules", "target", ".venv", "venv", "__pycache__", ".next", "dist", "build",
+];
+
+const GIT_REF_PREFIX: &str = "refs/aemi/checkpoints/";
+
+/// Identity used for checkpoint commits (they never land on a branch)
+const GIT_IDENT: &[(&str, &str)] = &[
+    ("GIT_AUTHOR_NAME", "aemi"),
+    ("GIT_AUTHOR_EMAIL", "aemi@localhost"),
+    ("GIT_COMMITTER_NAME", "aemi"),
+    ("GIT_COMMITTER_EMAIL", "aemi@localhost"),
+];
+
+/// Maximum checkpoints kept per directory (`AEMI_MAX_CHECKPOINTS`, default 20, 0 = disabled)
+pub fn max_checkpoints() -> usize {
+    static MAX: OnceLock<usize> = OnceLock::new();
+    *MAX.get_or_init(|| {
+        std::env::var("AEMI_MAX_CHECKPOINTS").ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_MAX_CHECKPOINTS)
+    })
+}
+
+/// A file recorded in a file snapshot
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileEntry {
+    pub hash: String,
+    pub size: u64,
+    /// Modification time in nanoseconds, used to skip rehashing unchanged files
+    pub mtime: u64,
+    pub mode: u32,
+}
+
+/// How the directory state was captured
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Snapshot {
+    /// The session directory is a git repo root: the working tree, untracked files included,
+    /// written as a commit under `refs/aemi/checkpoints/` without touching HEAD, the index or
+    /// any branch
+    Git { commit: String, tree: String },
+    /// Any other directory: file contents copied into a content-addressed blob store, so each
+    /// version of a file is stored once
+    Files {
+        files: BTreeMap<String, FileEntry>,
+        /// Files and directories left out of the snapshot (too large, unreadable or in
+        /// SKIP_DIRS). Restoring never deletes them.
+        #[serde(default)]
+        skipped: BTreeSet<String>,
+        /// When the snapshot was taken (nanoseconds since the epoch)
+        #[serde(default)]
+        taken_at: u64,
+    },
+}
+
+/// A workspace state taken before an agent turn
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub id: String,
+    pub created_at: String,
+    /// Preview of the prompt that started the turn
+    pub label: String,
+    pub snapshot: Snapshot,
+}
+
+impl Checkpoint {
+    pub fn kind(&self) -> &'static str {
+        match self.snapshot {
+            Snapshot::Git { .. } => "git",
+            Snapshot::Files { .. } => "files",
+        }
+    }
+}
+
+/// Result of restoring a checkpoint
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// Files written back (changed or deleted since the checkpoint)
+    pub restored: usize,
+    /// Files removed (created since the checkpoint)
+    pub removed: usize,
+}
+
+/// Checkpoint store for a directory: ~/.aemi/checkpoints/<sha256(canonical path)[..16]>,
+/// holding `index.json` and the blobs of file snapshots
+fn store_dir(work_dir: &str) -> Option<PathBuf> {
+    let canonical = fs::canonicalize(work_dir).ok()?;
+    let digest = Sha256::digest(canonical.to_string_lossy().as_bytes());
+    let key = hex::encode(&digest[..8]);
+    dirs::home_dir().map(|h| h.join(".aemi").join("checkpoints").join(key))
+}
+
+fn load_index(store: &Path) -> Vec<Checkpoint> {
+    fs::read_to_string(store.join("index.json")).ok()
+        .and_then(|c| serde_json::from_str(&c).ok())
+        .unwrap_or_default()
+}
+
+fn save_index(store: &Path, index: &[Checkpoint]) -> Result<(), String> {
+    let json = serde_json::to_string(index).map_err(|e| e.to_string())?;
+    let tmp = store.join("index.json.tmp");
+    fs::write(&tmp, json).map_err(|e| e.to_string())?;
+    fs::rename(&tmp, store.join("index.json")).map_err(|e| e.to_string())
+}
+
+fn blob_path(store: &Path, hash: &str) -> PathBuf {
+    store.join("blobs").join(&hash[..2]).join(hash)
+}
+
+fn mtime_nanos(meta: &fs::Metadata) -> u64 {
+    meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Files found under a directory by [`scan_files`]
+struct Scan {
+    /// Regular files eligible for file snapshots, keyed by relative path
+    files: BTreeMap<String, fs::Metadata>,
+    /// Relative paths of skipped directories and of files over MAX_FILE_BYTES
+    skipped: BTreeSet<String>,
+}
+
+/// Regular files under `root` eligible for file snapshots.
+/// Symlinks, skipped directories and files over MAX_FILE_BYTES are left out.
+fn scan_files(root: &Path) -> Result<Scan, String> {
+    let mut scan = Scan { files: BTreeMap::new(), skipped: BTreeSet::new() };
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else { continue };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            let Ok(meta) = fs::symlink_metadata(&path) else { continue };
+            let Ok(rel) = path.strip_prefix(root) else { continue };
+            let rel = rel.to_string_lossy().to_string();
+            if meta.is_dir() {
+                let name = entry.file_name();
+                if SKIP_DIRS.contains(&name.to_string_lossy().as_ref()) {
+                    scan.skipped.insert(rel);
+                } else {
+                    stack.push(path);
+                }
+            } else if meta.is_file() && meta.len() > MAX_FILE_BYTES {
+                scan.skipped.insert(rel);
+            } else if meta.is_file() {
+                scan.files.insert(rel, meta);
+                if scan.files.len() > MAX_FILES {
+                    return Err(format!("more than {} files, checkpoint skipped", MAX_FILES));
+                }
+            }
+        }
+    }
+    Ok(scan)
+}
+
+/// Whether `rel` is a skipped path or lies inside a skipped directory
+fn is_skipped(skipped: &BTreeSet<String>, rel: &str) -> bool {
+    Path::new(rel).ancestors().any(|p| skipped.contains(p.to_string_lossy().as_ref()))
+}
+
+/// Copy the directory's files into the blob store. Files whose size and mtime match the
+/// previous snapshot reuse its hash instead of being read again, unless they were modified
+/// close to when that snapshot was taken (timestamps are too coarse to trust there).
+fn file_snapshot(
+    store: &Path,
+    root: &Path,
+    previous: Option<(&BTreeMap<String, FileEntry>, u64)>,
+) -> Result<Snapshot, String> {
+    const RACY_WINDOW_NANOS: u64 = 2_000_000_000;
+    let taken_at = std::time::SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let Scan { files: found, mut skipped } = scan_files(root)?;
+    let mut files = BTreeMap::new();
+    for (rel, meta) in found {
+        let size = meta.len();
+        let mtime = mtime_nanos(&meta);
+        let mode = meta.permissions().mode();
+        let reused = previous
+            .and_then(|(p, prev_taken)| p.get(&rel).filter(|_| mtime + RACY_WINDOW_NANOS < prev_taken))
+            .filter(|e| e.size == size && e.mtime == mtime && blob_path(store, &e.hash).exists())
+            .map(|e| e.hash.clone());
+
+        let hash = match reused {
+            Some(hash) => hash,
+            None => {
+                let Ok(bytes) = fs::read(root.join(&rel)) else {
+                    skipped.insert(rel);
+                    continue;
+                };
+                let hash = hex::encode(Sha256::digest(&bytes));
+                let blob = blob_path(store, &hash);
+                if !blob.exists() {
+                    if let Some(parent) = blob.parent() {
+                        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+                    }
+                    fs::write(&blob, &bytes).map_err(|e| e.to_string())?;
+                }
+                hash
+            }
+        };
+        files.insert(rel, FileEntry { hash, size, mtime, mode });
+    }
+    Ok(Snapshot::Files { files, skipped, taken_at })
+}
+
+/// Write the working tree (tracked and untracked, respecting .gitignore) as a tree object
+/// using a throwaway index, so the real index is never touched
+fn git_write_tree(store: &Path, dir: &str) -> Result<String, String> {
+    let tmp_index = store.join("git-index.tmp");
+    let _ = fs::remove_file(&tmp_index);
+    // Seed from the real index so unchanged files are not rehashed
+    if let Ok(real) = git::run_git(dir, &["rev-parse", "--git-path", "index"]) {
+        let _ = fs::copy(Path::new(dir).join(real), &tmp_index);
+    }
+    let index_str = tmp_index.to_string_lossy().to_string();
+    let env = [("GIT_INDEX_FILE", index_str.as_str())];
+    let result = git::run_git_with_env(dir, &["add", "-A"], &env)
+        .and_then(|_| git::run_git_with_env(dir, &["write-tree"], &env));
+    let _ = fs::remove_file(&tmp_index);
+    result
+}
+
+fn git_snapshot(store: &Path, dir: &str) -> Result<Snapshot, String> {
+    let tree = git_write_tree(store, dir)?;
+    let mut args = vec!["commit-tree", tree.as_str(), "-m", "aemi checkpoint"];
+    if git::has_head(dir) {
+        args.extend(["-p", "HEAD"]);
+    }
+    let commit = git::run_git_with_env(dir, &args, GIT_IDENT)?;
+    Ok(Snapshot::Git { commit, tree })
+}
+
+fn same_state(a: &Snapshot, b: &Snapshot) -> bool {
+    match (a, b) {
+        (Snapshot::Git { tree: a, .. }, Snapshot::Git { tree: b, .. }) => a == b,
+        (Snapshot::Files { files: a, .. }, Snapshot::Files { files: b, .. }) => {
+            a.len() == b.len() && a.iter().zip(b).all(|((pa, ea), (pb, eb))| pa == pb && ea.hash == eb.hash)
+        }
+        _ => false,
+    }
+}
+
+/// Release a dropped checkpoint's storage (git ref; blobs are collected by `gc_blobs`)
+fn release(dir: &str, checkpoint: &Checkpoint) {
+    if let Snapshot::Git { .. } = checkpoint.snapshot {
+        let _ = git::run_git(dir, &["update-ref", "-d", &format!("{}{}", GIT_REF_PREFIX, checkpoint.id)]);
+    }
+}
+
+/// Delete blobs no longer referenced by any checkpoint
+fn gc_blobs(store: &Path, index: &[Checkpoint]) {
+    let referenced: HashSet<&str> = index.iter()
+        .filter_map(|c| match &c.snapshot {
+            Snapshot::Files { files, .. } => Some(files.values().map(|e| e.hash.as_str())),
+            Snapshot::Git { .. } => None,
+        })
+        .flatten()
+        .collect();
+    let Ok(shards) = fs::read_dir(store.join("blobs")) else { return };
+    for shard in shards.filter_map(|e| e.ok()) {
+        let Ok(blobs) = fs::read_dir(shard.path()) else { continue };
+        for blob in blobs.filter_map(|e| e.ok()) {
+            if !referenced.contains(blob.file_name().to_string_lossy().as_ref()) {
+                let _ = fs::remove_file(blob.path());
+            }
+        }
+        let _ = fs::remove_dir(shard.path());
+    }
+}
+
+fn create_in(store: &Path, dir: &str, label: &str, max: usize) -> Result<Option<Checkpoint>, String> {
+    if max == 0 {
+        return Ok(None);
+    }
+    fs::create_dir_all(store).map_err(|e| e.to_string())?;
+    let mut index = load_index(store);
+
+    let snapshot = if git::is_repo_root(dir) {
+        git_snapshot(store, dir)?
+    } else {
+        let previous = index.iter().rev().find_map(|c| match &c.snapshot {
+            Snapshot::Files { files, taken_at, .. } => Some((files, *taken_at)),
+            Snapshot::Git { .. } => None,
+        });
+        file_snapshot(store, Path::new(dir), previous)?
+    };
+
+    // Nothing changed since the last checkpoint: undoing to it would be a no-op
+    if index.last().map(|c| same_state(&c.snapshot, &snapshot)).unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let id = format!(
+        "{}-{:04x}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S"),
+        rand::thread_rng().gen::<u16>()
+    );
+    if let Snapshot::Git { commit, .. } = &snapshot {
+        // A ref keeps the commit from being garbage-collected
+        git::run_git(dir, &["update-ref", &format!("{}{}", GIT_REF_PREFIX, id), commit])?;
+    }
+    let checkpoint = Checkpoint {
+        id,
+        created_at: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        label: label.to_string(),
+        snapshot,
+    };
+    index.push(checkpoint.clone());
+
+    if index.len() > max {
+        let excess = index.len() - max;
+        for old in index.drain(..excess) {
+            release(dir, &old);
+        }
+        gc_blobs(store, &index);
+    }
+    save_index(store, &index)?;
+    Ok(Some(checkpoint))
+}
+
+/// Write back changed and deleted files and remove files created since the snapshot.
+/// Paths the snapshot left out are never removed: their state at the time is unknown.
+fn restore_files(
+    store: &Path,
+    root: &Path,
+    files: &BTreeMap<String, FileEntry>,
+    skipped: &BTreeSet<String>,
+) -> Result<RestoreReport, String> {
+    let mut report = RestoreReport::default();
+    let current = scan_files(root)?.files;
+
+    for rel in current.keys().filter(|rel| !files.contains_key(*rel) && !is_skipped(skipped, rel)) {
+        let path = root.join(rel);
+        if fs::remove_file(&path).is_ok() {
+            report.removed += 1;
+            // Remove directories left empty, up to the session root
+            let mut parent = path.parent();
+            while let Some(dir) = parent.filter(|d| *d != root) {
+                if fs::remove_dir(dir).is_err() {
+                    break;
+                }
+                parent = dir.parent();
+            }
+        }
+    }
+
+    for (rel, entry) in files {
+        let path = root.join(rel);
+        let unchanged = current.get(rel)
+            .filter(|m| m.len() == entry.size)
+            .and_then(|_| fs::read(&path).ok())
+            .map(|bytes| hex::encode(Sha256::digest(&bytes)) == entry.hash)
+            .unwrap_or(false);
+        if unchanged {
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        fs::copy(blob_path(store, &entry.hash), &path)
+            .map_err(|e| format!("{}: {}", rel, e))?;
+        let _ = fs::set_permissions(&path, fs::Permissions::from_mode(entry.mode));
+        report.restored += 1;
+    }
+    Ok(report)
+}
+
+fn restore_git(store: &Path, dir: &str, tree: &str) -> Result<RestoreReport, String> {
+    let current_tree = git_write_tree(store, dir)?;
+    let changed = |filter: &str| -> Result<Vec<String>, String> {
+        let out = git::run_git(dir, &["diff-tree", "-r", "-z", "--name-only", filter, tree, &current_tree])?;
+        Ok(out.split('\0').filter(|s| !s.is_empty()).map(String::from).collect())
+    };
+    let added = changed("--diff-filter=A")?;
+    let restored = changed("--diff-filter=MDT")?;
+
+    for rel in &added {
+        let _ = fs::remove_file(Path::new(dir).join(rel));
+    }
+
+    let tmp_index = store.join("git-index.tmp");
+    let index_str = tmp_index.to_string_lossy().to_string();
+    let env = [("GIT_INDEX_FILE", index_str.as_str())];
+    let result = git::run_git_with_env(dir, &["read-tree", tree], &env)
+        .and_then(|_| git::run_git_with_env(dir, &["checkout-index", "-a", "-f"], &env));
+    let _ = fs::remove_file(&tmp_index);
+    result?;
+
+    Ok(RestoreReport { restored: restored.len(), removed: added.len() })
+}
+
+/// Restore the checkpoint with `id`, then drop it and every newer checkpoint
+fn restore_in(store: &Path, dir: &str, id: &str) -> Result<(Checkpoint, RestoreReport), String> {
+    let mut index = load_index(store);
+    let pos = index.iter().position(|c| c.id == id)
+        .ok_or_else(|| format!("checkpoint {} not found", id))?;
+    let checkpoint = index[pos].clone();
+
+    let report = match &checkpoint.snapshot {
+        Snapshot::Git { tree, .. } => restore_git(store, dir, tree)?,
+        Snapshot::Files { files, skipped, .. } => restore_files(store, Path::new(dir), files, skipped)?,
+    };
+
+    for dropped in index.drain(pos..) {
+        release(dir, &dropped);
+    }
+    gc_blobs(store, &index);
+    save_index(store, &index)?;
+    Ok((checkpoint, report))
+}
+
+/// Checkpoint label from a prompt: its first line, shortened
+pub fn label_for(prompt: &str) -> String {
+    const MAX_CHARS: usize = 60;
+    let line = prompt.lines().find(|l| !l.trim().is_empty()).unwrap_or("").trim();
+    if line.chars().count() > MAX_CHARS {
+        format!("{}…", line.chars().take(MAX_CHARS).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+/// Take a checkpoint before an agent turn, logging instead of failing (a turn is never
+/// blocked by a checkpoint error)
+pub async fn create_before_turn(dir: &str, prompt: &str) {
+    let dir = dir.to_string();
+    let label = label_for(prompt);
+    let result = tokio::task::spawn_blocking(move || create(&dir, &label)).await;
+    if let Ok(Err(e)) = result {
+        let ts = chrono::Local::now().format("%H:%M:%S");
+        println!("  [{ts}] ⚠ Checkpoint failed: {}", e);
+    }
+}
+
+/// Snapshot `dir` before an agent turn. Returns None if checkpoints are disabled
+/// or nothing changed since the last checkpoint.
+pub fn create(dir: &str, label: &str) -> Result<Option<Checkpoint>, String> {
+    let store = store_dir(dir).ok_or_else(|| "cannot resolve checkpoint directory".to_string())?;
+    create_in(&store, dir, label, max_checkpoints())
+}
+
+/// Checkpoints for `dir`, oldest first
+pub fn list(dir: &str) -> Vec<Checkpoint> {
+    store_dir(dir).map(|store| load_index(&store)).unwrap_or_default()
+}
+
+/// Restore a checkpoint by id; it and all newer checkpoints are dropped
+pub fn restore(dir: &str, id: &str) -> Result<(Checkpoint, RestoreReport), String> {
+    let store = store_dir(dir).ok_or_else(|| "cannot resolve checkpoint directory".to_string())?;
+    restore_in(&store, dir, id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, rel: &str, content: &str) {
+        let path = root.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_file_checkpoint_roundtrip() {
+        let store = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path().to_str().unwrap();
+        write(work.path(), "a.txt", "one");
+        write(work.path(), "sub/b.txt", "two");
+        write(work.path(), "node_modules/dep.js", "skip me");
+
+        let cp = create_in(store.path(), dir, "first", 5).unwrap().unwrap();
+        assert_eq!(cp.kind(), "files");
+
+        // Simulate an agent turn
+        write(work.path(), "a.txt", "changed");
+        fs::remove_file(work.path().join("sub/b.txt")).unwrap();
+        write(work.path(), "new/c.txt", "added");
+
+        let (_, report) = restore_in(store.path(), dir, &cp.id).unwrap();
+        assert_eq!(report.restored, 2);
+        assert_eq!(report.removed, 1);
+        assert_eq!(fs::read_to_string(work.path().join("a.txt")).unwrap(), "one");
+        assert_eq!(fs::read_to_string(work.path().join("sub/b.txt")).unwrap(), "two");
+        assert!(!work.path().join("new").exists());
+        assert!(work.path().join("node_modules/dep.js").exists());
+        assert!(load_index(store.path()).is_empty());
+    }
+
+    #[test]
+    fn test_unchanged_directory_is_not_checkpointed_twice() {
+        let store = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path().to_str().unwrap();
+        write(work.path(), "a.txt", "one");
+
+        assert!(create_in(store.path(), dir, "1", 5).unwrap().is_some());
+        assert!(create_in(store.path(), dir, "2", 5).unwrap().is_none());
+        write(work.path(), "a.txt", "two");
+        assert!(create_in(store.path(), dir, "3", 5).unwrap().is_some());
+        assert_eq!(load_index(store.path()).len(), 2);
+    }
+
+    #[test]
+    fn test_limit_drops_oldest_and_collects_blobs() {
+        let store = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path().to_str().unwrap();
+        for i in 0..4 {
+            write(work.path(), "a.txt", &format!("v{}", i));
+            create_in(store.path(), dir, &i.to_string(), 2).unwrap();
+        }
+        let index = load_index(store.path());
+        assert_eq!(index.iter().map(|c| c.label.as_str()).collect::<Vec<_>>(), vec!["2", "3"]);
+        let old_hash = hex::encode(Sha256::digest(b"v0"));
+        assert!(!blob_path(store.path(), &old_hash).exists());
+    }
+
+    #[test]
+    fn test_unchanged_files_are_not_reread() {
+        let store = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path().to_str().unwrap();
+        write(work.path(), "a.txt", "one");
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(60);
+        fs::File::options().write(true).open(work.path().join("a.txt")).unwrap().set_modified(old).unwrap();
+        assert!(create_in(store.path(), dir, "1", 5).unwrap().is_some());
+
+        // Same size and mtime: the previous hash is reused without reading the file
+        write(work.path(), "a.txt", "two");
+        fs::File::options().write(true).open(work.path().join("a.txt")).unwrap().set_modified(old).unwrap();
+        assert!(create_in(store.path(), dir, "2", 5).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restore_keeps_skipped_paths() {
+        let store = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path().to_str().unwrap();
+        write(work.path(), "a.txt", "one");
+        fs::File::create(work.path().join("big.bin")).unwrap().set_len(MAX_FILE_BYTES + 1).unwrap();
+        write(work.path(), ".aemi/notes.md", "aemi data");
+
+        let cp = create_in(store.path(), dir, "turn", 5).unwrap().unwrap();
+        let Snapshot::Files { skipped, .. } = &cp.snapshot else { panic!("expected a file snapshot") };
+        assert!(skipped.contains("big.bin") && skipped.contains(".aemi"));
+
+        // The big file shrinks below the limit; it was never recorded, so it must survive
+        fs::write(work.path().join("big.bin"), "small now").unwrap();
+        write(work.path(), "a.txt", "two");
+        let (_, report) = restore_in(store.path(), dir, &cp.id).unwrap();
+        assert_eq!(report.removed, 0);
+        assert_eq!(fs::read_to_string(work.path().join("a.txt")).unwrap(), "one");
+        assert_eq!(fs::read_to_string(work.path().join("big.bin")).unwrap(), "small now");
+        assert!(work.path().join(".aemi/notes.md").exists());
+    }
+
+    #[test]
+    fn test_label_for() {
+        assert_eq!(label_for("\n  fix the parser  \nmore"), "fix the parser");
+        assert_eq!(label_for(&"가".repeat(70)), format!("{}…", "가".repeat(60)));
+    }
+
+    #[test]
+    fn test_disabled_when_max_is_zero() {
+        let store = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        assert!(create_in(store.path(), work.path().to_str().unwrap(), "x", 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_git_checkpoint_roundtrip() {
+        let store = tempfile::tempdir().unwrap();
+        let work = tempfile::tempdir().unwrap();
+        let dir = work.path().to_str().unwrap();
+        git::run_git(dir, &["init", "-q"]).unwrap();
+        write(work.path(), "tracked.txt", "base");
+        git::run_git(dir, &["add", "-A"]).unwrap();
+        write(work.path(), "untracked.txt", "keep");
+
+        let cp = create_in(store.path(), dir, "turn", 5).unwrap().unwrap();
+        assert_eq!(cp.kind(), "git");
+        // The real index is untouched
+        assert_eq!(git::run_git(dir, &["ls-files"]).unwrap(), "tracked.txt");
+
+        write(work.path(), "tracked.txt", "edited");
+        fs::remove_file(work.path().join("untracked.txt")).unwrap();
+        write(work.path(), "scratch.txt", "new");
+
+        let (_, report) = restore_in(store.path(), dir, &cp.id).unwrap();
+        assert_eq!(report.restored, 2);
+        assert_eq!(report.removed, 1);
+        assert_eq!(fs::read_to_string(work.path().join("tracked.txt")).unwrap(), "base");
+        assert_eq!(fs::read_to_string(work.path().join("untracked.txt")).unwrap(), "keep");
+        assert!(!work.path().join("scratch.txt").exists());
+        assert!(git::run_git(dir, &["for-each-ref", GIT_REF_PREFIX]).unwrap().is_empty());
+    }
+}
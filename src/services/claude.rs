@@ -5,6 +5,7 @@ use serde_json::Value;
 
 pub use super::agent::{StreamMessage, CancelToken, AgentResponse};
 use super::provider_common::{self, StreamingConfig, DEFAULT_SYSTEM_PROMPT};
+use super::session::Usage;
 
 // Generate resolve_binary_path(), get_binary_path(), is_cli_available(), debug_log() for "claude"
 define_ai_service_helpers!("claude");
@@ -31,6 +32,41 @@ pub const DEFAULT_ALLOWED_TOOLS: &[&str] = &[
     "TaskCreate", "TaskGet", "TaskUpdate", "TaskList",
 ];
 
+/// Claude's built-in tools, disallowed for text-only jobs. `--allowedTools` only lists tools
+/// that run without asking, so an empty list alone would still leave read-only tools usable.
+const BUILTIN_TOOLS: &[&str] = &[
+    "Bash", "BashOutput", "KillShell", "Read", "Edit", "MultiEdit", "Write", "Glob", "Grep",
+    "LS", "Task", "TaskOutput", "TaskStop", "WebFetch", "WebSearch", "NotebookRead",
+    "NotebookEdit", "Skill", "SlashCommand", "TodoWrite", "TaskCreate", "TaskGet",
+    "TaskUpdate", "TaskList", "AskUserQuestion", "EnterPlanMode", "ExitPlanMode",
+];
+
+/// One-shot prompt for text-only jobs like conversation summaries and commit messages,
+/// with the built-in tools in [`BUILTIN_TOOLS`] disallowed. Tools that are not in that list
+/// (newer built-ins, MCP servers the user configured) are not blocked. The other agents
+/// auto-approve their tools, so these jobs always run on Claude whatever the chat's agent is.
+/// With a cancel token the CLI runs in its own process group and its pid is stored on the
+/// token, so a /stop kills it.
+pub fn execute_without_tools(prompt: &str, working_dir: &str, cancel_token: Option<&CancelToken>) -> ClaudeResponse {
+    let mut args = print_args(&[]);
+    args.push("--disallowedTools".to_string());
+    args.push(BUILTIN_TOOLS.join(","));
+    run_print(prompt, args, working_dir, cancel_token)
+}
+
+/// Arguments of a one-shot `claude -p` call with JSON output
+fn print_args(allowed_tools: &[String]) -> Vec<String> {
+    vec![
+        "-p".to_string(),
+        "--allowedTools".to_string(),
+        allowed_tools.join(","),
+        "--output-format".to_string(),
+        "json".to_string(),
+        "--append-system-prompt".to_string(),
+        DEFAULT_SYSTEM_PROMPT.to_string(),
+    ]
+}
+
 /// Execute a command using Claude CLI
 pub fn execute_command(
     prompt: &str,
@@ -38,19 +74,15 @@ pub fn execute_command(
     working_dir: &str,
     allowed_tools: Option<&[String]>,
 ) -> ClaudeResponse {
-    let tools_str = match allowed_tools {
-        Some(tools) => tools.join(","),
-        None => DEFAULT_ALLOWED_TOOLS.join(","),
+    let default_tools: Vec<String>;
+    let tools = match allowed_tools {
+        Some(tools) => tools,
+        None => {
+            default_tools = DEFAULT_ALLOWED_TOOLS.iter().map(|t| t.to_string()).collect();
+            &default_tools
+        }
     };
-    let mut args = vec![
-        "-p".to_string(),
-        "--allowedTools".to_string(),
-        tools_str,
-        "--output-format".to_string(),
-        "json".to_string(),
-        "--append-system-prompt".to_string(),
-        DEFAULT_SYSTEM_PROMPT.to_string(),
-    ];
+    let mut args = print_args(tools);
 
     // Resume session if available
     if let Some(sid) = session_id {
@@ -66,6 +98,16 @@ pub fn execute_command(
         args.push(sid.to_string());
     }
 
+    run_print(prompt, args, working_dir, None)
+}
+
+/// Run `claude` with `args`, feed it `prompt` on stdin and parse its JSON output
+fn run_print(
+    prompt: &str,
+    args: Vec<String>,
+    working_dir: &str,
+    cancel_token: Option<&CancelToken>,
+) -> ClaudeResponse {
     let claude_bin = match get_binary_path() {
         Some(path) => path,
         None => {
@@ -78,8 +120,8 @@ pub fn execute_command(
         }
     };
 
-    let mut child = match Command::new(claude_bin)
-        .args(&args)
+    let mut cmd = Command::new(claude_bin);
+    cmd.args(&args)
         .current_dir(working_dir)
         .env("CLAUDE_CODE_MAX_OUTPUT_TOKENS", "64000")
         .env("BASH_DEFAULT_TIMEOUT_MS", "86400000")  // 24 hours (no practical timeout)
@@ -87,9 +129,16 @@ pub fn execute_command(
         .env_remove("CLAUDECODE")  // Allow running from within Claude Code sessions
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
+        .stderr(Stdio::piped());
+    // Own process group when cancellable, like the streaming calls, so a cancel stops
+    // the CLI together with anything it started
+    #[cfg(unix)]
+    if cancel_token.is_some() {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    let mut child = match cmd.spawn() {
         Ok(child) => child,
         Err(e) => {
             return ClaudeResponse {
@@ -101,6 +150,16 @@ pub fn execute_command(
         }
     };
 
+    if let Some(token) = cancel_token {
+        if let Ok(mut pid) = token.child_pid.lock() {
+            *pid = Some(child.id());
+        }
+        // A cancel that came before the pid was stored had nothing to kill
+        if token.cancelled.load(std::sync::atomic::Ordering::Relaxed) {
+            token.kill_child();
+        }
+    }
+
     // Write prompt to stdin
     if let Some(mut stdin) = child.stdin.take() {
         let _ = stdin.write_all(prompt.as_bytes());
@@ -188,6 +247,7 @@ pub fn is_ai_supported() -> bool {
 /// Execute a command using Claude CLI with streaming output
 /// If `system_prompt` is None, uses the default file manager system prompt.
 /// If `system_prompt` is Some(""), no system prompt is appended.
+#[allow(clippy::too_many_arguments)]
 pub fn execute_command_streaming(
     prompt: &str,
     session_id: Option<&str>,
@@ -195,10 +255,42 @@ pub fn execute_command_streaming(
     sender: Sender<StreamMessage>,
     system_prompt: Option<&str>,
     allowed_tools: Option<&[String]>,
+    model: Option<&str>,
+    cancel_token: Option<std::sync::Arc<CancelToken>>,
+) -> Result<(), String> {
+    execute_streaming(prompt, session_id, false, working_dir, sender, system_prompt, allowed_tools, model, cancel_token)
+}
+
+/// Like [`execute_command_streaming`], but resumes `session_id` with `--fork-session`
+/// so Claude continues from that conversation under a new session id.
+#[allow(clippy::too_many_arguments)]
+pub fn execute_command_streaming_fork(
+    prompt: &str,
+    session_id: Option<&str>,
+    working_dir: &str,
+    sender: Sender<StreamMessage>,
+    system_prompt: Option<&str>,
+    allowed_tools: Option<&[String]>,
+    model: Option<&str>,
+    cancel_token: Option<std::sync::Arc<CancelToken>>,
+) -> Result<(), String> {
+    execute_streaming(prompt, session_id, true, working_dir, sender, system_prompt, allowed_tools, model, cancel_token)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_streaming(
+    prompt: &str,
+    session_id: Option<&str>,
+    fork_session: bool,
+    working_dir: &str,
+    sender: Sender<StreamMessage>,
+    system_prompt: Option<&str>,
+    allowed_tools: Option<&[String]>,
+    model: Option<&str>,
     cancel_token: Option<std::sync::Arc<CancelToken>>,
 ) -> Result<(), String> {
     debug_log(&format!("prompt_len: {} chars", prompt.len()));
-    debug_log(&format!("session_id: {:?}", session_id));
+    debug_log(&format!("session_id: {:?} fork: {}", session_id, fork_session));
 
     let tools_str = match allowed_tools {
         Some(tools) => tools.join(","),
@@ -224,6 +316,8 @@ pub fn execute_command_streaming(
         args.push(sp.to_string());
     }
 
+    provider_common::push_model_arg(&mut args, model);
+
     // Resume session if available
     if let Some(sid) = session_id {
         if !is_valid_session_id(sid) {
@@ -232,6 +326,9 @@ pub fn execute_command_streaming(
         }
         args.push("--resume".to_string());
         args.push(sid.to_string());
+        if fork_session {
+            args.push("--fork-session".to_string());
+        }
     }
 
     let binary_path = get_binary_path()
@@ -259,10 +356,45 @@ pub fn execute_command_streaming(
         &config,
         sender,
         cancel_token,
-        provider_common::make_default_handler(parse_stream_message),
+        |json: &Value, sender: &Sender<StreamMessage>, state: &mut provider_common::StreamState| {
+            // Model (init) and token usage (result) go out before the message itself,
+            // so the receiver has them when Done arrives
+            if let Some(meta) = parse_usage_message(json) {
+                let _ = sender.send(meta);
+            }
+            match parse_stream_message(json) {
+                Some(msg) => provider_common::handle_parsed_message(msg, sender, state),
+                None => true,
+            }
+        },
     )
 }
 
+/// Extract the model (system/init) or token usage and cost (result) from a stream-json line
+fn parse_usage_message(json: &Value) -> Option<StreamMessage> {
+    match json.get("type")?.as_str()? {
+        "system" if json.get("subtype").and_then(|v| v.as_str()) == Some("init") => {
+            let model = json.get("model")?.as_str()?.to_string();
+            Some(StreamMessage::Usage { model: Some(model), usage: None })
+        }
+        "result" => {
+            let usage = json.get("usage")?;
+            let tokens = |key: &str| usage.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+            Some(StreamMessage::Usage {
+                model: None,
+                usage: Some(Usage {
+                    input_tokens: tokens("input_tokens")
+                        + tokens("cache_creation_input_tokens")
+                        + tokens("cache_read_input_tokens"),
+                    output_tokens: tokens("output_tokens"),
+                    cost_usd: json.get("total_cost_usd").and_then(|v| v.as_f64()),
+                }),
+            })
+        }
+        _ => None,
+    }
+}
+
 /// Parse a stream-json line into a StreamMessage
 fn parse_stream_message(json: &Value) -> Option<StreamMessage> {
     let msg_type = json.get("type")?.as_str()?;
@@ -608,6 +740,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_usage_message_init_model() {
+        let json: Value = serde_json::from_str(
+            r#"{"type":"system","subtype":"init","session_id":"s","model":"claude-sonnet-4-5"}"#
+        ).unwrap();
+
+        match parse_usage_message(&json) {
+            Some(StreamMessage::Usage { model, usage }) => {
+                assert_eq!(model.as_deref(), Some("claude-sonnet-4-5"));
+                assert!(usage.is_none());
+            }
+            _ => panic!("Expected Usage message"),
+        }
+    }
+
+    #[test]
+    fn test_parse_usage_message_result() {
+        let json: Value = serde_json::from_str(
+            r#"{"type":"result","result":"ok","total_cost_usd":0.25,"usage":{"input_tokens":10,"cache_read_input_tokens":90,"output_tokens":5}}"#
+        ).unwrap();
+
+        match parse_usage_message(&json) {
+            Some(StreamMessage::Usage { usage: Some(u), .. }) => {
+                assert_eq!(u.input_tokens, 100);
+                assert_eq!(u.output_tokens, 5);
+                assert_eq!(u.cost_usd, Some(0.25));
+            }
+            _ => panic!("Expected Usage message"),
+        }
+    }
+
     #[test]
     fn test_parse_stream_message_unknown_type() {
         let json: Value = serde_json::from_str(
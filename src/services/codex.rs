@@ -134,6 +134,7 @@ fn parse_codex_jsonl_output(output: &str) -> AgentResponse {
 }
 
 /// Execute a command using Codex CLI with streaming output
+#[allow(clippy::too_many_arguments)]
 pub fn execute_command_streaming(
     prompt: &str,
     session_id: Option<&str>,
@@ -141,6 +142,7 @@ pub fn execute_command_streaming(
     sender: Sender<StreamMessage>,
     system_prompt: Option<&str>,
     _allowed_tools: Option<&[String]>, // Codex uses --full-auto instead of tool allowlist
+    model: Option<&str>,
     cancel_token: Option<std::sync::Arc<CancelToken>>,
 ) -> Result<(), String> {
     debug_log(&format!("prompt_len: {} chars", prompt.len()));
@@ -154,6 +156,7 @@ pub fn execute_command_streaming(
         "--json".to_string(),
         "--full-auto".to_string(),
     ];
+    provider_common::push_model_arg(&mut args, model);
 
     // Note: Codex CLI does not support --resume for session continuity.
     // Session tracking is handled at the aemi level only.
@@ -0,0 +1,167 @@
+//! Conversation compaction for `/compact`.
+
+use std::sync::OnceLock;
+
+use crate::services::agent::CancelToken;
+use crate::services::claude;
+use crate::services::bot_common;
+use crate::services::session::{HistoryItem, HistoryType, SessionData};
+use crate::services::utils::truncate_str;
+
+/// Max transcript characters included in the summary prompt (most recent kept)
+const TRANSCRIPT_LIMIT: usize = 60000;
+
+/// Max characters of a single history item in the transcript
+const ITEM_LIMIT: usize = 4000;
+
+/// First line of the history item that holds a compacted brief
+pub const BRIEF_HEADER: &str = "[Compacted conversation summary]";
+
+/// Why compaction cannot run without the Claude CLI
+pub const CLAUDE_REQUIRED: &str = "Compaction needs the Claude CLI: the summary is always written by claude with its tools disallowed, whatever the chat's agent is.";
+
+/// History size (items) that triggers compaction before the next turn, if configured
+pub fn auto_compact_items() -> Option<usize> {
+    static ITEMS: OnceLock<Option<usize>> = OnceLock::new();
+    *ITEMS.get_or_init(|| {
+        std::env::var("AEMI_AUTO_COMPACT").ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|n| *n > 0)
+    })
+}
+
+/// Whether the history is over the automatic compaction threshold
+pub fn over_threshold(history: &[HistoryItem]) -> bool {
+    auto_compact_items().map(|n| history.len() > n).unwrap_or(false)
+}
+
+/// Whether an agent error reports that the conversation no longer fits the context window
+pub fn is_context_overflow(message: &str) -> bool {
+    let lower = message.to_lowercase();
+    [
+        "prompt is too long",
+        "context length",
+        "context window",
+        "context_length_exceeded",
+        "maximum context",
+        "too many tokens",
+    ].iter().any(|pattern| lower.contains(pattern))
+}
+
+fn is_brief(item: &HistoryItem) -> bool {
+    item.item_type == HistoryType::Assistant && item.content.starts_with(BRIEF_HEADER)
+}
+
+/// Render history as a transcript, newest items first to fit the budget.
+/// A brief from an earlier compaction is always kept so nothing silently drops out.
+fn transcript(history: &[HistoryItem]) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut total = 0;
+    let mut cut = false;
+    for item in history.iter().rev() {
+        let prefix = match item.item_type {
+            HistoryType::User => "User",
+            HistoryType::Assistant => "Assistant",
+            HistoryType::ToolUse => "Tool",
+            _ => continue,
+        };
+        let line = format!("{}: {}", prefix, truncate_str(&item.content, ITEM_LIMIT));
+        total += line.len();
+        if total > TRANSCRIPT_LIMIT {
+            cut = true;
+            break;
+        }
+        lines.push(line);
+    }
+    if cut {
+        if let Some(first) = history.first().filter(|item| is_brief(item)) {
+            lines.push(format!("Assistant: {}", truncate_str(&first.content, TRANSCRIPT_LIMIT / 4)));
+        }
+    }
+    lines.reverse();
+    lines.join("\n\n")
+}
+
+/// Prompt asking the agent to summarize the conversation
+pub fn summary_prompt(history: &[HistoryItem]) -> String {
+    format!(
+        "Summarize the conversation below so it can be continued in a new session that has no \
+         other memory of it. Write a concise brief covering: the user's goals, decisions made, \
+         the current state of the work (files created or changed, commands that matter), open \
+         questions and the next steps. Keep exact file paths, names and values. Output only the \
+         brief, with no preamble and without using any tools.\n\n\
+         Conversation:\n{}",
+        transcript(history)
+    )
+}
+
+/// Summarize `history` with Claude and its tools disallowed (see [`claude::execute_without_tools`]),
+/// whatever the chat's agent: the others would run with their tools auto-approved.
+/// Blocking; call from `spawn_blocking`.
+/// Cancelling `cancel` kills the Claude process.
+pub fn generate_brief(dir: &str, history: &[HistoryItem], cancel: &CancelToken) -> Result<String, String> {
+    if !claude::is_claude_available() {
+        return Err(CLAUDE_REQUIRED.to_string());
+    }
+    let response = claude::execute_without_tools(&summary_prompt(history), dir, Some(cancel));
+    if !response.success {
+        return Err(response.error.unwrap_or_else(|| "agent failed".to_string()));
+    }
+    let brief = response.response.unwrap_or_default().trim().to_string();
+    if brief.is_empty() {
+        Err("agent returned an empty summary".to_string())
+    } else {
+        Ok(brief)
+    }
+}
+
+/// Context prepended to the first prompt of the compacted session
+pub fn seed_prompt(brief: &str) -> String {
+    format!(
+        "[Compacted conversation — summary of the earlier conversation for context]\n{}\n[End of summary]",
+        brief
+    )
+}
+
+/// Save a new session holding only the brief, linked back to `parent_session_id`.
+/// Like a fork, it gets a placeholder id until the provider assigns its own.
+pub fn start_compacted_session(parent_session_id: &str, brief: &str, current_path: &str) -> Option<SessionData> {
+    let item = HistoryItem::new(HistoryType::Assistant, format!("{}\n{}", BRIEF_HEADER, brief));
+    bot_common::fork_session(parent_session_id, &[item], current_path, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_context_overflow() {
+        assert!(is_context_overflow("API Error: 400 prompt is too long: 210000 tokens > 200000 maximum"));
+        assert!(is_context_overflow("context_length_exceeded"));
+        assert!(!is_context_overflow("session abc not found"));
+    }
+
+    #[test]
+    fn test_transcript_keeps_recent_and_earlier_brief() {
+        let mut history = vec![HistoryItem::new(HistoryType::Assistant, format!("{}\nold goals", BRIEF_HEADER))];
+        for i in 0..40 {
+            history.push(HistoryItem::new(HistoryType::User, format!("question {} {}", i, "x".repeat(3000))));
+            history.push(HistoryItem::new(HistoryType::System, "ignored"));
+        }
+        let text = transcript(&history);
+        assert!(text.len() < TRANSCRIPT_LIMIT + ITEM_LIMIT);
+        assert!(text.starts_with("Assistant: [Compacted conversation summary]\nold goals"));
+        assert!(text.contains("question 39"));
+        assert!(!text.contains("question 0 "));
+        assert!(!text.contains("ignored"));
+    }
+
+    #[test]
+    fn test_transcript_short_history() {
+        let history = vec![
+            HistoryItem::new(HistoryType::User, "hi"),
+            HistoryItem::new(HistoryType::Assistant, "hello"),
+        ];
+        assert_eq!(transcript(&history), "User: hi\n\nAssistant: hello");
+    }
+}
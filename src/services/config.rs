@@ -0,0 +1,294 @@
+//! Config file for `aemi serve` (default `~/.aemi/config.toml`).
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::services::access;
+use crate::services::bot_common::{self, BotOptions, ALL_TOOLS};
+use crate::services::credentials::{self, TokenSource};
+use crate::services::roles::Role;
+
+/// Default config path (~/.aemi/config.toml)
+pub fn default_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".aemi").join("config.toml"))
+}
+
+/// Each `[[bot]]` table runs one bot instance; Telegram and Discord bots can be mixed
+/// and all run in the same process
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default, rename = "bot")]
+    bots: Vec<BotEntry>,
+}
+
+/// One `[[bot]]` table as written in the file:
+///
+/// ```toml
+/// [[bot]]
+/// name = "work"                        # optional, shown in logs
+/// platform = "telegram"                # telegram | discord
+/// token_env = "AEMI_WORK_TOKEN"        # or token_file = "~/.aemi/work.token",
+///                                      # token_command = "pass show aemi/work", or token = "..."
+/// chats = [123456789]                  # Telegram chat ids / Discord channel ids
+/// agent = "claude"                     # default agent (default: claude)
+/// allowed_tools = ["Read", "Grep"]     # optional, replaces the saved tool list
+/// workspace_roots = ["~/projects"]     # optional, replaces AEMI_ALLOWED_ROOTS for this bot
+/// chat_role = "user"                   # optional role of chat members: admin | user | viewer
+/// users = { "987654321" = "viewer" }   # optional user ids and roles, added to /users
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BotEntry {
+    name: Option<String>,
+    platform: String,
+    token: Option<String>,
+    token_env: Option<String>,
+    token_file: Option<String>,
+    token_command: Option<String>,
+    #[serde(default)]
+    chats: Vec<i64>,
+    agent: Option<String>,
+    allowed_tools: Option<Vec<String>>,
+    workspace_roots: Option<Vec<String>>,
+    chat_role: Option<String>,
+    #[serde(default)]
+    users: BTreeMap<String, String>,
+}
+
+/// Messaging platform of a configured bot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Platform {
+    Telegram,
+    Discord,
+}
+
+impl Platform {
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Telegram => "telegram",
+            Platform::Discord => "discord",
+        }
+    }
+}
+
+/// A validated bot instance, ready to run
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    pub name: String,
+    pub platform: Platform,
+    pub token: String,
+    /// Chat ids (Telegram) or channel ids (Discord)
+    pub chats: Vec<i64>,
+    pub agent: String,
+    pub options: BotOptions,
+}
+
+/// Read the token from exactly one of `token`, `token_env`, `token_file` or `token_command`
+fn resolve_token(entry: &BotEntry, label: &str) -> Result<String, String> {
+    let source = match (&entry.token, &entry.token_env, &entry.token_file, &entry.token_command) {
+        (Some(token), None, None, None) => {
+            let token = token.trim().to_string();
+            if token.is_empty() {
+                return Err(format!("{}: token is empty", label));
+            }
+            return Ok(token);
+        }
+        (None, Some(var), None, None) => TokenSource::Env(var.clone()),
+        (None, None, Some(file), None) => TokenSource::File(file.clone()),
+        (None, None, None, Some(cmd)) => TokenSource::Command(cmd.clone()),
+        (None, None, None, None) => {
+            return Err(format!("{}: one of token, token_env, token_file or token_command is required", label));
+        }
+        _ => return Err(format!("{}: set only one of token, token_env, token_file or token_command", label)),
+    };
+    credentials::read(&source).map_err(|e| format!("{}: {}", label, e))
+}
+
+/// Match tool names case-insensitively against the known tools
+fn resolve_tools(names: &[String], label: &str) -> Result<Vec<String>, String> {
+    names.iter()
+        .map(|name| {
+            ALL_TOOLS.iter()
+                .find(|(tool, _, _)| tool.eq_ignore_ascii_case(name))
+                .map(|(tool, _, _)| tool.to_string())
+                .ok_or_else(|| format!("{}: unknown tool '{}'", label, name))
+        })
+        .collect()
+}
+
+fn parse_role(name: &str, label: &str) -> Result<Role, String> {
+    Role::parse(name).ok_or_else(|| format!("{}: unknown role '{}'. Roles: admin, user, viewer", label, name))
+}
+
+/// Parse the `users` table: user id → role
+fn resolve_users(users: &BTreeMap<String, String>, label: &str) -> Result<BTreeMap<u64, Role>, String> {
+    users.iter()
+        .map(|(id, role)| {
+            let id = id.parse::<u64>().map_err(|_| format!("{}: invalid user id '{}'", label, id))?;
+            Ok((id, parse_role(role, label)?))
+        })
+        .collect()
+}
+
+fn validate(entry: BotEntry, index: usize) -> Result<BotConfig, String> {
+    let label = match &entry.name {
+        Some(name) => format!("bot '{}'", name),
+        None => format!("bot #{}", index + 1),
+    };
+    let platform = match entry.platform.as_str() {
+        "telegram" => Platform::Telegram,
+        "discord" => Platform::Discord,
+        other => return Err(format!("{}: unsupported platform '{}'. Supported: telegram, discord", label, other)),
+    };
+    let agent = entry.agent.clone().unwrap_or_else(|| "claude".to_string());
+    if !bot_common::is_valid_agent(&agent) {
+        return Err(format!("{}: unsupported agent '{}'", label, agent));
+    }
+    if entry.chats.is_empty() {
+        return Err(format!("{}: chats must list at least one chat or channel id (security)", label));
+    }
+    if platform == Platform::Discord && entry.chats.iter().any(|id| *id <= 0) {
+        return Err(format!("{}: Discord channel ids must be positive", label));
+    }
+    let token = resolve_token(&entry, &label)?;
+    let allowed_tools = entry.allowed_tools.as_deref()
+        .map(|names| resolve_tools(names, &label))
+        .transpose()?;
+    let workspace_roots = entry.workspace_roots.as_deref().map(access::roots_from);
+    let chat_role = entry.chat_role.as_deref()
+        .map(|name| parse_role(name, &label))
+        .transpose()?;
+    let users = resolve_users(&entry.users, &label)?;
+
+    Ok(BotConfig {
+        name: entry.name.unwrap_or_else(|| format!("{}-{}", platform.name(), index + 1)),
+        platform,
+        token,
+        chats: entry.chats,
+        agent,
+        options: BotOptions { allowed_tools, workspace_roots, chat_role, users, config_path: None },
+    })
+}
+
+/// Parse and validate config file contents
+pub fn parse(content: &str) -> Result<Vec<BotConfig>, String> {
+    let file: ConfigFile = toml::from_str(content).map_err(|e| e.to_string())?;
+    if file.bots.is_empty() {
+        return Err("no [[bot]] entries defined".to_string());
+    }
+    let bots = file.bots.into_iter()
+        .enumerate()
+        .map(|(i, entry)| validate(entry, i))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut seen = HashSet::new();
+    for bot in &bots {
+        if !seen.insert((bot.platform, bot.token.as_str())) {
+            return Err(format!("bot '{}': the same token is used by more than one bot", bot.name));
+        }
+    }
+    Ok(bots)
+}
+
+/// Load the config file at `path`
+pub fn load(path: &Path) -> Result<Vec<BotConfig>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let mut bots = parse(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+    for bot in &mut bots {
+        bot.options.config_path = Some(path.to_path_buf());
+    }
+    Ok(bots)
+}
+
+/// Re-read the config file and return the entry of a running bot, matched by platform and token
+pub fn find(path: &Path, platform: Platform, token: &str) -> Result<BotConfig, String> {
+    load(path)?.into_iter()
+        .find(|bot| bot.platform == platform && bot.token == token)
+        .ok_or_else(|| format!("this bot is no longer in {}; restart aemi to stop it", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mixed_bots() {
+        let bots = parse(r#"
+            [[bot]]
+            name = "work"
+            platform = "telegram"
+            token = "123:abc"
+            chats = [111, -222]
+            agent = "codex"
+            allowed_tools = ["read", "GREP"]
+            chat_role = "viewer"
+            users = { "42" = "admin" }
+
+            [[bot]]
+            platform = "discord"
+            token = " dc-token \n"
+            chats = [333]
+        "#).unwrap();
+
+        assert_eq!(bots.len(), 2);
+        assert_eq!(bots[0].name, "work");
+        assert_eq!(bots[0].platform, Platform::Telegram);
+        assert_eq!(bots[0].chats, vec![111, -222]);
+        assert_eq!(bots[0].agent, "codex");
+        assert_eq!(bots[0].options.allowed_tools, Some(vec!["Read".to_string(), "Grep".to_string()]));
+        assert!(bots[0].options.workspace_roots.is_none());
+        assert_eq!(bots[0].options.chat_role, Some(Role::Viewer));
+        assert_eq!(bots[0].options.users, BTreeMap::from([(42, Role::Admin)]));
+
+        assert_eq!(bots[1].name, "discord-2");
+        assert_eq!(bots[1].token, "dc-token");
+        assert_eq!(bots[1].agent, "claude");
+        assert!(bots[1].options.chat_role.is_none());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |toml: &str| parse(toml).unwrap_err();
+        assert!(err("").contains("no [[bot]]"));
+        assert!(err("[[bot]]\nplatform = \"slack\"\ntoken = \"t\"\nchats = [1]").contains("unsupported platform"));
+        assert!(err("[[bot]]\nplatform = \"telegram\"\ntoken = \"t\"").contains("chats"));
+        assert!(err("[[bot]]\nplatform = \"telegram\"\nchats = [1]").contains("token"));
+        assert!(err("[[bot]]\nplatform = \"telegram\"\ntoken = \"t\"\ntoken_env = \"X\"\nchats = [1]").contains("only one"));
+        assert!(err("[[bot]]\nplatform = \"telegram\"\ntoken = \"t\"\nchats = [1]\nallowed_tools = [\"Nope\"]").contains("unknown tool"));
+        assert!(err("[[bot]]\nplatform = \"telegram\"\ntoken = \"t\"\nchats = [1]\nchat_role = \"root\"").contains("unknown role"));
+        assert!(err("[[bot]]\nplatform = \"telegram\"\ntoken = \"t\"\nchats = [1]\nusers = { bob = \"user\" }").contains("invalid user id"));
+        assert!(err("[[bot]]\nplatform = \"telegram\"\ntoken = \"t\"\nchats = [1]\ncolour = \"red\"").contains("colour"));
+        assert!(err("[[bot]]\nplatform = \"telegram\"\ntoken = \"t\"\nchats = [1]\n[[bot]]\nplatform = \"telegram\"\ntoken = \"t\"\nchats = [2]").contains("same token"));
+    }
+
+    #[test]
+    fn test_token_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("bot.token");
+        std::fs::write(&file, "from-file\n").unwrap();
+        let bots = parse(&format!(
+            "[[bot]]\nplatform = \"discord\"\ntoken_file = \"{}\"\nchats = [5]",
+            file.display()
+        )).unwrap();
+        assert_eq!(bots[0].token, "from-file");
+
+        let bots = parse("[[bot]]\nplatform = \"discord\"\ntoken_command = \"echo from-helper\"\nchats = [5]").unwrap();
+        assert_eq!(bots[0].token, "from-helper");
+    }
+
+    #[test]
+    fn test_find() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "[[bot]]\nplatform = \"telegram\"\ntoken = \"a\"\nchats = [1]\n\
+            [[bot]]\nplatform = \"telegram\"\ntoken = \"b\"\nchats = [2, 3]").unwrap();
+        let bot = find(&path, Platform::Telegram, "b").unwrap();
+        assert_eq!(bot.chats, vec![2, 3]);
+        assert_eq!(bot.options.config_path.as_deref(), Some(path.as_path()));
+        assert!(find(&path, Platform::Discord, "b").unwrap_err().contains("no longer"));
+    }
+}
@@ -0,0 +1,153 @@
+//! Bot token sources that keep tokens off the command line.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+
+use crate::services::access;
+
+/// Default environment variable for `aemi serve --routing telegram`
+pub const TELEGRAM_TOKEN_ENV: &str = "AEMI_TELEGRAM_TOKEN";
+/// Default environment variable for `aemi serve --routing discord`
+pub const DISCORD_TOKEN_ENV: &str = "AEMI_DISCORD_TOKEN";
+
+/// Where to read a bot token from: an environment variable, a file or a credential helper
+/// command whose stdout is the token (e.g. `pass show aemi/telegram`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenSource {
+    /// Environment variable name
+    Env(String),
+    /// Path to a file (`~` is expanded)
+    File(String),
+    /// Shell command run with `sh -c`; its stdout is the token
+    Command(String),
+}
+
+impl TokenSource {
+    /// Human-readable description for error messages
+    pub fn describe(&self) -> String {
+        match self {
+            TokenSource::Env(var) => format!("environment variable {}", var),
+            TokenSource::File(path) => format!("token file {}", path),
+            TokenSource::Command(cmd) => format!("credential helper `{}`", cmd),
+        }
+    }
+}
+
+/// Token variables taken out of the process environment, by name
+fn taken_env() -> &'static Mutex<HashMap<String, String>> {
+    static TAKEN: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    TAKEN.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Move a token variable out of the process environment. Agents and `!` shell commands
+/// inherit the environment, so an `env` there would print the token otherwise.
+/// [`read`] still finds the value, which a reload of the config file needs.
+pub fn take_env(var: &str) {
+    if let Some(value) = std::env::var_os(var) {
+        if let (Ok(mut taken), Some(value)) = (taken_env().lock(), value.to_str()) {
+            taken.insert(var.to_string(), value.to_string());
+        }
+        std::env::remove_var(var);
+    }
+}
+
+/// Take the default token variables out of the environment, whether or not a bot used them
+pub fn take_default_env() {
+    take_env(TELEGRAM_TOKEN_ENV);
+    take_env(DISCORD_TOKEN_ENV);
+}
+
+/// Read an environment variable, or its value from before [`take_env`] removed it
+fn env_value(var: &str) -> Option<String> {
+    std::env::var(var).ok()
+        .or_else(|| taken_env().lock().ok()?.get(var).cloned())
+}
+
+/// Read the raw value of a token source, trimmed. A variable that was read is taken out of
+/// the environment (see [`take_env`]).
+pub fn read(source: &TokenSource) -> Result<String, String> {
+    let value = match source {
+        TokenSource::Env(var) => {
+            let value = env_value(var)
+                .ok_or_else(|| format!("environment variable {} is not set", var))?;
+            take_env(var);
+            value
+        }
+        TokenSource::File(file) => {
+            let path = access::expand_tilde(file, dirs::home_dir().as_deref());
+            warn_if_shared(&path);
+            std::fs::read_to_string(&path)
+                .map_err(|e| format!("cannot read token file {}: {}", path.display(), e))?
+        }
+        TokenSource::Command(cmd) => {
+            let output = Command::new("sh")
+                .args(["-c", cmd])
+                .stderr(std::process::Stdio::inherit())
+                .output()
+                .map_err(|e| format!("cannot run credential helper `{}`: {}", cmd, e))?;
+            if !output.status.success() {
+                return Err(format!("credential helper `{}` failed ({})", cmd, output.status));
+            }
+            String::from_utf8(output.stdout)
+                .map_err(|_| format!("credential helper `{}` printed invalid UTF-8", cmd))?
+        }
+    };
+    let value = value.trim().to_string();
+    if value.is_empty() {
+        return Err(format!("{} is empty", source.describe()));
+    }
+    Ok(value)
+}
+
+/// Read a source holding one or more whitespace-separated tokens, which is how multiple
+/// Telegram bots are started from one source
+pub fn read_tokens(source: &TokenSource) -> Result<Vec<String>, String> {
+    Ok(read(source)?.split_whitespace().map(String::from).collect())
+}
+
+/// Warn when a token file can be read by other users
+fn warn_if_shared(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(meta) = std::fs::metadata(path) {
+        if meta.permissions().mode() & 0o077 != 0 {
+            eprintln!("  ⚠ Token file {} is readable by other users; run chmod 600 on it", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_file_and_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("tokens");
+        std::fs::write(&file, "  tok-1\ntok-2\n").unwrap();
+        let source = TokenSource::File(file.display().to_string());
+        assert_eq!(read_tokens(&source).unwrap(), vec!["tok-1", "tok-2"]);
+
+        assert_eq!(read(&TokenSource::Command("printf ' abc \\n'".to_string())).unwrap(), "abc");
+        assert!(read(&TokenSource::Command("exit 3".to_string())).unwrap_err().contains("failed"));
+        assert!(read(&TokenSource::Command("true".to_string())).unwrap_err().contains("empty"));
+    }
+
+    #[test]
+    fn test_env_token_is_taken_out_of_the_environment() {
+        let var = "AEMI_TEST_TAKEN_TOKEN_VAR";
+        std::env::set_var(var, "secret-token");
+        let source = TokenSource::Env(var.to_string());
+        assert_eq!(read(&source).unwrap(), "secret-token");
+        assert!(std::env::var_os(var).is_none());
+        // A later read, as on reload, still finds it
+        assert_eq!(read(&source).unwrap(), "secret-token");
+    }
+
+    #[test]
+    fn test_missing_sources() {
+        assert!(read(&TokenSource::Env("AEMI_TEST_UNSET_TOKEN_VAR".to_string())).unwrap_err().contains("not set"));
+        assert!(read(&TokenSource::File("/nonexistent/aemi.token".to_string())).unwrap_err().contains("cannot read"));
+    }
+}
@@ -0,0 +1,407 @@
+//! Optional at-rest encryption for session files and bot_settings.json.
+
+use std::fs;
+use std::io::{self, Write};
+use std::num::NonZeroU32;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::services::bot_common;
+use crate::services::session;
+use crate::services::settings_file;
+
+/// Marker prefix of encrypted content, stored as `aemi-enc:v1:<base64(nonce || ciphertext)>`.
+/// Plaintext files keep loading, so existing data can be migrated with `aemi encrypt` at any time.
+pub const ENCRYPTED_PREFIX: &str = "aemi-enc:v1:";
+
+const KEYRING_SERVICE: &str = "aemi";
+const KEYRING_ACCOUNT: &str = "encryption-key";
+const PBKDF2_ITERATIONS: u32 = 200_000;
+/// Random bytes in the per-install salt file
+const SALT_LEN: usize = 16;
+
+/// Raw 256-bit key given as 64 hex chars or base64 of exactly 32 bytes
+fn raw_key(material: &str) -> Option<[u8; 32]> {
+    let bytes = hex::decode(material).ok()
+        .or_else(|| BASE64.decode(material).ok())
+        .filter(|bytes| bytes.len() == 32)?;
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Some(key)
+}
+
+/// Stretch a passphrase into a 256-bit key with PBKDF2-HMAC-SHA256
+fn stretch_passphrase(passphrase: &str, salt: &[u8]) -> Option<[u8; 32]> {
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS)?;
+    let mut key = [0u8; 32];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    Some(key)
+}
+
+/// Turn key material into a 256-bit key.
+/// 64 hex chars or base64 of exactly 32 bytes are used as-is; anything else is a passphrase
+/// stretched with `salt()`, which is only called for passphrases.
+pub fn parse_key_material(material: &str, salt: impl FnOnce() -> Option<Vec<u8>>) -> Option<[u8; 32]> {
+    let material = material.trim();
+    if material.is_empty() {
+        return None;
+    }
+    raw_key(material).or_else(|| stretch_passphrase(material, &salt()?))
+}
+
+/// Per-install salt for passphrase keys, kept next to the data in `~/.aemi/encryption.salt`
+fn salt_path() -> Option<PathBuf> {
+    Some(dirs::home_dir()?.join(".aemi").join("encryption.salt"))
+}
+
+/// Read the salt file, creating it with fresh random bytes on first use
+fn load_or_create_salt(path: &Path) -> io::Result<Vec<u8>> {
+    if let Ok(content) = fs::read_to_string(path) {
+        return hex::decode(content.trim())
+            .ok()
+            .filter(|salt| salt.len() == SALT_LEN)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid salt"));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new().fill(&mut salt).map_err(|_| io::Error::other("no randomness"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let created = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path);
+    match created {
+        Ok(mut file) => {
+            file.write_all(hex::encode(salt).as_bytes())?;
+            file.sync_all()?;
+            Ok(salt.to_vec())
+        }
+        // Another process created it first
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => load_or_create_salt(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// The install's salt, or None with a warning when it cannot be read or created
+fn install_salt() -> Option<Vec<u8>> {
+    let path = salt_path()?;
+    load_or_create_salt(&path)
+        .map_err(|e| eprintln!("  ⚠ Cannot use encryption salt {}: {}", path.display(), e))
+        .ok()
+}
+
+/// Read key material from the OS keyring via the platform CLI
+fn keyring_material() -> Option<String> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args(["find-generic-password", "-s", KEYRING_SERVICE, "-a", KEYRING_ACCOUNT, "-w"])
+            .output()
+    } else {
+        Command::new("secret-tool")
+            .args(["lookup", "service", KEYRING_SERVICE, "account", KEYRING_ACCOUNT])
+            .output()
+    };
+    let output = output.ok().filter(|o| o.status.success())?;
+    let material = String::from_utf8(output.stdout).ok()?;
+    let material = material.trim().to_string();
+    if material.is_empty() { None } else { Some(material) }
+}
+
+/// Find key material and where it came from, in this order:
+/// - `AEMI_ENCRYPTION_KEY`       key material in the environment
+/// - `AEMI_ENCRYPTION_KEYFILE`   path to a file holding key material
+/// - OS keyring, when `AEMI_ENCRYPTION_KEYRING=1` (macOS `security`, Linux `secret-tool`;
+///   service `aemi`, account `encryption-key`)
+/// - `~/.aemi/encryption.key`    default keyfile, if it exists
+fn find_key_material() -> Option<(String, &'static str)> {
+    if let Ok(material) = std::env::var("AEMI_ENCRYPTION_KEY") {
+        return Some((material, "AEMI_ENCRYPTION_KEY"));
+    }
+    if let Ok(path) = std::env::var("AEMI_ENCRYPTION_KEYFILE") {
+        return match fs::read_to_string(&path) {
+            Ok(material) => Some((material, "AEMI_ENCRYPTION_KEYFILE")),
+            Err(e) => {
+                eprintln!("  ⚠ Cannot read AEMI_ENCRYPTION_KEYFILE {}: {}", path, e);
+                None
+            }
+        };
+    }
+    if std::env::var("AEMI_ENCRYPTION_KEYRING").map(|v| v == "1").unwrap_or(false) {
+        match keyring_material() {
+            Some(material) => return Some((material, "OS keyring")),
+            None => eprintln!("  ⚠ AEMI_ENCRYPTION_KEYRING=1 but no key found in the OS keyring"),
+        }
+    }
+    let default_keyfile = dirs::home_dir()?.join(".aemi").join("encryption.key");
+    fs::read_to_string(default_keyfile).ok().map(|m| (m, "~/.aemi/encryption.key"))
+}
+
+/// Process-wide key, resolved on first use. None means encryption is off.
+/// `AEMI_ENCRYPTION_KEY` is removed from the environment once read: agents and `!` shell
+/// commands inherit it, so an `env` there would print the key otherwise.
+fn key() -> Option<&'static LessSafeKey> {
+    static KEY: OnceLock<Option<LessSafeKey>> = OnceLock::new();
+    KEY.get_or_init(|| {
+        let found = find_key_material();
+        std::env::remove_var("AEMI_ENCRYPTION_KEY");
+        let (material, source) = found?;
+        if material.trim().is_empty() {
+            eprintln!("  ⚠ Encryption key from {} is empty; encryption disabled", source);
+            return None;
+        }
+        let Some(bytes) = parse_key_material(&material, install_salt) else {
+            eprintln!("  ⚠ Encryption key from {} cannot be used; encryption disabled", source);
+            return None;
+        };
+        key_from_bytes(&bytes)
+    }).as_ref()
+}
+
+/// Resolve the key now, while the process is still single-threaded (see [`key`])
+pub fn init() {
+    key();
+}
+
+/// Whether at-rest encryption is enabled
+pub fn is_enabled() -> bool {
+    key().is_some()
+}
+
+/// Whether content is in the encrypted format
+pub fn is_encrypted(content: &str) -> bool {
+    content.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// Encrypt with an explicit key (AES-256-GCM)
+pub fn encrypt_with(key: &LessSafeKey, plain: &str) -> Option<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).ok()?;
+    let mut in_out = plain.as_bytes().to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out)
+        .ok()?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&in_out);
+    Some(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(payload)))
+}
+
+/// Decrypt with an explicit key. Content without the encrypted prefix is returned unchanged.
+pub fn decrypt_with(key: Option<&LessSafeKey>, content: &str) -> Result<String, String> {
+    let Some(encoded) = content.strip_prefix(ENCRYPTED_PREFIX) else {
+        return Ok(content.to_string());
+    };
+    let key = key.ok_or_else(|| "file is encrypted but no encryption key is configured".to_string())?;
+    let payload = BASE64.decode(encoded.trim()).map_err(|e| format!("corrupt encrypted data: {}", e))?;
+    if payload.len() < NONCE_LEN {
+        return Err("corrupt encrypted data: too short".to_string());
+    }
+    let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| "corrupt encrypted data".to_string())?;
+    let mut in_out = ciphertext.to_vec();
+    let plain = key.open_in_place(nonce, Aad::empty(), &mut in_out)
+        .map_err(|_| "decryption failed (wrong key?)".to_string())?;
+    String::from_utf8(plain.to_vec()).map_err(|e| format!("decrypted data is not UTF-8: {}", e))
+}
+
+/// Build a key from raw bytes
+pub fn key_from_bytes(bytes: &[u8; 32]) -> Option<LessSafeKey> {
+    UnboundKey::new(&AES_256_GCM, bytes).ok().map(LessSafeKey::new)
+}
+
+/// Encrypt content if encryption is enabled, otherwise return it unchanged.
+/// Fails rather than falling back to plaintext when encryption is enabled.
+pub fn seal(plain: &str) -> io::Result<String> {
+    match key() {
+        Some(k) => encrypt_with(k, plain).ok_or_else(|| io::Error::other("encryption failed")),
+        None => Ok(plain.to_string()),
+    }
+}
+
+/// Decrypt content if it is encrypted; plaintext passes through
+pub fn open(content: &str) -> Result<String, String> {
+    decrypt_with(key(), content)
+}
+
+/// Read a file, decrypting it if needed
+pub fn read_to_string(path: impl AsRef<Path>) -> io::Result<String> {
+    let content = fs::read_to_string(path.as_ref())?;
+    open(&content).map_err(|e| {
+        eprintln!("  ⚠ {}: {}", path.as_ref().display(), e);
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    })
+}
+
+/// Write a file readable only by the owner, encrypting it if encryption is enabled.
+/// Nothing is written if encryption fails.
+pub fn write(path: impl AsRef<Path>, contents: impl AsRef<str>) -> io::Result<()> {
+    let sealed = seal(contents.as_ref())?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(sealed.as_bytes())
+}
+
+/// Outcome of converting one file between plaintext and encrypted form
+#[derive(Debug, PartialEq, Eq)]
+pub enum Conversion {
+    Converted,
+    Unchanged,
+}
+
+/// Rewrite a file encrypted (`encrypt = true`) or as plaintext, line by line for `.jsonl`.
+/// The new content replaces the original like a settings update (private temp file, then
+/// rename), holding the settings lock for bot_settings.json and its backup.
+/// With `dry_run`, nothing is written and the would-be outcome is returned.
+pub fn convert_file(path: &Path, encrypt: bool, dry_run: bool) -> Result<Conversion, String> {
+    let key = key().ok_or_else(|| "no encryption key configured".to_string())?;
+    let settings = bot_common::bot_settings_path()
+        .filter(|settings| path == settings || path == settings_file::backup_path(settings));
+    let _lock = match &settings {
+        Some(settings) => Some(settings_file::Lock::acquire(settings)
+            .map_err(|e| format!("cannot lock {}: {}", settings.display(), e))?),
+        None => None,
+    };
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    let per_line = path.extension().map(|e| e == "jsonl").unwrap_or(false);
+    let units: Vec<&str> = if per_line { content.lines().collect() } else { vec![content.as_str()] };
+
+    let mut changed = false;
+    let mut out = Vec::with_capacity(units.len());
+    for unit in units {
+        if unit.is_empty() {
+            continue;
+        }
+        let converted = match (encrypt, is_encrypted(unit)) {
+            (true, false) => encrypt_with(key, unit).ok_or_else(|| "encryption failed".to_string())?,
+            (false, true) => decrypt_with(Some(key), unit)?,
+            _ => unit.to_string(),
+        };
+        changed |= converted != unit;
+        out.push(converted);
+    }
+    if !changed {
+        return Ok(Conversion::Unchanged);
+    }
+    if dry_run {
+        return Ok(Conversion::Converted);
+    }
+
+    let mut new_content = out.join("\n");
+    if per_line {
+        new_content.push('\n');
+    }
+    settings_file::replace(path, &new_content).map_err(|e| e.to_string())?;
+    Ok(Conversion::Converted)
+}
+
+/// All files that hold session history or bot settings:
+/// `ai_sessions/*.json`, `ai_sessions/archive/*.jsonl`, `bot_settings.json` and its backup
+pub fn data_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Some(sessions_dir) = session::ai_sessions_dir() {
+        for (dir, ext) in [(sessions_dir.clone(), "json"), (sessions_dir.join("archive"), "jsonl")] {
+            let Ok(entries) = fs::read_dir(&dir) else { continue };
+            let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().map(|e| e == ext).unwrap_or(false))
+                .collect();
+            paths.sort();
+            files.extend(paths);
+        }
+    }
+    if let Some(settings) = bot_common::bot_settings_path() {
+        let backup = settings_file::backup_path(&settings);
+        files.extend([settings, backup].into_iter().filter(|p| p.exists()));
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> LessSafeKey {
+        key_from_bytes(&[7u8; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let key = test_key();
+        let sealed = encrypt_with(&key, "{\"token\":\"secret\"}").unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.contains("secret"));
+        assert_eq!(decrypt_with(Some(&key), &sealed).unwrap(), "{\"token\":\"secret\"}");
+    }
+
+    #[test]
+    fn test_nonce_is_random() {
+        let key = test_key();
+        assert_ne!(encrypt_with(&key, "same").unwrap(), encrypt_with(&key, "same").unwrap());
+    }
+
+    #[test]
+    fn test_plaintext_passes_through() {
+        assert_eq!(decrypt_with(None, "{\"a\":1}").unwrap(), "{\"a\":1}");
+    }
+
+    #[test]
+    fn test_encrypted_without_key_fails() {
+        let sealed = encrypt_with(&test_key(), "x").unwrap();
+        assert!(decrypt_with(None, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_wrong_key_fails() {
+        let sealed = encrypt_with(&test_key(), "x").unwrap();
+        let other = key_from_bytes(&[8u8; 32]).unwrap();
+        assert!(decrypt_with(Some(&other), &sealed).is_err());
+    }
+
+    #[test]
+    fn test_parse_key_material() {
+        let no_salt = || -> Option<Vec<u8>> { panic!("raw keys need no salt") };
+        let hex_key = "11".repeat(32);
+        assert_eq!(parse_key_material(&hex_key, no_salt), Some([0x11u8; 32]));
+        let b64_key = BASE64.encode([0x22u8; 32]);
+        assert_eq!(parse_key_material(&b64_key, no_salt), Some([0x22u8; 32]));
+        // Passphrases are stretched deterministically with the install's salt
+        let salt = || Some(vec![1u8; SALT_LEN]);
+        let a = parse_key_material("correct horse battery staple", salt).unwrap();
+        assert_eq!(Some(a), parse_key_material("correct horse battery staple\n", salt));
+        assert_ne!(a, [0u8; 32]);
+        let other_install = parse_key_material("correct horse battery staple", || Some(vec![2u8; SALT_LEN]));
+        assert_ne!(Some(a), other_install);
+        assert_eq!(parse_key_material("  ", salt), None);
+    }
+
+    #[test]
+    fn test_salt_is_created_once_and_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("encryption.salt");
+        let first = load_or_create_salt(&path).unwrap();
+        assert_eq!(first.len(), SALT_LEN);
+        assert_eq!(load_or_create_salt(&path).unwrap(), first);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::write(&path, "not hex").unwrap();
+        assert!(load_or_create_salt(&path).is_err());
+    }
+}
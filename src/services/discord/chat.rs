@@ -7,16 +7,24 @@ use serenity::model::id::ChannelId;
 use serenity::prelude::*;
 
 use crate::services::agent::{CancelToken, StreamMessage};
-use crate::services::claude::{self, DEFAULT_ALLOWED_TOOLS};
+use crate::services::claude;
 use crate::services::gemini;
 use crate::services::codex;
 use crate::services::opencode;
 use crate::services::oh_my_pi;
 use crate::services::provider_common;
-use crate::services::session::{self, HistoryItem, HistoryType};
+use crate::services::session::{self, HistoryItem, HistoryType, Usage};
 use crate::services::formatter;
 use crate::services::utils::{truncate_str, normalize_empty_lines};
+use crate::services::retention;
 use crate::services::bot_common;
+use crate::services::checkpoint;
+use crate::services::compact;
+use crate::services::roles::{self, Role};
+use crate::services::chat_state::Turn;
+use crate::services::shutdown;
+use crate::services::config::Platform;
+use crate::services::system_prompt::{self, PromptContext};
 
 use super::{SharedState, DISCORD_MSG_LIMIT, discord_token_hash};
 use super::messages::{rate_limit_wait, send_long_message_raw, unclosed_code_block_lang};
@@ -27,14 +35,28 @@ fn format_tool_input(name: &str, input: &str) -> String {
     formatter::format_tool_input(name, input, true)
 }
 
-/// Handle regular text messages - send to AI agent
+/// Handle regular text messages - send to AI agent.
+/// `reserved` is the placeholder token of a turn for queued messages (see [`run_queued_messages`]).
 pub async fn handle_text_message(
     ctx: &Context,
     channel_id: ChannelId,
     user_text: &str,
     user_msg_id: serenity::model::id::MessageId,
+    role: Role,
     state: &SharedState,
+    reserved: Option<&Arc<CancelToken>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Settings in effect for this channel: its own over the bot-wide ones
+    let settings = state.lock().await.effective_settings(channel_id);
+    if role == Role::Viewer {
+        let agent = &settings.agent.value;
+        if !roles::supports_read_only(agent) {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, format!("⛔ Viewers cannot chat with {agent}: it ignores the read-only tool list. Ask an admin to switch to /agent claude.")).await?;
+            return Ok(());
+        }
+    }
+
     // Get session info, allowed tools, and pending uploads (drop lock before any await)
     let (session_info, allowed_tools, pending_uploads) = {
         let mut data = state.lock().await;
@@ -43,7 +65,7 @@ pub async fn handle_text_message(
                 (session.session_id.clone(), session.current_path.clone().unwrap_or_default())
             })
         });
-        let tools = data.settings.allowed_tools.clone();
+        let tools = roles::tools_for(role, &settings.allowed_tools.value);
         // Drain pending uploads so they are sent to AI exactly once
         let uploads = data.sessions.get_mut(&channel_id)
             .map(|s| {
@@ -79,77 +101,99 @@ pub async fn handle_text_message(
         format!("{}\n\n{}", upload_context, sanitized_input)
     };
 
-    // Build disabled tools notice
-    let default_tools: std::collections::HashSet<&str> = DEFAULT_ALLOWED_TOOLS.iter().copied().collect();
-    let allowed_set: std::collections::HashSet<&str> = allowed_tools.iter().map(|s| s.as_str()).collect();
-    let disabled: Vec<&&str> = default_tools.iter().filter(|t| !allowed_set.contains(**t)).collect();
-    let disabled_notice = if disabled.is_empty() {
-        String::new()
-    } else {
-        let names: Vec<&str> = disabled.iter().map(|t| **t).collect();
-        format!(
-            "\n\nDISABLED TOOLS: The following tools have been disabled by the user: {}.\n\
-             You MUST NOT attempt to use these tools. \
-             If a user's request requires a disabled tool, do NOT proceed with the task. \
-             Instead, clearly inform the user which tool is needed and that it is currently disabled. \
-             Suggest they re-enable it with: /allowed +ToolName",
-            names.join(", ")
-        )
-    };
-
-    // Get token hash for sendfile reference
-    let token_hash = {
+    // Get token hash for sendfile reference, and the bot's prompt template
+    let (token_hash, origin, prompt_template) = {
         let data = state.lock().await;
-        discord_token_hash(&data.token)
+        (discord_token_hash(&data.token), super::session_origin(&data.token, channel_id), data.settings.prompt_template.clone())
     };
 
-    // Build system prompt for Discord
-    let system_prompt_owned = format!(
-        "You are chatting with a user through Discord.\n\
-         Current working directory: {}\n\n\
-         When your work produces a file the user would want (generated code, reports, images, archives, etc.),\n\
-         save it to the working directory and tell the user to use `/down <filepath>` to download it.\n\n\
-         Always keep the user informed about what you are doing. \
-         Briefly explain each step as you work (e.g. \"Reading the file...\", \"Creating the script...\", \"Running tests...\"). \
-         The user cannot see your tool calls, so narrate your progress so they know what is happening.\n\n\
-         IMPORTANT: The user is on Discord and CANNOT interact with any interactive prompts, dialogs, or confirmation requests. \
-         All tools that require user interaction (such as AskUserQuestion, EnterPlanMode, ExitPlanMode) will NOT work. \
-         Never use tools that expect user interaction. If you need clarification, just ask in plain text.\n\n\
-         FORMATTING RULES FOR DISCORD:\n\
-         - Use **bold** for emphasis\n\
-         - Use `code` for inline code, file paths, commands\n\
-         - Use ```language for code blocks (e.g. ```rust, ```bash)\n\
-         - Use > for blockquotes\n\
-         - Keep messages concise — Discord has a 2000 character limit per message\n\
-         - Avoid headers (# Title) — they render as large text in Discord\n\
-         - Use bullet lists (- item) for multiple items\n\
-         - NEVER write triple backticks in regular text or inline code — Discord misinterprets them as code block markers. Say \"code block\" in words instead.\n\
-         Token hash: {}{}",
-        current_path, token_hash, disabled_notice
+    // Layered system prompt: bot template, platform notes, workspace files, chat additions
+    let system_prompt_owned = build_system_prompt(
+        prompt_template.as_deref(), settings.system_prompt.value.as_deref(), &allowed_tools, role, channel_id, &current_path, &token_hash,
     );
 
-    // Create cancel token for this request
-    let cancel_token = Arc::new(CancelToken::new());
-    {
+    // Create cancel token for this request in place of a queued turn's reservation, and
+    // mark the turn as running so a restart before it ends can tell the user
+    let cancel_token = {
         let mut data = state.lock().await;
-        data.cancel_tokens.insert(channel_id, cancel_token.clone());
+        let Some(cancel_token) = bot_common::start_turn(&mut data.cancel_tokens, channel_id, reserved) else {
+            // Stopped while it was queued
+            drop(data);
+            rate_limit_wait(state, channel_id).await;
+            let _ = channel_id.edit_message(&ctx.http, placeholder_msg_id, EditMessage::new().content("[Stopped]")).await;
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ■ Stopped before start");
+            return Ok(());
+        };
+        data.settings.chat_state.entry(channel_id.get().to_string()).or_default()
+            .start_turn(Turn::new(user_text, &settings.agent.value));
+        bot_common::save_bot_settings(&token_hash, &data.settings, &[("platform", "discord")]);
+        cancel_token
+    };
+    let turn_guard = shutdown::track(cancel_token.clone());
+
+    // Compact first if the last turn overflowed the context or the history is over AEMI_AUTO_COMPACT
+    let should_compact = {
+        let data = state.lock().await;
+        data.sessions.get(&channel_id)
+            .map(|s| s.compact_brief.is_none() && (s.needs_compact || compact::over_threshold(&s.history)))
+            .unwrap_or(false)
+    };
+    if should_compact {
+        rate_limit_wait(state, channel_id).await;
+        let _ = channel_id.edit_message(&ctx.http, placeholder_msg_id, EditMessage::new().content("🗜 Compacting conversation...")).await;
+        if let Err(e) = super::commands::compact_chat(channel_id, state, &cancel_token).await {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}]   ⚠ Auto-compact failed: {e}");
+            rate_limit_wait(state, channel_id).await;
+            let _ = channel_id.say(&ctx.http, format!("Auto-compact failed: {}", e)).await;
+        }
     }
 
+    // Snapshot the workspace so /undo can roll this turn back
+    checkpoint::create_before_turn(&current_path, user_text).await;
+
     // Create channel for streaming
     let (tx, rx) = mpsc::channel();
 
-    let session_id_clone = session_id.clone();
     let current_path_clone = current_path.clone();
     let cancel_token_clone = cancel_token.clone();
 
-    // Get agent type from state
-    let agent_type = {
+    // Get agent type and pending /fork parent from state
+    let (agent_type, fork_from, fork_history, compact_brief) = {
         let data = state.lock().await;
-        data.agent_type.clone()
+        let agent = settings.agent.value.clone();
+        let session = data.sessions.get(&channel_id);
+        let fork_from = session.and_then(|s| s.fork_from.clone());
+        let history = session.map(|s| s.history.clone()).unwrap_or_default();
+        let compact_brief = session.and_then(|s| s.compact_brief.clone());
+        (agent, fork_from, history, compact_brief)
     };
 
-    // Run agent in a blocking thread
+    // Forked sessions: resume the parent with a native fork flag when the agent supports it,
+    // otherwise start a fresh provider session seeded with the copied transcript.
+    // A compacted session always starts fresh, seeded with the /compact brief.
+    let fork_native = compact_brief.is_none() && fork_from.is_some() && bot_common::supports_native_fork(&agent_type);
+    let (session_id_clone, context_prompt) = match (&compact_brief, &fork_from) {
+        (Some(brief), _) => (None, format!("{}\n\n{}", compact::seed_prompt(brief), context_prompt)),
+        (None, Some(parent)) if fork_native => (Some(parent.clone()), context_prompt),
+        (None, Some(_)) => (None, format!("{}\n\n{}", bot_common::fork_context_prompt(&fork_history), context_prompt)),
+        (None, None) => (session_id.clone(), context_prompt),
+    };
+
+    // Per-turn metadata recorded on the assistant reply
+    let agent_owned = agent_type.clone();
+    let turn_started = std::time::Instant::now();
+
+    let model = settings.model.value.clone();
+    let verbosity = settings.verbosity.value;
+
+    // Run agent in a blocking thread, unless the turn was stopped while compacting
+    let stopped = cancel_token.cancelled.load(Ordering::Relaxed);
     tokio::task::spawn_blocking(move || {
+        if stopped {
+            return;
+        }
         let result = match agent_type.as_str() {
             "gemini" => gemini::execute_command_streaming(
                 &context_prompt,
@@ -158,6 +202,7 @@ pub async fn handle_text_message(
                 tx.clone(),
                 Some(&system_prompt_owned),
                 Some(&allowed_tools),
+                model.as_deref(),
                 Some(cancel_token_clone),
             ),
             "codex" => codex::execute_command_streaming(
@@ -167,6 +212,7 @@ pub async fn handle_text_message(
                 tx.clone(),
                 Some(&system_prompt_owned),
                 Some(&allowed_tools),
+                model.as_deref(),
                 Some(cancel_token_clone),
             ),
             "opencode" => opencode::execute_command_streaming(
@@ -176,6 +222,17 @@ pub async fn handle_text_message(
                 tx.clone(),
                 Some(&system_prompt_owned),
                 Some(&allowed_tools),
+                model.as_deref(),
+                Some(cancel_token_clone),
+            ),
+            "oh-my-pi" if fork_native => oh_my_pi::execute_command_streaming_fork(
+                &context_prompt,
+                session_id_clone.as_deref(),
+                &current_path_clone,
+                tx.clone(),
+                Some(&system_prompt_owned),
+                Some(&allowed_tools),
+                model.as_deref(),
                 Some(cancel_token_clone),
             ),
             "oh-my-pi" => oh_my_pi::execute_command_streaming(
@@ -185,6 +242,17 @@ pub async fn handle_text_message(
                 tx.clone(),
                 Some(&system_prompt_owned),
                 Some(&allowed_tools),
+                model.as_deref(),
+                Some(cancel_token_clone),
+            ),
+            _ if fork_native => claude::execute_command_streaming_fork(
+                &context_prompt,
+                session_id_clone.as_deref(),
+                &current_path_clone,
+                tx.clone(),
+                Some(&system_prompt_owned),
+                Some(&allowed_tools),
+                model.as_deref(),
                 Some(cancel_token_clone),
             ),
             _ => claude::execute_command_streaming(
@@ -194,6 +262,7 @@ pub async fn handle_text_message(
                 tx.clone(),
                 Some(&system_prompt_owned),
                 Some(&allowed_tools),
+                model.as_deref(),
                 Some(cancel_token_clone),
             ),
         };
@@ -206,6 +275,7 @@ pub async fn handle_text_message(
     // Spawn the polling loop as a separate task so the handler returns immediately.
     // This allows serenity to process subsequent messages (e.g. /stop).
     let http = ctx.http.clone();
+    let ctx_owned = ctx.clone();
     let state_owned = state.clone();
     let user_text_owned = user_text.to_string();
     let channel_id_num = channel_id.get();
@@ -214,6 +284,8 @@ pub async fn handle_text_message(
     let watcher_placeholder_msg_id_num = placeholder_msg_id_num;
 
     let polling_handle = tokio::spawn(async move {
+        // The shutdown waits for this turn until the task ends
+        let _turn_guard = turn_guard;
         provider_common::debug_log_for(
             "discord",
             &format!(
@@ -227,7 +299,10 @@ pub async fn handle_text_message(
         let mut done = false;
         let mut cancelled = false;
         let mut new_session_id: Option<String> = None;
+        let mut turn_model: Option<String> = None;
+        let mut turn_usage: Option<Usage> = None;
         let mut session_not_found = false;
+        let mut context_overflow = false;
         let mut spin_idx: usize = 0;
         let mut last_tool_name = String::new();
         let mut last_file_path = String::new();
@@ -273,14 +348,16 @@ pub async fn handle_text_message(
 
                                 // Format tool use: header in blockquote, code blocks outside
                                 let lines: Vec<&str> = summary.lines().collect();
-                                if lines.len() <= 1 {
-                                    full_response.push_str(&format!("\n\n> ⚙️ {}\n", summary));
-                                } else {
-                                    // First line is the header (blockquoted), rest is code block
-                                    full_response.push_str(&format!("\n\n> ⚙️ {}\n", lines[0]));
-                                    for line in &lines[1..] {
-                                        full_response.push_str(line);
-                                        full_response.push('\n');
+                                if verbosity.shows_tool_calls() {
+                                    if lines.len() <= 1 {
+                                        full_response.push_str(&format!("\n\n> ⚙️ {}\n", summary));
+                                    } else {
+                                        // First line is the header (blockquoted), rest is code block
+                                        full_response.push_str(&format!("\n\n> ⚙️ {}\n", lines[0]));
+                                        for line in &lines[1..] {
+                                            full_response.push_str(line);
+                                            full_response.push('\n');
+                                        }
                                     }
                                 }
 
@@ -306,16 +383,24 @@ pub async fn handle_text_message(
                                 }
                                 let file_hint = if last_file_path.is_empty() { None } else { Some(last_file_path.as_str()) };
                                 let formatted = formatter::format_tool_result(&content, is_error, &last_tool_name, file_hint);
-                                if !formatted.is_empty() {
+                                if !formatted.is_empty() && verbosity.shows_tool_output() {
                                     full_response.push_str(&formatted);
                                 }
                                 progress_phase = String::from("Thinking");
                             }
                             StreamMessage::TaskNotification { summary, .. } => {
-                                if !summary.is_empty() {
+                                if !summary.is_empty() && verbosity.shows_tool_calls() {
                                     full_response.push_str(&format!("\n[Task: {}]\n", summary));
                                 }
                             }
+                            StreamMessage::Usage { model, usage } => {
+                                if model.is_some() {
+                                    turn_model = model;
+                                }
+                                if usage.is_some() {
+                                    turn_usage = usage;
+                                }
+                            }
                             StreamMessage::Done { result, session_id: sid } => {
                                 if !result.is_empty() && full_response.is_empty() {
                                     full_response = result;
@@ -332,6 +417,10 @@ pub async fn handle_text_message(
                                     session_not_found = true;
                                 }
                                 full_response = format!("Error: {}", message);
+                                if compact::is_context_overflow(&message) {
+                                    context_overflow = true;
+                                    full_response.push_str("\n\nThe conversation no longer fits the agent's context. It will be compacted before your next message (or run /compact now).");
+                                }
                                 done = true;
                             }
                         }
@@ -391,22 +480,17 @@ pub async fn handle_text_message(
 
         if cancelled {
             // Ensure child process is killed
-            if let Ok(guard) = cancel_token.child_pid.lock() {
-                if let Some(pid) = *guard {
-                    #[cfg(unix)]
-                    #[allow(unsafe_code)]
-                    unsafe {
-                        libc::kill(pid as libc::pid_t, libc::SIGTERM);
-                    }
-                }
-            }
+            cancel_token.kill_child();
 
-            // Build stopped response: show partial content + [Stopped] indicator
+            // Build stopped response: show partial content + [Stopped] indicator,
+            // or [Interrupted by shutdown] when the shutdown's grace period ran out
+            let interrupted = shutdown::interrupts_turns();
+            let mark = if interrupted { shutdown::INTERRUPTED_MARK } else { "[Stopped]" };
             let stopped_response = if full_response.trim().is_empty() {
-                "[Stopped]".to_string()
+                mark.to_string()
             } else {
                 let normalized = normalize_empty_lines(&full_response);
-                format!("{}\n\n[Stopped]", normalized)
+                format!("{}\n\n{}", normalized, mark)
             };
 
             // Update placeholder message with partial response
@@ -422,7 +506,7 @@ pub async fn handle_text_message(
                 let _ = send_long_message_raw(&http, channel_id, &stopped_response, &state_owned).await;
                 // Edit placeholder to indicate stopped
                 rate_limit_wait(&state_owned, channel_id).await;
-                let edit = EditMessage::new().content("[Stopped]");
+                let edit = EditMessage::new().content(mark);
                 let _ = channel_id.edit_message(&http, placeholder_msg_id, edit).await;
             }
 
@@ -439,21 +523,35 @@ pub async fn handle_text_message(
                     if session_not_found {
                         session.session_id = None;
                     } else if let Some(sid) = new_session_id {
+                        // A forked or compacted placeholder becomes the provider's new session
+                        if session.fork_from.take().is_some() {
+                            if let Some(fork_id) = session.session_id.as_deref() {
+                                bot_common::rename_session(fork_id, &sid);
+                            }
+                        }
                         session.session_id = Some(sid);
+                        session.compact_brief = None;
                     }
-                    session.history.push(HistoryItem {
-                        item_type: HistoryType::User,
-                        content: user_text_owned,
-                    });
-                    session.history.push(HistoryItem {
-                        item_type: HistoryType::Assistant,
-                        content: stopped_response,
-                    });
-
-                    bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &current_path);
+                    session.history.push(HistoryItem::new(HistoryType::User, user_text_owned));
+                    session.history.push(
+                        HistoryItem::new(HistoryType::Assistant, stopped_response)
+                            .with_turn_meta(&agent_owned, turn_model.take(), turn_started.elapsed(), turn_usage.take()),
+                    );
+
+                    retention::trim_history(session.session_id.as_deref(), &mut session.history);
+                    bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &current_path, &origin);
                 }
             }
+            if interrupted {
+                // Stays marked running, so the chat is offered /retry after the restart
+                data.remember_session(channel_id);
+            } else {
+                data.finish_turn(channel_id);
+            }
+            bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
 
+            // Messages queued after /stop run next
+            run_queued_messages(ctx_owned.clone(), channel_id, state_owned.clone());
             return;
         }
 
@@ -496,20 +594,30 @@ pub async fn handle_text_message(
                     if session_not_found {
                         session.session_id = None;
                     } else if let Some(sid) = new_session_id {
+                        // A forked or compacted placeholder becomes the provider's new session
+                        if session.fork_from.take().is_some() {
+                            if let Some(fork_id) = session.session_id.as_deref() {
+                                bot_common::rename_session(fork_id, &sid);
+                            }
+                        }
                         session.session_id = Some(sid);
+                        session.compact_brief = None;
+                    }
+                    if context_overflow {
+                        session.needs_compact = true;
                     }
-                    session.history.push(HistoryItem {
-                        item_type: HistoryType::User,
-                        content: user_text_owned,
-                    });
-                    session.history.push(HistoryItem {
-                        item_type: HistoryType::Assistant,
-                        content: full_response,
-                    });
-
-                    bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &current_path);
+                    session.history.push(HistoryItem::new(HistoryType::User, user_text_owned));
+                    session.history.push(
+                        HistoryItem::new(HistoryType::Assistant, full_response)
+                            .with_turn_meta(&agent_owned, turn_model.take(), turn_started.elapsed(), turn_usage.take()),
+                    );
+
+                    retention::trim_history(session.session_id.as_deref(), &mut session.history);
+                    bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &current_path, &origin);
                 }
             }
+            data.finish_turn(channel_id);
+            bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
         }
 
         // Send "Done" reply referencing user's original message
@@ -521,6 +629,9 @@ pub async fn handle_text_message(
 
         let ts = chrono::Local::now().format("%H:%M:%S");
         println!("  [{ts}] ▶ Response sent");
+
+        // Send messages queued during this turn as the next turn
+        run_queued_messages(ctx_owned.clone(), channel_id, state_owned.clone());
         provider_common::debug_log_for(
             "discord",
             &format!(
@@ -546,3 +657,60 @@ pub async fn handle_text_message(
 
     Ok(())
 }
+
+/// System prompt of a turn in this channel, also shown by `/systemprompt show`
+pub(super) fn build_system_prompt(
+    template: Option<&str>,
+    additions: Option<&str>,
+    allowed_tools: &[String],
+    role: Role,
+    channel_id: ChannelId,
+    current_path: &str,
+    token_hash: &str,
+) -> String {
+    let ctx = PromptContext {
+        platform: Platform::Discord,
+        cwd: current_path,
+        chat_id: channel_id.get().to_string(),
+        key: token_hash,
+        allowed_tools,
+        read_only: role == Role::Viewer,
+    };
+    system_prompt::build(template, &ctx, additions)
+}
+
+/// Start one turn for the messages queued while the agent was busy, merged into one prompt.
+/// Does nothing if another turn is running, which drains the queue when it ends, or the
+/// bot is shutting down.
+pub(super) fn run_queued_messages(ctx: Context, channel_id: ChannelId, state: SharedState) {
+    tokio::spawn(async move {
+        if shutdown::is_shutting_down() {
+            return;
+        }
+        let reservation = {
+            let mut data = state.lock().await;
+            let data = &mut *data;
+            bot_common::reserve_queued_turn(&mut data.cancel_tokens, &mut data.queued_messages, channel_id)
+        };
+        let Some((reserved, queued)) = reservation else {
+            return;
+        };
+
+        let texts: Vec<String> = queued.iter().map(|(text, _, _)| text.clone()).collect();
+        let last_msg_id = queued[queued.len() - 1].1;
+        // The merged turn runs with the lowest role among the senders
+        let role = queued.iter().map(|(_, _, role)| *role).min().unwrap_or(Role::Viewer);
+        let prompt = bot_common::merge_queued_messages(&texts);
+        let ts = chrono::Local::now().format("%H:%M:%S");
+        println!("  [{ts}] ▶ Sending {} queued message(s)", texts.len());
+
+        if let Err(e) = handle_text_message(&ctx, channel_id, &prompt, last_msg_id, role, &state, Some(&reserved)).await {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}]   ⚠ Queued turn failed: {e}");
+        }
+
+        // Release the reservation if the turn never started
+        let mut data = state.lock().await;
+        bot_common::release_reservation(&mut data.cancel_tokens, channel_id, &reserved);
+    });
+}
@@ -1,19 +1,37 @@
+use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use std::path::Path;
 use std::fs;
 
-use serenity::builder::{CreateAttachment, CreateMessage};
+use serenity::builder::{CreateAttachment, CreateMessage, EditMessage};
 use serenity::model::channel::Message;
 use serenity::model::id::ChannelId;
 use serenity::prelude::*;
 
-use crate::services::session::{HistoryItem, HistoryType};
+use crate::services::agent::CancelToken;
+use crate::services::session::{HistoryItem, HistoryType, SessionData};
+use crate::services::export::{self, ExportFormat};
+use crate::services::search;
+use crate::services::retention;
+use crate::services::git;
+use crate::services::checkpoint;
+use crate::services::template;
+use crate::services::access;
+use crate::services::compact;
+use crate::services::roles;
+use crate::services::chat_settings;
+use crate::services::utils::truncate_str;
 use crate::services::bot_common::{self, ALL_TOOLS, AVAILABLE_AGENTS, normalize_tool_name, tool_info, risk_badge, is_valid_agent};
 use crate::services::formatter;
 
 use super::{ChannelSession, SharedState, discord_token_hash};
 use super::messages::{rate_limit_wait, send_long_message};
 
+/// This bot's allowed workspace roots (None when unrestricted)
+async fn allowed_roots(state: &SharedState) -> Option<Vec<std::path::PathBuf>> {
+    state.lock().await.allowed_roots.clone()
+}
+
 /// Handle /help command
 pub async fn handle_help_command(
     ctx: &Context,
@@ -27,10 +45,21 @@ Manage server files & chat with Claude AI.
 **Session**
 `/start <path>` — Start session at directory
 `/start` — Start with auto-generated workspace
+`/start --template <name>` — New workspace from a template
+`/templates` — List workspace templates
 `/resume` — List & resume saved sessions
 `/pwd` — Show current working directory
 `/clear` — Clear AI conversation history
+`/retry` — Run the request cut off by a restart again
+`/export [md|html|json]` — Export conversation as a file
+`/fork [name]` — Branch conversation into a new session
+`/compact` — Summarize conversation into a fresh session (needs the Claude CLI)
+`/search <terms>` — Search saved sessions
+`/rename <title>` — Name the current session
+`/tag [-]<label>` — Add or remove a session tag
+`/pin` — Pin/unpin the session in /resume
 `/stop` — Stop current AI request
+`/queue [clear]` — Show or clear queued messages
 
 **File Transfer**
 `/down <file>` — Download file from server
@@ -40,20 +69,43 @@ Send a file — Upload to session directory
 `!<command>` — Run shell command directly
   e.g. `!ls -la`, `!git status`
 
+**Git**
+`/diff` — Show uncommitted changes
+`/commit [msg]` — Commit all changes (AI writes the message if omitted)
+`/branch [name]` — Create/switch to a branch for this session
+
+**Checkpoints**
+`/undo` — Restore files to before the last AI turn
+`/checkpoints [n]` — List checkpoints or restore one
+
 **AI Chat**
 Any other message is sent to AI agent.
 AI can read, edit, and run commands in your session.
 
 **Agent**
 `/agent` — Show current AI agent
-`/agent <name>` — Switch agent (claude, gemini, codex, opencode)
+`/agent <name>` — Switch agent for this channel (claude, gemini, codex, opencode)
+
+**Settings**
+`/settings` — Show this channel's settings and where they come from
+`/settings <key> <value>` — Set agent, model, tools, system_prompt or verbosity
+`/settings <key> reset` — Back to the bot-wide default
+`/systemprompt` — Show the full system prompt of this channel
+`/systemprompt <text>` — Set this channel's additions (`reset` removes them)
 
 **Tool Management**
 `/availabletools` — List all available tools
 `/allowedtools` — Show currently allowed tools
-`/allowed +name` — Add tool (e.g. `/allowed +Bash`)
+`/allowed +name` — Add tool for this channel (e.g. `/allowed +Bash`)
 `/allowed -name` — Remove tool
 
+**Users** (admin)
+`/users` — List users and roles
+`/users add <id> [role]` — Allow a user (admin, user, viewer)
+`/users role <id> <role>` — Change a user's role
+`/users remove <id>` — Remove a user
+`/reload` — Re-read the config file and bot settings
+
 `/help` — Show this help";
 
     rate_limit_wait(state, channel_id).await;
@@ -69,7 +121,32 @@ pub async fn handle_start_command(
     text: &str,
     state: &SharedState,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let path_str = text.strip_prefix("/start").unwrap_or("").trim();
+    let (template_name, path_str) = match template::parse_start_args(text.strip_prefix("/start").unwrap_or("")) {
+        Ok(parsed) => parsed,
+        Err(usage) => {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, &usage).await?;
+            return Ok(());
+        }
+    };
+    let path_str = path_str.as_str();
+    let template = match &template_name {
+        Some(_) if !path_str.is_empty() => {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, "Templates apply to new workspaces only. Use /start --template <name> without a path.").await?;
+            return Ok(());
+        }
+        Some(name) => match template::find_template(name) {
+            Some(t) => Some(t),
+            None => {
+                rate_limit_wait(state, channel_id).await;
+                channel_id.say(&ctx.http, &format!("Unknown template '{}'. Use /templates to list them.", name)).await?;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let mut template_lines = Vec::new();
 
     let canonical_path = if path_str.is_empty() {
         let Some(home) = dirs::home_dir() else {
@@ -90,6 +167,29 @@ pub async fn handle_start_command(
             channel_id.say(&ctx.http, &format!("Error: failed to create workspace: {}", e)).await?;
             return Ok(());
         }
+        if let Some(t) = template {
+            let dest = new_dir.clone();
+            let applied = tokio::task::spawn_blocking(move || {
+                template::apply(&t, &dest).map(|report| (t, report))
+            }).await.unwrap_or_else(|e| Err(e.to_string()));
+            match applied {
+                Ok((t, report)) => {
+                    template::record_workspace(&new_dir.display().to_string(), &t.name);
+                    template_lines.push(format!("Template `{}`: {} file(s) copied.", t.name, report.files_copied));
+                    if let Some((code, output)) = report.init {
+                        template_lines.push(format!("init.sh exited with {}.", code));
+                        if !output.is_empty() {
+                            template_lines.push(output);
+                        }
+                    }
+                }
+                Err(e) => {
+                    rate_limit_wait(state, channel_id).await;
+                    channel_id.say(&ctx.http, &format!("Error: failed to apply template: {}", e)).await?;
+                    return Ok(());
+                }
+            }
+        }
         new_dir.display().to_string()
     } else {
         let expanded = if path_str.starts_with("~/") || path_str == "~" {
@@ -107,12 +207,21 @@ pub async fn handle_start_command(
             channel_id.say(&ctx.http, &format!("Error: '{}' is not a valid directory.", expanded)).await?;
             return Ok(());
         }
-        path.canonicalize()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|_| expanded)
+        match access::check(allowed_roots(state).await.as_deref(), path, "start", &format!("channel {}", channel_id.get())) {
+            Ok(resolved) => resolved.display().to_string(),
+            Err(e) => {
+                rate_limit_wait(state, channel_id).await;
+                channel_id.say(&ctx.http, &e).await?;
+                return Ok(());
+            }
+        }
     };
 
-    let existing = bot_common::load_existing_session(&canonical_path);
+    let scope = {
+        let data = state.lock().await;
+        super::session_origin(&data.token, channel_id)
+    };
+    let existing = bot_common::load_existing_session(&canonical_path, &scope);
 
     let mut response_lines = Vec::new();
 
@@ -124,7 +233,13 @@ pub async fn handle_start_command(
             history: Vec::new(),
             pending_uploads: Vec::new(),
             cleared: false,
+            fork_from: None,
+            compact_brief: None,
+            needs_compact: false,
         });
+        session.fork_from = None;
+        session.compact_brief = None;
+        session.needs_compact = false;
 
         if let Some((session_data, _)) = &existing {
             session.session_id = Some(session_data.session_id.clone());
@@ -159,6 +274,7 @@ pub async fn handle_start_command(
             let ts = chrono::Local::now().format("%H:%M:%S");
             println!("  [{ts}] ▶ Session started: {canonical_path}");
             response_lines.push(format!("Session started at `{}`.", canonical_path));
+            response_lines.append(&mut template_lines);
         }
 
         data.token.clone()
@@ -168,6 +284,7 @@ pub async fn handle_start_command(
     {
         let mut data = state.lock().await;
         data.settings.last_sessions.insert(channel_id.get().to_string(), canonical_path);
+        data.remember_session(channel_id);
         bot_common::save_bot_settings(&discord_token_hash(&token), &data.settings, &[("platform", "discord")]);
     }
 
@@ -177,6 +294,26 @@ pub async fn handle_start_command(
     Ok(())
 }
 
+/// Handle /retry command - run the request cut off by the last restart again
+pub async fn handle_retry_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    msg_id: serenity::model::id::MessageId,
+    role: roles::Role,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let turn = {
+        let data = state.lock().await;
+        data.settings.chat_state.get(&channel_id.get().to_string()).and_then(|s| s.interrupted.clone())
+    };
+    let Some(turn) = turn else {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, "Nothing to retry.").await?;
+        return Ok(());
+    };
+    super::chat::handle_text_message(ctx, channel_id, &turn.prompt, msg_id, role, state, None).await
+}
+
 /// Handle /clear command
 pub async fn handle_clear_command(
     ctx: &Context,
@@ -190,13 +327,7 @@ pub async fn handle_clear_command(
     };
     if let Some(token) = cancel_token {
         token.cancelled.store(true, Ordering::Relaxed);
-        if let Ok(guard) = token.child_pid.lock() {
-            if let Some(pid) = *guard {
-                #[cfg(unix)]
-                #[allow(unsafe_code)]
-                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM); }
-            }
-        }
+        token.kill_child();
     }
 
     {
@@ -205,9 +336,16 @@ pub async fn handle_clear_command(
             session.session_id = None;
             session.history.clear();
             session.pending_uploads.clear();
+            session.fork_from = None;
+            session.compact_brief = None;
+            session.needs_compact = false;
             session.cleared = true;
         }
         data.cancel_tokens.remove(&channel_id);
+        data.queued_messages.remove(&channel_id);
+        // A restart must not bring the cleared session back
+        data.remember_session(channel_id);
+        bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
     }
 
     rate_limit_wait(state, channel_id).await;
@@ -253,20 +391,23 @@ pub async fn handle_stop_command(
                 return Ok(());
             }
 
+            // Stopping also drops anything queued for the next turn
+            let dropped = {
+                let mut data = state.lock().await;
+                data.queued_messages.remove(&channel_id).map(|q| q.len()).unwrap_or(0)
+            };
+            let stop_text = if dropped > 0 {
+                format!("Stopping... ({} queued message(s) dropped)", dropped)
+            } else {
+                "Stopping...".to_string()
+            };
+
             rate_limit_wait(state, channel_id).await;
-            channel_id.say(&ctx.http, "Stopping...").await?;
+            channel_id.say(&ctx.http, stop_text).await?;
 
             token.cancelled.store(true, Ordering::Relaxed);
 
-            if let Ok(guard) = token.child_pid.lock() {
-                if let Some(pid) = *guard {
-                    #[cfg(unix)]
-                    #[allow(unsafe_code)]
-                    unsafe {
-                        libc::kill(pid as libc::pid_t, libc::SIGTERM);
-                    }
-                }
-            }
+            token.kill_child();
 
             let ts = chrono::Local::now().format("%H:%M:%S");
             println!("  [{ts}] ■ Cancel signal sent");
@@ -280,6 +421,46 @@ pub async fn handle_stop_command(
     Ok(())
 }
 
+/// Handle /queue command - show or clear messages queued during a turn
+/// Usage: /queue         (list)
+///        /queue clear
+pub async fn handle_queue_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let arg = text.strip_prefix("/queue").unwrap_or("").trim();
+
+    let response = {
+        let mut data = state.lock().await;
+        match arg {
+            "" => match data.queued_messages.get(&channel_id).filter(|q| !q.is_empty()) {
+                Some(queue) => {
+                    let mut lines = vec![format!("**Queued messages** ({})", queue.len())];
+                    for (i, (text, _, _)) in queue.iter().enumerate() {
+                        lines.push(format!("{}. {}", i + 1, truncate_str(text, 80)));
+                    }
+                    lines.push(String::new());
+                    lines.push("They will be sent together when the current turn finishes. `/queue clear` to drop them.".to_string());
+                    lines.join("\n")
+                }
+                None => "Queue is empty.".to_string(),
+            },
+            "clear" => match data.queued_messages.remove(&channel_id) {
+                Some(queue) => format!("Cleared {} queued message(s).", queue.len()),
+                None => "Queue is empty.".to_string(),
+            },
+            _ => "Usage: /queue [clear]".to_string(),
+        }
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response).await?;
+
+    Ok(())
+}
+
 /// Handle /down <filepath> - send file to user
 pub async fn handle_down_command(
     ctx: &Context,
@@ -295,14 +476,14 @@ pub async fn handle_down_command(
         return Ok(());
     }
 
+    let current_path = {
+        let data = state.lock().await;
+        data.sessions.get(&channel_id).and_then(|s| s.current_path.clone())
+    };
     let resolved_path = if Path::new(file_path).is_absolute() {
         file_path.to_string()
     } else {
-        let current_path = {
-            let data = state.lock().await;
-            data.sessions.get(&channel_id).and_then(|s| s.current_path.clone())
-        };
-        match current_path {
+        match current_path.as_deref() {
             Some(base) => format!("{}/{}", base.trim_end_matches('/'), file_path),
             None => {
                 rate_limit_wait(state, channel_id).await;
@@ -323,10 +504,18 @@ pub async fn handle_down_command(
         channel_id.say(&ctx.http, &format!("Not a file: {}", resolved_path)).await?;
         return Ok(());
     }
+    let path = match access::check_download(allowed_roots(state).await.as_deref(), path, current_path.as_deref(), &format!("channel {}", channel_id.get())) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, &e).await?;
+            return Ok(());
+        }
+    };
 
     // Read file and send as attachment
     rate_limit_wait(state, channel_id).await;
-    let attachment = CreateAttachment::path(path).await?;
+    let attachment = CreateAttachment::path(&path).await?;
     let builder = CreateMessage::new();
     channel_id.send_files(&ctx.http, vec![attachment], builder).await?;
 
@@ -378,6 +567,15 @@ pub async fn handle_file_upload(
             .file_name()
             .unwrap_or_else(|| std::ffi::OsStr::new("uploaded_file"));
         let dest = Path::new(&save_dir).join(safe_name);
+        // Write where the path really leads, as checked
+        let dest = match access::check(allowed_roots(state).await.as_deref(), &dest, "upload", &format!("channel {}", channel_id.get())) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                rate_limit_wait(state, channel_id).await;
+                channel_id.say(&ctx.http, &e).await?;
+                continue;
+            }
+        };
         let file_size = buf.len();
         match fs::write(&dest, &buf) {
             Ok(_) => {
@@ -399,13 +597,12 @@ pub async fn handle_file_upload(
         );
         {
             let mut data = state.lock().await;
+            let origin = super::session_origin(&data.token, channel_id);
             if let Some(session) = data.sessions.get_mut(&channel_id) {
-                session.history.push(HistoryItem {
-                    item_type: HistoryType::User,
-                    content: upload_record.clone(),
-                });
+                session.history.push(HistoryItem::new(HistoryType::User, upload_record.clone()));
                 session.pending_uploads.push(upload_record);
-                bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &save_dir);
+                retention::trim_history(session.session_id.as_deref(), &mut session.history);
+                bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &save_dir, &origin);
             }
         }
     }
@@ -439,6 +636,12 @@ pub async fn handle_shell_command(
             })
     };
 
+    if let Err(e) = access::check(allowed_roots(state).await.as_deref(), Path::new(&working_dir), "shell", &format!("channel {}", channel_id.get())) {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, &e).await?;
+        return Ok(());
+    }
+
     let cmd_owned = cmd_str.to_string();
     let working_dir_clone = working_dir.clone();
 
@@ -525,7 +728,7 @@ pub async fn handle_allowedtools_command(
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let tools = {
         let data = state.lock().await;
-        data.settings.allowed_tools.clone()
+        data.effective_settings(channel_id).allowed_tools.value
     };
 
     let mut msg = String::from("**Allowed Tools**\n\n");
@@ -545,6 +748,188 @@ pub async fn handle_allowedtools_command(
     Ok(())
 }
 
+/// Handle /settings command - show or change this channel's settings
+/// Usage: /settings                  (effective values and where they come from)
+///        /settings <key> <value>    (set for this channel)
+///        /settings <key> reset      (back to the bot-wide default)
+///        /settings reset            (clear every setting of this channel)
+pub async fn handle_settings_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let arg = text.strip_prefix("/settings").unwrap_or("").trim();
+    let chat_key = channel_id.get().to_string();
+
+    let response_msg = {
+        let mut data = state.lock().await;
+        if arg.is_empty() {
+            let effective = data.effective_settings(channel_id);
+            let mut msg = String::from("**Settings**\n\n");
+            for (key, value, source) in chat_settings::describe(&effective) {
+                msg.push_str(&format!("**{}**: `{}` ({})\n", key, truncate_str(&value, 200), source.name()));
+            }
+            msg.push_str("\nchat = this channel, bot = bot-wide default\n\
+                `/settings <key> <value>` — Set for this channel\n\
+                `/settings <key> reset` — Use the bot default\n\
+                Verbosity: quiet, tools or full");
+            msg
+        } else {
+            let old_agent = data.effective_settings(channel_id).agent.value;
+            data.remember_session(channel_id);
+            let result = if arg.eq_ignore_ascii_case("reset") {
+                data.settings.chats.remove(&chat_key);
+                Ok("All settings of this channel reset to the bot defaults.".to_string())
+            } else {
+                let (key, value) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+                chat_settings::apply(data.settings.chats.entry(chat_key).or_default(), key, value)
+            };
+            data.settings.chats.retain(|_, chat| !chat.is_empty());
+            if data.effective_settings(channel_id).agent.value != old_agent {
+                data.resume_agent_session(channel_id);
+            }
+            match result {
+                Ok(done) => {
+                    bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
+                    done
+                }
+                Err(e) => e,
+            }
+        }
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response_msg).await?;
+
+    Ok(())
+}
+
+/// Handle /systemprompt command - show the rendered system prompt, or set this channel's additions
+pub async fn handle_systemprompt_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    role: roles::Role,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let arg = text.strip_prefix("/systemprompt").unwrap_or("").trim();
+
+    if arg.is_empty() || arg.eq_ignore_ascii_case("show") {
+        let inputs = {
+            let data = state.lock().await;
+            let effective = data.effective_settings(channel_id);
+            data.sessions.get(&channel_id).and_then(|s| s.current_path.clone()).map(|path| {
+                let tools = roles::tools_for(role, &effective.allowed_tools.value);
+                (path, tools, data.settings.prompt_template.clone(), effective.system_prompt, discord_token_hash(&data.token))
+            })
+        };
+        let Some((path, tools, prompt_template, additions, token_hash)) = inputs else {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, "No active session. Use /start <path> first.").await?;
+            return Ok(());
+        };
+        let prompt = super::chat::build_system_prompt(
+            prompt_template.as_deref(), additions.value.as_deref(), &tools, role, channel_id, &path, &token_hash,
+        );
+        let header = format!(
+            "**System prompt** (template: {}, additions: {})",
+            if prompt_template.is_some() { "bot" } else { "default" },
+            if additions.value.is_some() { additions.source.name() } else { "none" }
+        );
+        send_long_message(ctx, channel_id, &format!("{}\n\n{}", header, prompt), state).await?;
+        return Ok(());
+    }
+
+    let response_msg = {
+        let mut data = state.lock().await;
+        let result = chat_settings::apply(data.settings.chats.entry(channel_id.get().to_string()).or_default(), "system_prompt", arg);
+        data.settings.chats.retain(|_, chat| !chat.is_empty());
+        match result {
+            Ok(done) => {
+                bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
+                done
+            }
+            Err(e) => e,
+        }
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response_msg).await?;
+
+    Ok(())
+}
+
+/// Handle /reload command - re-read the config file and bot_settings.json
+pub async fn handle_reload_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let response_msg = match super::reload_and_log(state, "/reload").await {
+        Ok(changes) if changes.is_empty() => "Reloaded. No changes.".to_string(),
+        Ok(changes) => {
+            let lines: Vec<String> = changes.iter().map(|c| format!("• {}", c)).collect();
+            format!("**Reloaded**\n{}", lines.join("\n"))
+        }
+        Err(e) => format!("⚠ Reload failed, nothing changed: {}", e),
+    };
+
+    send_long_message(ctx, channel_id, &response_msg, state).await?;
+
+    Ok(())
+}
+
+/// Handle /users command - list users or add/remove/change roles (admin only)
+/// Usage: /users
+///        /users add <id> [role]
+///        /users remove <id>
+///        /users role <id> <role>
+pub async fn handle_users_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let arg = text.strip_prefix("/users").unwrap_or("").trim();
+
+    let response_msg = {
+        let mut data = state.lock().await;
+        if arg.is_empty() {
+            let mut msg = String::from("**Users**\n\n");
+            if let Some(owner) = data.settings.owner_user_id {
+                msg.push_str(&format!("`{}` — admin (owner)\n", owner));
+            }
+            for (id, role) in &data.settings.users {
+                msg.push_str(&format!("`{}` — {}\n", id, role));
+            }
+            if data.settings.owner_user_id.is_none() && data.settings.users.is_empty() {
+                msg.push_str("No users listed.\n");
+            }
+            if !data.allowed_channel_ids.is_empty() {
+                let ids: Vec<String> = data.allowed_channel_ids.iter().map(|id| id.to_string()).collect();
+                msg.push_str(&format!("\nAllowed channels: `{}` (members: {})\n", ids.join(", "), data.chat_role));
+            }
+            msg.push_str("\n/users add <id> [admin|user|viewer]\n/users role <id> <role>\n/users remove <id>");
+            msg
+        } else {
+            let owner = data.settings.owner_user_id;
+            match roles::apply_users_command(&mut data.settings.users, owner, arg) {
+                Ok(done) => {
+                    bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
+                    done
+                }
+                Err(e) => e,
+            }
+        }
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response_msg).await?;
+
+    Ok(())
+}
+
 /// Handle /allowed command - add/remove tools
 pub async fn handle_allowed_command(
     ctx: &Context,
@@ -584,21 +969,25 @@ pub async fn handle_allowed_command(
 
     let response_msg = {
         let mut data = state.lock().await;
+        // Changes apply to this chat, starting from the tools currently in effect
+        let mut tools = data.effective_settings(channel_id).allowed_tools.value;
         let token = data.token.clone();
         match op {
             '+' => {
-                if data.settings.allowed_tools.iter().any(|t| t == &tool_name) {
+                if tools.iter().any(|t| t == &tool_name) {
                     format!("`{}` is already in the list.", tool_name)
                 } else {
-                    data.settings.allowed_tools.push(tool_name.clone());
+                    tools.push(tool_name.clone());
+                    data.settings.chats.entry(channel_id.get().to_string()).or_default().allowed_tools = Some(tools);
                     bot_common::save_bot_settings(&discord_token_hash(&token), &data.settings, &[("platform", "discord")]);
                     format!("Added `{}`", tool_name)
                 }
             }
             '-' => {
-                let before_len = data.settings.allowed_tools.len();
-                data.settings.allowed_tools.retain(|t| t != &tool_name);
-                if data.settings.allowed_tools.len() < before_len {
+                let before_len = tools.len();
+                tools.retain(|t| t != &tool_name);
+                if tools.len() < before_len {
+                    data.settings.chats.entry(channel_id.get().to_string()).or_default().allowed_tools = Some(tools);
                     bot_common::save_bot_settings(&discord_token_hash(&token), &data.settings, &[("platform", "discord")]);
                     format!("Removed `{}`", tool_name)
                 } else {
@@ -615,18 +1004,102 @@ pub async fn handle_allowed_command(
     Ok(())
 }
 
+/// Format one /resume list entry
+fn format_session_entry(number: usize, s: &bot_common::SessionSummary) -> String {
+    let path_display: String = s.current_path.chars().take(40).collect();
+    let path_suffix = if s.current_path.chars().count() > 40 { "..." } else { "" };
+    let mut entry = format!(
+        "`{}`. {}**{}**\n    `{}{}`\n    {} | {} msgs\n",
+        number,
+        if s.pinned { "📌 " } else { "" },
+        s.title,
+        path_display,
+        path_suffix,
+        s.created_at,
+        s.history_count,
+    );
+    if !s.tags.is_empty() {
+        let tags: Vec<String> = s.tags.iter().map(|t| format!("#{}", t)).collect();
+        entry.push_str(&format!("    {}\n", tags.join(" ")));
+    }
+    if let Some(parent) = &s.parent_session_id {
+        entry.push_str(&format!("    fork of `{}`\n", parent));
+    }
+    entry
+}
+
+/// Handle /resume all - list sessions across all bots and channels (admins only)
+async fn handle_resume_all(
+    ctx: &Context,
+    channel_id: ChannelId,
+    state: &SharedState,
+    is_admin: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !is_admin {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, "Only admins can list sessions from other channels.").await?;
+        return Ok(());
+    }
+
+    let sessions = bot_common::list_all_sessions();
+    let mut msg = String::from("**All Saved Sessions**\n\n");
+    let max_display = 20;
+    for s in sessions.iter().take(max_display) {
+        let owner = match (&s.platform, &s.chat_id) {
+            (Some(platform), Some(chat)) => format!("{} {}", platform, chat),
+            _ => "unowned".to_string(),
+        };
+        msg.push_str(&format!(
+            "**{}** [{}]\n    `{}`\n    `{}` | {}\n",
+            s.title,
+            owner,
+            s.session_id,
+            s.current_path,
+            s.created_at,
+        ));
+    }
+    if sessions.is_empty() {
+        msg.push_str("No saved sessions found.");
+    } else if sessions.len() > max_display {
+        msg.push_str(&format!("\n... and {} more", sessions.len() - max_display));
+    }
+    if sessions.iter().any(|s| s.bot_hash.is_none()) {
+        msg.push_str("\nClaim an unowned session for this channel: `/resume <id>`");
+    }
+
+    send_long_message(ctx, channel_id, &msg, state).await?;
+    Ok(())
+}
+
 /// Handle /resume command - list saved sessions or resume a specific one
 /// Usage: /resume              (list all saved sessions)
 ///        /resume <number>     (resume session by number from the list)
+///        /resume <id>         (resume session by session ID)
+///        /resume <name|tag>   (resume by name or tag; lists matches if ambiguous)
+///        /resume all          (admins: list sessions of every bot and channel)
 pub async fn handle_resume_command(
     ctx: &Context,
     channel_id: ChannelId,
     text: &str,
     state: &SharedState,
+    is_admin: bool,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let arg = text.strip_prefix("/resume").unwrap_or("").trim();
 
-    let sessions = bot_common::list_all_sessions();
+    if arg == "all" {
+        return handle_resume_all(ctx, channel_id, state, is_admin).await;
+    }
+
+    // Only sessions owned by this bot and channel
+    let scope = {
+        let data = state.lock().await;
+        super::session_origin(&data.token, channel_id)
+    };
+    let mut sessions = bot_common::list_sessions_in_scope(&scope);
+    // Admins claim a session saved before ownership was recorded by its id (see /resume all)
+    if is_admin && !arg.is_empty() && !sessions.iter().any(|s| s.session_id == arg) {
+        sessions.extend(bot_common::find_unowned_session(arg));
+    }
 
     if sessions.is_empty() {
         rate_limit_wait(state, channel_id).await;
@@ -635,37 +1108,47 @@ pub async fn handle_resume_command(
     }
 
     if arg.is_empty() {
-        // List all saved sessions
+        // List all saved sessions (pinned first)
         let mut msg = String::from("**Saved Sessions**\n\n");
         let max_display = 10;
         for (i, s) in sessions.iter().take(max_display).enumerate() {
-            let path_display: String = s.current_path.chars().take(40).collect();
-            let path_suffix = if s.current_path.chars().count() > 40 { "..." } else { "" };
-            msg.push_str(&format!(
-                "`{}`. `{}{}`\n    {} | {} msgs\n",
-                i + 1,
-                path_display,
-                path_suffix,
-                s.created_at,
-                s.history_count,
-            ));
+            msg.push_str(&format_session_entry(i + 1, s));
         }
         if sessions.len() > max_display {
             msg.push_str(&format!("\n... and {} more", sessions.len() - max_display));
         }
-        msg.push_str("\nResume: `/resume <number>`");
+        msg.push_str("\nResume: `/resume <number|name|tag>`");
 
         send_long_message(ctx, channel_id, &msg, state).await?;
         return Ok(());
     }
 
-    // Parse number argument
+    // Parse number argument, or match a session ID, name or tag
     let num: usize = match arg.parse() {
         Ok(n) if n >= 1 && n <= sessions.len() => n,
         _ => {
-            rate_limit_wait(state, channel_id).await;
-            channel_id.say(&ctx.http, &format!("Invalid number. Use 1-{}.", sessions.len())).await?;
-            return Ok(());
+            let matches = bot_common::find_sessions(&sessions, arg);
+            match matches.as_slice() {
+                [idx] => idx + 1,
+                [] => {
+                    rate_limit_wait(state, channel_id).await;
+                    channel_id.say(&ctx.http, &format!(
+                        "No session matches `{}`. Use 1-{}, a name or a tag.",
+                        arg,
+                        sessions.len()
+                    )).await?;
+                    return Ok(());
+                }
+                _ => {
+                    let mut msg = format!("**Sessions matching {}**\n\n", arg);
+                    for idx in matches.iter().take(10) {
+                        msg.push_str(&format_session_entry(idx + 1, &sessions[*idx]));
+                    }
+                    msg.push_str("\nResume: `/resume <number>`");
+                    send_long_message(ctx, channel_id, &msg, state).await?;
+                    return Ok(());
+                }
+            }
         }
     };
 
@@ -689,6 +1172,11 @@ pub async fn handle_resume_command(
     };
 
     let canonical_path = selected.current_path.clone();
+    if let Err(e) = access::check(allowed_roots(state).await.as_deref(), Path::new(&canonical_path), "resume", &format!("channel {}", channel_id.get())) {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, &e).await?;
+        return Ok(());
+    }
     let mut response_lines = Vec::new();
 
     let token = {
@@ -699,12 +1187,18 @@ pub async fn handle_resume_command(
             history: Vec::new(),
             pending_uploads: Vec::new(),
             cleared: false,
+            fork_from: None,
+            compact_brief: None,
+            needs_compact: false,
         });
 
         session.session_id = Some(session_data.session_id.clone());
         session.current_path = Some(canonical_path.clone());
         session.history = session_data.history.clone();
         session.pending_uploads.clear();
+        session.fork_from = None;
+        session.compact_brief = None;
+        session.needs_compact = false;
         session.cleared = false;
 
         let ts = chrono::Local::now().format("%H:%M:%S");
@@ -736,6 +1230,7 @@ pub async fn handle_resume_command(
     {
         let mut data = state.lock().await;
         data.settings.last_sessions.insert(channel_id.get().to_string(), canonical_path);
+        data.remember_session(channel_id);
         bot_common::save_bot_settings(&discord_token_hash(&token), &data.settings, &[("platform", "discord")]);
     }
 
@@ -760,7 +1255,7 @@ pub async fn handle_agent_command(
         // Show current agent and list of available agents
         let current = {
             let data = state.lock().await;
-            data.agent_type.clone()
+            data.effective_settings(channel_id).agent.value
         };
 
         let mut msg = format!("**Current agent:** `{}`\n\n**Available agents:**\n", current);
@@ -789,15 +1284,20 @@ pub async fn handle_agent_command(
     }
 
     // Switch agent
-    let old_agent = {
+    let (old_agent, resumed) = {
         let mut data = state.lock().await;
-        let old = data.agent_type.clone();
-        data.agent_type = agent_name.clone();
-        old
+        let old = data.effective_settings(channel_id).agent.value;
+        data.remember_session(channel_id);
+        data.settings.chats.entry(channel_id.get().to_string()).or_default().agent = Some(agent_name.clone());
+        let resumed = old != agent_name && data.resume_agent_session(channel_id);
+        bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
+        (old, resumed)
     };
 
     let response = if old_agent == agent_name {
         format!("Already using `{}`.", agent_name)
+    } else if resumed {
+        format!("Switched: `{}` → `{}`\nResumed its last session in this workspace.", old_agent, agent_name)
     } else {
         format!("Switched: `{}` → `{}`", old_agent, agent_name)
     };
@@ -807,3 +1307,663 @@ pub async fn handle_agent_command(
 
     Ok(())
 }
+
+/// Handle /export command - render the current session transcript and send it as an attachment
+/// Usage: /export              (Markdown)
+///        /export <md|html|json>
+pub async fn handle_export_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let arg = text.strip_prefix("/export").unwrap_or("").trim();
+
+    let Some(format) = ExportFormat::parse(arg) else {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, "Usage: /export [md|html|json]").await?;
+        return Ok(());
+    };
+
+    let (session_data, agent) = {
+        let data = state.lock().await;
+        let session_data = data.sessions.get(&channel_id).and_then(|s| {
+            let session_id = s.session_id.clone()?;
+            let current_path = s.current_path.clone().unwrap_or_default();
+            // Prefer the saved file (it has the original created_at), fall back to memory
+            Some(bot_common::load_session_by_id(&session_id).unwrap_or_else(|| SessionData {
+                session_id,
+                history: s.history.clone(),
+                current_path,
+                created_at: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+                ..Default::default()
+            }))
+        });
+        (session_data, data.effective_settings(channel_id).agent.value)
+    };
+
+    let Some(session_data) = session_data.filter(|d| !d.history.is_empty()) else {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, "Nothing to export. Start a conversation first.").await?;
+        return Ok(());
+    };
+
+    match export::export_session_to_file(&session_data, format, Some(&agent)) {
+        Ok(path) => {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ▶ Session exported: {}", path.display());
+            rate_limit_wait(state, channel_id).await;
+            let attachment = CreateAttachment::path(&path).await;
+            // The transcript is only kept for the upload
+            let _ = std::fs::remove_file(&path);
+            let attachment = attachment?;
+            channel_id.send_files(&ctx.http, vec![attachment], CreateMessage::new()).await?;
+        }
+        Err(e) => {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, &format!("Export failed: {}", e)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle /fork command - copy the current conversation into a new session
+/// Usage: /fork              (unnamed fork)
+///        /fork <name>
+pub async fn handle_fork_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let name = text.strip_prefix("/fork").unwrap_or("").trim();
+    let name = if name.is_empty() { None } else { Some(name) };
+
+    let response = {
+        let mut data = state.lock().await;
+        let agent = data.effective_settings(channel_id).agent.value;
+        match data.sessions.get_mut(&channel_id) {
+            Some(session) if session.session_id.is_some() && !session.history.is_empty() => {
+                // Forking a fork that has not run yet keeps the original parent
+                let parent_id = session.fork_from.clone()
+                    .or_else(|| session.session_id.clone())
+                    .unwrap_or_default();
+                let current_path = session.current_path.clone().unwrap_or_default();
+                match bot_common::fork_session(&parent_id, &session.history, &current_path, name) {
+                    Some(fork) => {
+                        session.session_id = Some(fork.session_id.clone());
+                        session.fork_from = Some(parent_id.clone());
+                        session.pending_uploads.clear();
+
+                        let ts = chrono::Local::now().format("%H:%M:%S");
+                        println!("  [{ts}] ▶ Session forked: {parent_id} → {}", fork.session_id);
+                        let mode = if bot_common::supports_native_fork(&agent) {
+                            format!("`{}` will fork the conversation natively on your next message.", agent)
+                        } else {
+                            format!("`{}` cannot fork natively; the copied transcript will be sent as context.", agent)
+                        };
+                        format!(
+                            "Forked `{}` → `{}`{}\n{} messages copied.\n{}",
+                            parent_id,
+                            fork.session_id,
+                            name.map(|n| format!(" ({})", n)).unwrap_or_default(),
+                            fork.history.len(),
+                            mode,
+                        )
+                    }
+                    None => "Failed to save forked session.".to_string(),
+                }
+            }
+            _ => "Nothing to fork. Start a conversation first.".to_string(),
+        }
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response).await?;
+
+    Ok(())
+}
+
+/// Summarize the channel's conversation (with Claude, see [`compact::generate_brief`]) and move the channel to a new
+/// session that starts with the brief. Returns (old session id, new session id, items summarized).
+/// Shared by /compact and the automatic compaction before a turn; /stop cancels `cancel`,
+/// which kills the summarizing agent and abandons the result.
+pub(super) async fn compact_chat(
+    channel_id: ChannelId,
+    state: &SharedState,
+    cancel: &Arc<CancelToken>,
+) -> Result<(String, String, usize), String> {
+    let (old_id, history, current_path) = {
+        let mut data = state.lock().await;
+        match data.sessions.get_mut(&channel_id) {
+            Some(session) => {
+                session.needs_compact = false;
+                match (&session.session_id, &session.current_path) {
+                    (Some(id), Some(path)) if !session.history.is_empty() => {
+                        (id.clone(), session.history.clone(), path.clone())
+                    }
+                    _ => return Err("Nothing to compact. Start a conversation first.".to_string()),
+                }
+            }
+            None => return Err("Nothing to compact. Start a conversation first.".to_string()),
+        }
+    };
+
+    let item_count = history.len();
+    let dir = current_path.clone();
+    let cancel_clone = cancel.clone();
+    let brief = tokio::task::spawn_blocking(move || compact::generate_brief(&dir, &history, &cancel_clone))
+        .await
+        .map_err(|e| e.to_string())?;
+    if cancel.cancelled.load(Ordering::Relaxed) {
+        return Err("Compaction stopped.".to_string());
+    }
+    let brief = brief?;
+
+    let mut data = state.lock().await;
+    let Some(session) = data.sessions.get_mut(&channel_id)
+        .filter(|s| s.session_id.as_deref() == Some(old_id.as_str()))
+    else {
+        return Err("The session changed while compacting; nothing was replaced.".to_string());
+    };
+    let new = compact::start_compacted_session(&old_id, &brief, &current_path)
+        .ok_or_else(|| "Failed to save compacted session.".to_string())?;
+    session.session_id = Some(new.session_id.clone());
+    session.history = new.history;
+    session.fork_from = Some(old_id.clone());
+    session.compact_brief = Some(brief);
+
+    let ts = chrono::Local::now().format("%H:%M:%S");
+    println!("  [{ts}] ▶ Session compacted: {old_id} → {} ({item_count} items)", new.session_id);
+    Ok((old_id, new.session_id, item_count))
+}
+
+/// Handle /compact command - replace the conversation with an agent-written summary
+pub async fn handle_compact_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Hold a cancel token so other messages wait (and /stop works) like during a turn
+    let cancel_token = Arc::new(CancelToken::new());
+    {
+        let mut data = state.lock().await;
+        data.cancel_tokens.insert(channel_id, cancel_token.clone());
+    }
+
+    rate_limit_wait(state, channel_id).await;
+    let progress = channel_id.say(&ctx.http, "🗜 Compacting conversation...").await;
+
+    let result = compact_chat(channel_id, state, &cancel_token).await;
+
+    {
+        let mut data = state.lock().await;
+        data.cancel_tokens.remove(&channel_id);
+    }
+
+    super::chat::run_queued_messages(ctx.clone(), channel_id, state.clone());
+
+    let response = match result {
+        Ok((old_id, new_id, count)) => format!(
+            "Compacted {} messages into a summary.\nNew session `{}` starts with it on your next message.\nThe full conversation is kept as `{}` (/resume {} to go back).",
+            count, new_id, old_id, old_id,
+        ),
+        Err(e) => format!("Compaction failed: {}", e),
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    match progress {
+        Ok(msg) => {
+            channel_id.edit_message(&ctx.http, msg.id, EditMessage::new().content(&response)).await?;
+        }
+        Err(_) => {
+            channel_id.say(&ctx.http, &response).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle /search command - full-text search across saved sessions
+/// Usage: /search <terms>
+pub async fn handle_search_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let query = text.strip_prefix("/search").unwrap_or("").trim();
+    if query.is_empty() {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, "Usage: `/search <terms>`").await?;
+        return Ok(());
+    }
+
+    let scope = {
+        let data = state.lock().await;
+        super::session_origin(&data.token, channel_id)
+    };
+    let terms = query.to_string();
+    let hits = tokio::task::spawn_blocking(move || search::search_sessions(&terms, 10, Some(&scope))).await
+        .unwrap_or_default();
+    if hits.is_empty() {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, &format!("No sessions match `{}`.", query)).await?;
+        return Ok(());
+    }
+
+    let mut msg = format!("**Search: {}**\n\n", query);
+    for (i, hit) in hits.iter().enumerate() {
+        msg.push_str(&format!(
+            "`{}`. `{}`\n    {}{}\n",
+            i + 1,
+            hit.current_path,
+            hit.created_at,
+            hit.name.as_ref().map(|n| format!(" | *{}*", n)).unwrap_or_default(),
+        ));
+        for snippet in &hit.snippets {
+            msg.push_str(&format!("    » {}\n", snippet.replace('`', "'")));
+        }
+        msg.push_str(&format!("    `/resume {}`\n\n", hit.session_id));
+    }
+
+    send_long_message(ctx, channel_id, &msg, state).await?;
+
+    Ok(())
+}
+
+/// Current session id for a channel, if any
+async fn current_session_id(channel_id: ChannelId, state: &SharedState) -> Option<String> {
+    let data = state.lock().await;
+    data.sessions.get(&channel_id).and_then(|s| s.session_id.clone())
+}
+
+const UNSAVED_SESSION_MSG: &str = "No saved session yet. Send a message first.";
+
+/// Handle /rename command - set the current session's name
+/// Usage: /rename <title>
+pub async fn handle_rename_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let title = text.strip_prefix("/rename").unwrap_or("").trim();
+    let response = if title.is_empty() {
+        "Usage: `/rename <title>`".to_string()
+    } else {
+        match current_session_id(channel_id, state).await
+            .and_then(|sid| bot_common::update_session_meta(&sid, |d| d.name = Some(title.to_string())))
+        {
+            Some(_) => format!("Session renamed to **{}**.", title),
+            None => UNSAVED_SESSION_MSG.to_string(),
+        }
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response).await?;
+
+    Ok(())
+}
+
+/// Handle /tag command - add or remove a label on the current session
+/// Usage: /tag              (show tags)
+///        /tag <label>      (add)
+///        /tag -<label>     (remove)
+pub async fn handle_tag_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let arg = text.strip_prefix("/tag").unwrap_or("").trim();
+    let (remove, label) = match arg.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, arg),
+    };
+
+    let response = match current_session_id(channel_id, state).await {
+        None => UNSAVED_SESSION_MSG.to_string(),
+        Some(sid) if arg.is_empty() => match bot_common::load_session_by_id(&sid) {
+            Some(d) if d.tags.is_empty() => "No tags. Add one with `/tag <label>`.".to_string(),
+            Some(d) => {
+                let tags: Vec<String> = d.tags.iter().map(|t| format!("#{}", t)).collect();
+                format!("Tags: {}", tags.join(" "))
+            }
+            None => UNSAVED_SESSION_MSG.to_string(),
+        },
+        Some(sid) => match bot_common::normalize_tag(label) {
+            None => "Invalid tag. Use a single word up to 32 characters.".to_string(),
+            Some(tag) => {
+                let updated = bot_common::update_session_meta(&sid, |d| {
+                    if remove {
+                        d.tags.retain(|t| t != &tag);
+                    } else if !d.tags.contains(&tag) {
+                        d.tags.push(tag.clone());
+                    }
+                });
+                match updated {
+                    Some(_) if remove => format!("Removed tag `#{}`.", tag),
+                    Some(_) => format!("Tagged `#{}`. Resume later with `/resume {}`.", tag, tag),
+                    None => UNSAVED_SESSION_MSG.to_string(),
+                }
+            }
+        },
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response).await?;
+
+    Ok(())
+}
+
+/// Handle /pin command - toggle pinning of the current session in /resume
+pub async fn handle_pin_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let response = match current_session_id(channel_id, state).await
+        .and_then(|sid| bot_common::update_session_meta(&sid, |d| d.pinned = !d.pinned))
+    {
+        Some(d) if d.pinned => "📌 Session pinned. It is listed first in `/resume`.",
+        Some(_) => "Session unpinned.",
+        None => UNSAVED_SESSION_MSG,
+    };
+
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+/// Current session directory for a channel, if a session is active
+async fn current_path(channel_id: ChannelId, state: &SharedState) -> Option<String> {
+    let data = state.lock().await;
+    data.sessions.get(&channel_id).and_then(|s| s.current_path.clone())
+}
+
+const NO_SESSION_MSG: &str = "No active session. Use /start <path> first.";
+
+/// Handle /diff command - show uncommitted changes in the session directory
+pub async fn handle_diff_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(path) = current_path(channel_id, state).await else {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+
+    let dir = path.clone();
+    let result = tokio::task::spawn_blocking(move || git::working_tree_diff(&dir)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+    match result {
+        Ok(diff) if diff.is_empty() => {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, "No uncommitted changes.").await?;
+        }
+        Ok(diff) if git::fits_inline(&diff) => {
+            send_long_message(ctx, channel_id, &git::render_diff_markdown(&diff), state).await?;
+        }
+        Ok(diff) => {
+            match git::write_patch_file(&path, &diff) {
+                Ok(patch_path) => {
+                    let mut summary = format!("```\n{}\n```", diff.stat);
+                    if !diff.untracked.is_empty() {
+                        summary.push_str(&format!("\nUntracked: {}", diff.untracked.join(", ")));
+                    }
+                    rate_limit_wait(state, channel_id).await;
+                    let attachment = CreateAttachment::path(&patch_path).await;
+                    // The patch is only kept for the upload
+                    let _ = std::fs::remove_file(&patch_path);
+                    channel_id.send_files(
+                        &ctx.http,
+                        vec![attachment?],
+                        CreateMessage::new().content(truncate_str(&summary, 1900)),
+                    ).await?;
+                }
+                Err(e) => {
+                    rate_limit_wait(state, channel_id).await;
+                    channel_id.say(&ctx.http, &format!("Failed to write patch: {}", e)).await?;
+                }
+            }
+        }
+        Err(e) => {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, &e).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle /commit command - commit all changes in the session directory
+/// Usage: /commit             (claude writes the message)
+///        /commit <message>
+pub async fn handle_commit_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(path) = current_path(channel_id, state).await else {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+    let message = text.strip_prefix("/commit").unwrap_or("").trim().to_string();
+    let dir = path.clone();
+    let diff = tokio::task::spawn_blocking(move || git::working_tree_diff(&dir)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    let diff = match diff {
+        Ok(diff) if diff.is_empty() => {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, "Nothing to commit.").await?;
+            return Ok(());
+        }
+        Ok(diff) => diff,
+        Err(e) => {
+            rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, &e).await?;
+            return Ok(());
+        }
+    };
+
+    if message.is_empty() {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, "Asking claude for a commit message...").await?;
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        let message = if message.is_empty() {
+            git::generate_commit_message(&path, &diff)
+                .map_err(|e| format!("Could not generate a commit message: {}", e))?
+        } else {
+            message
+        };
+        git::commit_all(&path, &message)
+    }).await.unwrap_or_else(|e| Err(e.to_string()));
+
+    let response = match result {
+        Ok(summary) => {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ▶ Committed: {}", summary);
+            format!("Committed `{}`", summary)
+        }
+        Err(e) => format!("Commit failed:\n```\n{}\n```", e),
+    };
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response).await?;
+
+    Ok(())
+}
+
+/// Handle /branch command - create and switch to a branch for this session
+/// Usage: /branch             (aemi/<session name or id>)
+///        /branch <name>
+pub async fn handle_branch_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(path) = current_path(channel_id, state).await else {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+    let arg = text.strip_prefix("/branch").unwrap_or("").trim();
+
+    let branch = if !arg.is_empty() {
+        arg.to_string()
+    } else {
+        match current_session_id(channel_id, state).await {
+            Some(sid) => {
+                let name = bot_common::load_session_by_id(&sid).and_then(|d| d.name);
+                git::session_branch_name(&sid, name.as_deref())
+            }
+            None => {
+                rate_limit_wait(state, channel_id).await;
+                channel_id.say(&ctx.http, UNSAVED_SESSION_MSG).await?;
+                return Ok(());
+            }
+        }
+    };
+
+    let branch_owned = branch.clone();
+    let result = tokio::task::spawn_blocking(move || git::switch_branch(&path, &branch_owned)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+    let response = match result {
+        Ok(true) => format!("Created and switched to branch `{}`", branch),
+        Ok(false) => format!("Switched to branch `{}`", branch),
+        Err(e) => format!("Branch switch failed:\n```\n{}\n```", e),
+    };
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response).await?;
+
+    Ok(())
+}
+
+/// Restore a checkpoint and describe the result
+async fn restore_checkpoint(path: String, id: String) -> String {
+    let result = tokio::task::spawn_blocking(move || checkpoint::restore(&path, &id)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    match result {
+        Ok((cp, report)) => {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ▶ Restored checkpoint {}", cp.id);
+            format!(
+                "Restored workspace to before *{}* ({})\n{} file(s) restored, {} removed.\nConversation history is unchanged.",
+                cp.label, cp.created_at, report.restored, report.removed
+            )
+        }
+        Err(e) => format!("Restore failed:\n```\n{}\n```", e),
+    }
+}
+
+/// Handle /undo command - roll the workspace back to before the last AI turn
+pub async fn handle_undo_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(path) = current_path(channel_id, state).await else {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+
+    let response = match checkpoint::list(&path).last() {
+        Some(latest) => restore_checkpoint(path.clone(), latest.id.clone()).await,
+        None => "No checkpoints for this directory.".to_string(),
+    };
+    rate_limit_wait(state, channel_id).await;
+    channel_id.say(&ctx.http, &response).await?;
+
+    Ok(())
+}
+
+/// Handle /checkpoints command - list workspace checkpoints or restore one
+/// Usage: /checkpoints        (list, newest first)
+///        /checkpoints <n>    (restore; newer checkpoints are dropped)
+pub async fn handle_checkpoints_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    text: &str,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(path) = current_path(channel_id, state).await else {
+        rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+    let arg = text.strip_prefix("/checkpoints").unwrap_or("").trim();
+    let mut checkpoints = checkpoint::list(&path);
+    checkpoints.reverse();
+
+    let response = if checkpoints.is_empty() {
+        "No checkpoints for this directory.".to_string()
+    } else if arg.is_empty() {
+        let mut msg = String::from("**Checkpoints** (newest first)\n\n");
+        for (i, cp) in checkpoints.iter().enumerate() {
+            msg.push_str(&format!("{}. {} [{}] {}\n", i + 1, cp.created_at, cp.kind(), cp.label));
+        }
+        msg.push_str("\n`/checkpoints <n>` restores the workspace to before that turn; newer checkpoints are dropped.");
+        msg
+    } else {
+        match arg.parse::<usize>().ok().and_then(|n| checkpoints.get(n.wrapping_sub(1))) {
+            Some(cp) => restore_checkpoint(path.clone(), cp.id.clone()).await,
+            None => format!("Usage: /checkpoints [1-{}]", checkpoints.len()),
+        }
+    };
+    send_long_message(ctx, channel_id, &response, state).await?;
+
+    Ok(())
+}
+
+/// Handle /templates command - list workspace templates
+pub async fn handle_templates_command(
+    ctx: &Context,
+    channel_id: ChannelId,
+    state: &SharedState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let templates = template::list_templates();
+    let response = if templates.is_empty() {
+        let dir = template::templates_dir()
+            .map(|d| d.display().to_string())
+            .unwrap_or_else(|| "~/.aemi/templates".to_string());
+        format!(
+            "No templates. Create a directory in `{}/<name>/` with a `files/` directory, \
+             and optionally `init.sh`, `prompt.md` and `description.txt`.",
+            dir
+        )
+    } else {
+        let mut msg = String::from("**Templates**\n\n");
+        for t in &templates {
+            let mut parts = Vec::new();
+            if t.has_init {
+                parts.push("init");
+            }
+            if t.has_prompt {
+                parts.push("prompt");
+            }
+            let extras = if parts.is_empty() { String::new() } else { format!(" [{}]", parts.join(", ")) };
+            msg.push_str(&format!("`{}`{}", t.name, extras));
+            if let Some(desc) = &t.description {
+                msg.push_str(&format!(" — {}", desc));
+            }
+            msg.push('\n');
+        }
+        msg.push_str("\nStart one with `/start --template <name>`");
+        msg
+    };
+    send_long_message(ctx, channel_id, &response, state).await?;
+
+    Ok(())
+}
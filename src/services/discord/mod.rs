@@ -4,6 +4,7 @@ mod messages;
 mod formatting;
 
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use tokio::sync::Mutex;
@@ -14,8 +15,16 @@ use serenity::model::id::ChannelId;
 use serenity::prelude::*;
 
 use crate::services::agent::CancelToken;
-use crate::services::bot_common::{self, BotSettings};
-use crate::services::session::HistoryItem;
+use crate::services::bot_common::{self, BotOptions, BotSettings};
+use crate::services::chat_settings;
+use crate::services::chat_state;
+use crate::services::config::{self, Platform};
+use crate::services::reload;
+use crate::services::retention;
+use crate::services::roles::{self, ChatAccess, Role};
+use crate::services::shutdown;
+use crate::services::access;
+use crate::services::session::{HistoryItem, SessionOrigin};
 use crate::services::utils::truncate_str;
 
 /// Per-channel session state
@@ -27,6 +36,13 @@ pub(crate) struct ChannelSession {
     pub pending_uploads: Vec<String>,
     /// Set to true by /clear to prevent a racing polling loop from re-populating history.
     pub cleared: bool,
+    /// Parent session id set by /fork. The next turn forks from it (natively where the
+    /// agent supports it) and the fork placeholder is renamed to the new provider id.
+    pub fork_from: Option<String>,
+    /// Brief set by /compact. The next turn starts a fresh provider session seeded with it.
+    pub compact_brief: Option<String>,
+    /// Set when the provider reported a context overflow; the next turn compacts first.
+    pub needs_compact: bool,
 }
 
 /// Shared state: per-channel sessions + bot settings
@@ -35,14 +51,99 @@ pub(crate) struct SharedData {
     pub settings: BotSettings,
     /// Per-channel cancel tokens for stopping in-progress AI requests
     pub cancel_tokens: HashMap<ChannelId, Arc<CancelToken>>,
+    /// Plain messages received while a turn was running (text, message id, sender role).
+    /// Sent as one merged follow-up prompt when the turn ends.
+    pub queued_messages: HashMap<ChannelId, Vec<(String, serenity::model::id::MessageId, Role)>>,
     /// Per-channel timestamp of the last Discord API call (for rate limiting)
     pub api_timestamps: HashMap<ChannelId, tokio::time::Instant>,
-    /// If set, only messages from this channel ID are allowed (--channel-id parameter)
-    pub allowed_channel_id: Option<u64>,
+    /// If non-empty, only messages from these channel IDs are allowed (--channel-id or config `chats`)
+    pub allowed_channel_ids: Vec<u64>,
+    /// Role of allowed-channel members who are not listed users
+    pub chat_role: Role,
+    /// Workspace roots for /start, /down, shell and uploads (None = unrestricted)
+    pub allowed_roots: Option<Vec<PathBuf>>,
     /// Bot token (stored for settings persistence)
     pub token: String,
-    /// Agent type: "claude" or "gemini"
+    /// Bot-wide agent from --agent or the config file; chats can override it
     pub agent_type: String,
+    /// Notices for channels whose turn was interrupted by the last restart, sent once connected
+    pub interrupted_notices: Vec<(ChannelId, String)>,
+    /// Config file the bot was started from (None for command-line bots)
+    pub config_path: Option<PathBuf>,
+}
+
+impl SharedData {
+    /// Current values of everything a reload can change
+    pub fn snapshot(&self) -> reload::Snapshot {
+        reload::Snapshot {
+            chats: self.allowed_channel_ids.iter().map(|id| *id as i64).collect(),
+            agent: self.agent_type.clone(),
+            chat_role: self.chat_role,
+            allowed_roots: self.allowed_roots.clone(),
+            settings: self.settings.clone(),
+        }
+    }
+
+    /// Replace the reloadable values. Agent sessions and running turns are runtime
+    /// state and stay as they are.
+    pub fn apply_snapshot(&mut self, snapshot: reload::Snapshot) {
+        let chat_state = std::mem::take(&mut self.settings.chat_state);
+        self.allowed_channel_ids = snapshot.chats.iter().map(|id| *id as u64).collect();
+        self.agent_type = snapshot.agent;
+        self.chat_role = snapshot.chat_role;
+        self.allowed_roots = snapshot.allowed_roots;
+        self.settings = snapshot.settings;
+        self.settings.chat_state = chat_state;
+    }
+
+    /// Settings in effect for a channel: its own settings over the bot-wide defaults
+    pub fn effective_settings(&self, channel_id: ChannelId) -> chat_settings::Effective {
+        chat_settings::effective(&self.settings, &self.agent_type, &channel_id.get().to_string())
+    }
+
+    /// Remember the channel's provider session under its current agent (saved with the settings)
+    pub fn remember_session(&mut self, channel_id: ChannelId) {
+        let agent = self.effective_settings(channel_id).agent.value;
+        let session_id = self.sessions.get(&channel_id).and_then(|s| s.session_id.clone());
+        self.settings.chat_state.entry(channel_id.get().to_string()).or_default()
+            .set_session(&agent, session_id.as_deref());
+    }
+
+    /// Pick up the provider session the channel's current agent last used in this workspace,
+    /// after an agent switch (call [`Self::remember_session`] before switching).
+    /// Each agent keeps its own session; returns true if one was resumed.
+    pub fn resume_agent_session(&mut self, channel_id: ChannelId) -> bool {
+        let agent = self.effective_settings(channel_id).agent.value;
+        let saved = self.settings.chat_state.get(&channel_id.get().to_string()).and_then(|s| s.sessions.get(&agent).cloned());
+        let Some(session) = self.sessions.get_mut(&channel_id) else {
+            return false;
+        };
+        let resumed = saved.as_deref()
+            .and_then(bot_common::load_session_by_id)
+            .filter(|data| session.current_path.as_deref() == Some(data.current_path.as_str()));
+        session.fork_from = None;
+        session.compact_brief = None;
+        session.needs_compact = false;
+        match resumed {
+            Some(data) => {
+                session.session_id = Some(data.session_id);
+                session.history = data.history;
+                true
+            }
+            None => {
+                session.session_id = None;
+                false
+            }
+        }
+    }
+
+    /// The channel's turn ended: clear its running mark and remember the session it used
+    pub fn finish_turn(&mut self, channel_id: ChannelId) {
+        self.remember_session(channel_id);
+        if let Some(state) = self.settings.chat_state.get_mut(&channel_id.get().to_string()) {
+            state.running = None;
+        }
+    }
 }
 
 pub(crate) type SharedState = Arc<Mutex<SharedData>>;
@@ -55,6 +156,15 @@ pub(crate) fn discord_token_hash(token: &str) -> String {
     bot_common::token_hash(token, Some("dc"))
 }
 
+/// Session origin recorded in saved session files
+pub(crate) fn session_origin(token: &str, channel_id: ChannelId) -> SessionOrigin {
+    SessionOrigin {
+        platform: "discord".to_string(),
+        chat_id: channel_id.get().to_string(),
+        bot_hash: discord_token_hash(token),
+    }
+}
+
 /// TypeMapKey for storing shared state in serenity's data map
 struct BotState;
 impl TypeMapKey for BotState {
@@ -85,32 +195,132 @@ impl EventHandler for Handler {
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         println!("  ✓ Bot connected as {} — Listening for messages", ready.user.name);
+
+        // Tell channels whose turn was interrupted; /retry runs it again
+        let Some(state) = ctx.data.read().await.get::<BotState>().cloned() else {
+            return;
+        };
+        let notices = std::mem::take(&mut state.lock().await.interrupted_notices);
+        for (channel_id, notice) in notices {
+            messages::rate_limit_wait(&state, channel_id).await;
+            let _ = channel_id.say(&ctx.http, notice).await;
+        }
     }
 }
 
+/// Re-read the config file and bot_settings.json and apply them in one step (SIGHUP or
+/// /reload). Returns what changed; on error nothing is changed.
+pub(crate) async fn reload_bot(state: &SharedState) -> Result<Vec<String>, String> {
+    let (config_path, token) = {
+        let data = state.lock().await;
+        (data.config_path.clone(), data.token.clone())
+    };
+    // The config may run a token helper, so read it off the async runtime
+    let entry = match config_path {
+        Some(path) => {
+            let token = token.clone();
+            let found = tokio::task::spawn_blocking(move || config::find(&path, Platform::Discord, &token)).await;
+            Some(found.map_err(|e| e.to_string())??)
+        }
+        None => None,
+    };
+
+    let mut data = state.lock().await;
+    let settings = bot_common::read_bot_settings(&discord_token_hash(&token))?;
+    let current = data.snapshot();
+    let new = match entry {
+        Some(bot) => reload::snapshot(bot.chats, &bot.agent, bot.options, settings),
+        // Command-line bots keep their channels and agent
+        None => reload::snapshot(current.chats.clone(), &current.agent, BotOptions::default(), settings),
+    };
+    let changes = reload::changes(&current, &new);
+    data.apply_snapshot(new);
+    Ok(changes)
+}
+
+/// Reload and log the outcome
+async fn reload_and_log(state: &SharedState, trigger: &str) -> Result<Vec<String>, String> {
+    let result = reload_bot(state).await;
+    let ts = chrono::Local::now().format("%H:%M:%S");
+    match &result {
+        Ok(changes) if changes.is_empty() => println!("  [{ts}] ↻ Reloaded ({trigger}): no changes"),
+        Ok(changes) => println!("  [{ts}] ↻ Reloaded ({trigger}): {}", changes.join("; ")),
+        Err(e) => println!("  [{ts}] ⚠ Reload failed ({trigger}): {e}"),
+    }
+    result
+}
+
 /// Entry point: start the Discord bot
-pub async fn run_bot(token: &str, allowed_channel_id: Option<u64>, agent_type: &str) {
-    let bot_settings = bot_common::load_bot_settings(&discord_token_hash(token));
+pub async fn run_bot(token: &str, allowed_channel_ids: Vec<u64>, agent_type: &str, options: BotOptions) {
+    let config_path = options.config_path.clone();
+    let chats = allowed_channel_ids.iter().map(|id| *id as i64).collect();
+    let startup = reload::snapshot(chats, agent_type, options, bot_common::load_bot_settings(&discord_token_hash(token)));
+    let reload::Snapshot { chat_role, allowed_roots, settings: mut bot_settings, .. } = startup;
+
+    // Turns still running when the bot last stopped were cut off
+    let interrupted = chat_state::mark_interrupted(&mut bot_settings.chat_state);
+    if !interrupted.is_empty() {
+        bot_common::save_bot_settings(&discord_token_hash(token), &bot_settings, &[("platform", "discord")]);
+        println!("  ⚠ Interrupted turns: {} channel(s)", interrupted.len());
+    }
+    let interrupted_notices = interrupted.iter()
+        .filter_map(|(key, turn)| Some((ChannelId::new(key.parse().ok()?), chat_state::interrupted_notice(turn))))
+        .collect();
 
-    if let Some(cid) = allowed_channel_id {
-        println!("  ✓ Channel ID restriction: {cid}");
+    if !allowed_channel_ids.is_empty() {
+        let ids: Vec<String> = allowed_channel_ids.iter().map(|id| id.to_string()).collect();
+        println!("  ✓ Channel ID restriction: {} (members: {chat_role})", ids.join(", "));
     } else {
         match bot_settings.owner_user_id {
             Some(owner_id) => println!("  ✓ Owner: {owner_id}"),
             None => println!("  ⚠ No owner registered — first user will be registered as owner"),
         }
     }
+    if !bot_settings.users.is_empty() {
+        println!("  ✓ Users: {}", bot_settings.users.len());
+    }
 
     let shared_state: SharedState = Arc::new(Mutex::new(SharedData {
         sessions: HashMap::new(),
         settings: bot_settings,
         cancel_tokens: HashMap::new(),
+        queued_messages: HashMap::new(),
         api_timestamps: HashMap::new(),
-        allowed_channel_id,
+        allowed_channel_ids,
+        chat_role,
+        allowed_roots,
         token: token.to_string(),
         agent_type: agent_type.to_string(),
+        interrupted_notices,
+        config_path,
+    }));
+
+    // SIGHUP reloads the config and settings without dropping the gateway connection
+    let reload_state = shared_state.clone();
+    tokio::spawn(async move {
+        let Ok(mut hangup) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) else {
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            let _ = reload_and_log(&reload_state, "SIGHUP").await;
+        }
+    });
+
+    // Retention: prune old sessions, workspaces and logs at startup and periodically.
+    // Sessions and workspaces open in a chat of any bot in this process are never pruned.
+    let prune_state = shared_state.clone();
+    retention::register(Box::new(move || {
+        let state = prune_state.clone();
+        Box::pin(async move {
+            let data = state.lock().await;
+            retention::Protected {
+                session_ids: data.sessions.values().filter_map(|s| s.session_id.clone()).collect(),
+                paths: data.sessions.values().filter_map(|s| s.current_path.clone()).collect(),
+                changed_within: None,
+            }
+        })
     }));
 
     let intents = GatewayIntents::GUILD_MESSAGES
@@ -130,6 +340,14 @@ pub async fn run_bot(token: &str, allowed_channel_id: Option<u64>, agent_type: &
         }
     };
 
+    // Ctrl-C and SIGTERM drain running turns before the gateway disconnects
+    shutdown::listen();
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        shutdown::stopped().await;
+        shard_manager.shutdown_all().await;
+    });
+
     if let Err(e) = client.start().await {
         eprintln!("  ✗ Discord client error: {e}");
     }
@@ -146,46 +364,54 @@ async fn handle_message(
     let user_id = msg.author.id.get();
     let timestamp = chrono::Local::now().format("%H:%M:%S");
 
-    // Auth check: --channel-id restriction takes priority over imprinting
-    let (allowed_cid, _token) = {
-        let data = state.lock().await;
-        (data.allowed_channel_id, data.token.clone())
-    };
-
-    if let Some(allowed) = allowed_cid {
-        if channel_id.get() != allowed {
-            println!("  [{timestamp}] ✗ Rejected (channel:{}, user:{user_name}/{user_id}) — allowed channel: {allowed}", channel_id.get());
-            return Ok(());
-        }
-    } else {
-        // Imprinting mode
-        let imprinted = {
-            let mut data = state.lock().await;
-            match data.settings.owner_user_id {
-                None => {
-                    data.settings.owner_user_id = Some(user_id);
-                    bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
-                    println!("  [{timestamp}] ★ Owner registered: {user_name} (id:{user_id})");
-                    true
-                }
-                Some(owner_id) => {
-                    if user_id != owner_id {
-                        println!("  [{timestamp}] ✗ Rejected: {user_name} (id:{user_id})");
-                        return Ok(());
-                    }
-                    false
-                }
-            }
+    // Auth: the owner and listed users are allowed in any channel, members of allowed
+    // channels get the chat role, and without a channel restriction the first user becomes the owner
+    let role = {
+        let mut data = state.lock().await;
+        let chat = if !data.allowed_channel_ids.contains(&channel_id.get()) {
+            ChatAccess::NotListed
+        } else if msg.guild_id.is_none() {
+            ChatAccess::ListedPrivate
+        } else {
+            ChatAccess::Listed
         };
-        if imprinted {
-            // Owner registration logged to console only
+        let role = roles::resolve(&data.settings.users, data.settings.owner_user_id, data.chat_role, Some(user_id), chat);
+        let imprint = data.allowed_channel_ids.is_empty()
+            && data.settings.owner_user_id.is_none()
+            && data.settings.users.is_empty();
+        match role {
+            None if imprint => {
+                data.settings.owner_user_id = Some(user_id);
+                bot_common::save_bot_settings(&discord_token_hash(&data.token), &data.settings, &[("platform", "discord")]);
+                println!("  [{timestamp}] ★ Owner registered: {user_name} (id:{user_id})");
+                Some(Role::Admin)
+            }
+            role => role,
         }
-    }
+    };
+    let Some(role) = role else {
+        println!("  [{timestamp}] ✗ Rejected (channel:{}, user:{user_name}/{user_id})", channel_id.get());
+        return Ok(());
+    };
 
     let user_display = format!("{user_name}({user_id})");
 
+    // Shutting down: no new turns, but /stop can still end a running one early
+    if shutdown::is_shutting_down() && !msg.content.starts_with("/stop") {
+        println!("  [{timestamp}] ✗ [{user_display}] Rejected: shutting down");
+        messages::rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, "⏻ The bot is shutting down. Send this again once it is back.").await?;
+        return Ok(());
+    }
+
     // Handle file attachments
     if !msg.attachments.is_empty() {
+        if role < Role::User {
+            println!("  [{timestamp}] ✗ [{user_display}] Upload rejected ({role})");
+            messages::rate_limit_wait(state, channel_id).await;
+            channel_id.say(&ctx.http, "⛔ Viewers cannot upload files.").await?;
+            return Ok(());
+        }
         println!("  [{timestamp}] ◀ [{user_display}] Upload: {} file(s)", msg.attachments.len());
         commands::handle_file_upload(ctx, msg, state).await?;
         println!("  [{timestamp}] ▶ [{user_display}] Upload complete");
@@ -199,24 +425,41 @@ async fn handle_message(
 
     let preview = truncate_str(&text, 60);
 
+    let required = roles::required_role(&text);
+    if role < required {
+        println!("  [{timestamp}] ✗ [{user_display}] {preview} — needs {required} role ({role})");
+        messages::rate_limit_wait(state, channel_id).await;
+        channel_id.say(&ctx.http, format!("⛔ This needs the {required} role. You are a {role}.")).await?;
+        return Ok(());
+    }
+
     // Auto-restore session from bot_settings.json if not in memory
     if !text.starts_with("/start") {
         let mut data = state.lock().await;
         if !data.sessions.contains_key(&channel_id) {
             if let Some(last_path) = data.settings.last_sessions.get(&channel_id.get().to_string()).cloned() {
-                if std::path::Path::new(&last_path).is_dir() {
-                    let existing = bot_common::load_existing_session(&last_path);
+                let who = format!("channel {}", channel_id.get());
+                if std::path::Path::new(&last_path).is_dir()
+                    && access::check(data.allowed_roots.as_deref(), std::path::Path::new(&last_path), "auto-restore", &who).is_ok()
+                {
+                    let agent = data.effective_settings(channel_id).agent.value;
+                    let agent_session = data.settings.chat_state.get(&channel_id.get().to_string())
+                        .and_then(|s| s.sessions.get(&agent).cloned());
+                    let existing = bot_common::restore_session(&last_path, agent_session.as_deref(), &session_origin(&data.token, channel_id));
                     let session = data.sessions.entry(channel_id).or_insert_with(|| ChannelSession {
                         session_id: None,
                         current_path: None,
                         history: Vec::new(),
                         pending_uploads: Vec::new(),
                         cleared: false,
+                        fork_from: None,
+                        compact_brief: None,
+                        needs_compact: false,
                     });
                     session.current_path = Some(last_path.clone());
-                    if let Some((session_data, _)) = existing {
-                        session.session_id = Some(session_data.session_id.clone());
-                        session.history = session_data.history.clone();
+                    if let Some(session_data) = existing {
+                        session.session_id = Some(session_data.session_id);
+                        session.history = session_data.history;
                     }
                     let ts = chrono::Local::now().format("%H:%M:%S");
                     println!("  [{ts}] ↻ [{user_display}] Auto-restored session: {last_path}");
@@ -225,10 +468,34 @@ async fn handle_message(
         }
     }
 
-    // Block all messages except /stop while an AI request is in progress
-    if !text.starts_with("/stop") {
-        let data = state.lock().await;
-        if data.cancel_tokens.contains_key(&channel_id) {
+    // While an AI request is in progress, plain messages are queued for the next turn
+    // and everything else except /stop and /queue is rejected
+    if !text.starts_with("/stop") && !text.starts_with("/queue") {
+        let mut data = state.lock().await;
+        let busy = data.cancel_tokens.contains_key(&channel_id);
+        let queueable = !text.starts_with('/') && !text.starts_with('!');
+        // Messages arriving after a turn ended but before its queue ran join the queue to keep order
+        if queueable && (busy || data.queued_messages.contains_key(&channel_id)) {
+            let queue = data.queued_messages.entry(channel_id).or_default();
+            if queue.len() >= bot_common::MAX_QUEUED_MESSAGES {
+                drop(data);
+                messages::rate_limit_wait(state, channel_id).await;
+                channel_id.say(&ctx.http, "Queue is full. Wait for the current turn or use /queue clear.").await?;
+                return Ok(());
+            }
+            queue.push((text.clone(), msg.id, role));
+            let position = queue.len();
+            drop(data);
+            println!("  [{timestamp}] ◀ [{user_display}] Queued #{position}: {preview}");
+            if busy {
+                messages::rate_limit_wait(state, channel_id).await;
+                channel_id.say(&ctx.http, format!("⏳ Queued (#{}). It will be sent when the current turn finishes. /queue to view or clear.", position)).await?;
+            } else {
+                chat::run_queued_messages(ctx.clone(), channel_id, state.clone());
+            }
+            return Ok(());
+        }
+        if busy {
             drop(data);
             messages::rate_limit_wait(state, channel_id).await;
             channel_id.say(&ctx.http, "AI request in progress. Use /stop to cancel.").await?;
@@ -239,19 +506,64 @@ async fn handle_message(
     if text.starts_with("/stop") {
         println!("  [{timestamp}] ◀ [{user_display}] /stop");
         commands::handle_stop_command(ctx, channel_id, state).await?;
+    } else if text.starts_with("/queue") {
+        println!("  [{timestamp}] ◀ [{user_display}] /queue {}", text.strip_prefix("/queue").unwrap_or("").trim());
+        commands::handle_queue_command(ctx, channel_id, &text, state).await?;
     } else if text.starts_with("/help") {
         println!("  [{timestamp}] ◀ [{user_display}] /help");
         commands::handle_help_command(ctx, channel_id, state).await?;
+    } else if text.starts_with("/templates") {
+        println!("  [{timestamp}] ◀ [{user_display}] /templates");
+        commands::handle_templates_command(ctx, channel_id, state).await?;
     } else if text.starts_with("/start") {
         println!("  [{timestamp}] ◀ [{user_display}] /start");
         commands::handle_start_command(ctx, channel_id, &text, state).await?;
     } else if text.starts_with("/resume") {
         println!("  [{timestamp}] ◀ [{user_display}] /resume {}", text.strip_prefix("/resume").unwrap_or("").trim());
-        commands::handle_resume_command(ctx, channel_id, &text, state).await?;
+        commands::handle_resume_command(ctx, channel_id, &text, state, role == Role::Admin).await?;
+    } else if text.starts_with("/retry") {
+        println!("  [{timestamp}] ◀ [{user_display}] /retry");
+        commands::handle_retry_command(ctx, channel_id, msg.id, role, state).await?;
     } else if text.starts_with("/clear") {
         println!("  [{timestamp}] ◀ [{user_display}] /clear");
         commands::handle_clear_command(ctx, channel_id, state).await?;
         println!("  [{timestamp}] ▶ [{user_display}] Session cleared");
+    } else if text.starts_with("/export") {
+        println!("  [{timestamp}] ◀ [{user_display}] /export {}", text.strip_prefix("/export").unwrap_or("").trim());
+        commands::handle_export_command(ctx, channel_id, &text, state).await?;
+    } else if text.starts_with("/compact") {
+        println!("  [{timestamp}] ◀ [{user_display}] /compact");
+        commands::handle_compact_command(ctx, channel_id, state).await?;
+    } else if text.starts_with("/fork") {
+        println!("  [{timestamp}] ◀ [{user_display}] /fork {}", text.strip_prefix("/fork").unwrap_or("").trim());
+        commands::handle_fork_command(ctx, channel_id, &text, state).await?;
+    } else if text.starts_with("/search") {
+        println!("  [{timestamp}] ◀ [{user_display}] /search {}", text.strip_prefix("/search").unwrap_or("").trim());
+        commands::handle_search_command(ctx, channel_id, &text, state).await?;
+    } else if text.starts_with("/rename") {
+        println!("  [{timestamp}] ◀ [{user_display}] /rename {}", text.strip_prefix("/rename").unwrap_or("").trim());
+        commands::handle_rename_command(ctx, channel_id, &text, state).await?;
+    } else if text.starts_with("/tag") {
+        println!("  [{timestamp}] ◀ [{user_display}] /tag {}", text.strip_prefix("/tag").unwrap_or("").trim());
+        commands::handle_tag_command(ctx, channel_id, &text, state).await?;
+    } else if text.starts_with("/pin") {
+        println!("  [{timestamp}] ◀ [{user_display}] /pin");
+        commands::handle_pin_command(ctx, channel_id, state).await?;
+    } else if text.starts_with("/diff") {
+        println!("  [{timestamp}] ◀ [{user_display}] /diff");
+        commands::handle_diff_command(ctx, channel_id, state).await?;
+    } else if text.starts_with("/commit") {
+        println!("  [{timestamp}] ◀ [{user_display}] /commit {}", text.strip_prefix("/commit").unwrap_or("").trim());
+        commands::handle_commit_command(ctx, channel_id, &text, state).await?;
+    } else if text.starts_with("/branch") {
+        println!("  [{timestamp}] ◀ [{user_display}] /branch {}", text.strip_prefix("/branch").unwrap_or("").trim());
+        commands::handle_branch_command(ctx, channel_id, &text, state).await?;
+    } else if text.starts_with("/undo") {
+        println!("  [{timestamp}] ◀ [{user_display}] /undo");
+        commands::handle_undo_command(ctx, channel_id, state).await?;
+    } else if text.starts_with("/checkpoints") {
+        println!("  [{timestamp}] ◀ [{user_display}] /checkpoints {}", text.strip_prefix("/checkpoints").unwrap_or("").trim());
+        commands::handle_checkpoints_command(ctx, channel_id, &text, state).await?;
     } else if text.starts_with("/pwd") {
         println!("  [{timestamp}] ◀ [{user_display}] /pwd");
         commands::handle_pwd_command(ctx, channel_id, state).await?;
@@ -264,6 +576,18 @@ async fn handle_message(
     } else if text.starts_with("/availabletools") {
         println!("  [{timestamp}] ◀ [{user_display}] /availabletools");
         commands::handle_availabletools_command(ctx, channel_id, state).await?;
+    } else if text.starts_with("/settings") {
+        println!("  [{timestamp}] ◀ [{user_display}] /settings {}", text.strip_prefix("/settings").unwrap_or("").trim());
+        commands::handle_settings_command(ctx, channel_id, &text, state).await?;
+    } else if text.starts_with("/systemprompt") {
+        println!("  [{timestamp}] ◀ [{user_display}] /systemprompt {}", truncate_str(text.strip_prefix("/systemprompt").unwrap_or("").trim(), 60));
+        commands::handle_systemprompt_command(ctx, channel_id, &text, role, state).await?;
+    } else if text.starts_with("/reload") {
+        println!("  [{timestamp}] ◀ [{user_display}] /reload");
+        commands::handle_reload_command(ctx, channel_id, state).await?;
+    } else if text.starts_with("/users") {
+        println!("  [{timestamp}] ◀ [{user_display}] /users {}", text.strip_prefix("/users").unwrap_or("").trim());
+        commands::handle_users_command(ctx, channel_id, &text, state).await?;
     } else if text.starts_with("/allowedtools") {
         println!("  [{timestamp}] ◀ [{user_display}] /allowedtools");
         commands::handle_allowedtools_command(ctx, channel_id, state).await?;
@@ -276,7 +600,7 @@ async fn handle_message(
         println!("  [{timestamp}] ▶ [{user_display}] Shell done");
     } else {
         println!("  [{timestamp}] ◀ [{user_display}] {preview}");
-        chat::handle_text_message(ctx, channel_id, &text, msg.id, state).await?;
+        chat::handle_text_message(ctx, channel_id, &text, msg.id, role, state, None).await?;
     }
 
     Ok(())
@@ -0,0 +1,312 @@
+//! Session transcript export (Markdown, HTML, JSON).
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+
+use crate::services::formatter;
+use crate::services::retention;
+use crate::services::session::{HistoryItem, HistoryType, SessionData};
+
+/// Supported export formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    Json,
+}
+
+impl ExportFormat {
+    /// Parse a user-supplied format name. Empty input defaults to Markdown.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "md" | "markdown" => Some(Self::Markdown),
+            "html" | "htm" => Some(Self::Html),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    /// File extension (without dot)
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Html => "html",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// Get the exports directory path (~/.aemi/exports)
+pub fn exports_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".aemi").join("exports"))
+}
+
+/// Display label for a history item author. Assistant items are labeled with the agent
+/// that wrote them, falling back to `agent` for items saved without one.
+fn item_label(item: &HistoryItem, agent: Option<&str>) -> String {
+    match item.item_type {
+        HistoryType::User => "You".to_string(),
+        HistoryType::Assistant => match item.agent.as_deref().or(agent) {
+            Some(a) => format!("AI ({})", a),
+            None => "AI".to_string(),
+        },
+        HistoryType::Error => "Error".to_string(),
+        HistoryType::System => "System".to_string(),
+        HistoryType::ToolUse => "Tool".to_string(),
+        HistoryType::ToolResult => "Result".to_string(),
+    }
+}
+
+/// One-line metadata for a history item (time, model, duration, tokens), if recorded
+fn item_meta(item: &HistoryItem) -> Option<String> {
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(ts) = &item.timestamp {
+        parts.push(ts.clone());
+    }
+    if let Some(model) = &item.model {
+        parts.push(model.clone());
+    }
+    if let Some(ms) = item.duration_ms {
+        parts.push(format!("{:.1}s", ms as f64 / 1000.0));
+    }
+    if let Some(usage) = &item.usage {
+        parts.push(format!("{} in / {} out tokens", usage.input_tokens, usage.output_tokens));
+    }
+    if parts.is_empty() { None } else { Some(parts.join(" · ")) }
+}
+
+/// Render a single history item body as markdown.
+/// Tool uses are stored as `<name>\n<json input>` and tool results as raw output;
+/// both go through the shared formatter so exports match what the bots display.
+fn item_body_markdown(item: &HistoryItem) -> String {
+    match item.item_type {
+        HistoryType::ToolUse => {
+            let (name, input) = item.content.split_once('\n').unwrap_or((item.content.as_str(), ""));
+            format!("⚙️ {}", formatter::format_tool_input(name.trim(), input, false))
+        }
+        HistoryType::ToolResult => {
+            formatter::format_tool_result(&item.content, false, "", None).trim().to_string()
+        }
+        HistoryType::Error => formatter::format_tool_result(&item.content, true, "", None).trim().to_string(),
+        _ => item.content.clone(),
+    }
+}
+
+/// Render a session transcript as Markdown
+pub fn render_markdown(data: &SessionData, agent: Option<&str>) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("# Session {}\n\n", data.session_id));
+    out.push_str(&format!("- **Directory:** `{}`\n", data.current_path));
+    out.push_str(&format!("- **Created:** {}\n", data.created_at));
+    if !data.updated_at.is_empty() {
+        out.push_str(&format!("- **Updated:** {}\n", data.updated_at));
+    }
+    if let Some(a) = agent {
+        out.push_str(&format!("- **Agent:** {}\n", a));
+    }
+    out.push_str(&format!("- **Items:** {}\n", data.history.len()));
+
+    for item in &data.history {
+        out.push_str(&format!("\n---\n\n### {}\n\n", item_label(item, agent)));
+        if let Some(meta) = item_meta(item) {
+            out.push_str(&format!("*{}*\n\n", meta));
+        }
+        out.push_str(&item_body_markdown(item));
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Escape special HTML characters
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render a session transcript as a standalone HTML page
+pub fn render_html(data: &SessionData, agent: Option<&str>) -> String {
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!("<title>Session {}</title>\n", html_escape(&data.session_id)));
+    out.push_str(
+        "<style>\n\
+         body { font-family: sans-serif; max-width: 960px; margin: 2em auto; padding: 0 1em; }\n\
+         .item { border-left: 4px solid #ccc; margin: 1em 0; padding: 0.5em 1em; }\n\
+         .user { border-color: #2b7de9; }\n\
+         .assistant { border-color: #2ea44f; }\n\
+         .error { border-color: #d73a49; }\n\
+         .tool, .result { border-color: #999; background: #f6f8fa; }\n\
+         .label { font-weight: bold; margin-bottom: 0.3em; }\n\
+         .meta { color: #777; font-size: 0.85em; margin-bottom: 0.3em; }\n\
+         pre { white-space: pre-wrap; word-wrap: break-word; margin: 0; }\n\
+         </style>\n",
+    );
+    out.push_str("</head>\n<body>\n");
+    out.push_str(&format!("<h1>Session {}</h1>\n<ul>\n", html_escape(&data.session_id)));
+    out.push_str(&format!("<li><b>Directory:</b> <code>{}</code></li>\n", html_escape(&data.current_path)));
+    out.push_str(&format!("<li><b>Created:</b> {}</li>\n", html_escape(&data.created_at)));
+    if !data.updated_at.is_empty() {
+        out.push_str(&format!("<li><b>Updated:</b> {}</li>\n", html_escape(&data.updated_at)));
+    }
+    if let Some(a) = agent {
+        out.push_str(&format!("<li><b>Agent:</b> {}</li>\n", html_escape(a)));
+    }
+    out.push_str(&format!("<li><b>Items:</b> {}</li>\n</ul>\n", data.history.len()));
+
+    for item in &data.history {
+        let class = match item.item_type {
+            HistoryType::User => "user",
+            HistoryType::Assistant => "assistant",
+            HistoryType::Error => "error",
+            HistoryType::System => "system",
+            HistoryType::ToolUse => "tool",
+            HistoryType::ToolResult => "result",
+        };
+        let meta = item_meta(item)
+            .map(|m| format!("<div class=\"meta\">{}</div>\n", html_escape(&m)))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "<div class=\"item {}\">\n<div class=\"label\">{}</div>\n{}<pre>{}</pre>\n</div>\n",
+            class,
+            html_escape(&item_label(item, agent)),
+            meta,
+            html_escape(&item_body_markdown(item)),
+        ));
+    }
+
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Render a session transcript as pretty-printed JSON
+pub fn render_json(data: &SessionData) -> String {
+    serde_json::to_string_pretty(data).unwrap_or_else(|_| "{}".to_string())
+}
+
+/// Render a session in the requested format
+pub fn render_session(data: &SessionData, format: ExportFormat, agent: Option<&str>) -> String {
+    match format {
+        ExportFormat::Markdown => render_markdown(data, agent),
+        ExportFormat::Html => render_html(data, agent),
+        ExportFormat::Json => render_json(data),
+    }
+}
+
+/// The session with history trimmed into its archive put back ahead of the live history
+pub fn with_archived_history(data: &SessionData) -> SessionData {
+    let archived = retention::load_archived_history(&data.session_id);
+    SessionData {
+        history: archived.into_iter().chain(data.history.iter().cloned()).collect(),
+        ..data.clone()
+    }
+}
+
+/// Render a session and write it to ~/.aemi/exports/<session_id>.<ext>, readable only by
+/// the owner. History trimmed into the session archive is included (see [`with_archived_history`]).
+/// Returns the written file path; the bots delete it once it is sent.
+pub fn export_session_to_file(
+    data: &SessionData,
+    format: ExportFormat,
+    agent: Option<&str>,
+) -> Result<PathBuf, String> {
+    let dir = exports_dir().ok_or_else(|| "cannot determine home directory".to_string())?;
+    fs::create_dir_all(&dir).map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+
+    let file_path = dir.join(format!("{}.{}", data.session_id, format.extension()));
+
+    // Security: Verify the path is within exports directory
+    if file_path.parent() != Some(dir.as_path()) {
+        return Err("invalid session id".to_string());
+    }
+
+    let rendered = render_session(&with_archived_history(data), format, agent);
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&file_path)
+        .and_then(|mut file| {
+            // An export left over from an earlier version may be world-readable
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            file.write_all(rendered.as_bytes())
+        })
+        .map_err(|e| format!("failed to write {}: {}", file_path.display(), e))?;
+    Ok(file_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_session() -> SessionData {
+        SessionData {
+            session_id: "abc-123".to_string(),
+            history: vec![
+                HistoryItem::new(HistoryType::User, "list files <now>".to_string()),
+                HistoryItem::new(HistoryType::ToolUse, "Bash\n{\"command\":\"ls\"}".to_string()),
+                HistoryItem::new(HistoryType::ToolResult, "a.txt\nb.txt".to_string()),
+                HistoryItem::new(HistoryType::Assistant, "Two files.".to_string()),
+            ],
+            current_path: "/tmp/project".to_string(),
+            created_at: "2024-01-01 12:00:00".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_export_format_parse() {
+        assert_eq!(ExportFormat::parse(""), Some(ExportFormat::Markdown));
+        assert_eq!(ExportFormat::parse("MD"), Some(ExportFormat::Markdown));
+        assert_eq!(ExportFormat::parse("html"), Some(ExportFormat::Html));
+        assert_eq!(ExportFormat::parse("json"), Some(ExportFormat::Json));
+        assert_eq!(ExportFormat::parse("pdf"), None);
+    }
+
+    #[test]
+    fn test_render_markdown_contains_items() {
+        let md = render_markdown(&sample_session(), Some("claude"));
+        assert!(md.starts_with("# Session abc-123"));
+        assert!(md.contains("`/tmp/project`"));
+        assert!(md.contains("### You"));
+        assert!(md.contains("### AI (claude)"));
+        assert!(md.contains("Two files."));
+        // Tool use goes through the shared formatter
+        assert!(md.contains("⚙️"));
+        assert!(md.contains("ls"));
+    }
+
+    #[test]
+    fn test_label_uses_item_agent() {
+        let mut data = sample_session();
+        let mut item = HistoryItem::new(HistoryType::Assistant, "Done.".to_string());
+        item.agent = Some("codex".to_string());
+        data.history.push(item);
+        let md = render_markdown(&data, Some("claude"));
+        // Items saved without an agent fall back to the session's
+        assert!(md.contains("### AI (claude)"));
+        assert!(md.contains("### AI (codex)"));
+    }
+
+    #[test]
+    fn test_render_html_escapes_content() {
+        let html = render_html(&sample_session(), None);
+        assert!(html.contains("list files &lt;now&gt;"));
+        assert!(!html.contains("<now>"));
+        assert!(html.contains("class=\"item user\""));
+    }
+
+    #[test]
+    fn test_render_json_roundtrip() {
+        let json = render_json(&sample_session());
+        let parsed: SessionData = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.session_id, "abc-123");
+        assert_eq!(parsed.history.len(), 4);
+    }
+}
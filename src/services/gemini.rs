@@ -130,6 +130,7 @@ fn parse_gemini_json_output(output: &str) -> AgentResponse {
 }
 
 /// Execute a command using Gemini CLI with streaming output
+#[allow(clippy::too_many_arguments)]
 pub fn execute_command_streaming(
     prompt: &str,
     _session_id: Option<&str>, // Gemini non-interactive is single-turn, session_id ignored
@@ -137,6 +138,7 @@ pub fn execute_command_streaming(
     sender: Sender<StreamMessage>,
     system_prompt: Option<&str>,
     _allowed_tools: Option<&[String]>, // Gemini uses --yolo instead of tool allowlist
+    model: Option<&str>,
     cancel_token: Option<std::sync::Arc<CancelToken>>,
 ) -> Result<(), String> {
     debug_log(&format!("prompt_len: {} chars", prompt.len()));
@@ -144,13 +146,14 @@ pub fn execute_command_streaming(
     let effective_prompt = provider_common::build_effective_prompt(system_prompt, prompt);
 
     // Gemini CLI: -p/--prompt takes the prompt text as its argument value (not stdin)
-    let args = vec![
+    let mut args = vec![
         "-p".to_string(),
         effective_prompt,
         "--output-format".to_string(),
         "stream-json".to_string(),
         "--yolo".to_string(),
     ];
+    provider_common::push_model_arg(&mut args, model);
 
     let binary_path = get_binary_path()
         .ok_or_else(|| {
@@ -0,0 +1,367 @@
+//! Git helpers for session working directories.
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use crate::services::claude;
+use crate::services::export;
+use crate::services::formatter;
+
+/// Diffs longer than this are attached as a .patch file instead of shown inline
+/// (matches the formatter's tool-result limit, so inline diffs are never truncated)
+pub const DIFF_INLINE_LIMIT: usize = 1500;
+
+/// Max diff characters included in the commit message prompt
+const COMMIT_PROMPT_DIFF_LIMIT: usize = 12000;
+
+/// Prefix for per-session branches
+const BRANCH_PREFIX: &str = "aemi/";
+
+/// Run a git command in `dir` and return trimmed stdout, or stderr on failure.
+/// Like every helper here it blocks; call it from `spawn_blocking`.
+pub fn run_git(dir: &str, args: &[&str]) -> Result<String, String> {
+    run_git_with_env(dir, args, &[])
+}
+
+/// Like `run_git`, with extra environment variables (e.g. `GIT_INDEX_FILE`)
+pub fn run_git_with_env(dir: &str, args: &[&str], env: &[(&str, &str)]) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .envs(env.iter().copied())
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run git: {}", e))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Err(if stderr.is_empty() { format!("git {} failed", args.join(" ")) } else { stderr })
+    }
+}
+
+/// Whether `dir` is inside a git work tree
+pub fn is_repo(dir: &str) -> bool {
+    run_git(dir, &["rev-parse", "--is-inside-work-tree"]).map(|s| s == "true").unwrap_or(false)
+}
+
+/// Whether `dir` is the top level of a git work tree
+pub fn is_repo_root(dir: &str) -> bool {
+    let Ok(top) = run_git(dir, &["rev-parse", "--show-toplevel"]) else {
+        return false;
+    };
+    match (std::fs::canonicalize(top), std::fs::canonicalize(dir)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Whether the repository has at least one commit
+pub fn has_head(dir: &str) -> bool {
+    run_git(dir, &["rev-parse", "--verify", "--quiet", "HEAD"]).is_ok()
+}
+
+/// Current branch name (None when detached)
+pub fn current_branch(dir: &str) -> Option<String> {
+    run_git(dir, &["symbolic-ref", "--short", "-q", "HEAD"]).ok().filter(|b| !b.is_empty())
+}
+
+/// Uncommitted changes in a work tree
+#[derive(Debug, Default)]
+pub struct WorkingTreeDiff {
+    /// `git diff --stat` summary
+    pub stat: String,
+    /// Unified diff of tracked files (staged and unstaged)
+    pub patch: String,
+    /// Untracked files, not included in `patch`
+    pub untracked: Vec<String>,
+}
+
+impl WorkingTreeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.patch.trim().is_empty() && self.untracked.is_empty()
+    }
+}
+
+/// Collect the working-tree diff against HEAD (or against the empty tree before the first commit)
+pub fn working_tree_diff(dir: &str) -> Result<WorkingTreeDiff, String> {
+    if !is_repo(dir) {
+        return Err(format!("{} is not a git repository", dir));
+    }
+    let (patch, stat) = if has_head(dir) {
+        (
+            run_git(dir, &["diff", "--no-color", "HEAD"])?,
+            run_git(dir, &["diff", "--no-color", "--stat", "HEAD"])?,
+        )
+    } else {
+        let staged = run_git(dir, &["diff", "--no-color", "--cached"])?;
+        let unstaged = run_git(dir, &["diff", "--no-color"])?;
+        let staged_stat = run_git(dir, &["diff", "--no-color", "--stat", "--cached"])?;
+        let unstaged_stat = run_git(dir, &["diff", "--no-color", "--stat"])?;
+        (
+            [staged, unstaged].into_iter().filter(|s| !s.is_empty()).collect::<Vec<_>>().join("\n"),
+            [staged_stat, unstaged_stat].into_iter().filter(|s| !s.is_empty()).collect::<Vec<_>>().join("\n"),
+        )
+    };
+    let untracked = run_git(dir, &["ls-files", "--others", "--exclude-standard"])?
+        .lines()
+        .map(String::from)
+        .collect();
+    Ok(WorkingTreeDiff { stat, patch, untracked })
+}
+
+/// Whether the diff is short enough to show inline
+pub fn fits_inline(diff: &WorkingTreeDiff) -> bool {
+    diff.patch.len() <= DIFF_INLINE_LIMIT
+}
+
+/// Render a diff as markdown through the shared formatter (diff code block plus untracked list)
+pub fn render_diff_markdown(diff: &WorkingTreeDiff) -> String {
+    let mut out = String::new();
+    if !diff.patch.trim().is_empty() {
+        out.push_str(formatter::format_tool_result(&diff.patch, false, "", None).trim());
+        out.push('\n');
+    }
+    if !diff.untracked.is_empty() {
+        out.push_str(&format!("\nUntracked: {}\n", diff.untracked.iter()
+            .map(|f| format!("`{}`", f))
+            .collect::<Vec<_>>()
+            .join(", ")));
+    }
+    out.trim().to_string()
+}
+
+/// Write the patch to ~/.aemi/exports/<dir name>-<timestamp>[-<n>].patch, readable only by
+/// the owner. A new file is always created, so two diffs in the same second do not overwrite
+/// each other. The bots delete it once it is sent.
+pub fn write_patch_file(dir: &str, diff: &WorkingTreeDiff) -> Result<PathBuf, String> {
+    let exports = export::exports_dir().ok_or_else(|| "cannot determine home directory".to_string())?;
+    write_patch_in(&exports, dir, diff)
+}
+
+fn write_patch_in(exports: &std::path::Path, dir: &str, diff: &WorkingTreeDiff) -> Result<PathBuf, String> {
+    fs::create_dir_all(exports).map_err(|e| e.to_string())?;
+    let base = std::path::Path::new(dir)
+        .file_name()
+        .map(|n| sanitize_branch_component(&n.to_string_lossy()))
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "workdir".to_string());
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let mut content = diff.patch.clone();
+    content.push('\n');
+    let mut n = 1;
+    loop {
+        let name = if n == 1 {
+            format!("{}-{}.patch", base, stamp)
+        } else {
+            format!("{}-{}-{}.patch", base, stamp, n)
+        };
+        let path = exports.join(name);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path);
+        match file {
+            Ok(mut file) => {
+                file.write_all(content.as_bytes()).map_err(|e| e.to_string())?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Stage everything and commit. Returns "<short hash> <subject>".
+pub fn commit_all(dir: &str, message: &str) -> Result<String, String> {
+    run_git(dir, &["add", "-A"])?;
+    run_git(dir, &["commit", "-q", "-m", message])?;
+    run_git(dir, &["log", "-1", "--format=%h %s"])
+}
+
+/// Prompt asking an agent for a commit message describing `diff`
+pub fn commit_message_prompt(diff: &WorkingTreeDiff) -> String {
+    let mut patch = diff.patch.clone();
+    if patch.len() > COMMIT_PROMPT_DIFF_LIMIT {
+        let end = patch.char_indices()
+            .map(|(i, _)| i)
+            .take_while(|i| *i <= COMMIT_PROMPT_DIFF_LIMIT)
+            .last()
+            .unwrap_or(0);
+        patch.truncate(end);
+        patch.push_str("\n... (truncated)");
+    }
+    let mut prompt = String::from(
+        "Write a git commit message for the changes below. \
+         Reply with the commit message only: a short imperative subject line (max 72 chars), \
+         optionally followed by a blank line and a brief body. No code fences, no commentary.\n\n",
+    );
+    if !diff.stat.is_empty() {
+        prompt.push_str(&format!("Summary:\n{}\n\n", diff.stat));
+    }
+    if !diff.untracked.is_empty() {
+        prompt.push_str(&format!("New files:\n{}\n\n", diff.untracked.join("\n")));
+    }
+    prompt.push_str(&format!("Diff:\n{}\n", patch));
+    prompt
+}
+
+/// Strip code fences, quotes and surrounding blank lines from an agent reply
+pub fn clean_commit_message(raw: &str) -> String {
+    let lines: Vec<&str> = raw.lines()
+        .filter(|l| !l.trim_start().starts_with("```"))
+        .collect();
+    let text = lines.join("\n");
+    let text = text.trim().trim_matches('"').trim();
+    text.to_string()
+}
+
+/// Ask Claude, with its tools disallowed, for a commit message (one-shot, no session). The other
+/// agents would run in the repository with their tools auto-approved.
+pub fn generate_commit_message(dir: &str, diff: &WorkingTreeDiff) -> Result<String, String> {
+    let response = claude::execute_without_tools(&commit_message_prompt(diff), dir, None);
+    if !response.success {
+        return Err(response.error.unwrap_or_else(|| "agent failed".to_string()));
+    }
+    let message = clean_commit_message(&response.response.unwrap_or_default());
+    if message.is_empty() {
+        Err("agent returned an empty commit message".to_string())
+    } else {
+        Ok(message)
+    }
+}
+
+/// Reduce text to characters safe in a branch name component
+pub fn sanitize_branch_component(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        let c = if c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-' { c.to_ascii_lowercase() } else { '-' };
+        if c == '-' && out.ends_with('-') {
+            continue;
+        }
+        out.push(c);
+    }
+    out.trim_matches(|c| c == '-' || c == '.').replace("..", ".")
+}
+
+/// Branch name for a session: `aemi/<name>` if it has a usable name, else `aemi/<first 8 chars of id>`
+pub fn session_branch_name(session_id: &str, name: Option<&str>) -> String {
+    let component = name
+        .map(sanitize_branch_component)
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| session_id.chars().take(8).collect());
+    format!("{}{}", BRANCH_PREFIX, component)
+}
+
+/// Switch to `branch`, creating it from the current HEAD if needed. Returns true if it was created.
+pub fn switch_branch(dir: &str, branch: &str) -> Result<bool, String> {
+    if !is_repo(dir) {
+        return Err(format!("{} is not a git repository", dir));
+    }
+    run_git(dir, &["check-ref-format", "--branch", branch])
+        .map_err(|_| format!("invalid branch name: {}", branch))?;
+    if current_branch(dir).as_deref() == Some(branch) {
+        return Ok(false);
+    }
+    let exists = run_git(dir, &["rev-parse", "--verify", "--quiet", &format!("refs/heads/{}", branch)]).is_ok();
+    if exists {
+        run_git(dir, &["switch", "-q", branch])?;
+    } else {
+        run_git(dir, &["switch", "-q", "-c", branch])?;
+    }
+    Ok(!exists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_repo() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        run_git(path, &["init", "-q"]).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_sanitize_branch_component() {
+        assert_eq!(sanitize_branch_component("Fix Parser Bug!"), "fix-parser-bug");
+        assert_eq!(sanitize_branch_component("  --a..b--  "), "a.b");
+        assert_eq!(sanitize_branch_component("한글"), "");
+    }
+
+    #[test]
+    fn test_session_branch_name() {
+        assert_eq!(session_branch_name("0123456789abcdef", None), "aemi/01234567");
+        assert_eq!(session_branch_name("0123456789abcdef", Some("Login flow")), "aemi/login-flow");
+        assert_eq!(session_branch_name("0123456789abcdef", Some("!!!")), "aemi/01234567");
+    }
+
+    #[test]
+    fn test_clean_commit_message() {
+        assert_eq!(clean_commit_message("```\nFix parser\n```\n"), "Fix parser");
+        assert_eq!(clean_commit_message("\"Add tests\""), "Add tests");
+        assert_eq!(clean_commit_message("Subject\n\nBody line"), "Subject\n\nBody line");
+    }
+
+    #[test]
+    fn test_render_diff_markdown() {
+        let diff = WorkingTreeDiff {
+            stat: String::new(),
+            patch: "diff --git a/x b/x\n--- a/x\n+++ b/x\n@@ -1 +1 @@\n-old\n+new".to_string(),
+            untracked: vec!["new.rs".to_string()],
+        };
+        let md = render_diff_markdown(&diff);
+        assert!(md.starts_with("```diff\n"));
+        assert!(md.contains("+new"));
+        assert!(md.ends_with("Untracked: `new.rs`"));
+    }
+
+    #[test]
+    fn test_commit_prompt_truncates_long_diff() {
+        let diff = WorkingTreeDiff {
+            patch: "x".repeat(COMMIT_PROMPT_DIFF_LIMIT * 2),
+            ..Default::default()
+        };
+        let prompt = commit_message_prompt(&diff);
+        assert!(prompt.len() < COMMIT_PROMPT_DIFF_LIMIT + 1000);
+        assert!(prompt.ends_with("(truncated)\n"));
+    }
+
+    #[test]
+    fn test_patch_files_are_private_and_unique() {
+        use std::os::unix::fs::PermissionsExt;
+        let exports = tempfile::tempdir().unwrap();
+        let diff = WorkingTreeDiff { patch: "+new".to_string(), ..Default::default() };
+        let first = write_patch_in(exports.path(), "/tmp/My Repo", &diff).unwrap();
+        let second = write_patch_in(exports.path(), "/tmp/My Repo", &diff).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs::read_to_string(&first).unwrap(), "+new\n");
+        assert_eq!(fs::metadata(&second).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_working_tree_diff_and_branch() {
+        let dir = init_repo();
+        let path = dir.path().to_str().unwrap();
+        assert!(working_tree_diff(path).unwrap().is_empty());
+
+        fs::write(dir.path().join("a.txt"), "hello\n").unwrap();
+        let diff = working_tree_diff(path).unwrap();
+        assert_eq!(diff.untracked, vec!["a.txt".to_string()]);
+
+        assert!(switch_branch(path, "aemi/test").unwrap());
+        assert_eq!(current_branch(path).as_deref(), Some("aemi/test"));
+        assert!(switch_branch(path, "bad..name").is_err());
+    }
+
+    #[test]
+    fn test_not_a_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(working_tree_diff(dir.path().to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Local control socket of a running bot (`~/.aemi/run/<bot hash>.sock`).
+
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+
+/// How long `aemi send` waits for the bot to answer (uploads can be slow)
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Request sent by `aemi send`, so the CLI never needs the bot token.
+/// One JSON request line is answered with one JSON [`Response`] line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    SendFile { chat: i64, path: PathBuf },
+    SendText { chat: i64, text: String },
+}
+
+/// Reply from the bot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Get the run directory path (~/.aemi/run)
+pub fn run_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".aemi").join("run"))
+}
+
+/// Socket path for a bot hash key. None if the key is not a plain hash.
+pub fn socket_path(hash_key: &str) -> Option<PathBuf> {
+    let valid = !hash_key.is_empty()
+        && hash_key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return None;
+    }
+    run_dir().map(|d| d.join(format!("{}.sock", hash_key)))
+}
+
+/// Bind the bot's socket, replacing a stale one left by a crashed server.
+/// Fails if another live server already owns it. The run directory is 0700 and the
+/// socket 0600: only the user running the server can connect.
+pub fn bind(hash_key: &str) -> std::io::Result<(UnixListener, PathBuf)> {
+    let path = socket_path(hash_key)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid bot key"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{} is in use by another aemi server", path.display()),
+            ));
+        }
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    Ok((listener, path))
+}
+
+/// Answer requests on `listener` with `handler` until the task is dropped
+pub async fn serve<F, Fut>(listener: UnixListener, handler: F)
+where
+    F: Fn(Request) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), String>> + Send,
+{
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let (read, mut write) = stream.into_split();
+            let mut line = String::new();
+            if tokio::io::BufReader::new(read).read_line(&mut line).await.is_err() {
+                return;
+            }
+            let result = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => handler(request).await,
+                Err(e) => Err(format!("bad request: {}", e)),
+            };
+            let response = Response { ok: result.is_ok(), error: result.err() };
+            if let Ok(mut reply) = serde_json::to_string(&response) {
+                reply.push('\n');
+                let _ = write.write_all(reply.as_bytes()).await;
+            }
+        });
+    }
+}
+
+/// Send a request to the running bot for `hash_key` and wait for its answer
+pub fn request(hash_key: &str, request: &Request) -> Result<(), String> {
+    let path = socket_path(hash_key).ok_or_else(|| format!("invalid bot key: {}", hash_key))?;
+    request_at(&path, request)
+        .map_err(|e| format!("bot {}: {}", hash_key, e))
+}
+
+fn request_at(path: &Path, request: &Request) -> Result<(), String> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)
+        .map_err(|e| format!("no running bot ({}: {})", path.display(), e))?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).map_err(|e| e.to_string())?;
+    let mut line = serde_json::to_string(request).map_err(|e| e.to_string())?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)
+        .map_err(|e| format!("no answer from the bot: {}", e))?;
+    let response: Response = serde_json::from_str(&reply)
+        .map_err(|e| format!("bad answer from the bot: {}", e))?;
+    match response {
+        Response { ok: true, .. } => Ok(()),
+        Response { error, .. } => Err(error.unwrap_or_else(|| "request failed".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socket_path_rejects_traversal() {
+        assert!(socket_path("0123abcd").is_some_and(|p| p.ends_with("run/0123abcd.sock")));
+        assert!(socket_path("dc_0123abcd").is_some());
+        assert!(socket_path("../x").is_none());
+        assert!(socket_path("").is_none());
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        let request = Request::SendFile { chat: -100, path: PathBuf::from("/tmp/a.txt") };
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(json, r#"{"op":"send_file","chat":-100,"path":"/tmp/a.txt"}"#);
+        assert_eq!(serde_json::from_str::<Request>(&json).unwrap(), request);
+    }
+
+    #[test]
+    fn test_serve_and_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bot.sock");
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let listener = rt.block_on(async { UnixListener::bind(&path) }).unwrap();
+        rt.spawn(serve(listener, |request| async move {
+            match request {
+                Request::SendText { text, .. } if text == "hi" => Ok(()),
+                _ => Err("rejected".to_string()),
+            }
+        }));
+
+        assert_eq!(request_at(&path, &Request::SendText { chat: 1, text: "hi".to_string() }), Ok(()));
+        assert_eq!(
+            request_at(&path, &Request::SendText { chat: 1, text: "no".to_string() }),
+            Err("rejected".to_string())
+        );
+        assert!(request_at(&dir.path().join("missing.sock"), &Request::SendText { chat: 1, text: String::new() })
+            .unwrap_err().contains("no running bot"));
+    }
+}
@@ -12,3 +12,22 @@ pub mod telegram;
 pub mod discord;
 pub mod session;
 pub mod formatter;
+pub mod export;
+pub mod search;
+pub mod retention;
+pub mod crypto;
+pub mod git;
+pub mod checkpoint;
+pub mod template;
+pub mod access;
+pub mod compact;
+pub mod config;
+pub mod credentials;
+pub mod ipc;
+pub mod settings_file;
+pub mod roles;
+pub mod chat_settings;
+pub mod chat_state;
+pub mod reload;
+pub mod shutdown;
+pub mod system_prompt;
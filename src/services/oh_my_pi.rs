@@ -317,6 +317,7 @@ fn handle_omp_json(
 }
 
 /// Execute a command using oh-my-pi CLI with streaming output
+#[allow(clippy::too_many_arguments)]
 pub fn execute_command_streaming(
     prompt: &str,
     session_id: Option<&str>,
@@ -324,24 +325,56 @@ pub fn execute_command_streaming(
     sender: Sender<StreamMessage>,
     system_prompt: Option<&str>,
     _allowed_tools: Option<&[String]>, // oh-my-pi manages tools internally
+    model: Option<&str>,
+    cancel_token: Option<std::sync::Arc<CancelToken>>,
+) -> Result<(), String> {
+    execute_streaming(prompt, session_id, false, working_dir, sender, system_prompt, model, cancel_token)
+}
+
+/// Like [`execute_command_streaming`], but passes `--fork <session_id>` so oh-my-pi
+/// branches the conversation into a new session instead of resuming it.
+#[allow(clippy::too_many_arguments)]
+pub fn execute_command_streaming_fork(
+    prompt: &str,
+    session_id: Option<&str>,
+    working_dir: &str,
+    sender: Sender<StreamMessage>,
+    system_prompt: Option<&str>,
+    _allowed_tools: Option<&[String]>,
+    model: Option<&str>,
+    cancel_token: Option<std::sync::Arc<CancelToken>>,
+) -> Result<(), String> {
+    execute_streaming(prompt, session_id, true, working_dir, sender, system_prompt, model, cancel_token)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_streaming(
+    prompt: &str,
+    session_id: Option<&str>,
+    fork_session: bool,
+    working_dir: &str,
+    sender: Sender<StreamMessage>,
+    system_prompt: Option<&str>,
+    model: Option<&str>,
     cancel_token: Option<std::sync::Arc<CancelToken>>,
 ) -> Result<(), String> {
     debug_log(&format!("prompt_len: {} chars", prompt.len()));
-    debug_log(&format!("session_id: {:?}", session_id));
+    debug_log(&format!("session_id: {:?} fork: {}", session_id, fork_session));
 
     let effective_prompt = provider_common::build_effective_prompt(system_prompt, prompt);
 
-    // Build args: omp --print --mode json [--resume <session_id>] "prompt"
+    // Build args: omp --print --mode json [--resume|--fork <session_id>] "prompt"
     let mut args = vec![
         "--print".to_string(),
         "--mode".to_string(),
         "json".to_string(),
     ];
+    provider_common::push_model_arg(&mut args, model);
 
-    // Session resume support
+    // Session resume/fork support
     if let Some(sid) = session_id {
-        debug_log(&format!("Resuming session: {}", sid));
-        args.push("--resume".to_string());
+        debug_log(&format!("{} session: {}", if fork_session { "Forking" } else { "Resuming" }, sid));
+        args.push(if fork_session { "--fork" } else { "--resume" }.to_string());
         args.push(sid.to_string());
     }
 
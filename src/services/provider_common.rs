@@ -10,20 +10,21 @@ use std::sync::mpsc::Sender;
 use std::sync::{Arc, OnceLock};
 use serde_json::Value;
 
-use super::agent::{StreamMessage, CancelToken};
+use super::agent::{StreamMessage, CancelToken, kill_process_group};
 
 // ---------------------------------------------------------------------------
-// Default system prompt (shared across all providers)
+// Default system prompt (shared across all providers, for requests without one;
+// bot turns build theirs in system_prompt.rs)
 // ---------------------------------------------------------------------------
 
-pub const DEFAULT_SYSTEM_PROMPT: &str = r#"You are a terminal file manager assistant. Be concise. Focus on file operations. Respond in the same language as the user.
+pub const DEFAULT_SYSTEM_PROMPT: &str = r#"You are an AI assistant working in the current directory from a terminal. Be concise. Respond in the same language as the user.
 
 SECURITY RULES (MUST FOLLOW):
 - NEVER execute destructive commands like rm -rf, format, mkfs, dd, etc.
 - NEVER modify system files in /etc, /sys, /proc, /boot
 - NEVER access or modify files outside the current working directory without explicit user path
 - NEVER execute commands that could harm the system or compromise security
-- ONLY suggest safe file operations: copy, move, rename, create directory, view, edit
+- Prefer safe, reversible changes, and say what you changed
 - If a request seems dangerous, explain the risk and suggest a safer alternative
 
 BASH EXECUTION RULES (MUST FOLLOW):
@@ -58,6 +59,14 @@ pub fn build_effective_prompt(system_prompt: Option<&str>, prompt: &str) -> Stri
     }
 }
 
+/// Append `--model <model>` when a model was chosen (every supported CLI uses this flag)
+pub fn push_model_arg(args: &mut Vec<String>, model: Option<&str>) {
+    if let Some(model) = model.filter(|m| !m.is_empty()) {
+        args.push("--model".to_string());
+        args.push(model.to_string());
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Debug logging (provider-name aware, for use in shared code)
 // ---------------------------------------------------------------------------
@@ -196,6 +205,15 @@ where
     }
     if config.stdin_data.is_some() {
         cmd.stdin(Stdio::piped());
+    } else {
+        cmd.stdin(Stdio::null());
+    }
+    // Own process group: a cancel or shutdown stops the CLI together with the tools it
+    // started, and Ctrl-C in the terminal reaches aemi only, which drains first
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
     }
 
     let mut child = cmd.spawn().map_err(|e| {
@@ -321,7 +339,8 @@ fn check_cancelled(
 ) -> bool {
     if let Some(ref token) = cancel_token {
         if token.cancelled.load(std::sync::atomic::Ordering::Relaxed) {
-            debug_log_for(provider_name, "Cancel detected — killing child process");
+            debug_log_for(provider_name, "Cancel detected — killing child process group");
+            kill_process_group(child.id());
             let _ = child.kill();
             let _ = child.wait();
             return true;
@@ -0,0 +1,135 @@
+//! Hot reload of a running bot (SIGHUP or `/reload`).
+
+use std::path::PathBuf;
+
+use crate::services::access;
+use crate::services::bot_common::{BotOptions, BotSettings};
+use crate::services::roles::Role;
+
+/// Everything a reload can change for one bot: its `[[bot]]` table (matched by platform and
+/// token) and its entry of `bot_settings.json`. Sessions, running turns and the connection
+/// are kept; a new token or platform needs a restart.
+#[derive(Clone)]
+pub struct Snapshot {
+    /// Allowed chat ids (Telegram) or channel ids (Discord)
+    pub chats: Vec<i64>,
+    /// Bot-wide agent
+    pub agent: String,
+    pub chat_role: Role,
+    pub allowed_roots: Option<Vec<PathBuf>>,
+    pub settings: BotSettings,
+}
+
+/// Combine startup options with the saved settings, the same way at startup and on reload
+pub fn snapshot(chats: Vec<i64>, agent: &str, options: BotOptions, mut settings: BotSettings) -> Snapshot {
+    if let Some(tools) = options.allowed_tools {
+        settings.allowed_tools = tools;
+    }
+    settings.users.extend(options.users);
+    Snapshot {
+        chats,
+        agent: agent.to_string(),
+        chat_role: options.chat_role.unwrap_or(Role::User),
+        allowed_roots: options.workspace_roots.or_else(|| access::allowed_roots().map(|r| r.to_vec())),
+        settings,
+    }
+}
+
+fn change<T: PartialEq>(changes: &mut Vec<String>, what: &str, old: &T, new: &T, show: impl Fn(&T) -> String) {
+    if old != new {
+        changes.push(format!("{}: {} → {}", what, show(old), show(new)));
+    }
+}
+
+fn list<T: ToString>(items: &[T]) -> String {
+    if items.is_empty() {
+        "(none)".to_string()
+    } else {
+        items.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+fn optional<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or_else(|| "(none)".to_string())
+}
+
+/// Human-readable list of what a reload changes
+pub fn changes(old: &Snapshot, new: &Snapshot) -> Vec<String> {
+    let mut changes = Vec::new();
+    change(&mut changes, "chats", &old.chats, &new.chats, |c| list(c));
+    change(&mut changes, "agent", &old.agent, &new.agent, |a| a.clone());
+    change(&mut changes, "chat role", &old.chat_role, &new.chat_role, |r| r.to_string());
+    change(&mut changes, "workspace roots", &old.allowed_roots, &new.allowed_roots, |roots| match roots {
+        Some(roots) => list(&roots.iter().map(|r| r.display()).collect::<Vec<_>>()),
+        None => "(unrestricted)".to_string(),
+    });
+    let (old_settings, new_settings) = (&old.settings, &new.settings);
+    change(&mut changes, "allowed tools", &old_settings.allowed_tools, &new_settings.allowed_tools, |t| list(t));
+    change(&mut changes, "owner", &old_settings.owner_user_id, &new_settings.owner_user_id, optional);
+    change(&mut changes, "users", &old_settings.users, &new_settings.users, |users| {
+        list(&users.iter().map(|(id, role)| format!("{} ({})", id, role)).collect::<Vec<_>>())
+    });
+    change(&mut changes, "model", &old_settings.model, &new_settings.model, optional);
+    change(&mut changes, "prompt template", &old_settings.prompt_template, &new_settings.prompt_template, |t| {
+        if t.is_some() { "set".to_string() } else { "(default)".to_string() }
+    });
+    change(&mut changes, "system prompt", &old_settings.system_prompt, &new_settings.system_prompt, |p| {
+        if p.is_some() { "set".to_string() } else { "(none)".to_string() }
+    });
+    change(&mut changes, "verbosity", &old_settings.verbosity, &new_settings.verbosity, |v| {
+        v.map(|v| v.name()).unwrap_or("full").to_string()
+    });
+    if old_settings.chats != new_settings.chats {
+        let mut keys: Vec<&String> = old_settings.chats.keys().chain(new_settings.chats.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        let changed = keys.iter().filter(|k| old_settings.chats.get(**k) != new_settings.chats.get(**k)).count();
+        changes.push(format!("per-chat settings of {} chat(s)", changed));
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::services::chat_settings::ChatSettings;
+
+    fn base() -> Snapshot {
+        snapshot(vec![1], "claude", BotOptions { workspace_roots: Some(vec![]), ..BotOptions::default() }, BotSettings::default())
+    }
+
+    #[test]
+    fn test_snapshot_applies_options() {
+        let options = BotOptions {
+            allowed_tools: Some(vec!["Read".to_string()]),
+            chat_role: Some(Role::Viewer),
+            users: BTreeMap::from([(7, Role::Admin)]),
+            ..BotOptions::default()
+        };
+        let mut settings = BotSettings::default();
+        settings.users.insert(8, Role::User);
+        let s = snapshot(vec![1, 2], "codex", options, settings);
+        assert_eq!(s.settings.allowed_tools, vec!["Read"]);
+        assert_eq!(s.chat_role, Role::Viewer);
+        assert_eq!(s.settings.users, BTreeMap::from([(7, Role::Admin), (8, Role::User)]));
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = base();
+        assert!(changes(&old, &old).is_empty());
+
+        let mut new = base();
+        new.chats = vec![1, 2];
+        new.agent = "gemini".to_string();
+        new.settings.users.insert(5, Role::Viewer);
+        new.settings.chats.insert("1".to_string(), ChatSettings { model: Some("o3".to_string()), ..ChatSettings::default() });
+        assert_eq!(changes(&old, &new), vec![
+            "chats: 1 → 1, 2",
+            "agent: claude → gemini",
+            "users: (none) → 5 (viewer)",
+            "per-chat settings of 1 chat(s)",
+        ]);
+    }
+}
@@ -0,0 +1,542 @@
+//! Retention and pruning for saved sessions, workspaces and debug logs.
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::future::Future;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Mutex, Once, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use crate::services::crypto;
+use crate::services::session::{self, HistoryItem, HistoryType, SessionData};
+
+/// How often the bots re-run pruning after the startup pass
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// `aemi sessions prune` leaves sessions changed this recently alone, since a running bot
+/// may still hold them in memory and write them back
+pub const RECENT_CHANGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Rough per-item JSON overhead used when estimating session file size
+const ITEM_OVERHEAD_BYTES: u64 = 48;
+
+/// Retention limits. `None` means unlimited; every limit is off unless set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_sessions: Option<usize>,
+    pub max_session_bytes: Option<u64>,
+    pub history_window: Option<usize>,
+    pub max_log_bytes: Option<u64>,
+}
+
+/// Read a numeric limit from the environment. Unset or 0 means no limit.
+fn env_limit(name: &str) -> Option<u64> {
+    std::env::var(name).ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|n| *n > 0)
+}
+
+impl RetentionPolicy {
+    /// Limits from `AEMI_RETENTION_DAYS`, `AEMI_MAX_SESSIONS`, `AEMI_MAX_SESSION_BYTES`,
+    /// `AEMI_HISTORY_WINDOW` and `AEMI_MAX_LOG_BYTES`
+    pub fn from_env() -> Self {
+        Self {
+            max_age: env_limit("AEMI_RETENTION_DAYS").map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            max_sessions: env_limit("AEMI_MAX_SESSIONS").map(|n| n as usize),
+            max_session_bytes: env_limit("AEMI_MAX_SESSION_BYTES"),
+            history_window: env_limit("AEMI_HISTORY_WINDOW").map(|n| n as usize),
+            max_log_bytes: env_limit("AEMI_MAX_LOG_BYTES"),
+        }
+    }
+
+    /// True if no limit removes or trims anything
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// What a pruning pass must leave alone
+#[derive(Debug, Clone, Default)]
+pub struct Protected {
+    /// Sessions open in a chat
+    pub session_ids: HashSet<String>,
+    /// Working directories of chats; workspaces holding one are kept
+    pub paths: HashSet<String>,
+    /// Sessions whose file changed within this time are neither removed nor trimmed
+    pub changed_within: Option<Duration>,
+}
+
+impl Protected {
+    pub fn extend(&mut self, other: Protected) {
+        self.session_ids.extend(other.session_ids);
+        self.paths.extend(other.paths);
+    }
+}
+
+/// Process-wide policy, read from the environment on first use
+pub fn policy() -> &'static RetentionPolicy {
+    static POLICY: OnceLock<RetentionPolicy> = OnceLock::new();
+    POLICY.get_or_init(RetentionPolicy::from_env)
+}
+
+/// Get the history archive directory path (~/.aemi/ai_sessions/archive)
+pub fn archive_dir() -> Option<PathBuf> {
+    session::ai_sessions_dir().map(|d| d.join("archive"))
+}
+
+fn archive_path(session_id: &str) -> Option<PathBuf> {
+    let dir = archive_dir()?;
+    let path = dir.join(format!("{}.jsonl", session_id));
+    // Security: Verify the path is within the archive directory
+    if path.parent() != Some(dir.as_path()) {
+        return None;
+    }
+    Some(path)
+}
+
+/// Delete a saved session and its history archive (`aemi sessions delete`).
+/// Returns false if the session does not exist.
+pub fn delete_session(session_id: &str) -> std::io::Result<bool> {
+    let Some(sessions_dir) = session::ai_sessions_dir() else {
+        return Ok(false);
+    };
+    let path = sessions_dir.join(format!("{}.json", session_id));
+    // Security: Verify the path is within the sessions directory
+    if path.parent() != Some(sessions_dir.as_path()) || !path.is_file() {
+        return Ok(false);
+    }
+    fs::remove_file(&path)?;
+    if let Some(archive) = archive_path(session_id).filter(|p| p.exists()) {
+        fs::remove_file(archive)?;
+    }
+    Ok(true)
+}
+
+/// Number of oldest items to move out of `history` so it fits the window and byte limits.
+/// The cut is moved forward to the next user message so the kept history starts with a turn,
+/// and the last item is always kept.
+pub fn items_to_archive(history: &[HistoryItem], policy: &RetentionPolicy) -> usize {
+    if history.len() <= 1 {
+        return 0;
+    }
+    let mut cut = match policy.history_window {
+        Some(window) if history.len() > window => history.len() - window,
+        _ => 0,
+    };
+
+    if let Some(max_bytes) = policy.max_session_bytes {
+        let mut size: u64 = history[cut..].iter()
+            .map(|item| item.content.len() as u64 + ITEM_OVERHEAD_BYTES)
+            .sum();
+        while size > max_bytes && cut < history.len() - 1 {
+            size -= history[cut].content.len() as u64 + ITEM_OVERHEAD_BYTES;
+            cut += 1;
+        }
+    }
+
+    if cut == 0 {
+        return 0;
+    }
+    let last = history.len() - 1;
+    while cut < last && history[cut].item_type != HistoryType::User {
+        cut += 1;
+    }
+    cut
+}
+
+/// Append history items to the session's archive file
+fn append_archive(session_id: &str, items: &[HistoryItem]) -> std::io::Result<()> {
+    let path = archive_path(session_id)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid session id"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).mode(0o600).open(path)?;
+    for item in items.iter().filter(|i| !matches!(i.item_type, HistoryType::System)) {
+        if let Ok(line) = serde_json::to_string(item) {
+            writeln!(file, "{}", crypto::seal(&line)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Trim in-memory history to the retention window, archiving the trimmed items.
+/// Call before saving a session. Does nothing without a session ID or if archiving fails.
+pub fn trim_history(session_id: Option<&str>, history: &mut Vec<HistoryItem>) {
+    let Some(session_id) = session_id else {
+        return;
+    };
+    let cut = items_to_archive(history, policy());
+    if cut == 0 {
+        return;
+    }
+    if append_archive(session_id, &history[..cut]).is_ok() {
+        history.drain(..cut);
+    }
+}
+
+/// Load archived (trimmed) history for a session, oldest first
+pub fn load_archived_history(session_id: &str) -> Vec<HistoryItem> {
+    let Some(path) = archive_path(session_id) else {
+        return Vec::new();
+    };
+    let Ok(content) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    // Each line is sealed separately, so plaintext and encrypted lines can be mixed
+    content.lines()
+        .filter_map(|line| crypto::open(line).ok())
+        .filter_map(|line| serde_json::from_str::<HistoryItem>(&line).ok())
+        .collect()
+}
+
+/// Outcome of a pruning pass
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub sessions_removed: Vec<PathBuf>,
+    /// Session files that could not be read or decrypted; workspaces are kept while any exist
+    pub sessions_unreadable: Vec<PathBuf>,
+    pub sessions_trimmed: Vec<(String, usize)>,
+    pub workspaces_removed: Vec<PathBuf>,
+    pub logs_removed: Vec<PathBuf>,
+    pub logs_rotated: Vec<PathBuf>,
+    pub bytes_freed: u64,
+}
+
+impl PruneReport {
+    pub fn is_empty(&self) -> bool {
+        self.sessions_removed.is_empty()
+            && self.sessions_trimmed.is_empty()
+            && self.workspaces_removed.is_empty()
+            && self.logs_removed.is_empty()
+            && self.logs_rotated.is_empty()
+    }
+
+    /// One-line summary for logs
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} sessions removed, {} trimmed, {} workspaces removed, {} logs removed, {} rotated ({} KiB)",
+            self.sessions_removed.len(),
+            self.sessions_trimmed.len(),
+            self.workspaces_removed.len(),
+            self.logs_removed.len(),
+            self.logs_rotated.len(),
+            self.bytes_freed / 1024,
+        );
+        if !self.sessions_unreadable.is_empty() {
+            summary.push_str(&format!("; {} unreadable sessions, workspaces kept", self.sessions_unreadable.len()));
+        }
+        summary
+    }
+}
+
+fn modified(path: &Path) -> SystemTime {
+    path.metadata()
+        .and_then(|m| m.modified())
+        .unwrap_or(std::time::UNIX_EPOCH)
+}
+
+/// Newest modification time of a file or anywhere inside a directory tree.
+/// A directory's own mtime only changes when entries are added or removed.
+fn newest_modified(path: &Path) -> SystemTime {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return std::time::UNIX_EPOCH;
+    };
+    let own = meta.modified().unwrap_or(std::time::UNIX_EPOCH);
+    if !meta.is_dir() {
+        return own;
+    }
+    fs::read_dir(path)
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| newest_modified(&e.path())).fold(own, SystemTime::max))
+        .unwrap_or(own)
+}
+
+fn older_than(time: SystemTime, age: Option<Duration>, now: SystemTime) -> bool {
+    match age {
+        Some(age) => now.duration_since(time).map(|d| d > age).unwrap_or(false),
+        None => false,
+    }
+}
+
+fn is_expired(path: &Path, max_age: Option<Duration>, now: SystemTime) -> bool {
+    older_than(modified(path), max_age, now)
+}
+
+/// Total size of a file or directory tree
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| disk_usage(&e.path())).sum())
+        .unwrap_or(0)
+}
+
+/// Apply the retention policy to sessions, workspaces and debug logs.
+/// Protected and pinned sessions are never removed, and protected ones are not trimmed.
+/// A workspace is removed only when nothing inside it changed within the age limit, no
+/// session or chat uses it and every session file could be read.
+/// With `dry_run`, nothing is changed and the report lists what would be.
+pub fn prune(policy: &RetentionPolicy, protected: &Protected, dry_run: bool) -> PruneReport {
+    let mut report = PruneReport::default();
+    let Some(home) = dirs::home_dir() else {
+        return report;
+    };
+    let now = SystemTime::now();
+    let aemi_dir = home.join(".aemi");
+
+    // --- Sessions ---
+    let mut sessions: Vec<(PathBuf, SystemTime, SessionData)> = Vec::new();
+    if let Some(sessions_dir) = session::ai_sessions_dir() {
+        if let Ok(entries) = fs::read_dir(&sessions_dir) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if !path.extension().map(|e| e == "json").unwrap_or(false) {
+                    continue;
+                }
+                let Some(data) = crypto::read_to_string(&path).ok()
+                    .and_then(|c| session::parse_session(&c))
+                else {
+                    report.sessions_unreadable.push(path);
+                    continue;
+                };
+                sessions.push((path.clone(), modified(&path), data));
+            }
+        }
+    }
+    sessions.sort_by_key(|s| Reverse(s.1));
+
+    // Workspaces of every session seen, including the ones removed now: a workspace
+    // outlives its session by at least one pass
+    let mut used_paths: HashSet<String> = protected.paths.clone();
+    let mut rank = 0;
+    for (path, changed, data) in sessions.iter_mut() {
+        used_paths.insert(data.current_path.clone());
+        let is_protected = protected.session_ids.contains(&data.session_id)
+            || protected.changed_within.is_some_and(|within| !older_than(*changed, Some(within), now));
+        if data.pinned {
+            // Pinned sessions are kept and do not take up a place in the count
+            if !is_protected {
+                trim_saved(path, data, policy, dry_run, &mut report);
+            }
+            continue;
+        }
+        let over_count = policy.max_sessions.map(|max| rank >= max).unwrap_or(false);
+        rank += 1;
+        if !is_protected && (over_count || older_than(*changed, policy.max_age, now)) {
+            report.bytes_freed += disk_usage(path);
+            let archive = archive_path(&data.session_id);
+            if let Some(archive) = &archive {
+                report.bytes_freed += disk_usage(archive);
+            }
+            if !dry_run {
+                let _ = fs::remove_file(&*path);
+                if let Some(archive) = archive {
+                    let _ = fs::remove_file(archive);
+                }
+            }
+            report.sessions_removed.push(path.clone());
+            continue;
+        }
+        if !is_protected {
+            trim_saved(path, data, policy, dry_run, &mut report);
+        }
+    }
+
+    // --- Workspaces created by /start without a path ---
+    let workspaces = if report.sessions_unreadable.is_empty() { fs::read_dir(aemi_dir.join("workspace")).ok() } else { None };
+    if let Some(entries) = workspaces {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !path.is_dir() || !older_than(newest_modified(&path), policy.max_age, now) {
+                continue;
+            }
+            let referenced = used_paths.iter().any(|p| Path::new(p).starts_with(&path));
+            if referenced {
+                continue;
+            }
+            report.bytes_freed += disk_usage(&path);
+            if !dry_run {
+                let _ = fs::remove_dir_all(&path);
+            }
+            report.workspaces_removed.push(path);
+        }
+    }
+
+    // --- Debug logs ---
+    if let Ok(entries) = fs::read_dir(aemi_dir.join("debug")) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if !path.is_file() {
+                continue;
+            }
+            let size = disk_usage(&path);
+            if is_expired(&path, policy.max_age, now) {
+                report.bytes_freed += size;
+                if !dry_run {
+                    let _ = fs::remove_file(&path);
+                }
+                report.logs_removed.push(path);
+            } else if path.extension().map(|e| e == "log").unwrap_or(false)
+                && policy.max_log_bytes.map(|max| size > max).unwrap_or(false)
+            {
+                // Keep one rotated copy; the previous one is replaced
+                let rotated = path.with_extension("log.1");
+                report.bytes_freed += disk_usage(&rotated);
+                if !dry_run {
+                    let _ = fs::rename(&path, rotated);
+                }
+                report.logs_rotated.push(path);
+            }
+        }
+    }
+
+    report
+}
+
+/// Move history over the limits of a saved session to its archive
+fn trim_saved(path: &Path, data: &mut SessionData, policy: &RetentionPolicy, dry_run: bool, report: &mut PruneReport) {
+    let cut = items_to_archive(&data.history, policy);
+    if cut == 0 {
+        return;
+    }
+    if !dry_run && append_archive(&data.session_id, &data.history[..cut]).is_ok() {
+        data.history.drain(..cut);
+        if let Ok(json) = serde_json::to_string_pretty(&*data) {
+            let _ = crypto::write(path, json);
+        }
+    }
+    report.sessions_trimmed.push((data.session_id.clone(), cut));
+}
+
+/// Run one pruning pass with the process-wide policy and log the result
+pub fn prune_and_log(protected: &Protected) {
+    let report = prune(policy(), protected, false);
+    if !report.is_empty() {
+        let ts = chrono::Local::now().format("%H:%M:%S");
+        println!("  [{ts}] ▶ Retention: {}", report.summary());
+    }
+}
+
+/// Reports what one bot has open, for the process-wide pruner
+pub type InUse = Box<dyn Fn() -> Pin<Box<dyn Future<Output = Protected> + Send>> + Send + Sync>;
+
+fn in_use_sources() -> &'static Mutex<Vec<InUse>> {
+    static SOURCES: OnceLock<Mutex<Vec<InUse>>> = OnceLock::new();
+    SOURCES.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Register a bot with the process-wide pruner, which runs at startup and every
+/// `PRUNE_INTERVAL` (once per process, however many bots run). Every pass protects what
+/// all registered bots have open. Does nothing while every limit is off.
+pub fn register(in_use: InUse) {
+    if policy().is_unlimited() {
+        return;
+    }
+    if let Ok(mut sources) = in_use_sources().lock() {
+        sources.push(in_use);
+    }
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        tokio::spawn(async {
+            loop {
+                let pending: Vec<_> = in_use_sources().lock()
+                    .map(|sources| sources.iter().map(|source| source()).collect())
+                    .unwrap_or_default();
+                let mut protected = Protected::default();
+                for bot in pending {
+                    protected.extend(bot.await);
+                }
+                let _ = tokio::task::spawn_blocking(move || prune_and_log(&protected)).await;
+                tokio::time::sleep(PRUNE_INTERVAL).await;
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(item_type: HistoryType, content: &str) -> HistoryItem {
+        HistoryItem::new(item_type, content)
+    }
+
+    fn conversation(turns: usize) -> Vec<HistoryItem> {
+        (0..turns)
+            .flat_map(|i| vec![
+                item(HistoryType::User, &format!("question {}", i)),
+                item(HistoryType::ToolUse, "Bash\n{}"),
+                item(HistoryType::Assistant, &format!("answer {}", i)),
+            ])
+            .collect()
+    }
+
+    fn unlimited() -> RetentionPolicy {
+        RetentionPolicy::default()
+    }
+
+    #[test]
+    fn test_items_to_archive_within_window() {
+        let policy = RetentionPolicy { history_window: Some(100), ..unlimited() };
+        assert_eq!(items_to_archive(&conversation(10), &policy), 0);
+    }
+
+    #[test]
+    fn test_items_to_archive_cuts_at_user_turn() {
+        // 30 items, window 10: item 20 is an assistant reply, so the cut moves to the next user message
+        let policy = RetentionPolicy { history_window: Some(10), ..unlimited() };
+        let history = conversation(10);
+        let cut = items_to_archive(&history, &policy);
+        assert_eq!(cut, 21);
+        assert_eq!(history[cut].item_type, HistoryType::User);
+    }
+
+    #[test]
+    fn test_items_to_archive_byte_limit() {
+        let mut history = conversation(2);
+        history.insert(0, item(HistoryType::User, &"x".repeat(10_000)));
+        let policy = RetentionPolicy { max_session_bytes: Some(1_000), ..unlimited() };
+        let cut = items_to_archive(&history, &policy);
+        assert_eq!(cut, 1);
+    }
+
+    #[test]
+    fn test_items_to_archive_keeps_last_item() {
+        let history = vec![
+            item(HistoryType::User, &"x".repeat(10_000)),
+            item(HistoryType::Assistant, &"y".repeat(10_000)),
+        ];
+        let policy = RetentionPolicy { max_session_bytes: Some(10), ..unlimited() };
+        assert_eq!(items_to_archive(&history, &policy), 1);
+    }
+
+    #[test]
+    fn test_env_limit_unset_is_unlimited() {
+        assert_eq!(env_limit("AEMI_TEST_UNSET_LIMIT_VAR"), None);
+        assert!(RetentionPolicy::default().is_unlimited());
+    }
+
+    #[test]
+    fn test_newest_modified_looks_inside() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("src/deep");
+        fs::create_dir_all(&nested).unwrap();
+        let file = nested.join("main.rs");
+        fs::write(&file, "fn main() {}").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(10 * 24 * 60 * 60);
+        for path in [dir.path(), &dir.path().join("src"), &nested] {
+            fs::File::open(path).unwrap().set_modified(old).unwrap();
+        }
+        assert!(newest_modified(dir.path()) > old);
+        assert_eq!(newest_modified(dir.path()), modified(&file));
+    }
+}
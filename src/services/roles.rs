@@ -0,0 +1,288 @@
+//! User roles for bot access control.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::services::bot_common::ALL_TOOLS;
+
+/// A user listed in the bot's `users` settings has that role in every chat the bot
+/// sees. The owner is always an admin. Other members of an allowed chat get the
+/// bot's chat role (`user` by default).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// Chat with read-only tools and the commands that only show something
+    Viewer,
+    /// Chat, uploads, `!` shell commands, git commands and session changes
+    User,
+    /// Everything, including `/users`, `/reload`, `/settings` and `/allowed` changes and agent switching
+    Admin,
+}
+
+/// All roles, lowest first
+pub const ALL_ROLES: &[Role] = &[Role::Viewer, Role::User, Role::Admin];
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Parse a role name (case-insensitive)
+    pub fn parse(name: &str) -> Option<Role> {
+        ALL_ROLES.iter().copied().find(|r| r.name().eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How a chat relates to the bot's chat allowlist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatAccess {
+    /// The chat is not on the allowlist (or there is no allowlist)
+    NotListed,
+    /// The chat is on the allowlist
+    Listed,
+    /// The chat is on the allowlist and is the private chat of the sender
+    ListedPrivate,
+}
+
+/// Role of `user_id` writing in a chat, or None if they may not use the bot.
+/// Imprinting (registering the first user as owner) is left to the caller.
+pub fn resolve(
+    users: &BTreeMap<u64, Role>,
+    owner: Option<u64>,
+    chat_role: Role,
+    user_id: Option<u64>,
+    chat: ChatAccess,
+) -> Option<Role> {
+    if let Some(uid) = user_id {
+        if owner == Some(uid) {
+            return Some(Role::Admin);
+        }
+        if let Some(role) = users.get(&uid) {
+            return Some(*role);
+        }
+    }
+    match chat {
+        // Single-user setups with --chat-id have no owner; the allowed user is the admin
+        ChatAccess::ListedPrivate => Some(Role::Admin),
+        ChatAccess::Listed => Some(chat_role),
+        ChatAccess::NotListed => None,
+    }
+}
+
+/// Minimum role needed to send `text` (a command, a `!` shell command or a prompt)
+pub fn required_role(text: &str) -> Role {
+    let mut words = text.split_whitespace();
+    let first = words.next().unwrap_or("");
+    let second = words.next();
+    let has_args = second.is_some();
+    // Telegram group commands may carry the bot name: /agent@my_bot
+    let command = first.split('@').next().unwrap_or(first);
+    match command {
+        // A prompt; viewers get read-only tools
+        c if !c.starts_with('/') && !c.starts_with('!') => Role::Viewer,
+        "/users" | "/reload" => Role::Admin,
+        "/agent" | "/settings" | "/allowed" if has_args => Role::Admin,
+        "/systemprompt" if has_args && second != Some("show") => Role::Admin,
+        // Commands that only show something
+        "/help" | "/pwd" | "/search" | "/diff" | "/export" | "/templates"
+        | "/availabletools" | "/allowedtools" => Role::Viewer,
+        "/resume" | "/settings" | "/checkpoints" | "/agent" | "/queue" | "/allowed" if !has_args => Role::Viewer,
+        // Viewers may chat, so they may stop a turn too
+        "/stop" => Role::Viewer,
+        // Everything else changes the chat, its session or the workspace
+        _ => Role::User,
+    }
+}
+
+/// Tools the agent may use in a turn started by `role`: viewers only get the
+/// non-destructive built-in tools from the bot's allowed list
+pub fn tools_for(role: Role, allowed_tools: &[String]) -> Vec<String> {
+    if role > Role::Viewer {
+        return allowed_tools.to_vec();
+    }
+    allowed_tools.iter()
+        .filter(|name| ALL_TOOLS.iter().any(|(tool, _, destructive)| tool == name && !destructive))
+        .cloned()
+        .collect()
+}
+
+/// Whether `agent` honors the tool list, so viewer turns really are read-only.
+/// The other agents run in their own auto-approve modes and ignore it.
+pub fn supports_read_only(agent: &str) -> bool {
+    agent == "claude"
+}
+
+/// Parse the `users` object of a bot_settings.json entry (`{"<user id>": "<role>"}`).
+/// Entries with an invalid id or role are skipped.
+pub fn users_from_json(value: Option<&serde_json::Value>) -> BTreeMap<u64, Role> {
+    value.and_then(|v| v.as_object())
+        .map(|obj| {
+            obj.iter()
+                .filter_map(|(id, role)| Some((id.parse().ok()?, Role::parse(role.as_str()?)?)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Serialize users for bot_settings.json
+pub fn users_to_json(users: &BTreeMap<u64, Role>) -> serde_json::Value {
+    users.iter()
+        .map(|(id, role)| (id.to_string(), serde_json::json!(role.name())))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Apply `/users add|remove|role` arguments to the user list.
+/// Returns a confirmation message or a usage error.
+pub fn apply_users_command(users: &mut BTreeMap<u64, Role>, owner: Option<u64>, args: &str) -> Result<String, String> {
+    let parts: Vec<&str> = args.split_whitespace().collect();
+    let parse_id = |s: &str| s.parse::<u64>().map_err(|_| format!("Invalid user ID: {}", s));
+    let parse_role = |s: &str| Role::parse(s).ok_or_else(|| format!("Unknown role: {}. Roles: admin, user, viewer", s));
+    match parts.as_slice() {
+        ["add", id] | ["add", id, _] | ["role", id, _] | ["remove", id] if owner == parse_id(id).ok() => {
+            Err("The owner is always an admin.".to_string())
+        }
+        ["add", id] => {
+            let id = parse_id(id)?;
+            users.insert(id, Role::User);
+            Ok(format!("Added {} as user.", id))
+        }
+        ["add", id, role] => {
+            let (id, role) = (parse_id(id)?, parse_role(role)?);
+            users.insert(id, role);
+            Ok(format!("Added {} as {}.", id, role))
+        }
+        ["remove", id] => {
+            let id = parse_id(id)?;
+            match users.remove(&id) {
+                Some(_) => Ok(format!("Removed {}.", id)),
+                None => Err(format!("{} is not in the user list.", id)),
+            }
+        }
+        ["role", id, role] => {
+            let (id, role) = (parse_id(id)?, parse_role(role)?);
+            match users.get_mut(&id) {
+                Some(current) => {
+                    *current = role;
+                    Ok(format!("{} is now {}.", id, role))
+                }
+                None => Err(format!("{} is not in the user list. Use /users add {} {}", id, id, role)),
+            }
+        }
+        _ => Err("Usage: /users add <id> [role] | /users remove <id> | /users role <id> <role>".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let users = BTreeMap::from([(2, Role::Viewer), (3, Role::Admin)]);
+        let resolve = |uid, chat| resolve(&users, Some(1), Role::User, Some(uid), chat);
+        assert_eq!(resolve(1, ChatAccess::NotListed), Some(Role::Admin));
+        assert_eq!(resolve(2, ChatAccess::NotListed), Some(Role::Viewer));
+        // Listed users keep their role inside allowed chats
+        assert_eq!(resolve(2, ChatAccess::Listed), Some(Role::Viewer));
+        assert_eq!(resolve(3, ChatAccess::Listed), Some(Role::Admin));
+        assert_eq!(resolve(4, ChatAccess::Listed), Some(Role::User));
+        assert_eq!(resolve(4, ChatAccess::ListedPrivate), Some(Role::Admin));
+        assert_eq!(resolve(4, ChatAccess::NotListed), None);
+        assert_eq!(super::resolve(&users, None, Role::Viewer, None, ChatAccess::Listed), Some(Role::Viewer));
+    }
+
+    #[test]
+    fn test_required_role() {
+        let cases = [
+            ("what does main.rs do?", Role::Viewer),
+            ("!ls -la", Role::User),
+            ("/help", Role::Viewer),
+            ("/pwd", Role::Viewer),
+            ("/search parser", Role::Viewer),
+            ("/diff", Role::Viewer),
+            ("/export html", Role::Viewer),
+            ("/templates", Role::Viewer),
+            ("/allowedtools", Role::Viewer),
+            ("/resume", Role::Viewer),
+            ("/resume 3", Role::User),
+            ("/settings", Role::Viewer),
+            ("/settings model opus", Role::Admin),
+            ("/checkpoints", Role::Viewer),
+            ("/checkpoints 2", Role::User),
+            ("/queue", Role::Viewer),
+            ("/queue clear", Role::User),
+            ("/agent", Role::Viewer),
+            ("/agent codex", Role::Admin),
+            ("/agent@my_bot codex", Role::Admin),
+            ("/start", Role::User),
+            ("/start ~/project", Role::User),
+            ("/start --template rust", Role::User),
+            ("/clear", Role::User),
+            ("/fork", Role::User),
+            ("/compact", Role::User),
+            ("/rename parser work", Role::User),
+            ("/tag wip", Role::User),
+            ("/pin", Role::User),
+            ("/stop", Role::Viewer),
+            ("/retry", Role::User),
+            ("/down notes.md", Role::User),
+            ("/commit fix", Role::User),
+            ("/undo", Role::User),
+            ("/systemprompt", Role::User),
+            ("/systemprompt show", Role::User),
+            ("/systemprompt Answer in French.", Role::Admin),
+            ("/allowed", Role::Viewer),
+            ("/allowed +Bash", Role::Admin),
+            ("/users", Role::Admin),
+            ("/reload", Role::Admin),
+            ("/unknown", Role::User),
+        ];
+        for (text, role) in cases {
+            assert_eq!(required_role(text), role, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_tools_for_viewer() {
+        let tools: Vec<String> = ["Bash", "Read", "Grep", "Write", "MyTool"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(tools_for(Role::User, &tools), tools);
+        assert_eq!(tools_for(Role::Viewer, &tools), vec!["Read", "Grep"]);
+    }
+
+    #[test]
+    fn test_users_json_roundtrip() {
+        let users = BTreeMap::from([(5, Role::Admin), (6, Role::Viewer)]);
+        let json = users_to_json(&users);
+        assert_eq!(json, serde_json::json!({"5": "admin", "6": "viewer"}));
+        assert_eq!(users_from_json(Some(&json)), users);
+        let bad = serde_json::json!({"x": "admin", "7": "root", "8": "USER"});
+        assert_eq!(users_from_json(Some(&bad)), BTreeMap::from([(8, Role::User)]));
+        assert!(users_from_json(None).is_empty());
+    }
+
+    #[test]
+    fn test_apply_users_command() {
+        let mut users = BTreeMap::new();
+        assert!(apply_users_command(&mut users, Some(1), "add 5").is_ok());
+        assert_eq!(users.get(&5), Some(&Role::User));
+        assert!(apply_users_command(&mut users, Some(1), "role 5 viewer").is_ok());
+        assert_eq!(users.get(&5), Some(&Role::Viewer));
+        assert!(apply_users_command(&mut users, Some(1), "role 6 admin").unwrap_err().contains("not in the user list"));
+        assert!(apply_users_command(&mut users, Some(1), "add 6 root").unwrap_err().contains("Unknown role"));
+        assert!(apply_users_command(&mut users, Some(1), "add 1 viewer").unwrap_err().contains("owner"));
+        assert!(apply_users_command(&mut users, Some(1), "add abc").unwrap_err().contains("Invalid user ID"));
+        assert!(apply_users_command(&mut users, Some(1), "remove 5").is_ok());
+        assert!(users.is_empty());
+        assert!(apply_users_command(&mut users, Some(1), "delete 5").unwrap_err().starts_with("Usage"));
+    }
+}
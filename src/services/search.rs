@@ -0,0 +1,290 @@
+//! Full-text search across saved sessions.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use crate::services::crypto;
+use crate::services::session::{self, HistoryType, SessionData, SessionOrigin};
+
+/// Characters of context shown on each side of a match
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Maximum snippets returned per session
+const MAX_SNIPPETS: usize = 2;
+
+/// A session matching a search query
+pub struct SearchHit {
+    pub session_id: String,
+    pub current_path: String,
+    pub created_at: String,
+    pub name: Option<String>,
+    pub score: usize,
+    pub snippets: Vec<String>,
+    pub modified: SystemTime,
+}
+
+struct IndexedSession {
+    modified: SystemTime,
+    data: SessionData,
+}
+
+/// In-process index of `~/.aemi/ai_sessions` keyed by file path and modification time,
+/// so a query only re-reads files that changed since the previous one
+fn index() -> &'static Mutex<HashMap<PathBuf, IndexedSession>> {
+    static INDEX: OnceLock<Mutex<HashMap<PathBuf, IndexedSession>>> = OnceLock::new();
+    INDEX.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Bring the index up to date with the sessions directory.
+/// Only files whose modification time changed are re-read; deleted files are dropped.
+fn refresh_index(index: &mut HashMap<PathBuf, IndexedSession>) {
+    let Some(sessions_dir) = session::ai_sessions_dir() else {
+        index.clear();
+        return;
+    };
+    let Ok(entries) = fs::read_dir(&sessions_dir) else {
+        index.clear();
+        return;
+    };
+
+    let mut seen = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !path.extension().map(|e| e == "json").unwrap_or(false) {
+            continue;
+        }
+        let modified = path.metadata()
+            .and_then(|m| m.modified())
+            .unwrap_or(std::time::UNIX_EPOCH);
+        seen.push(path.clone());
+
+        if index.get(&path).map(|s| s.modified == modified).unwrap_or(false) {
+            continue;
+        }
+        let parsed = crypto::read_to_string(&path).ok()
+            .and_then(|content| session::parse_session(&content));
+        match parsed {
+            Some(data) => {
+                index.insert(path, IndexedSession { modified, data });
+            }
+            None => {
+                index.remove(&path);
+            }
+        }
+    }
+
+    index.retain(|path, _| seen.contains(path));
+}
+
+/// Lowercase each char to exactly one char, so char offsets match the original text
+fn fold(s: &str) -> String {
+    s.chars().map(|c| c.to_lowercase().next().unwrap_or(c)).collect()
+}
+
+/// Split a query into lowercase terms
+pub fn parse_terms(query: &str) -> Vec<String> {
+    query.split_whitespace()
+        .map(fold)
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Extract a single-line snippet around the first occurrence of `term`
+fn snippet(content: &str, folded: &str, term: &str) -> Option<String> {
+    let byte_idx = folded.find(term)?;
+    let char_idx = folded[..byte_idx].chars().count();
+    let term_chars = term.chars().count();
+    let total = content.chars().count();
+
+    let start = char_idx.saturating_sub(SNIPPET_CONTEXT);
+    let end = (char_idx + term_chars + SNIPPET_CONTEXT).min(total);
+    let body: String = content.chars()
+        .skip(start)
+        .take(end - start)
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .collect();
+
+    Some(format!(
+        "{}{}{}",
+        if start > 0 { "..." } else { "" },
+        body.trim(),
+        if end < total { "..." } else { "" },
+    ))
+}
+
+/// Score a session against the query terms.
+/// Every term must appear somewhere in the session (history, name or path).
+/// Conversation text weighs more than tool output; name and path matches get a bonus.
+pub fn score_session(data: &SessionData, terms: &[String]) -> Option<(usize, Vec<String>)> {
+    if terms.is_empty() {
+        return None;
+    }
+
+    let meta = fold(&format!("{} {}", data.name.as_deref().unwrap_or(""), data.current_path));
+    let mut found = vec![false; terms.len()];
+    let mut score = 0;
+    let mut item_hits: Vec<(usize, usize)> = Vec::new(); // (hits, history index)
+    let mut folded_items = Vec::with_capacity(data.history.len());
+
+    for (i, term) in terms.iter().enumerate() {
+        let n = meta.matches(term.as_str()).count();
+        if n > 0 {
+            found[i] = true;
+            score += n * 5;
+        }
+    }
+
+    for (idx, item) in data.history.iter().enumerate() {
+        let weight = match item.item_type {
+            HistoryType::User | HistoryType::Assistant => 3,
+            HistoryType::System => 0,
+            _ => 1,
+        };
+        let folded = fold(&item.content);
+        let mut hits = 0;
+        if weight > 0 {
+            for (i, term) in terms.iter().enumerate() {
+                let n = folded.matches(term.as_str()).count();
+                if n > 0 {
+                    found[i] = true;
+                    hits += n * weight;
+                }
+            }
+        }
+        if hits > 0 {
+            item_hits.push((hits, idx));
+            score += hits;
+        }
+        folded_items.push(folded);
+    }
+
+    if !found.iter().all(|f| *f) || score == 0 {
+        return None;
+    }
+
+    // Snippets from the best-matching items, shown in conversation order
+    item_hits.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let mut best: Vec<usize> = item_hits.iter().take(MAX_SNIPPETS).map(|(_, idx)| *idx).collect();
+    best.sort_unstable();
+    let snippets = best.into_iter()
+        .filter_map(|idx| {
+            let folded = &folded_items[idx];
+            let term = terms.iter().find(|t| folded.contains(t.as_str()))?;
+            snippet(&data.history[idx].content, folded, term)
+        })
+        .collect();
+
+    Some((score, snippets))
+}
+
+/// Search saved sessions. Results are ranked by score, most recent first on ties.
+/// With a scope, only sessions owned by that bot and chat are searched.
+pub fn search_sessions(query: &str, limit: usize, scope: Option<&SessionOrigin>) -> Vec<SearchHit> {
+    let terms = parse_terms(query);
+    if terms.is_empty() {
+        return Vec::new();
+    }
+
+    let Ok(mut index) = index().lock() else {
+        return Vec::new();
+    };
+    refresh_index(&mut index);
+
+    let mut hits: Vec<SearchHit> = index.values()
+        .filter(|s| match scope {
+            Some(scope) => s.data.bot_hash.as_deref() == Some(scope.bot_hash.as_str())
+                && s.data.chat_id.as_deref() == Some(scope.chat_id.as_str()),
+            None => true,
+        })
+        .filter_map(|s| {
+            let (score, snippets) = score_session(&s.data, &terms)?;
+            Some(SearchHit {
+                session_id: s.data.session_id.clone(),
+                current_path: s.data.current_path.clone(),
+                created_at: s.data.created_at.clone(),
+                name: s.data.name.clone(),
+                score,
+                snippets,
+                modified: s.modified,
+            })
+        })
+        .collect();
+
+    hits.sort_by(|a, b| b.score.cmp(&a.score).then(b.modified.cmp(&a.modified)));
+    hits.truncate(limit);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::session::HistoryItem;
+
+    fn item(item_type: HistoryType, content: &str) -> HistoryItem {
+        HistoryItem::new(item_type, content)
+    }
+
+    fn sample_session() -> SessionData {
+        SessionData {
+            session_id: "s1".to_string(),
+            history: vec![
+                item(HistoryType::User, "Why does the Parser panic on empty input?"),
+                item(HistoryType::ToolUse, "Bash\n{\"command\":\"cargo test parser\"}"),
+                item(HistoryType::Assistant, "The parser indexed past the end; fixed with a bounds check."),
+            ],
+            current_path: "/home/me/compiler".to_string(),
+            created_at: "2024-01-01 12:00:00".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_terms_lowercases_and_splits() {
+        assert_eq!(parse_terms("  Parser  PANIC "), vec!["parser", "panic"]);
+        assert!(parse_terms("   ").is_empty());
+    }
+
+    #[test]
+    fn test_score_requires_all_terms() {
+        let data = sample_session();
+        assert!(score_session(&data, &parse_terms("parser panic")).is_some());
+        assert!(score_session(&data, &parse_terms("parser segfault")).is_none());
+    }
+
+    #[test]
+    fn test_score_matches_path() {
+        let data = sample_session();
+        let (score, _) = score_session(&data, &parse_terms("compiler")).unwrap();
+        assert_eq!(score, 5);
+    }
+
+    #[test]
+    fn test_conversation_outweighs_tool_output() {
+        let data = sample_session();
+        let (score, snippets) = score_session(&data, &parse_terms("parser")).unwrap();
+        // user (3) + tool (1) + assistant (3)
+        assert_eq!(score, 7);
+        assert_eq!(snippets.len(), 2);
+        assert!(snippets[0].contains("Parser panic"));
+        assert!(snippets[1].contains("parser indexed"));
+    }
+
+    #[test]
+    fn test_snippet_truncates_long_content() {
+        let content = format!("{}needle{}", "a".repeat(100), "b".repeat(100));
+        let s = snippet(&content, &fold(&content), "needle").unwrap();
+        assert!(s.starts_with("..."));
+        assert!(s.ends_with("..."));
+        assert!(s.contains("needle"));
+    }
+
+    #[test]
+    fn test_snippet_multibyte() {
+        let content = "한글 검색 테스트 Needle 끝";
+        let s = snippet(content, &fold(content), "needle").unwrap();
+        assert_eq!(s, "한글 검색 테스트 Needle 끝");
+    }
+}
@@ -3,10 +3,75 @@ use std::path::PathBuf;
 
 use crate::services::utils::floor_char_boundary;
 
+/// Current on-disk `SessionData` format.
+/// - 1 (implicit, no `version` field): `created_at` held the last save time
+/// - 2: per-item metadata, `updated_at`, session origin (platform, chat id, bot hash)
+pub const SESSION_SCHEMA_VERSION: u32 = 2;
+
+/// Token usage reported by the agent for one turn
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistoryItem {
     pub item_type: HistoryType,
     pub content: String,
+    /// Local time the item was recorded ("%Y-%m-%d %H:%M:%S")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    /// Agent that produced the item (assistant replies)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    /// Model reported by the agent, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Wall-clock duration of the turn in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+impl HistoryItem {
+    /// Create an item stamped with the current local time
+    pub fn new(item_type: HistoryType, content: impl Into<String>) -> Self {
+        Self {
+            item_type,
+            content: content.into(),
+            timestamp: Some(now_timestamp()),
+            agent: None,
+            model: None,
+            duration_ms: None,
+            usage: None,
+        }
+    }
+}
+
+impl HistoryItem {
+    /// Attach per-turn metadata to an assistant reply
+    pub fn with_turn_meta(
+        mut self,
+        agent: &str,
+        model: Option<String>,
+        duration: std::time::Duration,
+        usage: Option<Usage>,
+    ) -> Self {
+        self.agent = Some(agent.to_string());
+        self.model = model;
+        self.duration_ms = Some(duration.as_millis() as u64);
+        self.usage = usage;
+        self
+    }
+}
+
+/// Current local time in the format used by session files
+pub fn now_timestamp() -> String {
+    chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -20,12 +85,71 @@ pub enum HistoryType {
 }
 
 /// Session data structure for file persistence
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SessionData {
+    /// Schema version; 0 when read from a file written before versioning
+    #[serde(default)]
+    pub version: u32,
     pub session_id: String,
     pub history: Vec<HistoryItem>,
     pub current_path: String,
+    /// Time the session was first saved
     pub created_at: String,
+    /// Time the session was last saved
+    #[serde(default)]
+    pub updated_at: String,
+    /// Platform the session was last used from ("telegram", "discord")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// Chat (Telegram) or channel (Discord) id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<String>,
+    /// Hash of the bot token that owns the session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bot_hash: Option<String>,
+    /// Session this one was forked from (set by /fork)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_session_id: Option<String>,
+    /// User-given session name (set by /fork or /rename)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// User-given labels (set by /tag)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Pinned sessions are listed first by /resume (toggled by /pin)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+}
+
+impl SessionData {
+    /// Upgrade data read from an older file to the current schema
+    pub fn migrate(&mut self) {
+        if self.version < 2 {
+            // v1 overwrote created_at on every save, so it is really the last save time
+            // and the best available estimate of the creation time
+            if self.updated_at.is_empty() {
+                self.updated_at = self.created_at.clone();
+            }
+        }
+        if self.version < SESSION_SCHEMA_VERSION {
+            self.version = SESSION_SCHEMA_VERSION;
+        }
+    }
+}
+
+/// Where a session is being used from; recorded on save
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionOrigin {
+    pub platform: String,
+    pub chat_id: String,
+    pub bot_hash: String,
+}
+
+/// Parse a session file, migrating older schema versions transparently
+pub fn parse_session(content: &str) -> Option<SessionData> {
+    let mut data = serde_json::from_str::<SessionData>(content).ok()?;
+    data.migrate();
+    Some(data)
 }
 
 /// Get the AI sessions directory path (~/.aemi/ai_sessions)
@@ -189,6 +313,50 @@ mod tests {
         assert_eq!(sanitize_user_input(""), "");
     }
 
+    // --- schema migration ---
+
+    #[test]
+    fn test_parse_session_migrates_v1() {
+        let v1 = r#"{"session_id":"s1","history":[{"item_type":"User","content":"hi"}],"current_path":"/tmp","created_at":"2024-01-01 12:00:00"}"#;
+        let data = parse_session(v1).unwrap();
+        assert_eq!(data.version, SESSION_SCHEMA_VERSION);
+        assert_eq!(data.updated_at, "2024-01-01 12:00:00");
+        assert_eq!(data.created_at, "2024-01-01 12:00:00");
+        assert!(data.history[0].timestamp.is_none());
+        assert!(data.platform.is_none());
+    }
+
+    #[test]
+    fn test_parse_session_keeps_v2_fields() {
+        let mut item = HistoryItem::new(HistoryType::Assistant, "done");
+        item.agent = Some("claude".to_string());
+        item.usage = Some(Usage { input_tokens: 10, output_tokens: 20, cost_usd: None });
+        let data = SessionData {
+            version: SESSION_SCHEMA_VERSION,
+            session_id: "s2".to_string(),
+            history: vec![item],
+            created_at: "2024-01-01 12:00:00".to_string(),
+            updated_at: "2024-01-02 12:00:00".to_string(),
+            platform: Some("telegram".to_string()),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&data).unwrap();
+        let parsed = parse_session(&json).unwrap();
+        assert_eq!(parsed.created_at, "2024-01-01 12:00:00");
+        assert_eq!(parsed.updated_at, "2024-01-02 12:00:00");
+        assert_eq!(parsed.platform.as_deref(), Some("telegram"));
+        assert_eq!(parsed.history[0].agent.as_deref(), Some("claude"));
+        assert_eq!(parsed.history[0].usage.as_ref().map(|u| u.output_tokens), Some(20));
+    }
+
+    #[test]
+    fn test_history_item_new_serializes_compactly() {
+        let json = serde_json::to_string(&HistoryItem::new(HistoryType::User, "hi")).unwrap();
+        assert!(json.contains("\"timestamp\""));
+        assert!(!json.contains("agent"));
+        assert!(!json.contains("usage"));
+    }
+
     // --- ai_sessions_dir ---
 
     #[test]
@@ -0,0 +1,225 @@
+//! Safe reads and writes of `bot_settings.json`.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::services::crypto;
+
+/// Why the settings file could not be used
+#[derive(Debug)]
+pub enum ReadError {
+    /// Exists but cannot be read (e.g. encrypted with a key we don't have). Never overwrite it.
+    Unreadable(String),
+    /// Not valid JSON, and there is no usable backup
+    Corrupt(String),
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Backup of the last good file (`<path>.bak`)
+pub fn backup_path(path: &Path) -> PathBuf {
+    sibling(path, ".bak")
+}
+
+/// Exclusive `flock` on `<path>.lock`, released when dropped. Several bots in one process
+/// (and `aemi settings set`) update the same file, so every read-modify-write holds it.
+pub struct Lock {
+    _file: fs::File,
+}
+
+impl Lock {
+    pub fn acquire(path: &Path) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(sibling(path, ".lock"))?;
+        // SAFETY: flock on a file descriptor we own; the lock is released when it is closed
+        #[allow(unsafe_code)]
+        let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Lock { _file: file })
+    }
+}
+
+fn parse_object(content: &str) -> Result<Value, String> {
+    match serde_json::from_str::<Value>(content) {
+        Ok(json) if json.is_object() => Ok(json),
+        Ok(_) => Err("not a JSON object".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Read the settings file. A missing file is an empty object. A corrupt file falls
+/// back to the backup; both cases print a warning. The flag tells whether the value
+/// came from `path` itself (as opposed to the backup or an empty default).
+fn read_with_origin(path: &Path) -> Result<(Value, bool), ReadError> {
+    let content = match crypto::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((serde_json::json!({}), false)),
+        Err(e) => return Err(ReadError::Unreadable(format!("cannot read {}: {}", path.display(), e))),
+    };
+    let error = match parse_object(&content) {
+        Ok(json) => return Ok((json, true)),
+        Err(e) => e,
+    };
+    let backup = backup_path(path);
+    eprintln!("  ⚠ {} is corrupt ({}); trying backup {}", path.display(), error, backup.display());
+    match crypto::read_to_string(&backup).map_err(|e| e.to_string()).and_then(|c| parse_object(&c)) {
+        Ok(json) => {
+            eprintln!("  ⚠ Using settings from {}", backup.display());
+            Ok((json, false))
+        }
+        Err(backup_error) => {
+            eprintln!("  ⚠ Backup unusable ({}); bot settings fall back to defaults", backup_error);
+            Err(ReadError::Corrupt(error))
+        }
+    }
+}
+
+/// Read the settings file (see [`update`] for how corruption is handled)
+pub fn read(path: &Path) -> Result<Value, ReadError> {
+    read_with_origin(path).map(|(json, _)| json)
+}
+
+/// Write `content` as-is to a temp file readable only by the owner and rename it over `path`
+pub fn replace(path: &Path, content: &str) -> io::Result<()> {
+    let tmp = sibling(path, &format!(".tmp.{}", std::process::id()));
+    let _ = fs::remove_file(&tmp);
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Write `json` to a temp file and rename it over `path`, keeping the current
+/// file as the backup (`<path>.bak`) when it was good
+fn write_atomic(path: &Path, json: &Value, current_is_good: bool) -> io::Result<()> {
+    let content = serde_json::to_string_pretty(json)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let sealed = crypto::seal(&content)?;
+    if current_is_good {
+        let _ = fs::copy(path, backup_path(path));
+    }
+    replace(path, &sealed)
+}
+
+/// Apply `modify` to the settings file under an exclusive lock and write it back atomically.
+/// An unreadable file is never overwritten. A corrupt file with no usable backup is moved
+/// aside to `<path>.corrupt` before starting from an empty object.
+pub fn update(path: &Path, modify: impl FnOnce(&mut Value) -> Result<(), String>) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("cannot create {}: {}", parent.display(), e))?;
+    }
+    let _lock = Lock::acquire(path).map_err(|e| format!("cannot lock {}: {}", path.display(), e))?;
+    let (mut json, current_is_good) = match read_with_origin(path) {
+        Ok(read) => read,
+        Err(ReadError::Unreadable(e)) => return Err(e),
+        Err(ReadError::Corrupt(_)) => {
+            let aside = sibling(path, ".corrupt");
+            eprintln!("  ⚠ Moving corrupt {} to {}", path.display(), aside.display());
+            fs::rename(path, &aside).map_err(|e| format!("cannot move {}: {}", path.display(), e))?;
+            (serde_json::json!({}), false)
+        }
+    };
+    modify(&mut json)?;
+    write_atomic(path, &json, current_is_good).map_err(|e| format!("cannot write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_updates_keep_every_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bot_settings.json");
+        let threads: Vec<_> = (0..8).map(|t| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                for i in 0..10 {
+                    update(&path, |json| {
+                        json[format!("bot{}", t)] = serde_json::json!(i);
+                        Ok(())
+                    }).unwrap();
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let json = read(&path).unwrap();
+        for t in 0..8 {
+            assert_eq!(json[format!("bot{}", t)], 9);
+        }
+        assert!(backup_path(&path).exists());
+    }
+
+    #[test]
+    fn test_corrupt_file_uses_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bot_settings.json");
+        update(&path, |json| { json["a"] = serde_json::json!({"owner_user_id": 1}); Ok(()) }).unwrap();
+        update(&path, |json| { json["b"] = serde_json::json!({}); Ok(()) }).unwrap();
+        fs::write(&path, "{\"a\": {\"owner").unwrap();
+
+        // The backup holds the state before the last write
+        let json = read(&path).unwrap();
+        assert_eq!(json["a"]["owner_user_id"], 1);
+        assert!(json.get("b").is_none());
+
+        // The next update starts from the backup and does not replace it with the corrupt file
+        update(&path, |json| { json["c"] = serde_json::json!({}); Ok(()) }).unwrap();
+        let json = read(&path).unwrap();
+        assert_eq!(json["a"]["owner_user_id"], 1);
+        assert!(json.get("c").is_some());
+        assert_eq!(read(&backup_path(&path)).unwrap()["a"]["owner_user_id"], 1);
+    }
+
+    #[test]
+    fn test_corrupt_file_without_backup_is_moved_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bot_settings.json");
+        fs::write(&path, "garbage").unwrap();
+        assert!(matches!(read(&path), Err(ReadError::Corrupt(_))));
+
+        update(&path, |json| { json["a"] = serde_json::json!({}); Ok(()) }).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join("bot_settings.json.corrupt")).unwrap(), "garbage");
+        assert!(read(&path).unwrap().get("a").is_some());
+    }
+
+    #[test]
+    fn test_replace_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.json");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        replace(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
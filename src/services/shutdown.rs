@@ -0,0 +1,252 @@
+//! Coordinated shutdown on Ctrl-C or SIGTERM.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+use crate::services::agent::CancelToken;
+
+/// Default grace period for running turns
+pub const DEFAULT_GRACE_SECS: u64 = 30;
+
+/// How long cancelled turns get to save their session and update their message
+const CANCEL_WAIT: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Appended to the partial response of a turn cancelled by the shutdown
+pub const INTERRUPTED_MARK: &str = "[Interrupted by shutdown]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Running,
+    /// New messages are refused, running turns are finishing
+    Draining,
+    /// The grace period is over and the remaining turns are being cancelled
+    Cancelling,
+    /// Every turn is done; bots disconnect
+    Stopped,
+}
+
+/// Grace period for running turns (`AEMI_SHUTDOWN_GRACE`, in seconds)
+pub fn grace_period() -> Duration {
+    static GRACE: OnceLock<Duration> = OnceLock::new();
+    *GRACE.get_or_init(|| {
+        let secs = std::env::var("AEMI_SHUTDOWN_GRACE").ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_GRACE_SECS);
+        Duration::from_secs(secs)
+    })
+}
+
+/// Running turns and the shutdown phase
+pub struct Coordinator {
+    phase: watch::Sender<Phase>,
+    turns: Mutex<HashMap<u64, Arc<CancelToken>>>,
+    next_id: AtomicU64,
+}
+
+/// Registration of a running turn; dropping it marks the turn as done
+pub struct TurnGuard {
+    coordinator: &'static Coordinator,
+    id: u64,
+}
+
+impl Drop for TurnGuard {
+    fn drop(&mut self) {
+        if let Ok(mut turns) = self.coordinator.turns.lock() {
+            turns.remove(&self.id);
+        }
+    }
+}
+
+impl Coordinator {
+    fn new() -> Self {
+        Coordinator {
+            phase: watch::channel(Phase::Running).0,
+            turns: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        *self.phase.borrow()
+    }
+
+    /// Register a running turn until the returned guard is dropped
+    pub fn track(&'static self, token: Arc<CancelToken>) -> TurnGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut turns) = self.turns.lock() {
+            turns.insert(id, token);
+        }
+        TurnGuard { coordinator: self, id }
+    }
+
+    fn running(&self) -> Vec<Arc<CancelToken>> {
+        self.turns.lock().map(|turns| turns.values().cloned().collect()).unwrap_or_default()
+    }
+
+    async fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.running().is_empty() {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Refuse new turns, give running ones `grace` to finish, cancel the rest and wait
+    /// for them to save. Returns the number of cancelled turns.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        self.phase.send_replace(Phase::Draining);
+        let mut cancelled = 0;
+        if !self.wait_idle(grace).await {
+            self.phase.send_replace(Phase::Cancelling);
+            let left = self.running();
+            cancelled = left.len();
+            for token in left {
+                token.cancelled.store(true, Ordering::Relaxed);
+                token.kill_child();
+            }
+            self.wait_idle(CANCEL_WAIT).await;
+        }
+        self.phase.send_replace(Phase::Stopped);
+        cancelled
+    }
+
+    /// Resolves once the drain is over
+    pub async fn stopped(&self) {
+        let mut phase = self.phase.subscribe();
+        let _ = phase.wait_for(|p| *p == Phase::Stopped).await;
+    }
+}
+
+fn coordinator() -> &'static Coordinator {
+    static COORDINATOR: OnceLock<Coordinator> = OnceLock::new();
+    COORDINATOR.get_or_init(Coordinator::new)
+}
+
+/// True once a shutdown has started; no new turns are accepted
+pub fn is_shutting_down() -> bool {
+    coordinator().phase() != Phase::Running
+}
+
+/// True if a cancelled turn was cut off by the shutdown rather than by /stop
+pub fn interrupts_turns() -> bool {
+    matches!(coordinator().phase(), Phase::Cancelling | Phase::Stopped)
+}
+
+/// Register a running turn for the process-wide shutdown
+pub fn track(token: Arc<CancelToken>) -> TurnGuard {
+    coordinator().track(token)
+}
+
+/// Resolves when the process-wide shutdown has drained every turn
+pub async fn stopped() {
+    coordinator().stopped().await
+}
+
+/// Listen for Ctrl-C and SIGTERM (once per process, however many bots run).
+/// The first signal starts draining: no new turns, and running ones get [`grace_period`]
+/// to finish before they are cancelled, marked [`INTERRUPTED_MARK`] and their session saved.
+/// A second signal exits at once.
+pub fn listen() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        let (tx, mut signals) = tokio::sync::mpsc::unbounded_channel::<&'static str>();
+        let ctrl_c = tx.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if ctrl_c.send("Ctrl-C").is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            let Ok(mut term) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) else {
+                return;
+            };
+            while term.recv().await.is_some() {
+                if tx.send("SIGTERM").is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            let Some(signal) = signals.recv().await else { return };
+            let grace = grace_period();
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ⏻ {signal}: shutting down, running turns have {}s to finish (again to exit now)", grace.as_secs());
+            tokio::spawn(async move {
+                if let Some(signal) = signals.recv().await {
+                    let ts = chrono::Local::now().format("%H:%M:%S");
+                    println!("  [{ts}] ⏻ {signal} again: exiting now");
+                    std::process::exit(130);
+                }
+            });
+            let cancelled = coordinator().drain(grace).await;
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            if cancelled > 0 {
+                println!("  [{ts}] ⏻ Interrupted {cancelled} running turn(s)");
+            }
+            println!("  [{ts}] ⏻ Shutdown complete");
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaked() -> &'static Coordinator {
+        Box::leak(Box::new(Coordinator::new()))
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap()
+    }
+
+    #[test]
+    fn test_drain_waits_for_turns() {
+        let coordinator = leaked();
+        let token = Arc::new(CancelToken::new());
+        let guard = coordinator.track(token.clone());
+        runtime().block_on(async {
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                drop(guard);
+            });
+            assert_eq!(coordinator.drain(Duration::from_secs(5)).await, 0);
+        });
+        assert!(!token.cancelled.load(Ordering::Relaxed));
+        assert_eq!(coordinator.phase(), Phase::Stopped);
+    }
+
+    #[test]
+    fn test_drain_cancels_after_grace() {
+        let coordinator = leaked();
+        let token = Arc::new(CancelToken::new());
+        let guard = coordinator.track(token.clone());
+        let watched = token.clone();
+        runtime().block_on(async {
+            // A turn that only ends when cancelled, like the polling loop in chat.rs
+            tokio::spawn(async move {
+                while !watched.cancelled.load(Ordering::Relaxed) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                drop(guard);
+            });
+            assert_eq!(coordinator.drain(Duration::from_millis(50)).await, 1);
+            coordinator.stopped().await;
+        });
+        assert!(token.cancelled.load(Ordering::Relaxed));
+        assert!(coordinator.running().is_empty());
+    }
+}
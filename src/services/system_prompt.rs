@@ -0,0 +1,238 @@
+//! Layered system prompts for bot turns.
+
+use std::path::Path;
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::services::claude::DEFAULT_ALLOWED_TOOLS;
+use crate::services::config::Platform;
+use crate::services::template;
+use crate::services::utils::truncate_str;
+
+/// Placeholders a template may use
+pub const PLACEHOLDERS: &[&str] = &["cwd", "platform", "chat_id", "disabled_tools", "key"];
+
+/// Instructions file read from the session directory
+pub const WORKSPACE_FILE: &str = "AEMI.md";
+
+/// Longer workspace instructions are cut off
+const WORKSPACE_FILE_LIMIT: usize = 16 * 1024;
+
+/// Bot-wide template used when none is set
+pub const DEFAULT_TEMPLATE: &str = "You are chatting with a user through {platform}.\n\
+Current working directory: {cwd}\n\n\
+Always keep the user informed about what you are doing. \
+Briefly explain each step as you work (e.g. \"Reading the file...\", \"Creating the script...\", \"Running tests...\"). \
+The user cannot see your tool calls, so narrate your progress so they know what is happening.\n\n\
+IMPORTANT: The user is on {platform} and CANNOT interact with any interactive prompts, dialogs, or confirmation requests. \
+All tools that require user interaction (such as AskUserQuestion, EnterPlanMode, ExitPlanMode) will NOT work. \
+Never use tools that expect user interaction. If you need clarification, just ask in plain text.";
+
+const TELEGRAM_NOTES: &str = "When your work produces a file the user would want (generated code, reports, images, archives, etc.),\n\
+send it by running this bash command:\n\n\
+aemi send file <filepath> --chat {chat_id} --key {key}\n\n\
+This delivers the file directly to the user's Telegram chat.\n\
+Do NOT tell the user to use /down — use the command above instead.";
+
+const DISCORD_NOTES: &str = "When your work produces a file the user would want (generated code, reports, images, archives, etc.),\n\
+save it to the working directory and tell the user to use `/down <filepath>` to download it.\n\n\
+FORMATTING RULES FOR DISCORD:\n\
+- Use **bold** for emphasis\n\
+- Use `code` for inline code, file paths, commands\n\
+- Use ```language for code blocks (e.g. ```rust, ```bash)\n\
+- Use > for blockquotes\n\
+- Keep messages concise — Discord has a 2000 character limit per message\n\
+- Avoid headers (# Title) — they render as large text in Discord\n\
+- Use bullet lists (- item) for multiple items\n\
+- NEVER write triple backticks in regular text or inline code — Discord misinterprets them as code block markers. Say \"code block\" in words instead.\n\
+Token hash: {key}";
+
+/// What a prompt is rendered for
+pub struct PromptContext<'a> {
+    pub platform: Platform,
+    /// Session directory
+    pub cwd: &'a str,
+    /// Chat id (Telegram) or channel id (Discord)
+    pub chat_id: String,
+    /// The bot's token hash, the key for `aemi send`
+    pub key: &'a str,
+    /// Tools the turn may use
+    pub allowed_tools: &'a [String],
+    /// The turn was sent by a viewer
+    pub read_only: bool,
+}
+
+impl PromptContext<'_> {
+    /// Default tools missing from the turn's tool list, in their usual order
+    fn disabled_tools(&self) -> Vec<&'static str> {
+        DEFAULT_ALLOWED_TOOLS.iter()
+            .filter(|tool| !self.allowed_tools.iter().any(|t| t == *tool))
+            .copied()
+            .collect()
+    }
+}
+
+fn platform_title(platform: Platform) -> &'static str {
+    match platform {
+        Platform::Telegram => "Telegram",
+        Platform::Discord => "Discord",
+    }
+}
+
+/// `{name}` placeholders
+fn placeholder_regex() -> Option<&'static Regex> {
+    static RE: OnceLock<Option<Regex>> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\{([a-z_]+)\}").ok()).as_ref()
+}
+
+/// Fill in the placeholders of `template` in a single pass, so values that look like
+/// placeholders (a directory named `{chat_id}`) are not expanded again.
+/// Unknown placeholders are left as they are.
+pub fn render(template: &str, ctx: &PromptContext) -> String {
+    let Some(re) = placeholder_regex() else {
+        return template.to_string();
+    };
+    let disabled = ctx.disabled_tools();
+    let disabled = if disabled.is_empty() { "none".to_string() } else { disabled.join(", ") };
+    re.replace_all(template, |caps: &regex::Captures| match &caps[1] {
+        "cwd" => ctx.cwd.to_string(),
+        "platform" => platform_title(ctx.platform).to_string(),
+        "chat_id" => ctx.chat_id.clone(),
+        "disabled_tools" => disabled.clone(),
+        "key" => ctx.key.to_string(),
+        _ => caps[0].to_string(),
+    }).into_owned()
+}
+
+/// Placeholders in `template` that [`render`] does not know, to reject a typo before it
+/// reaches the agent
+pub fn unknown_placeholders(template: &str) -> Vec<String> {
+    let Some(re) = placeholder_regex() else {
+        return Vec::new();
+    };
+    let mut unknown: Vec<String> = re.captures_iter(template)
+        .map(|c| c[1].to_string())
+        .filter(|name| !PLACEHOLDERS.contains(&name.as_str()))
+        .collect();
+    unknown.sort();
+    unknown.dedup();
+    unknown
+}
+
+fn disabled_tools_notice(ctx: &PromptContext) -> Option<String> {
+    let disabled = ctx.disabled_tools();
+    if disabled.is_empty() {
+        return None;
+    }
+    let names = disabled.join(", ");
+    Some(if ctx.read_only {
+        format!(
+            "READ-ONLY USER: This user has the viewer role, so these tools are disabled: {}.\n\
+             You MUST NOT attempt to use these tools or change any files. \
+             If a request needs them, do NOT proceed; explain that an admin must give the user the user role.",
+            names
+        )
+    } else {
+        format!(
+            "DISABLED TOOLS: The following tools have been disabled by the user: {}.\n\
+             You MUST NOT attempt to use these tools. \
+             If a user's request requires a disabled tool, do NOT proceed with the task. \
+             Instead, clearly inform the user which tool is needed and that it is currently disabled. \
+             Suggest they re-enable it with: /allowed +ToolName",
+            names
+        )
+    })
+}
+
+/// Contents of `AEMI.md` in the session directory, if there is one
+pub fn workspace_instructions(cwd: &str) -> Option<String> {
+    let text = std::fs::read_to_string(Path::new(cwd).join(WORKSPACE_FILE)).ok()?;
+    let text = text.trim();
+    if text.is_empty() { None } else { Some(truncate_str(text, WORKSPACE_FILE_LIMIT)) }
+}
+
+/// The full system prompt of a turn. `template` is the bot-wide template, `additions`
+/// the chat's or bot's system prompt additions. Layers, in order:
+/// 1. the bot-wide template (else [`DEFAULT_TEMPLATE`]) with its placeholders filled in
+/// 2. the platform's notes: how files reach the user and how to format replies
+/// 3. the disabled-tools notice, when the chat's tool list or the sender's role leaves
+///    default tools out
+/// 4. the workspace template's `prompt.md`, for workspaces made with `/start --template`
+/// 5. `AEMI.md` in the session directory
+/// 6. the chat's additions (`/systemprompt`, `/settings`), else the bot-wide ones
+pub fn build(template: Option<&str>, ctx: &PromptContext, additions: Option<&str>) -> String {
+    let notes = match ctx.platform {
+        Platform::Telegram => TELEGRAM_NOTES,
+        Platform::Discord => DISCORD_NOTES,
+    };
+    let mut layers = vec![render(template.unwrap_or(DEFAULT_TEMPLATE), ctx), render(notes, ctx)];
+    layers.extend(disabled_tools_notice(ctx));
+    layers.extend(template::prompt_for_workspace(ctx.cwd));
+    layers.extend(workspace_instructions(ctx.cwd));
+    layers.extend(additions.map(str::trim).filter(|a| !a.is_empty()).map(String::from));
+    layers.join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context<'a>(cwd: &'a str, tools: &'a [String]) -> PromptContext<'a> {
+        PromptContext {
+            platform: Platform::Telegram,
+            cwd,
+            chat_id: "42".to_string(),
+            key: "abc123",
+            allowed_tools: tools,
+            read_only: false,
+        }
+    }
+
+    fn all_tools() -> Vec<String> {
+        DEFAULT_ALLOWED_TOOLS.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_render_placeholders() {
+        let tools: Vec<String> = all_tools().into_iter().filter(|t| t != "Bash").collect();
+        let ctx = context("/work", &tools);
+        assert_eq!(
+            render("{platform} chat {chat_id} in {cwd}, without {disabled_tools}; {other}", &ctx),
+            "Telegram chat 42 in /work, without Bash; {other}"
+        );
+        let tools = all_tools();
+        assert_eq!(render("{disabled_tools}", &context("/work", &tools)), "none");
+        assert_eq!(render("--key {key}", &context("/work", &tools)), "--key abc123");
+        // Values are not expanded again
+        assert_eq!(render("{cwd} {platform}", &context("/tmp/{chat_id}", &tools)), "/tmp/{chat_id} Telegram");
+    }
+
+    #[test]
+    fn test_unknown_placeholders() {
+        assert!(unknown_placeholders("In {cwd} on {platform}, fn main() {}").is_empty());
+        assert!(unknown_placeholders("aemi send file x --key {key}").is_empty());
+        assert_eq!(unknown_placeholders("{cwd} {dir} {dir} {Key}"), vec!["dir"]);
+    }
+
+    #[test]
+    fn test_build_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path().to_str().unwrap();
+        let tools = all_tools();
+
+        let prompt = build(None, &context(cwd, &tools), None);
+        assert!(prompt.starts_with("You are chatting with a user through Telegram.\nCurrent working directory: "));
+        assert!(prompt.contains("aemi send file <filepath> --chat 42 --key abc123"));
+        assert!(!prompt.contains("DISABLED TOOLS"));
+
+        std::fs::write(dir.path().join(WORKSPACE_FILE), "Run `make check` before answering.\n").unwrap();
+        let tools: Vec<String> = tools.into_iter().filter(|t| t != "Write").collect();
+        let prompt = build(Some("You are Ada, working in {cwd}."), &context(cwd, &tools), Some("Answer in French."));
+        assert!(prompt.starts_with(&format!("You are Ada, working in {}.\n\n", cwd)));
+        let disabled = prompt.find("DISABLED TOOLS: The following tools have been disabled by the user: Write.").unwrap();
+        let workspace = prompt.find("Run `make check` before answering.").unwrap();
+        assert!(disabled < workspace);
+        assert!(prompt.ends_with("\n\nAnswer in French."));
+    }
+}
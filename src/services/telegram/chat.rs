@@ -6,16 +6,24 @@ use teloxide::prelude::*;
 use teloxide::types::ParseMode;
 
 use crate::services::agent::{CancelToken, StreamMessage};
-use crate::services::claude::{self, DEFAULT_ALLOWED_TOOLS};
+use crate::services::claude;
 use crate::services::gemini;
 use crate::services::codex;
 use crate::services::opencode;
 use crate::services::oh_my_pi;
 use crate::services::provider_common;
-use crate::services::session::{self, HistoryItem, HistoryType};
+use crate::services::session::{self, HistoryItem, HistoryType, Usage};
 use crate::services::formatter;
 use crate::services::utils::{truncate_str, normalize_empty_lines};
+use crate::services::retention;
 use crate::services::bot_common;
+use crate::services::checkpoint;
+use crate::services::compact;
+use crate::services::roles::{self, Role};
+use crate::services::chat_state::Turn;
+use crate::services::shutdown;
+use crate::services::config::Platform;
+use crate::services::system_prompt::{self, PromptContext};
 
 use super::{SharedState, TELEGRAM_MSG_LIMIT, token_hash};
 use super::messages::{shared_rate_limit_wait, send_long_message};
@@ -26,23 +34,38 @@ fn format_tool_input(name: &str, input: &str) -> String {
     formatter::format_tool_input(name, input, false)
 }
 
-/// Handle regular text messages - send to Claude AI
+/// Handle regular text messages - send to Claude AI.
+/// `reserved` is the placeholder token of a turn for queued messages (see [`run_queued_messages`]).
 pub async fn handle_text_message(
     bot: &Bot,
     chat_id: ChatId,
     user_text: &str,
     user_msg_id: teloxide::types::MessageId,
+    role: Role,
     state: &SharedState,
+    reserved: Option<&Arc<CancelToken>>,
 ) -> ResponseResult<()> {
+    // Settings in effect for this chat: its own over the bot-wide ones
+    let settings = state.lock().await.effective_settings(chat_id);
+    if role == Role::Viewer {
+        let agent = &settings.agent.value;
+        if !roles::supports_read_only(agent) {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, format!("⛔ Viewers cannot chat with {agent}: it ignores the read-only tool list. Ask an admin to switch to /agent claude."))
+                .await?;
+            return Ok(());
+        }
+    }
+
     // Get session info, allowed tools, and pending uploads (drop lock before any await)
-    let (session_info, allowed_tools, pending_uploads) = {
+    let (session_info, allowed_tools, pending_uploads, prompt_template) = {
         let mut data = state.lock().await;
         let info = data.sessions.get(&chat_id).and_then(|session| {
             session.current_path.as_ref().map(|_| {
                 (session.session_id.clone(), session.current_path.clone().unwrap_or_default())
             })
         });
-        let tools = data.settings.allowed_tools.clone();
+        let tools = roles::tools_for(role, &settings.allowed_tools.value);
         // Drain pending uploads so they are sent to Claude exactly once
         let uploads = data.sessions.get_mut(&chat_id)
             .map(|s| {
@@ -50,7 +73,7 @@ pub async fn handle_text_message(
                 std::mem::take(&mut s.pending_uploads)
             })
             .unwrap_or_default();
-        (info, tools, uploads)
+        (info, tools, uploads, data.settings.prompt_template.clone())
     };
 
     let (session_id, current_path) = match session_info {
@@ -83,119 +106,39 @@ pub async fn handle_text_message(
         format!("{}\n\n{}", upload_context, sanitized_input)
     };
 
-    // Build disabled tools notice
-    let default_tools: std::collections::HashSet<&str> = DEFAULT_ALLOWED_TOOLS.iter().copied().collect();
-    let allowed_set: std::collections::HashSet<&str> = allowed_tools.iter().map(|s| s.as_str()).collect();
-    let disabled: Vec<&&str> = default_tools.iter().filter(|t| !allowed_set.contains(**t)).collect();
-    let disabled_notice = if disabled.is_empty() {
-        String::new()
-    } else {
-        let names: Vec<&str> = disabled.iter().map(|t| **t).collect();
-        format!(
-            "\n\nDISABLED TOOLS: The following tools have been disabled by the user: {}.\n\
-             You MUST NOT attempt to use these tools. \
-             If a user's request requires a disabled tool, do NOT proceed with the task. \
-             Instead, clearly inform the user which tool is needed and that it is currently disabled. \
-             Suggest they re-enable it with: /allowed +ToolName",
-            names.join(", ")
-        )
-    };
-
-    // Build system prompt with sendfile instructions
-    let system_prompt_owned = format!(
-        "You are chatting with a user through Telegram.\n\
-         Current working directory: {}\n\n\
-         When your work produces a file the user would want (generated code, reports, images, archives, etc.),\n\
-         send it by running this bash command:\n\n\
-         aemi --sendfile <filepath> --chat {} --key {}\n\n\
-         This delivers the file directly to the user's Telegram chat.\n\
-         Do NOT tell the user to use /down — use the command above instead.\n\n\
-         Always keep the user informed about what you are doing. \
-         Briefly explain each step as you work (e.g. \"Reading the file...\", \"Creating the script...\", \"Running tests...\"). \
-         The user cannot see your tool calls, so narrate your progress so they know what is happening.\n\n\
-         IMPORTANT: The user is on Telegram and CANNOT interact with any interactive prompts, dialogs, or confirmation requests. \
-         All tools that require user interaction (such as AskUserQuestion, EnterPlanMode, ExitPlanMode) will NOT work. \
-         Never use tools that expect user interaction. If you need clarification, just ask in plain text.{}",
-        current_path, chat_id.0, token_hash(bot.token()), disabled_notice
+    // Layered system prompt: bot template, platform notes, workspace files, chat additions
+    let system_prompt_owned = build_system_prompt(
+        prompt_template.as_deref(), settings.system_prompt.value.as_deref(), &allowed_tools, role, chat_id, &current_path, &token_hash(bot.token()),
     );
 
-    // Create cancel token for this request
-    let cancel_token = Arc::new(CancelToken::new());
-    {
+    // Create cancel token for this request in place of a queued turn's reservation, and
+    // mark the turn as running so a restart before it ends can tell the user
+    let cancel_token = {
         let mut data = state.lock().await;
-        data.cancel_tokens.insert(chat_id, cancel_token.clone());
-    }
-
-    // Create channel for streaming
-    let (tx, rx) = mpsc::channel();
-
-    let session_id_clone = session_id.clone();
-    let current_path_clone = current_path.clone();
-    let cancel_token_clone = cancel_token.clone();
-
-    // Get agent type from state
-    let agent_type = {
-        let data = state.lock().await;
-        data.agent_type.clone()
-    };
-
-    // Run agent in a blocking thread
-    tokio::task::spawn_blocking(move || {
-        let result = match agent_type.as_str() {
-            "gemini" => gemini::execute_command_streaming(
-                &context_prompt,
-                session_id_clone.as_deref(),
-                &current_path_clone,
-                tx.clone(),
-                Some(&system_prompt_owned),
-                Some(&allowed_tools),
-                Some(cancel_token_clone),
-            ),
-            "codex" => codex::execute_command_streaming(
-                &context_prompt,
-                session_id_clone.as_deref(),
-                &current_path_clone,
-                tx.clone(),
-                Some(&system_prompt_owned),
-                Some(&allowed_tools),
-                Some(cancel_token_clone),
-            ),
-            "opencode" => opencode::execute_command_streaming(
-                &context_prompt,
-                session_id_clone.as_deref(),
-                &current_path_clone,
-                tx.clone(),
-                Some(&system_prompt_owned),
-                Some(&allowed_tools),
-                Some(cancel_token_clone),
-            ),
-            "oh-my-pi" => oh_my_pi::execute_command_streaming(
-                &context_prompt,
-                session_id_clone.as_deref(),
-                &current_path_clone,
-                tx.clone(),
-                Some(&system_prompt_owned),
-                Some(&allowed_tools),
-                Some(cancel_token_clone),
-            ),
-            _ => claude::execute_command_streaming(
-                &context_prompt,
-                session_id_clone.as_deref(),
-                &current_path_clone,
-                tx.clone(),
-                Some(&system_prompt_owned),
-                Some(&allowed_tools),
-                Some(cancel_token_clone),
-            ),
+        let Some(cancel_token) = bot_common::start_turn(&mut data.cancel_tokens, chat_id, reserved) else {
+            // Stopped while it was queued
+            let stop_msg_id = data.stop_message_ids.remove(&chat_id);
+            drop(data);
+            shared_rate_limit_wait(state, chat_id).await;
+            let _ = bot.edit_message_text(chat_id, placeholder_msg_id, "[Stopped]").await;
+            if let Some(msg_id) = stop_msg_id {
+                shared_rate_limit_wait(state, chat_id).await;
+                let _ = bot.delete_message(chat_id, msg_id).await;
+            }
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ■ Stopped before start");
+            return Ok(());
         };
+        data.settings.chat_state.entry(chat_id.0.to_string()).or_default()
+            .start_turn(Turn::new(user_text, &settings.agent.value));
+        bot_common::save_bot_settings(&token_hash(bot.token()), &data.settings, &[("platform", "telegram")]);
+        cancel_token
+    };
+    let turn_guard = shutdown::track(cancel_token.clone());
 
-        if let Err(e) = result {
-            let _ = tx.send(StreamMessage::Error { message: e });
-        }
-    });
-
-    // Spawn the polling loop as a separate task so the handler returns immediately.
+    // Compact, start the agent and poll it in a separate task so the handler returns immediately.
     // This allows teloxide's per-chat worker to process subsequent messages (e.g. /stop).
+    let origin = super::session_origin(bot, chat_id);
     let bot_owned = bot.clone();
     let state_owned = state.clone();
     let user_text_owned = user_text.to_string();
@@ -205,6 +148,152 @@ pub async fn handle_text_message(
     let watcher_placeholder_msg_id_num = placeholder_msg_id_num;
 
     let polling_handle = tokio::spawn(async move {
+        // The shutdown waits for this turn until the task ends
+        let _turn_guard = turn_guard;
+        let bot = &bot_owned;
+        let state = &state_owned;
+        let user_text = user_text_owned.as_str();
+
+        // Compact first if the last turn overflowed the context or the history is over AEMI_AUTO_COMPACT
+        let should_compact = {
+            let data = state.lock().await;
+            data.sessions.get(&chat_id)
+                .map(|s| s.compact_brief.is_none() && (s.needs_compact || compact::over_threshold(&s.history)))
+                .unwrap_or(false)
+        };
+        if should_compact {
+            shared_rate_limit_wait(state, chat_id).await;
+            let _ = bot.edit_message_text(chat_id, placeholder_msg_id, "🗜 Compacting conversation...").await;
+            if let Err(e) = super::commands::compact_chat(chat_id, state, &cancel_token).await {
+                let ts = chrono::Local::now().format("%H:%M:%S");
+                println!("  [{ts}]   ⚠ Auto-compact failed: {e}");
+                shared_rate_limit_wait(state, chat_id).await;
+                let _ = bot.send_message(chat_id, format!("Auto-compact failed: {}", e)).await;
+            }
+        }
+
+        // Snapshot the workspace so /undo can roll this turn back
+        checkpoint::create_before_turn(&current_path, user_text).await;
+
+        // Create channel for streaming
+        let (tx, rx) = mpsc::channel();
+
+        let current_path_clone = current_path.clone();
+        let cancel_token_clone = cancel_token.clone();
+
+        // Get agent type and pending /fork parent from state
+        let (agent_type, fork_from, fork_history, compact_brief) = {
+            let data = state.lock().await;
+            let agent = settings.agent.value.clone();
+            let session = data.sessions.get(&chat_id);
+            let fork_from = session.and_then(|s| s.fork_from.clone());
+            let history = session.map(|s| s.history.clone()).unwrap_or_default();
+            let compact_brief = session.and_then(|s| s.compact_brief.clone());
+            (agent, fork_from, history, compact_brief)
+        };
+
+        // Forked sessions: resume the parent with a native fork flag when the agent supports it,
+        // otherwise start a fresh provider session seeded with the copied transcript.
+        // A compacted session always starts fresh, seeded with the /compact brief.
+        let fork_native = compact_brief.is_none() && fork_from.is_some() && bot_common::supports_native_fork(&agent_type);
+        let (session_id_clone, context_prompt) = match (&compact_brief, &fork_from) {
+            (Some(brief), _) => (None, format!("{}\n\n{}", compact::seed_prompt(brief), context_prompt)),
+            (None, Some(parent)) if fork_native => (Some(parent.clone()), context_prompt),
+            (None, Some(_)) => (None, format!("{}\n\n{}", bot_common::fork_context_prompt(&fork_history), context_prompt)),
+            (None, None) => (session_id.clone(), context_prompt),
+        };
+
+        // Per-turn metadata recorded on the assistant reply
+        let agent_owned = agent_type.clone();
+        let turn_started = std::time::Instant::now();
+
+        let model = settings.model.value.clone();
+        let verbosity = settings.verbosity.value;
+
+        // Run agent in a blocking thread, unless the turn was stopped while compacting
+        let stopped = cancel_token.cancelled.load(Ordering::Relaxed);
+        tokio::task::spawn_blocking(move || {
+            if stopped {
+                return;
+            }
+            let result = match agent_type.as_str() {
+                "gemini" => gemini::execute_command_streaming(
+                    &context_prompt,
+                    session_id_clone.as_deref(),
+                    &current_path_clone,
+                    tx.clone(),
+                    Some(&system_prompt_owned),
+                    Some(&allowed_tools),
+                    model.as_deref(),
+                    Some(cancel_token_clone),
+                ),
+                "codex" => codex::execute_command_streaming(
+                    &context_prompt,
+                    session_id_clone.as_deref(),
+                    &current_path_clone,
+                    tx.clone(),
+                    Some(&system_prompt_owned),
+                    Some(&allowed_tools),
+                    model.as_deref(),
+                    Some(cancel_token_clone),
+                ),
+                "opencode" => opencode::execute_command_streaming(
+                    &context_prompt,
+                    session_id_clone.as_deref(),
+                    &current_path_clone,
+                    tx.clone(),
+                    Some(&system_prompt_owned),
+                    Some(&allowed_tools),
+                    model.as_deref(),
+                    Some(cancel_token_clone),
+                ),
+                "oh-my-pi" if fork_native => oh_my_pi::execute_command_streaming_fork(
+                    &context_prompt,
+                    session_id_clone.as_deref(),
+                    &current_path_clone,
+                    tx.clone(),
+                    Some(&system_prompt_owned),
+                    Some(&allowed_tools),
+                    model.as_deref(),
+                    Some(cancel_token_clone),
+                ),
+                "oh-my-pi" => oh_my_pi::execute_command_streaming(
+                    &context_prompt,
+                    session_id_clone.as_deref(),
+                    &current_path_clone,
+                    tx.clone(),
+                    Some(&system_prompt_owned),
+                    Some(&allowed_tools),
+                    model.as_deref(),
+                    Some(cancel_token_clone),
+                ),
+                _ if fork_native => claude::execute_command_streaming_fork(
+                    &context_prompt,
+                    session_id_clone.as_deref(),
+                    &current_path_clone,
+                    tx.clone(),
+                    Some(&system_prompt_owned),
+                    Some(&allowed_tools),
+                    model.as_deref(),
+                    Some(cancel_token_clone),
+                ),
+                _ => claude::execute_command_streaming(
+                    &context_prompt,
+                    session_id_clone.as_deref(),
+                    &current_path_clone,
+                    tx.clone(),
+                    Some(&system_prompt_owned),
+                    Some(&allowed_tools),
+                    model.as_deref(),
+                    Some(cancel_token_clone),
+                ),
+            };
+
+            if let Err(e) = result {
+                let _ = tx.send(StreamMessage::Error { message: e });
+            }
+        });
+
         const SPINNER_CLOCKS: &[&str] = &[
             "🕐", "🕑", "🕒", "🕓", "🕔", "🕕",
             "🕖", "🕗", "🕘", "🕙", "🕚", "🕛",
@@ -222,7 +311,10 @@ pub async fn handle_text_message(
         let mut done = false;
         let mut cancelled = false;
         let mut new_session_id: Option<String> = None;
+        let mut turn_model: Option<String> = None;
+        let mut turn_usage: Option<Usage> = None;
         let mut session_not_found = false;
+        let mut context_overflow = false;
         let mut spin_idx: usize = 0;
         let mut last_tool_name = String::new();
         let mut last_file_path = String::new();
@@ -261,7 +353,9 @@ pub async fn handle_text_message(
                                 let summary = format_tool_input(&name, &input);
                                 let ts = chrono::Local::now().format("%H:%M:%S");
                                 println!("  [{ts}]   ⚙ {name}: {}", truncate_str(&summary, 80));
-                                full_response.push_str(&format!("\n\n⚙️ {}\n", summary));
+                                if verbosity.shows_tool_calls() {
+                                    full_response.push_str(&format!("\n\n⚙️ {}\n", summary));
+                                }
                                 // Update progress phase with current tool name
                                 progress_phase = format!("⚙️ {name}");
                                 // Extract file path for language detection in subsequent ToolResult
@@ -286,16 +380,24 @@ pub async fn handle_text_message(
                                 }
                                 let file_hint = if last_file_path.is_empty() { None } else { Some(last_file_path.as_str()) };
                                 let formatted = formatter::format_tool_result(&content, is_error, &last_tool_name, file_hint);
-                                if !formatted.is_empty() {
+                                if !formatted.is_empty() && verbosity.shows_tool_output() {
                                     full_response.push_str(&formatted);
                                 }
                                 progress_phase = String::from("Thinking");
                             }
                             StreamMessage::TaskNotification { summary, .. } => {
-                                if !summary.is_empty() {
+                                if !summary.is_empty() && verbosity.shows_tool_calls() {
                                     full_response.push_str(&format!("\n[Task: {}]\n", summary));
                                 }
                             }
+                            StreamMessage::Usage { model, usage } => {
+                                if model.is_some() {
+                                    turn_model = model;
+                                }
+                                if usage.is_some() {
+                                    turn_usage = usage;
+                                }
+                            }
                             StreamMessage::Done { result, session_id: sid } => {
                                 if !result.is_empty() && full_response.is_empty() {
                                     full_response = result;
@@ -312,6 +414,10 @@ pub async fn handle_text_message(
                                     session_not_found = true;
                                 }
                                 full_response = format!("Error: {}", message);
+                                if compact::is_context_overflow(&message) {
+                                    context_overflow = true;
+                                    full_response.push_str("\n\nThe conversation no longer fits the agent's context. It will be compacted before your next message (or run /compact now).");
+                                }
                                 done = true;
                             }
                         }
@@ -368,22 +474,17 @@ pub async fn handle_text_message(
             // handle_stop_command may have missed the kill if the PID wasn't stored yet
             // (race condition when /stop arrives before spawn_blocking runs).
             // By now the blocking thread has most likely started and stored the PID.
-            if let Ok(guard) = cancel_token.child_pid.lock() {
-                if let Some(pid) = *guard {
-                    #[cfg(unix)]
-                    #[allow(unsafe_code)]
-                    unsafe {
-                        libc::kill(pid as libc::pid_t, libc::SIGTERM);
-                    }
-                }
-            }
+            cancel_token.kill_child();
 
-            // Build stopped response: show partial content + [Stopped] indicator
+            // Build stopped response: show partial content + [Stopped] indicator,
+            // or [Interrupted by shutdown] when the shutdown's grace period ran out
+            let interrupted = shutdown::interrupts_turns();
+            let mark = if interrupted { shutdown::INTERRUPTED_MARK } else { "[Stopped]" };
             let stopped_response = if full_response.trim().is_empty() {
-                "[Stopped]".to_string()
+                mark.to_string()
             } else {
                 let normalized = normalize_empty_lines(&full_response);
-                format!("{}\n\n[Stopped]", normalized)
+                format!("{}\n\n{}", normalized, mark)
             };
 
             // Rate limit before final API call
@@ -449,21 +550,35 @@ pub async fn handle_text_message(
                     if session_not_found {
                         session.session_id = None;
                     } else if let Some(sid) = new_session_id {
+                        // A forked or compacted placeholder becomes the provider's new session
+                        if session.fork_from.take().is_some() {
+                            if let Some(fork_id) = session.session_id.as_deref() {
+                                bot_common::rename_session(fork_id, &sid);
+                            }
+                        }
                         session.session_id = Some(sid);
+                        session.compact_brief = None;
                     }
-                    session.history.push(HistoryItem {
-                        item_type: HistoryType::User,
-                        content: user_text_owned,
-                    });
-                    session.history.push(HistoryItem {
-                        item_type: HistoryType::Assistant,
-                        content: stopped_response,
-                    });
-
-                    bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &current_path);
+                    session.history.push(HistoryItem::new(HistoryType::User, user_text_owned));
+                    session.history.push(
+                        HistoryItem::new(HistoryType::Assistant, stopped_response)
+                            .with_turn_meta(&agent_owned, turn_model.take(), turn_started.elapsed(), turn_usage.take()),
+                    );
+
+                    retention::trim_history(session.session_id.as_deref(), &mut session.history);
+                    bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &current_path, &origin);
                 }
             }
+            if interrupted {
+                // Stays marked running, so the chat is offered /retry after the restart
+                data.remember_session(chat_id);
+            } else {
+                data.finish_turn(chat_id);
+            }
+            bot_common::save_bot_settings(&token_hash(bot_owned.token()), &data.settings, &[("platform", "telegram")]);
 
+            // Messages queued after /stop run next
+            run_queued_messages(bot_owned.clone(), chat_id, state_owned.clone());
             return;
         }
 
@@ -544,20 +659,30 @@ pub async fn handle_text_message(
                     if session_not_found {
                         session.session_id = None;
                     } else if let Some(sid) = new_session_id {
+                        // A forked or compacted placeholder becomes the provider's new session
+                        if session.fork_from.take().is_some() {
+                            if let Some(fork_id) = session.session_id.as_deref() {
+                                bot_common::rename_session(fork_id, &sid);
+                            }
+                        }
                         session.session_id = Some(sid);
+                        session.compact_brief = None;
+                    }
+                    if context_overflow {
+                        session.needs_compact = true;
                     }
-                    session.history.push(HistoryItem {
-                        item_type: HistoryType::User,
-                        content: user_text_owned,
-                    });
-                    session.history.push(HistoryItem {
-                        item_type: HistoryType::Assistant,
-                        content: full_response,
-                    });
-
-                    bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &current_path);
+                    session.history.push(HistoryItem::new(HistoryType::User, user_text_owned));
+                    session.history.push(
+                        HistoryItem::new(HistoryType::Assistant, full_response)
+                            .with_turn_meta(&agent_owned, turn_model.take(), turn_started.elapsed(), turn_usage.take()),
+                    );
+
+                    retention::trim_history(session.session_id.as_deref(), &mut session.history);
+                    bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &current_path, &origin);
                 }
             }
+            data.finish_turn(chat_id);
+            bot_common::save_bot_settings(&token_hash(bot_owned.token()), &data.settings, &[("platform", "telegram")]);
         }
 
         // Send a reply to the user's original message so they get a notification
@@ -568,6 +693,9 @@ pub async fn handle_text_message(
 
         let ts = chrono::Local::now().format("%H:%M:%S");
         println!("  [{ts}] ▶ Response sent");
+
+        // Send messages queued during this turn as the next turn
+        run_queued_messages(bot_owned.clone(), chat_id, state_owned.clone());
         provider_common::debug_log_for(
             "telegram",
             &format!(
@@ -593,3 +721,60 @@ pub async fn handle_text_message(
 
     Ok(())
 }
+
+/// System prompt of a turn in this chat, also shown by `/systemprompt show`
+pub(super) fn build_system_prompt(
+    template: Option<&str>,
+    additions: Option<&str>,
+    allowed_tools: &[String],
+    role: Role,
+    chat_id: ChatId,
+    current_path: &str,
+    token_hash: &str,
+) -> String {
+    let ctx = PromptContext {
+        platform: Platform::Telegram,
+        cwd: current_path,
+        chat_id: chat_id.0.to_string(),
+        key: token_hash,
+        allowed_tools,
+        read_only: role == Role::Viewer,
+    };
+    system_prompt::build(template, &ctx, additions)
+}
+
+/// Start one turn for the messages queued while the agent was busy, merged into one prompt.
+/// Does nothing if another turn is running, which drains the queue when it ends, or the
+/// bot is shutting down.
+pub(super) fn run_queued_messages(bot: Bot, chat_id: ChatId, state: SharedState) {
+    tokio::spawn(async move {
+        if shutdown::is_shutting_down() {
+            return;
+        }
+        let reservation = {
+            let mut data = state.lock().await;
+            let data = &mut *data;
+            bot_common::reserve_queued_turn(&mut data.cancel_tokens, &mut data.queued_messages, chat_id)
+        };
+        let Some((reserved, queued)) = reservation else {
+            return;
+        };
+
+        let texts: Vec<String> = queued.iter().map(|(text, _, _)| text.clone()).collect();
+        let last_msg_id = queued[queued.len() - 1].1;
+        // The merged turn runs with the lowest role among the senders
+        let role = queued.iter().map(|(_, _, role)| *role).min().unwrap_or(Role::Viewer);
+        let prompt = bot_common::merge_queued_messages(&texts);
+        let ts = chrono::Local::now().format("%H:%M:%S");
+        println!("  [{ts}] ▶ Sending {} queued message(s)", texts.len());
+
+        if let Err(e) = handle_text_message(&bot, chat_id, &prompt, last_msg_id, role, &state, Some(&reserved)).await {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}]   ⚠ Queued turn failed: {e}");
+        }
+
+        // Release the reservation if the turn never started
+        let mut data = state.lock().await;
+        bot_common::release_reservation(&mut data.cancel_tokens, chat_id, &reserved);
+    });
+}
@@ -1,14 +1,34 @@
+use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use std::path::Path;
 use std::fs;
 
 use teloxide::prelude::*;
-use teloxide::types::ParseMode;
-
-use crate::services::session::{HistoryItem, HistoryType};
+use teloxide::types::{MessageId, ParseMode};
+
+use crate::services::agent::CancelToken;
+use crate::services::session::{HistoryItem, HistoryType, SessionData};
+use crate::services::export::{self, ExportFormat};
+use crate::services::search;
+use crate::services::retention;
+use crate::services::git;
+use crate::services::checkpoint;
+use crate::services::template;
+use crate::services::access;
+use crate::services::compact;
+use crate::services::roles;
+use crate::services::chat_settings;
+use crate::services::utils::truncate_str;
 use crate::services::bot_common::{self, ALL_TOOLS, AVAILABLE_AGENTS, normalize_tool_name, tool_info, risk_badge, is_valid_agent};
-use super::{ChatSession, SharedState, token_hash};
+use super::{ChatSession, SharedData, SharedState, token_hash};
+use super::keyboards::{self, Action};
 use super::messages::{shared_rate_limit_wait, send_long_message, html_escape};
+use super::markdown::markdown_to_telegram_html;
+
+/// This bot's allowed workspace roots (None when unrestricted)
+async fn allowed_roots(state: &SharedState) -> Option<Vec<std::path::PathBuf>> {
+    state.lock().await.allowed_roots.clone()
+}
 
 /// Handle /help command
 pub async fn handle_help_command(
@@ -23,10 +43,21 @@ Manage server files &amp; chat with Claude AI.
 <b>Session</b>
 <code>/start &lt;path&gt;</code> — Start session at directory
 <code>/start</code> — Start with auto-generated workspace
-<code>/resume</code> — List &amp; resume saved sessions
+<code>/start --template &lt;name&gt;</code> — New workspace from a template
+<code>/templates</code> — List workspace templates
+<code>/resume</code> — List saved sessions; tap one to resume it
 <code>/pwd</code> — Show current working directory
 <code>/clear</code> — Clear AI conversation history
+<code>/retry</code> — Run the request cut off by a restart again
+<code>/export [md|html|json]</code> — Export conversation as a file
+<code>/fork [name]</code> — Branch conversation into a new session
+<code>/compact</code> — Summarize conversation into a fresh session (needs the Claude CLI)
+<code>/search &lt;terms&gt;</code> — Search saved sessions
+<code>/rename &lt;title&gt;</code> — Name the current session
+<code>/tag [-]&lt;label&gt;</code> — Add or remove a session tag
+<code>/pin</code> — Pin/unpin the session in /resume
 <code>/stop</code> — Stop current AI request
+<code>/queue [clear]</code> — Show or clear queued messages
 
 <b>File Transfer</b>
 <code>/down &lt;file&gt;</code> — Download file from server
@@ -36,20 +67,43 @@ Send a file/photo — Upload to session directory
 <code>!&lt;command&gt;</code> — Run shell command directly
   e.g. <code>!ls -la</code>, <code>!git status</code>
 
+<b>Git</b>
+<code>/diff</code> — Show uncommitted changes
+<code>/commit [msg]</code> — Commit all changes (AI writes the message if omitted)
+<code>/branch [name]</code> — Create/switch to a branch for this session
+
+<b>Checkpoints</b>
+<code>/undo</code> — Restore files to before the last AI turn
+<code>/checkpoints [n]</code> — List checkpoints or restore one
+
 <b>AI Chat</b>
 Any other message is sent to AI agent.
 AI can read, edit, and run commands in your session.
 
 <b>Agent</b>
-<code>/agent</code> — Show current AI agent
-<code>/agent &lt;name&gt;</code> — Switch agent (claude, gemini, codex, opencode)
+<code>/agent</code> — Show current AI agent; tap one to switch
+<code>/agent &lt;name&gt;</code> — Switch agent for this chat (claude, gemini, codex, opencode)
+
+<b>Settings</b>
+<code>/settings</code> — Show this chat's settings and where they come from
+<code>/settings &lt;key&gt; &lt;value&gt;</code> — Set agent, model, tools, system_prompt or verbosity
+<code>/settings &lt;key&gt; reset</code> — Back to the bot-wide default
+<code>/systemprompt</code> — Show the full system prompt of this chat
+<code>/systemprompt &lt;text&gt;</code> — Set this chat's additions (<code>reset</code> removes them)
 
 <b>Tool Management</b>
 <code>/availabletools</code> — List all available tools
-<code>/allowedtools</code> — Show currently allowed tools
-<code>/allowed +name</code> — Add tool (e.g. <code>/allowed +Bash</code>)
+<code>/allowedtools</code> — Show currently allowed tools; tap one to toggle it
+<code>/allowed +name</code> — Add tool for this chat (e.g. <code>/allowed +Bash</code>)
 <code>/allowed -name</code> — Remove tool
 
+<b>Users</b> (admin)
+<code>/users</code> — List users and roles
+<code>/users add &lt;id&gt; [role]</code> — Allow a user (admin, user, viewer)
+<code>/users role &lt;id&gt; &lt;role&gt;</code> — Change a user's role
+<code>/users remove &lt;id&gt;</code> — Remove a user
+<code>/reload</code> — Re-read the config file and bot settings
+
 <code>/help</code> — Show this help";
 
     shared_rate_limit_wait(state, chat_id).await;
@@ -68,8 +122,36 @@ pub async fn handle_start_command(
     state: &SharedState,
     token: &str,
 ) -> ResponseResult<()> {
-    // Extract path from "/start <path>"
-    let path_str = text.strip_prefix("/start").unwrap_or("").trim();
+    // Extract path from "/start <path>" or "/start --template <name>"
+    let (template_name, path_str) = match template::parse_start_args(text.strip_prefix("/start").unwrap_or("")) {
+        Ok(parsed) => parsed,
+        Err(usage) => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, usage)
+                .await?;
+            return Ok(());
+        }
+    };
+    let path_str = path_str.as_str();
+    let template = match &template_name {
+        Some(_) if !path_str.is_empty() => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, "Templates apply to new workspaces only. Use /start --template <name> without a path.")
+                .await?;
+            return Ok(());
+        }
+        Some(name) => match template::find_template(name) {
+            Some(t) => Some(t),
+            None => {
+                shared_rate_limit_wait(state, chat_id).await;
+                bot.send_message(chat_id, format!("Unknown template '{}'. Use /templates to list them.", name))
+                    .await?;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let mut template_lines = Vec::new();
 
     let canonical_path = if path_str.is_empty() {
         // Create random workspace directory
@@ -93,6 +175,30 @@ pub async fn handle_start_command(
                 .await?;
             return Ok(());
         }
+        if let Some(t) = template {
+            let dest = new_dir.clone();
+            let applied = tokio::task::spawn_blocking(move || {
+                template::apply(&t, &dest).map(|report| (t, report))
+            }).await.unwrap_or_else(|e| Err(e.to_string()));
+            match applied {
+                Ok((t, report)) => {
+                    template::record_workspace(&new_dir.display().to_string(), &t.name);
+                    template_lines.push(format!("Template `{}`: {} file(s) copied.", t.name, report.files_copied));
+                    if let Some((code, output)) = report.init {
+                        template_lines.push(format!("init.sh exited with {}.", code));
+                        if !output.is_empty() {
+                            template_lines.push(output);
+                        }
+                    }
+                }
+                Err(e) => {
+                    shared_rate_limit_wait(state, chat_id).await;
+                    bot.send_message(chat_id, format!("Error: failed to apply template: {}", e))
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
         new_dir.display().to_string()
     } else {
         // Expand ~ to home directory
@@ -113,13 +219,18 @@ pub async fn handle_start_command(
                 .await?;
             return Ok(());
         }
-        path.canonicalize()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|_| expanded)
+        match access::check(allowed_roots(state).await.as_deref(), path, "start", &format!("chat {}", chat_id.0)) {
+            Ok(resolved) => resolved.display().to_string(),
+            Err(e) => {
+                shared_rate_limit_wait(state, chat_id).await;
+                bot.send_message(chat_id, e).await?;
+                return Ok(());
+            }
+        }
     };
 
     // Try to load existing session for this path
-    let existing = bot_common::load_existing_session(&canonical_path);
+    let existing = bot_common::load_existing_session(&canonical_path, &super::session_origin(bot, chat_id));
 
     let mut response_lines = Vec::new();
 
@@ -131,7 +242,13 @@ pub async fn handle_start_command(
             history: Vec::new(),
             pending_uploads: Vec::new(),
             cleared: false,
+            fork_from: None,
+            compact_brief: None,
+            needs_compact: false,
         });
+        session.fork_from = None;
+        session.compact_brief = None;
+        session.needs_compact = false;
 
         if let Some((session_data, _)) = &existing {
             session.session_id = Some(session_data.session_id.clone());
@@ -168,6 +285,7 @@ pub async fn handle_start_command(
             let ts = chrono::Local::now().format("%H:%M:%S");
             println!("  [{ts}] ▶ Session started: {canonical_path}");
             response_lines.push(format!("Session started at `{}`.", canonical_path));
+            response_lines.append(&mut template_lines);
         }
     }
 
@@ -175,7 +293,8 @@ pub async fn handle_start_command(
     {
         let mut data = state.lock().await;
         data.settings.last_sessions.insert(chat_id.0.to_string(), canonical_path);
-        bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("token", token)]);
+        data.remember_session(chat_id);
+        bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("platform", "telegram")]);
     }
 
     let response_text = response_lines.join("\n");
@@ -184,6 +303,26 @@ pub async fn handle_start_command(
     Ok(())
 }
 
+/// Handle /retry command - run the request cut off by the last restart again
+pub async fn handle_retry_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    msg_id: teloxide::types::MessageId,
+    role: roles::Role,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let turn = {
+        let data = state.lock().await;
+        data.settings.chat_state.get(&chat_id.0.to_string()).and_then(|s| s.interrupted.clone())
+    };
+    let Some(turn) = turn else {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, "Nothing to retry.").await?;
+        return Ok(());
+    };
+    super::chat::handle_text_message(bot, chat_id, &turn.prompt, msg_id, role, state, None).await
+}
+
 /// Handle /clear command
 pub async fn handle_clear_command(
     bot: &Bot,
@@ -197,13 +336,7 @@ pub async fn handle_clear_command(
     };
     if let Some(token) = cancel_token {
         token.cancelled.store(true, Ordering::Relaxed);
-        if let Ok(guard) = token.child_pid.lock() {
-            if let Some(pid) = *guard {
-                #[cfg(unix)]
-                #[allow(unsafe_code)]
-                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM); }
-            }
-        }
+        token.kill_child();
     }
 
     {
@@ -212,10 +345,17 @@ pub async fn handle_clear_command(
             session.session_id = None;
             session.history.clear();
             session.pending_uploads.clear();
+            session.fork_from = None;
+            session.compact_brief = None;
+            session.needs_compact = false;
             session.cleared = true;
         }
         data.cancel_tokens.remove(&chat_id);
         data.stop_message_ids.remove(&chat_id);
+        data.queued_messages.remove(&chat_id);
+        // A restart must not bring the cleared session back
+        data.remember_session(chat_id);
+        bot_common::save_bot_settings(&token_hash(bot.token()), &data.settings, &[("platform", "telegram")]);
     }
 
     shared_rate_limit_wait(state, chat_id).await;
@@ -263,9 +403,20 @@ pub async fn handle_stop_command(
                 return Ok(());
             }
 
+            // Stopping also drops anything queued for the next turn
+            let dropped = {
+                let mut data = state.lock().await;
+                data.queued_messages.remove(&chat_id).map(|q| q.len()).unwrap_or(0)
+            };
+            let stop_text = if dropped > 0 {
+                format!("Stopping... ({} queued message(s) dropped)", dropped)
+            } else {
+                "Stopping...".to_string()
+            };
+
             // Send immediate feedback to user
             shared_rate_limit_wait(state, chat_id).await;
-            let stop_msg = bot.send_message(chat_id, "Stopping...").await?;
+            let stop_msg = bot.send_message(chat_id, stop_text).await?;
 
             // Store the stop message ID so the polling loop can update it later
             {
@@ -278,15 +429,7 @@ pub async fn handle_stop_command(
 
             // Kill child process directly to unblock reader.lines()
             // When the child dies, its stdout pipe closes → reader returns EOF → blocking thread exits
-            if let Ok(guard) = token.child_pid.lock() {
-                if let Some(pid) = *guard {
-                    #[cfg(unix)]
-                    #[allow(unsafe_code)]
-                    unsafe {
-                        libc::kill(pid as libc::pid_t, libc::SIGTERM);
-                    }
-                }
-            }
+            token.kill_child();
 
             let ts = chrono::Local::now().format("%H:%M:%S");
             println!("  [{ts}] ■ Cancel signal sent");
@@ -301,6 +444,48 @@ pub async fn handle_stop_command(
     Ok(())
 }
 
+/// Handle /queue command - show or clear messages queued during a turn
+/// Usage: /queue         (list)
+///        /queue clear
+pub async fn handle_queue_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let arg = text.strip_prefix("/queue").unwrap_or("").trim();
+
+    let response = {
+        let mut data = state.lock().await;
+        match arg {
+            "" => match data.queued_messages.get(&chat_id).filter(|q| !q.is_empty()) {
+                Some(queue) => {
+                    let mut lines = vec![format!("<b>Queued messages</b> ({})", queue.len())];
+                    for (i, (text, _, _)) in queue.iter().enumerate() {
+                        lines.push(format!("{}. {}", i + 1, html_escape(&truncate_str(text, 80))));
+                    }
+                    lines.push(String::new());
+                    lines.push("They will be sent together when the current turn finishes. /queue clear to drop them.".to_string());
+                    lines.join("\n")
+                }
+                None => "Queue is empty.".to_string(),
+            },
+            "clear" => match data.queued_messages.remove(&chat_id) {
+                Some(queue) => format!("Cleared {} queued message(s).", queue.len()),
+                None => "Queue is empty.".to_string(),
+            },
+            _ => "Usage: /queue [clear]".to_string(),
+        }
+    };
+
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
 /// Handle /down <filepath> - send file to user
 pub async fn handle_down_command(
     bot: &Bot,
@@ -318,14 +503,14 @@ pub async fn handle_down_command(
     }
 
     // Resolve relative path using current session path
+    let current_path = {
+        let data = state.lock().await;
+        data.sessions.get(&chat_id).and_then(|s| s.current_path.clone())
+    };
     let resolved_path = if Path::new(file_path).is_absolute() {
         file_path.to_string()
     } else {
-        let current_path = {
-            let data = state.lock().await;
-            data.sessions.get(&chat_id).and_then(|s| s.current_path.clone())
-        };
-        match current_path {
+        match current_path.as_deref() {
             Some(base) => format!("{}/{}", base.trim_end_matches('/'), file_path),
             None => {
                 shared_rate_limit_wait(state, chat_id).await;
@@ -347,6 +532,14 @@ pub async fn handle_down_command(
         bot.send_message(chat_id, &format!("Not a file: {}", resolved_path)).await?;
         return Ok(());
     }
+    let path = match access::check_download(allowed_roots(state).await.as_deref(), path, current_path.as_deref(), &format!("chat {}", chat_id.0)) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, &e).await?;
+            return Ok(());
+        }
+    };
 
     shared_rate_limit_wait(state, chat_id).await;
     bot.send_document(chat_id, teloxide::types::InputFile::file(path))
@@ -416,6 +609,15 @@ pub async fn handle_file_upload(
         .file_name()
         .unwrap_or_else(|| std::ffi::OsStr::new("uploaded_file"));
     let dest = Path::new(&save_dir).join(safe_name);
+    // Write where the path really leads, as checked
+    let dest = match access::check(allowed_roots(state).await.as_deref(), &dest, "upload", &format!("chat {}", chat_id.0)) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, &e).await?;
+            return Ok(());
+        }
+    };
     let file_size = buf.len();
     match fs::write(&dest, &buf) {
         Ok(_) => {
@@ -438,12 +640,10 @@ pub async fn handle_file_upload(
     {
         let mut data = state.lock().await;
         if let Some(session) = data.sessions.get_mut(&chat_id) {
-            session.history.push(HistoryItem {
-                item_type: HistoryType::User,
-                content: upload_record.clone(),
-            });
+            session.history.push(HistoryItem::new(HistoryType::User, upload_record.clone()));
             session.pending_uploads.push(upload_record);
-            bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &save_dir);
+            retention::trim_history(session.session_id.as_deref(), &mut session.history);
+            bot_common::save_session_to_file(session.session_id.as_deref(), &session.history, &save_dir, &super::session_origin(bot, chat_id));
         }
     }
 
@@ -478,6 +678,12 @@ pub async fn handle_shell_command(
             })
     };
 
+    if let Err(e) = access::check(allowed_roots(state).await.as_deref(), Path::new(&working_dir), "shell", &format!("chat {}", chat_id.0)) {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, &e).await?;
+        return Ok(());
+    }
+
     let cmd_owned = cmd_str.to_string();
     let working_dir_clone = working_dir.clone();
 
@@ -552,6 +758,23 @@ pub async fn handle_availabletools_command(
     Ok(())
 }
 
+/// The /allowedtools list; its keyboard turns tools on and off
+fn allowed_tools_message(tools: &[String]) -> String {
+    let mut msg = String::from("<b>Allowed Tools</b>\n\n");
+    for tool in tools {
+        let (desc, destructive) = tool_info(tool);
+        let badge = risk_badge(destructive);
+        if badge.is_empty() {
+            msg.push_str(&format!("<code>{}</code> — {}\n", html_escape(tool), html_escape(desc)));
+        } else {
+            msg.push_str(&format!("<code>{}</code> {} — {}\n", html_escape(tool), badge, html_escape(desc)));
+        }
+    }
+    msg.push_str(&format!("\n{} = destructive\nTotal: {}", risk_badge(true), tools.len()));
+    msg.push_str("\nToggle: tap a tool, or <code>/allowed +name</code> / <code>/allowed -name</code>");
+    msg
+}
+
 /// Handle /allowedtools command - show current allowed tools list
 pub async fn handle_allowedtools_command(
     bot: &Bot,
@@ -560,31 +783,229 @@ pub async fn handle_allowedtools_command(
 ) -> ResponseResult<()> {
     let tools = {
         let data = state.lock().await;
-        data.settings.allowed_tools.clone()
+        data.effective_settings(chat_id).allowed_tools.value
     };
 
-    let mut msg = String::from("<b>Allowed Tools</b>\n\n");
-    for tool in &tools {
-        let (desc, destructive) = tool_info(tool);
-        let badge = risk_badge(destructive);
-        if badge.is_empty() {
-            msg.push_str(&format!("<code>{}</code> — {}\n", html_escape(tool), html_escape(desc)));
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, allowed_tools_message(&tools))
+        .parse_mode(ParseMode::Html)
+        .reply_markup(keyboards::tools_keyboard(&tools))
+        .await?;
+
+    Ok(())
+}
+
+/// Handle /settings command - show or change this chat's settings
+/// Usage: /settings                  (effective values and where they come from)
+///        /settings <key> <value>    (set for this chat)
+///        /settings <key> reset      (back to the bot-wide default)
+///        /settings reset            (clear every setting of this chat)
+pub async fn handle_settings_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let arg = text.strip_prefix("/settings").unwrap_or("").trim();
+    let chat_key = chat_id.0.to_string();
+
+    let response_msg = {
+        let mut data = state.lock().await;
+        if arg.is_empty() {
+            let effective = data.effective_settings(chat_id);
+            let mut msg = String::from("<b>Settings</b>\n\n");
+            for (key, value, source) in chat_settings::describe(&effective) {
+                msg.push_str(&format!("<b>{}</b>: <code>{}</code> ({})\n", key, html_escape(&truncate_str(&value, 200)), source.name()));
+            }
+            msg.push_str("\nchat = this chat, bot = bot-wide default\n\
+                <code>/settings &lt;key&gt; &lt;value&gt;</code> — Set for this chat\n\
+                <code>/settings &lt;key&gt; reset</code> — Use the bot default\n\
+                Verbosity: quiet, tools or full");
+            msg
         } else {
-            msg.push_str(&format!("<code>{}</code> {} — {}\n", html_escape(tool), badge, html_escape(desc)));
+            let old_agent = data.effective_settings(chat_id).agent.value;
+            data.remember_session(chat_id);
+            let result = if arg.eq_ignore_ascii_case("reset") {
+                data.settings.chats.remove(&chat_key);
+                Ok("All settings of this chat reset to the bot defaults.".to_string())
+            } else {
+                let (key, value) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+                chat_settings::apply(data.settings.chats.entry(chat_key).or_default(), key, value)
+            };
+            data.settings.chats.retain(|_, chat| !chat.is_empty());
+            if data.effective_settings(chat_id).agent.value != old_agent {
+                data.resume_agent_session(chat_id);
+            }
+            match result {
+                Ok(done) => {
+                    bot_common::save_bot_settings(&token_hash(bot.token()), &data.settings, &[("platform", "telegram")]);
+                    format!("✅ {}", html_escape(&done))
+                }
+                Err(e) => html_escape(&e),
+            }
         }
+    };
+
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response_msg)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+/// Handle /systemprompt command - show the rendered system prompt, or set this chat's additions
+pub async fn handle_systemprompt_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    role: roles::Role,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let arg = text.strip_prefix("/systemprompt").unwrap_or("").trim();
+
+    if arg.is_empty() || arg.eq_ignore_ascii_case("show") {
+        let inputs = {
+            let data = state.lock().await;
+            let effective = data.effective_settings(chat_id);
+            data.sessions.get(&chat_id).and_then(|s| s.current_path.clone()).map(|path| {
+                let tools = roles::tools_for(role, &effective.allowed_tools.value);
+                (path, tools, data.settings.prompt_template.clone(), effective.system_prompt)
+            })
+        };
+        let Some((path, tools, prompt_template, additions)) = inputs else {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, "No active session. Use /start <path> first.").await?;
+            return Ok(());
+        };
+        let prompt = super::chat::build_system_prompt(
+            prompt_template.as_deref(), additions.value.as_deref(), &tools, role, chat_id, &path, &token_hash(bot.token()),
+        );
+        let header = format!(
+            "System prompt (template: {}, additions: {})",
+            if prompt_template.is_some() { "bot" } else { "default" },
+            if additions.value.is_some() { additions.source.name() } else { "none" }
+        );
+        send_long_message(bot, chat_id, &format!("{}\n\n{}", header, prompt), None, state).await?;
+        return Ok(());
     }
-    msg.push_str(&format!("\n{} = destructive\nTotal: {}", risk_badge(true), tools.len()));
+
+    let response_msg = {
+        let mut data = state.lock().await;
+        let result = chat_settings::apply(data.settings.chats.entry(chat_id.0.to_string()).or_default(), "system_prompt", arg);
+        data.settings.chats.retain(|_, chat| !chat.is_empty());
+        match result {
+            Ok(done) => {
+                bot_common::save_bot_settings(&token_hash(bot.token()), &data.settings, &[("platform", "telegram")]);
+                format!("✅ {}", done)
+            }
+            Err(e) => e,
+        }
+    };
+
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response_msg).await?;
+
+    Ok(())
+}
+
+/// Handle /reload command - re-read the config file and bot_settings.json
+pub async fn handle_reload_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+    token: &str,
+) -> ResponseResult<()> {
+    let response_msg = match super::reload_and_log(state, token, "/reload").await {
+        Ok(changes) if changes.is_empty() => "Reloaded. No changes.".to_string(),
+        Ok(changes) => {
+            let lines: Vec<String> = changes.iter().map(|c| format!("• {}", html_escape(c))).collect();
+            format!("<b>Reloaded</b>\n{}", lines.join("\n"))
+        }
+        Err(e) => format!("⚠ Reload failed, nothing changed: {}", html_escape(&e)),
+    };
+
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response_msg)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+/// Handle /users command - list users or add/remove/change roles (admin only)
+/// Usage: /users
+///        /users add <id> [role]
+///        /users remove <id>
+///        /users role <id> <role>
+pub async fn handle_users_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+    token: &str,
+) -> ResponseResult<()> {
+    let arg = text.strip_prefix("/users").unwrap_or("").trim();
+    // Drop a Telegram bot-name suffix (/users@my_bot add ...)
+    let arg = if arg.starts_with('@') { arg.split_once(' ').map(|(_, rest)| rest.trim()).unwrap_or("") } else { arg };
+
+    let response_msg = {
+        let mut data = state.lock().await;
+        if arg.is_empty() {
+            let mut msg = String::from("<b>Users</b>\n\n");
+            if let Some(owner) = data.settings.owner_user_id {
+                msg.push_str(&format!("<code>{}</code> — admin (owner)\n", owner));
+            }
+            for (id, role) in &data.settings.users {
+                msg.push_str(&format!("<code>{}</code> — {}\n", id, role));
+            }
+            if data.settings.owner_user_id.is_none() && data.settings.users.is_empty() {
+                msg.push_str("No users listed.\n");
+            }
+            if !data.allowed_chat_ids.is_empty() {
+                let ids: Vec<String> = data.allowed_chat_ids.iter().map(|id| id.to_string()).collect();
+                msg.push_str(&format!("\nAllowed chats: <code>{}</code> (members: {})\n", ids.join(", "), data.chat_role));
+            }
+            msg.push_str("\n/users add &lt;id&gt; [admin|user|viewer]\n/users role &lt;id&gt; &lt;role&gt;\n/users remove &lt;id&gt;");
+            msg
+        } else {
+            let owner = data.settings.owner_user_id;
+            match roles::apply_users_command(&mut data.settings.users, owner, arg) {
+                Ok(done) => {
+                    bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("platform", "telegram")]);
+                    format!("✅ {}", html_escape(&done))
+                }
+                Err(e) => html_escape(&e),
+            }
+        }
+    };
 
     shared_rate_limit_wait(state, chat_id).await;
-    bot.send_message(chat_id, &msg)
+    bot.send_message(chat_id, &response_msg)
         .parse_mode(ParseMode::Html)
         .await?;
 
     Ok(())
 }
 
+/// Add ('+') or remove ('-') a tool for this chat, starting from the tools currently in
+/// effect. Returns false if the list already was that way.
+fn change_allowed_tool(data: &mut SharedData, chat_id: ChatId, op: char, tool_name: &str, token: &str) -> bool {
+    let mut tools = data.effective_settings(chat_id).allowed_tools.value;
+    let present = tools.iter().any(|t| t == tool_name);
+    match op {
+        '+' if !present => tools.push(tool_name.to_string()),
+        '-' if present => tools.retain(|t| t != tool_name),
+        _ => return false,
+    }
+    data.settings.chats.entry(chat_id.0.to_string()).or_default().allowed_tools = Some(tools);
+    bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("platform", "telegram")]);
+    true
+}
+
 /// Handle /allowed command - add/remove tools
-/// Usage: /allowed +toolname  (add)
+/// Usage: /allowed            (show the list with buttons to toggle tools)
+///        /allowed +toolname  (add)
 ///        /allowed -toolname  (remove)
 pub async fn handle_allowed_command(
     bot: &Bot,
@@ -595,16 +1016,8 @@ pub async fn handle_allowed_command(
 ) -> ResponseResult<()> {
     let arg = text.strip_prefix("/allowed").unwrap_or("").trim();
 
-    if arg.is_empty() {
-        shared_rate_limit_wait(state, chat_id).await;
-        bot.send_message(chat_id, "Usage:\n/allowed +toolname — Add a tool\n/allowed -toolname — Remove a tool\n/allowedtools — Show current list")
-            .await?;
-        return Ok(());
-    }
-
     // Skip if argument starts with "tools" (that's /allowedtools handled separately)
-    if arg.starts_with("tools") {
-        // This shouldn't happen due to routing order, but just in case
+    if arg.is_empty() || arg.starts_with("tools") {
         return handle_allowedtools_command(bot, chat_id, state).await;
     }
 
@@ -628,30 +1041,15 @@ pub async fn handle_allowed_command(
 
     let tool_name = normalize_tool_name(raw_name);
 
-    let response_msg = {
+    let changed = {
         let mut data = state.lock().await;
-        match op {
-            '+' => {
-                if data.settings.allowed_tools.iter().any(|t| t == &tool_name) {
-                    format!("<code>{}</code> is already in the list.", html_escape(&tool_name))
-                } else {
-                    data.settings.allowed_tools.push(tool_name.clone());
-                    bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("token", token)]);
-                    format!("✅ Added <code>{}</code>", html_escape(&tool_name))
-                }
-            }
-            '-' => {
-                let before_len = data.settings.allowed_tools.len();
-                data.settings.allowed_tools.retain(|t| t != &tool_name);
-                if data.settings.allowed_tools.len() < before_len {
-                    bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("token", token)]);
-                    format!("❌ Removed <code>{}</code>", html_escape(&tool_name))
-                } else {
-                    format!("<code>{}</code> is not in the list.", html_escape(&tool_name))
-                }
-            }
-            _ => unreachable!(),
-        }
+        change_allowed_tool(&mut data, chat_id, op, &tool_name, token)
+    };
+    let response_msg = match (op, changed) {
+        ('+', true) => format!("✅ Added <code>{}</code>", html_escape(&tool_name)),
+        ('+', false) => format!("<code>{}</code> is already in the list.", html_escape(&tool_name)),
+        (_, true) => format!("❌ Removed <code>{}</code>", html_escape(&tool_name)),
+        (_, false) => format!("<code>{}</code> is not in the list.", html_escape(&tool_name)),
     };
 
     shared_rate_limit_wait(state, chat_id).await;
@@ -662,19 +1060,118 @@ pub async fn handle_allowed_command(
     Ok(())
 }
 
+/// Sessions shown by /resume
+const RESUME_LIST_LEN: usize = 10;
+
+/// Format one /resume list entry; `current` marks the chat's open session
+fn format_session_entry(number: usize, s: &bot_common::SessionSummary, current: bool) -> String {
+    // Shorten path for display
+    let path_display: String = s.current_path.chars().take(40).collect();
+    let path_suffix = if s.current_path.chars().count() > 40 { "..." } else { "" };
+    let mut entry = format!(
+        "<code>{}</code>. {}<b>{}</b>{}\n    <code>{}{}</code>\n    {} | {} msgs\n",
+        number,
+        if s.pinned { "📌 " } else { "" },
+        html_escape(&s.title),
+        if current { " ◀" } else { "" },
+        html_escape(&path_display),
+        path_suffix,
+        html_escape(&s.created_at),
+        s.history_count,
+    );
+    if !s.tags.is_empty() {
+        let tags: Vec<String> = s.tags.iter().map(|t| format!("#{}", html_escape(t))).collect();
+        entry.push_str(&format!("    {}\n", tags.join(" ")));
+    }
+    if let Some(parent) = &s.parent_session_id {
+        entry.push_str(&format!("    fork of <code>{}</code>\n", html_escape(parent)));
+    }
+    entry
+}
+
+/// Handle /resume all - list sessions across all bots and chats (admins only)
+async fn handle_resume_all(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+    is_admin: bool,
+) -> ResponseResult<()> {
+    if !is_admin {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, "Only admins can list sessions from other chats.")
+            .await?;
+        return Ok(());
+    }
+
+    let sessions = bot_common::list_all_sessions();
+    let mut msg = String::from("<b>All Saved Sessions</b>\n\n");
+    let max_display = 20;
+    for s in sessions.iter().take(max_display) {
+        let owner = match (&s.platform, &s.chat_id) {
+            (Some(platform), Some(chat)) => format!("{} {}", platform, chat),
+            _ => "unowned".to_string(),
+        };
+        msg.push_str(&format!(
+            "<b>{}</b> [{}]\n    <code>{}</code>\n    <code>{}</code> | {}\n",
+            html_escape(&s.title),
+            html_escape(&owner),
+            html_escape(&s.session_id),
+            html_escape(&s.current_path),
+            html_escape(&s.created_at),
+        ));
+    }
+    if sessions.is_empty() {
+        msg.push_str("No saved sessions found.");
+    } else if sessions.len() > max_display {
+        msg.push_str(&format!("\n... and {} more", sessions.len() - max_display));
+    }
+    if sessions.iter().any(|s| s.bot_hash.is_none()) {
+        msg.push_str("\nClaim an unowned session for this chat: <code>/resume &lt;id&gt;</code>");
+    }
+
+    send_long_message(bot, chat_id, &msg, Some(ParseMode::Html), state).await?;
+    Ok(())
+}
+
+/// The /resume list (pinned first); its keyboard resumes a session
+fn resume_list_message(sessions: &[bot_common::SessionSummary], current: Option<&str>) -> String {
+    let mut msg = String::from("<b>Saved Sessions</b>\n\n");
+    for (i, s) in sessions.iter().take(RESUME_LIST_LEN).enumerate() {
+        msg.push_str(&format_session_entry(i + 1, s, current == Some(s.session_id.as_str())));
+    }
+    if sessions.len() > RESUME_LIST_LEN {
+        msg.push_str(&format!("\n... and {} more", sessions.len() - RESUME_LIST_LEN));
+    }
+    msg.push_str("\nResume: tap a session, or <code>/resume &lt;number|name|tag&gt;</code>");
+    msg
+}
+
 /// Handle /resume command - list saved sessions or resume a specific one
-/// Usage: /resume              (list all saved sessions)
+/// Usage: /resume              (list saved sessions with a button for each)
 ///        /resume <number>     (resume session by number from the list)
+///        /resume <id>         (resume session by session ID)
+///        /resume <name|tag>   (resume by name or tag; lists matches if ambiguous)
+///        /resume all          (admins: list sessions of every bot and chat)
 pub async fn handle_resume_command(
     bot: &Bot,
     chat_id: ChatId,
     text: &str,
     state: &SharedState,
     token: &str,
+    is_admin: bool,
 ) -> ResponseResult<()> {
     let arg = text.strip_prefix("/resume").unwrap_or("").trim();
 
-    let sessions = bot_common::list_all_sessions();
+    if arg == "all" {
+        return handle_resume_all(bot, chat_id, state, is_admin).await;
+    }
+
+    // Only sessions owned by this bot and chat
+    let mut sessions = bot_common::list_sessions_in_scope(&super::session_origin(bot, chat_id));
+    // Admins claim a session saved before ownership was recorded by its id (see /resume all)
+    if is_admin && !arg.is_empty() && !sessions.iter().any(|s| s.session_id == arg) {
+        sessions.extend(bot_common::find_unowned_session(arg));
+    }
 
     if sessions.is_empty() {
         shared_rate_limit_wait(state, chat_id).await;
@@ -684,42 +1181,46 @@ pub async fn handle_resume_command(
     }
 
     if arg.is_empty() {
-        // List all saved sessions
-        let mut msg = String::from("<b>Saved Sessions</b>\n\n");
-        let max_display = 10;
-        for (i, s) in sessions.iter().take(max_display).enumerate() {
-            // Shorten path for display
-            let path_display: String = s.current_path.chars().take(40).collect();
-            let path_suffix = if s.current_path.chars().count() > 40 { "..." } else { "" };
-            msg.push_str(&format!(
-                "<code>{}</code>. <code>{}{}</code>\n    {} | {} msgs\n",
-                i + 1,
-                html_escape(&path_display),
-                path_suffix,
-                html_escape(&s.created_at),
-                s.history_count,
-            ));
-        }
-        if sessions.len() > max_display {
-            msg.push_str(&format!("\n... and {} more", sessions.len() - max_display));
-        }
-        msg.push_str("\nResume: <code>/resume &lt;number&gt;</code>");
-
+        let current = current_session_id(chat_id, state).await;
         shared_rate_limit_wait(state, chat_id).await;
-        bot.send_message(chat_id, &msg)
+        bot.send_message(chat_id, resume_list_message(&sessions, current.as_deref()))
             .parse_mode(ParseMode::Html)
+            .reply_markup(keyboards::resume_keyboard(&sessions, RESUME_LIST_LEN))
             .await?;
         return Ok(());
     }
 
-    // Parse number argument
+    // Parse number argument, or match a session ID, name or tag
     let num: usize = match arg.parse() {
         Ok(n) if n >= 1 && n <= sessions.len() => n,
         _ => {
-            shared_rate_limit_wait(state, chat_id).await;
-            bot.send_message(chat_id, &format!("Invalid number. Use 1-{}.", sessions.len()))
-                .await?;
-            return Ok(());
+            let matches = bot_common::find_sessions(&sessions, arg);
+            match matches.as_slice() {
+                [idx] => idx + 1,
+                [] => {
+                    shared_rate_limit_wait(state, chat_id).await;
+                    bot.send_message(chat_id, format!(
+                        "No session matches <code>{}</code>. Use 1-{}, a name or a tag.",
+                        html_escape(arg),
+                        sessions.len()
+                    ))
+                        .parse_mode(ParseMode::Html)
+                        .await?;
+                    return Ok(());
+                }
+                _ => {
+                    let mut msg = format!("<b>Sessions matching {}</b>\n\n", html_escape(arg));
+                    for idx in matches.iter().take(10) {
+                        msg.push_str(&format_session_entry(idx + 1, &sessions[*idx], false));
+                    }
+                    msg.push_str("\nResume: <code>/resume &lt;number&gt;</code>");
+                    shared_rate_limit_wait(state, chat_id).await;
+                    bot.send_message(chat_id, &msg)
+                        .parse_mode(ParseMode::Html)
+                        .await?;
+                    return Ok(());
+                }
+            }
         }
     };
 
@@ -748,6 +1249,11 @@ pub async fn handle_resume_command(
     };
 
     let canonical_path = selected.current_path.clone();
+    if let Err(e) = access::check(allowed_roots(state).await.as_deref(), Path::new(&canonical_path), "resume", &format!("chat {}", chat_id.0)) {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, e).await?;
+        return Ok(());
+    }
     let mut response_lines = Vec::new();
 
     {
@@ -758,12 +1264,18 @@ pub async fn handle_resume_command(
             history: Vec::new(),
             pending_uploads: Vec::new(),
             cleared: false,
+            fork_from: None,
+            compact_brief: None,
+            needs_compact: false,
         });
 
         session.session_id = Some(session_data.session_id.clone());
         session.current_path = Some(canonical_path.clone());
         session.history = session_data.history.clone();
         session.pending_uploads.clear();
+        session.fork_from = None;
+        session.compact_brief = None;
+        session.needs_compact = false;
         session.cleared = false;
 
         let ts = chrono::Local::now().format("%H:%M:%S");
@@ -793,7 +1305,8 @@ pub async fn handle_resume_command(
     {
         let mut data = state.lock().await;
         data.settings.last_sessions.insert(chat_id.0.to_string(), canonical_path);
-        bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("token", token)]);
+        data.remember_session(chat_id);
+        bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("platform", "telegram")]);
     }
 
     let response_text = response_lines.join("\n");
@@ -802,8 +1315,31 @@ pub async fn handle_resume_command(
     Ok(())
 }
 
+/// The /agent list; its keyboard switches agents
+fn agent_list_message(current: &str) -> String {
+    let mut msg = format!("<b>Current agent:</b> <code>{}</code>\n\n<b>Available agents:</b>\n", html_escape(current));
+    for &(name, desc) in AVAILABLE_AGENTS {
+        let marker = if name == current { " ◀" } else { "" };
+        msg.push_str(&format!("<code>{}</code> — {}{}\n", html_escape(name), html_escape(desc), marker));
+    }
+    msg.push_str("\nSwitch: tap an agent, or <code>/agent &lt;name&gt;</code>");
+    msg
+}
+
+/// Switch the chat to a valid agent. Returns the previous agent and whether the new
+/// one's last session in this workspace was resumed.
+async fn switch_agent(state: &SharedState, chat_id: ChatId, agent_name: &str, token: &str) -> (String, bool) {
+    let mut data = state.lock().await;
+    let old = data.effective_settings(chat_id).agent.value;
+    data.remember_session(chat_id);
+    data.settings.chats.entry(chat_id.0.to_string()).or_default().agent = Some(agent_name.to_string());
+    let resumed = old != agent_name && data.resume_agent_session(chat_id);
+    bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("platform", "telegram")]);
+    (old, resumed)
+}
+
 /// Handle /agent command - switch AI agent or show current agent
-/// Usage: /agent               (show current agent)
+/// Usage: /agent               (show current agent, with a button for each agent)
 ///        /agent <name>        (switch to agent)
 pub async fn handle_agent_command(
     bot: &Bot,
@@ -817,19 +1353,13 @@ pub async fn handle_agent_command(
         // Show current agent and list of available agents
         let current = {
             let data = state.lock().await;
-            data.agent_type.clone()
+            data.effective_settings(chat_id).agent.value
         };
 
-        let mut msg = format!("<b>Current agent:</b> <code>{}</code>\n\n<b>Available agents:</b>\n", html_escape(&current));
-        for &(name, desc) in AVAILABLE_AGENTS {
-            let marker = if name == current { " ◀" } else { "" };
-            msg.push_str(&format!("<code>{}</code> — {}{}\n", html_escape(name), html_escape(desc), marker));
-        }
-        msg.push_str("\nSwitch: <code>/agent &lt;name&gt;</code>");
-
         shared_rate_limit_wait(state, chat_id).await;
-        bot.send_message(chat_id, &msg)
+        bot.send_message(chat_id, agent_list_message(&current))
             .parse_mode(ParseMode::Html)
+            .reply_markup(keyboards::agent_keyboard(&current))
             .await?;
         return Ok(());
     }
@@ -849,16 +1379,12 @@ pub async fn handle_agent_command(
         return Ok(());
     }
 
-    // Switch agent
-    let old_agent = {
-        let mut data = state.lock().await;
-        let old = data.agent_type.clone();
-        data.agent_type = agent_name.clone();
-        old
-    };
+    let (old_agent, resumed) = switch_agent(state, chat_id, &agent_name, bot.token()).await;
 
     let response = if old_agent == agent_name {
         format!("Already using <code>{}</code>.", html_escape(&agent_name))
+    } else if resumed {
+        format!("Switched: <code>{}</code> → <code>{}</code>\nResumed its last session in this workspace.", html_escape(&old_agent), html_escape(&agent_name))
     } else {
         format!("Switched: <code>{}</code> → <code>{}</code>", html_escape(&old_agent), html_escape(&agent_name))
     };
@@ -870,3 +1396,760 @@ pub async fn handle_agent_command(
 
     Ok(())
 }
+
+/// Handle a tap on a /resume, /agent or /allowed list button: run what it stands for and
+/// edit the list message to show the new state. Returns the notice shown to the user who
+/// tapped (empty when the chat gets a reply instead).
+pub async fn handle_button(
+    bot: &Bot,
+    chat_id: ChatId,
+    list_id: MessageId,
+    action: &Action,
+    state: &SharedState,
+    token: &str,
+    is_admin: bool,
+) -> ResponseResult<String> {
+    let (text, keyboard, notice) = match action {
+        Action::Agent(name) => {
+            if !is_valid_agent(name) {
+                return Ok(format!("Unknown agent: {}", name));
+            }
+            let (old_agent, resumed) = switch_agent(state, chat_id, name, token).await;
+            let notice = if old_agent == *name {
+                format!("Already using {}.", name)
+            } else if resumed {
+                format!("Switched to {}, resumed its last session in this workspace.", name)
+            } else {
+                format!("Switched to {}.", name)
+            };
+            (agent_list_message(name), keyboards::agent_keyboard(name), notice)
+        }
+        Action::Tool(name) => {
+            let tools = {
+                let mut data = state.lock().await;
+                let allowed = data.effective_settings(chat_id).allowed_tools.value.contains(name);
+                change_allowed_tool(&mut data, chat_id, if allowed { '-' } else { '+' }, name, token);
+                data.effective_settings(chat_id).allowed_tools.value
+            };
+            let notice = if tools.contains(name) { format!("✅ Added {}", name) } else { format!("❌ Removed {}", name) };
+            (allowed_tools_message(&tools), keyboards::tools_keyboard(&tools), notice)
+        }
+        Action::Resume(arg) => {
+            // Replies like the typed command, then the list marks the open session
+            handle_resume_command(bot, chat_id, &format!("/resume {}", arg), state, token, is_admin).await?;
+            let sessions = bot_common::list_sessions_in_scope(&super::session_origin(bot, chat_id));
+            let current = current_session_id(chat_id, state).await;
+            let keyboard = keyboards::resume_keyboard(&sessions, RESUME_LIST_LEN);
+            (resume_list_message(&sessions, current.as_deref()), keyboard, String::new())
+        }
+    };
+
+    // Fails when nothing changed ("message is not modified") or the list is too old to edit
+    shared_rate_limit_wait(state, chat_id).await;
+    let _ = bot.edit_message_text(chat_id, list_id, text)
+        .parse_mode(ParseMode::Html)
+        .reply_markup(keyboard)
+        .await;
+
+    Ok(notice)
+}
+
+/// Handle /export command - render the current session transcript and send it as a document
+/// Usage: /export              (Markdown)
+///        /export <md|html|json>
+pub async fn handle_export_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let arg = text.strip_prefix("/export").unwrap_or("").trim();
+
+    let Some(format) = ExportFormat::parse(arg) else {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, "Usage: /export [md|html|json]")
+            .await?;
+        return Ok(());
+    };
+
+    let (session_data, agent) = {
+        let data = state.lock().await;
+        let session_data = data.sessions.get(&chat_id).and_then(|s| {
+            let session_id = s.session_id.clone()?;
+            let current_path = s.current_path.clone().unwrap_or_default();
+            // Prefer the saved file (it has the original created_at), fall back to memory
+            Some(bot_common::load_session_by_id(&session_id).unwrap_or_else(|| SessionData {
+                session_id,
+                history: s.history.clone(),
+                current_path,
+                created_at: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+                ..Default::default()
+            }))
+        });
+        (session_data, data.effective_settings(chat_id).agent.value)
+    };
+
+    let Some(session_data) = session_data.filter(|d| !d.history.is_empty()) else {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, "Nothing to export. Start a conversation first.")
+            .await?;
+        return Ok(());
+    };
+
+    match export::export_session_to_file(&session_data, format, Some(&agent)) {
+        Ok(path) => {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ▶ Session exported: {}", path.display());
+            shared_rate_limit_wait(state, chat_id).await;
+            let sent = bot.send_document(chat_id, teloxide::types::InputFile::file(&path))
+                .await;
+            // The transcript is only kept for the upload
+            let _ = std::fs::remove_file(&path);
+            sent?;
+        }
+        Err(e) => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, format!("Export failed: {}", e))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle /fork command - copy the current conversation into a new session
+/// Usage: /fork              (unnamed fork)
+///        /fork <name>
+pub async fn handle_fork_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let name = text.strip_prefix("/fork").unwrap_or("").trim();
+    let name = if name.is_empty() { None } else { Some(name) };
+
+    let response = {
+        let mut data = state.lock().await;
+        let agent = data.effective_settings(chat_id).agent.value;
+        match data.sessions.get_mut(&chat_id) {
+            Some(session) if session.session_id.is_some() && !session.history.is_empty() => {
+                // Forking a fork that has not run yet keeps the original parent
+                let parent_id = session.fork_from.clone()
+                    .or_else(|| session.session_id.clone())
+                    .unwrap_or_default();
+                let current_path = session.current_path.clone().unwrap_or_default();
+                match bot_common::fork_session(&parent_id, &session.history, &current_path, name) {
+                    Some(fork) => {
+                        session.session_id = Some(fork.session_id.clone());
+                        session.fork_from = Some(parent_id.clone());
+                        session.pending_uploads.clear();
+
+                        let ts = chrono::Local::now().format("%H:%M:%S");
+                        println!("  [{ts}] ▶ Session forked: {parent_id} → {}", fork.session_id);
+                        let mode = if bot_common::supports_native_fork(&agent) {
+                            format!("{} will fork the conversation natively on your next message.", agent)
+                        } else {
+                            format!("{} cannot fork natively; the copied transcript will be sent as context.", agent)
+                        };
+                        format!(
+                            "Forked <code>{}</code> → <code>{}</code>{}\n{} messages copied.\n{}",
+                            html_escape(&parent_id),
+                            html_escape(&fork.session_id),
+                            name.map(|n| format!(" ({})", html_escape(n))).unwrap_or_default(),
+                            fork.history.len(),
+                            html_escape(&mode),
+                        )
+                    }
+                    None => "Failed to save forked session.".to_string(),
+                }
+            }
+            _ => "Nothing to fork. Start a conversation first.".to_string(),
+        }
+    };
+
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+/// Summarize the chat's conversation (with Claude, see [`compact::generate_brief`]) and move the chat to a new
+/// session that starts with the brief. Returns (old session id, new session id, items summarized).
+/// Shared by /compact and the automatic compaction before a turn; /stop cancels `cancel`,
+/// which kills the summarizing agent and abandons the result.
+pub(super) async fn compact_chat(
+    chat_id: ChatId,
+    state: &SharedState,
+    cancel: &Arc<CancelToken>,
+) -> Result<(String, String, usize), String> {
+    let (old_id, history, current_path) = {
+        let mut data = state.lock().await;
+        match data.sessions.get_mut(&chat_id) {
+            Some(session) => {
+                session.needs_compact = false;
+                match (&session.session_id, &session.current_path) {
+                    (Some(id), Some(path)) if !session.history.is_empty() => {
+                        (id.clone(), session.history.clone(), path.clone())
+                    }
+                    _ => return Err("Nothing to compact. Start a conversation first.".to_string()),
+                }
+            }
+            None => return Err("Nothing to compact. Start a conversation first.".to_string()),
+        }
+    };
+
+    let item_count = history.len();
+    let dir = current_path.clone();
+    let cancel_clone = cancel.clone();
+    let brief = tokio::task::spawn_blocking(move || compact::generate_brief(&dir, &history, &cancel_clone))
+        .await
+        .map_err(|e| e.to_string())?;
+    if cancel.cancelled.load(Ordering::Relaxed) {
+        return Err("Compaction stopped.".to_string());
+    }
+    let brief = brief?;
+
+    let mut data = state.lock().await;
+    let Some(session) = data.sessions.get_mut(&chat_id)
+        .filter(|s| s.session_id.as_deref() == Some(old_id.as_str()))
+    else {
+        return Err("The session changed while compacting; nothing was replaced.".to_string());
+    };
+    let new = compact::start_compacted_session(&old_id, &brief, &current_path)
+        .ok_or_else(|| "Failed to save compacted session.".to_string())?;
+    session.session_id = Some(new.session_id.clone());
+    session.history = new.history;
+    session.fork_from = Some(old_id.clone());
+    session.compact_brief = Some(brief);
+
+    let ts = chrono::Local::now().format("%H:%M:%S");
+    println!("  [{ts}] ▶ Session compacted: {old_id} → {} ({item_count} items)", new.session_id);
+    Ok((old_id, new.session_id, item_count))
+}
+
+/// Handle /compact command - replace the conversation with an agent-written summary
+pub async fn handle_compact_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    // Hold a cancel token so other messages wait (and /stop works) like during a turn
+    let cancel_token = Arc::new(CancelToken::new());
+    {
+        let mut data = state.lock().await;
+        data.cancel_tokens.insert(chat_id, cancel_token.clone());
+    }
+
+    // Summarize in a task so this chat's handler returns and a /stop can be handled
+    let bot = bot.clone();
+    let state = state.clone();
+    tokio::spawn(async move {
+        shared_rate_limit_wait(&state, chat_id).await;
+        let progress = bot.send_message(chat_id, "🗜 Compacting conversation...").await;
+
+        let result = compact_chat(chat_id, &state, &cancel_token).await;
+
+        let stop_msg_id = {
+            let mut data = state.lock().await;
+            data.cancel_tokens.remove(&chat_id);
+            data.stop_message_ids.remove(&chat_id)
+        };
+        if let Some(msg_id) = stop_msg_id {
+            shared_rate_limit_wait(&state, chat_id).await;
+            let _ = bot.delete_message(chat_id, msg_id).await;
+        }
+
+        super::chat::run_queued_messages(bot.clone(), chat_id, state.clone());
+
+        let response = match result {
+            Ok((old_id, new_id, count)) => format!(
+                "Compacted {} messages into a summary.\nNew session <code>{}</code> starts with it on your next message.\nThe full conversation is kept as <code>{}</code> (/resume {} to go back).",
+                count,
+                html_escape(&new_id),
+                html_escape(&old_id),
+                html_escape(&old_id),
+            ),
+            Err(e) => format!("Compaction failed: {}", html_escape(&e)),
+        };
+
+        shared_rate_limit_wait(&state, chat_id).await;
+        match progress {
+            Ok(msg) => {
+                let _ = bot.edit_message_text(chat_id, msg.id, &response)
+                    .parse_mode(ParseMode::Html)
+                    .await;
+            }
+            Err(_) => {
+                let _ = bot.send_message(chat_id, &response)
+                    .parse_mode(ParseMode::Html)
+                    .await;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Handle /search command - full-text search across saved sessions
+/// Usage: /search <terms>
+pub async fn handle_search_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let query = text.strip_prefix("/search").unwrap_or("").trim();
+    if query.is_empty() {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, "Usage: /search <terms>")
+            .await?;
+        return Ok(());
+    }
+
+    let scope = super::session_origin(bot, chat_id);
+    let terms = query.to_string();
+    let hits = tokio::task::spawn_blocking(move || search::search_sessions(&terms, 10, Some(&scope))).await
+        .unwrap_or_default();
+    if hits.is_empty() {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, format!("No sessions match <code>{}</code>.", html_escape(query)))
+            .parse_mode(ParseMode::Html)
+            .await?;
+        return Ok(());
+    }
+
+    let mut msg = format!("<b>Search: {}</b>\n\n", html_escape(query));
+    for (i, hit) in hits.iter().enumerate() {
+        msg.push_str(&format!(
+            "<code>{}</code>. <code>{}</code>\n    {}{}\n",
+            i + 1,
+            html_escape(&hit.current_path),
+            html_escape(&hit.created_at),
+            hit.name.as_ref().map(|n| format!(" | <i>{}</i>", html_escape(n))).unwrap_or_default(),
+        ));
+        for snippet in &hit.snippets {
+            msg.push_str(&format!("    » {}\n", html_escape(snippet)));
+        }
+        msg.push_str(&format!("    <code>/resume {}</code>\n\n", html_escape(&hit.session_id)));
+    }
+
+    send_long_message(bot, chat_id, &msg, Some(ParseMode::Html), state).await?;
+    // Results may span several messages; the buttons go in their own
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, "Tap a match to resume it:")
+        .reply_markup(keyboards::search_keyboard(&hits))
+        .await?;
+
+    Ok(())
+}
+
+/// Current session id for a chat, if any
+async fn current_session_id(chat_id: ChatId, state: &SharedState) -> Option<String> {
+    let data = state.lock().await;
+    data.sessions.get(&chat_id).and_then(|s| s.session_id.clone())
+}
+
+const UNSAVED_SESSION_MSG: &str = "No saved session yet. Send a message first.";
+
+/// Handle /rename command - set the current session's name
+/// Usage: /rename <title>
+pub async fn handle_rename_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let title = text.strip_prefix("/rename").unwrap_or("").trim();
+    let response = if title.is_empty() {
+        "Usage: /rename &lt;title&gt;".to_string()
+    } else {
+        match current_session_id(chat_id, state).await
+            .and_then(|sid| bot_common::update_session_meta(&sid, |d| d.name = Some(title.to_string())))
+        {
+            Some(_) => format!("Session renamed to <b>{}</b>.", html_escape(title)),
+            None => UNSAVED_SESSION_MSG.to_string(),
+        }
+    };
+
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+/// Handle /tag command - add or remove a label on the current session
+/// Usage: /tag              (show tags)
+///        /tag <label>      (add)
+///        /tag -<label>     (remove)
+pub async fn handle_tag_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let arg = text.strip_prefix("/tag").unwrap_or("").trim();
+    let (remove, label) = match arg.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, arg),
+    };
+
+    let response = match current_session_id(chat_id, state).await {
+        None => UNSAVED_SESSION_MSG.to_string(),
+        Some(sid) if arg.is_empty() => match bot_common::load_session_by_id(&sid) {
+            Some(d) if d.tags.is_empty() => "No tags. Add one with <code>/tag &lt;label&gt;</code>.".to_string(),
+            Some(d) => {
+                let tags: Vec<String> = d.tags.iter().map(|t| format!("#{}", html_escape(t))).collect();
+                format!("Tags: {}", tags.join(" "))
+            }
+            None => UNSAVED_SESSION_MSG.to_string(),
+        },
+        Some(sid) => match bot_common::normalize_tag(label) {
+            None => "Invalid tag. Use a single word up to 32 characters.".to_string(),
+            Some(tag) => {
+                let updated = bot_common::update_session_meta(&sid, |d| {
+                    if remove {
+                        d.tags.retain(|t| t != &tag);
+                    } else if !d.tags.contains(&tag) {
+                        d.tags.push(tag.clone());
+                    }
+                });
+                match updated {
+                    Some(_) if remove => format!("Removed tag <code>#{}</code>.", html_escape(&tag)),
+                    Some(_) => format!("Tagged <code>#{}</code>. Resume later with <code>/resume {}</code>.", html_escape(&tag), html_escape(&tag)),
+                    None => UNSAVED_SESSION_MSG.to_string(),
+                }
+            }
+        },
+    };
+
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+/// Handle /pin command - toggle pinning of the current session in /resume
+pub async fn handle_pin_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let response = match current_session_id(chat_id, state).await
+        .and_then(|sid| bot_common::update_session_meta(&sid, |d| d.pinned = !d.pinned))
+    {
+        Some(d) if d.pinned => "📌 Session pinned. It is listed first in /resume.",
+        Some(_) => "Session unpinned.",
+        None => UNSAVED_SESSION_MSG,
+    };
+
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, response)
+        .await?;
+
+    Ok(())
+}
+
+/// Current session directory for a chat, if a session is active
+async fn current_path(chat_id: ChatId, state: &SharedState) -> Option<String> {
+    let data = state.lock().await;
+    data.sessions.get(&chat_id).and_then(|s| s.current_path.clone())
+}
+
+const NO_SESSION_MSG: &str = "No active session. Use /start <path> first.";
+
+/// Handle /diff command - show uncommitted changes in the session directory
+pub async fn handle_diff_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let Some(path) = current_path(chat_id, state).await else {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+
+    let dir = path.clone();
+    let result = tokio::task::spawn_blocking(move || git::working_tree_diff(&dir)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+    match result {
+        Ok(diff) if diff.is_empty() => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, "No uncommitted changes.").await?;
+        }
+        Ok(diff) if git::fits_inline(&diff) => {
+            let html = markdown_to_telegram_html(&git::render_diff_markdown(&diff));
+            send_long_message(bot, chat_id, &html, Some(ParseMode::Html), state).await?;
+        }
+        Ok(diff) => {
+            match git::write_patch_file(&path, &diff) {
+                Ok(patch_path) => {
+                    let mut caption = diff.stat.clone();
+                    if !diff.untracked.is_empty() {
+                        caption.push_str(&format!("\nUntracked: {}", diff.untracked.join(", ")));
+                    }
+                    shared_rate_limit_wait(state, chat_id).await;
+                    let sent = bot.send_document(chat_id, teloxide::types::InputFile::file(&patch_path))
+                        .caption(truncate_str(&caption, 1000))
+                        .await;
+                    // The patch is only kept for the upload
+                    let _ = std::fs::remove_file(&patch_path);
+                    sent?;
+                }
+                Err(e) => {
+                    shared_rate_limit_wait(state, chat_id).await;
+                    bot.send_message(chat_id, format!("Failed to write patch: {}", e)).await?;
+                }
+            }
+        }
+        Err(e) => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, e).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle /commit command - commit all changes in the session directory
+/// Usage: /commit             (claude writes the message)
+///        /commit <message>
+pub async fn handle_commit_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let Some(path) = current_path(chat_id, state).await else {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+    let message = text.strip_prefix("/commit").unwrap_or("").trim().to_string();
+    let dir = path.clone();
+    let diff = tokio::task::spawn_blocking(move || git::working_tree_diff(&dir)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    let diff = match diff {
+        Ok(diff) if diff.is_empty() => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, "Nothing to commit.").await?;
+            return Ok(());
+        }
+        Ok(diff) => diff,
+        Err(e) => {
+            shared_rate_limit_wait(state, chat_id).await;
+            bot.send_message(chat_id, e).await?;
+            return Ok(());
+        }
+    };
+
+    if message.is_empty() {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, "Asking claude for a commit message...").await?;
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        let message = if message.is_empty() {
+            git::generate_commit_message(&path, &diff)
+                .map_err(|e| format!("Could not generate a commit message: {}", e))?
+        } else {
+            message
+        };
+        git::commit_all(&path, &message)
+    }).await.unwrap_or_else(|e| Err(e.to_string()));
+
+    let response = match result {
+        Ok(summary) => {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ▶ Committed: {}", summary);
+            format!("Committed <code>{}</code>", html_escape(&summary))
+        }
+        Err(e) => format!("Commit failed:\n<pre>{}</pre>", html_escape(&e)),
+    };
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+/// Handle /branch command - create and switch to a branch for this session
+/// Usage: /branch             (aemi/<session name or id>)
+///        /branch <name>
+pub async fn handle_branch_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let Some(path) = current_path(chat_id, state).await else {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+    let arg = text.strip_prefix("/branch").unwrap_or("").trim();
+
+    let branch = if !arg.is_empty() {
+        arg.to_string()
+    } else {
+        match current_session_id(chat_id, state).await {
+            Some(sid) => {
+                let name = bot_common::load_session_by_id(&sid).and_then(|d| d.name);
+                git::session_branch_name(&sid, name.as_deref())
+            }
+            None => {
+                shared_rate_limit_wait(state, chat_id).await;
+                bot.send_message(chat_id, UNSAVED_SESSION_MSG).await?;
+                return Ok(());
+            }
+        }
+    };
+
+    let branch_owned = branch.clone();
+    let result = tokio::task::spawn_blocking(move || git::switch_branch(&path, &branch_owned)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+    let response = match result {
+        Ok(true) => format!("Created and switched to branch <code>{}</code>", html_escape(&branch)),
+        Ok(false) => format!("Switched to branch <code>{}</code>", html_escape(&branch)),
+        Err(e) => format!("Branch switch failed:\n<pre>{}</pre>", html_escape(&e)),
+    };
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+/// Restore a checkpoint and describe the result
+async fn restore_checkpoint(path: String, id: String) -> String {
+    let result = tokio::task::spawn_blocking(move || checkpoint::restore(&path, &id)).await
+        .unwrap_or_else(|e| Err(e.to_string()));
+    match result {
+        Ok((cp, report)) => {
+            let ts = chrono::Local::now().format("%H:%M:%S");
+            println!("  [{ts}] ▶ Restored checkpoint {}", cp.id);
+            format!(
+                "Restored workspace to before <i>{}</i> ({})\n{} file(s) restored, {} removed.\nConversation history is unchanged.",
+                html_escape(&cp.label), cp.created_at, report.restored, report.removed
+            )
+        }
+        Err(e) => format!("Restore failed:\n<pre>{}</pre>", html_escape(&e)),
+    }
+}
+
+/// Handle /undo command - roll the workspace back to before the last AI turn
+pub async fn handle_undo_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let Some(path) = current_path(chat_id, state).await else {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+
+    let response = match checkpoint::list(&path).last() {
+        Some(latest) => restore_checkpoint(path.clone(), latest.id.clone()).await,
+        None => "No checkpoints for this directory.".to_string(),
+    };
+    shared_rate_limit_wait(state, chat_id).await;
+    bot.send_message(chat_id, &response)
+        .parse_mode(ParseMode::Html)
+        .await?;
+
+    Ok(())
+}
+
+/// Handle /checkpoints command - list workspace checkpoints or restore one
+/// Usage: /checkpoints        (list, newest first)
+///        /checkpoints <n>    (restore; newer checkpoints are dropped)
+pub async fn handle_checkpoints_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let Some(path) = current_path(chat_id, state).await else {
+        shared_rate_limit_wait(state, chat_id).await;
+        bot.send_message(chat_id, NO_SESSION_MSG).await?;
+        return Ok(());
+    };
+    let arg = text.strip_prefix("/checkpoints").unwrap_or("").trim();
+    let mut checkpoints = checkpoint::list(&path);
+    checkpoints.reverse();
+
+    let response = if checkpoints.is_empty() {
+        "No checkpoints for this directory.".to_string()
+    } else if arg.is_empty() {
+        let mut msg = String::from("<b>Checkpoints</b> (newest first)\n\n");
+        for (i, cp) in checkpoints.iter().enumerate() {
+            msg.push_str(&format!(
+                "{}. {} [{}] {}\n",
+                i + 1, cp.created_at, cp.kind(), html_escape(&cp.label)
+            ));
+        }
+        msg.push_str("\n<code>/checkpoints &lt;n&gt;</code> restores the workspace to before that turn; newer checkpoints are dropped.");
+        msg
+    } else {
+        match arg.parse::<usize>().ok().and_then(|n| checkpoints.get(n.wrapping_sub(1))) {
+            Some(cp) => restore_checkpoint(path.clone(), cp.id.clone()).await,
+            None => format!("Usage: /checkpoints [1-{}]", checkpoints.len()),
+        }
+    };
+    send_long_message(bot, chat_id, &response, Some(ParseMode::Html), state).await?;
+
+    Ok(())
+}
+
+/// Handle /templates command - list workspace templates
+pub async fn handle_templates_command(
+    bot: &Bot,
+    chat_id: ChatId,
+    state: &SharedState,
+) -> ResponseResult<()> {
+    let templates = template::list_templates();
+    let response = if templates.is_empty() {
+        let dir = template::templates_dir()
+            .map(|d| d.display().to_string())
+            .unwrap_or_else(|| "~/.aemi/templates".to_string());
+        format!(
+            "No templates. Create a directory in <code>{}/&lt;name&gt;/</code> with a <code>files/</code> directory, \
+             and optionally <code>init.sh</code>, <code>prompt.md</code> and <code>description.txt</code>.",
+            html_escape(&dir)
+        )
+    } else {
+        let mut msg = String::from("<b>Templates</b>\n\n");
+        for t in &templates {
+            let mut parts = Vec::new();
+            if t.has_init {
+                parts.push("init");
+            }
+            if t.has_prompt {
+                parts.push("prompt");
+            }
+            let extras = if parts.is_empty() { String::new() } else { format!(" [{}]", parts.join(", ")) };
+            msg.push_str(&format!("<code>{}</code>{}", html_escape(&t.name), extras));
+            if let Some(desc) = &t.description {
+                msg.push_str(&format!(" — {}", html_escape(desc)));
+            }
+            msg.push('\n');
+        }
+        msg.push_str("\nStart one with <code>/start --template &lt;name&gt;</code>");
+        msg
+    };
+    send_long_message(bot, chat_id, &response, Some(ParseMode::Html), state).await?;
+
+    Ok(())
+}
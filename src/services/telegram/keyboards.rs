@@ -0,0 +1,118 @@
+//! Inline keyboards for /resume, /agent and /allowed.
+
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::services::bot_common::{SessionSummary, ALL_TOOLS, AVAILABLE_AGENTS};
+use crate::services::search::SearchHit;
+use crate::services::utils::truncate_str;
+
+/// Telegram's limit on callback data, in bytes
+const CALLBACK_DATA_LIMIT: usize = 64;
+
+/// Buttons per row for agents and tools
+const ROW_LEN: usize = 3;
+
+/// What a button does. The callback data names the command it stands for, so a tap is
+/// checked against the sender's role and run like the typed command: `resume:<session id>`,
+/// `agent:<name>` and `tool:<name>`, which turns the tool on or off for the chat.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Resume a session by id
+    Resume(String),
+    /// Switch the chat's agent
+    Agent(String),
+    /// Turn a tool on or off for the chat
+    Tool(String),
+}
+
+impl Action {
+    pub fn parse(data: &str) -> Option<Action> {
+        let (kind, arg) = data.split_once(':')?;
+        if arg.is_empty() {
+            return None;
+        }
+        match kind {
+            "resume" => Some(Action::Resume(arg.to_string())),
+            "agent" => Some(Action::Agent(arg.to_string())),
+            "tool" => Some(Action::Tool(arg.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Callback data of the button
+    pub fn data(&self) -> String {
+        match self {
+            Action::Resume(arg) => format!("resume:{}", arg),
+            Action::Agent(name) => format!("agent:{}", name),
+            Action::Tool(name) => format!("tool:{}", name),
+        }
+    }
+
+    /// The typed command a tap stands for; `tool_allowed` tells whether the tool is on now
+    pub fn command(&self, tool_allowed: bool) -> String {
+        match self {
+            Action::Resume(arg) => format!("/resume {}", arg),
+            Action::Agent(name) => format!("/agent {}", name),
+            Action::Tool(name) if tool_allowed => format!("/allowed -{}", name),
+            Action::Tool(name) => format!("/allowed +{}", name),
+        }
+    }
+}
+
+fn button(label: String, action: Action) -> InlineKeyboardButton {
+    InlineKeyboardButton::callback(label, action.data())
+}
+
+/// One button per listed session, numbered like the list. Buttons resume by id, which stays
+/// valid if the list changes; sessions whose id does not fit in the callback data get no button.
+pub fn resume_keyboard(sessions: &[SessionSummary], max_display: usize) -> InlineKeyboardMarkup {
+    let rows = sessions.iter().take(max_display).enumerate()
+        .map(|(i, s)| (i, s, Action::Resume(s.session_id.clone())))
+        .filter(|(_, _, action)| action.data().len() <= CALLBACK_DATA_LIMIT)
+        .map(|(i, s, action)| {
+            let pin = if s.pinned { "📌 " } else { "" };
+            vec![button(format!("{}. {}{}", i + 1, pin, truncate_str(&s.title, 40)), action)]
+        });
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// One button per search hit, numbered like the results. Hits whose id does not fit in the
+/// callback data get no button (their number means nothing to /resume).
+pub fn search_keyboard(hits: &[SearchHit]) -> InlineKeyboardMarkup {
+    let rows = hits.iter().enumerate()
+        .map(|(i, hit)| (i, hit, Action::Resume(hit.session_id.clone())))
+        .filter(|(_, _, action)| action.data().len() <= CALLBACK_DATA_LIMIT)
+        .map(|(i, hit, action)| {
+            let title = hit.name.as_deref().unwrap_or(&hit.current_path);
+            vec![button(format!("{}. {}", i + 1, truncate_str(title, 40)), action)]
+        });
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// Every agent, the current one checked
+pub fn agent_keyboard(current: &str) -> InlineKeyboardMarkup {
+    let buttons: Vec<InlineKeyboardButton> = AVAILABLE_AGENTS.iter()
+        .map(|&(name, _)| {
+            let label = if name == current { format!("✓ {}", name) } else { name.to_string() };
+            button(label, Action::Agent(name.to_string()))
+        })
+        .collect();
+    InlineKeyboardMarkup::new(buttons.chunks(ROW_LEN).map(|row| row.to_vec()))
+}
+
+/// Every known tool plus the chat's custom ones, checked when allowed
+pub fn tools_keyboard(allowed: &[String]) -> InlineKeyboardMarkup {
+    let custom = allowed.iter()
+        .filter(|name| !ALL_TOOLS.iter().any(|(tool, _, _)| tool == name))
+        .map(String::as_str);
+    let buttons: Vec<InlineKeyboardButton> = ALL_TOOLS.iter().map(|(tool, _, _)| *tool)
+        .chain(custom)
+        .map(|name| (name, Action::Tool(name.to_string())))
+        .filter(|(_, action)| action.data().len() <= CALLBACK_DATA_LIMIT)
+        .map(|(name, action)| {
+            let mark = if allowed.iter().any(|t| t == name) { "✅" } else { "⬜" };
+            button(format!("{} {}", mark, name), action)
+        })
+        .collect();
+    InlineKeyboardMarkup::new(buttons.chunks(ROW_LEN).map(|row| row.to_vec()))
+}
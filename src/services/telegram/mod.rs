@@ -2,17 +2,29 @@ mod commands;
 mod chat;
 mod messages;
 mod markdown;
+mod keyboards;
 
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use tokio::sync::Mutex;
 use teloxide::prelude::*;
+use teloxide::types::CallbackQuery;
 
 use crate::services::agent::CancelToken;
-use crate::services::bot_common::{self, BotSettings};
+use crate::services::bot_common::{self, BotOptions, BotSettings};
+use crate::services::chat_settings;
+use crate::services::chat_state;
+use crate::services::config::{self, Platform};
+use crate::services::reload;
+use crate::services::ipc;
+use crate::services::retention;
+use crate::services::roles::{self, ChatAccess, Role};
+use crate::services::shutdown;
+use crate::services::access;
 use crate::services::utils::truncate_str;
-use crate::services::session::HistoryItem;
+use crate::services::session::{HistoryItem, SessionOrigin};
 
 /// Per-chat session state
 pub(crate) struct ChatSession {
@@ -24,6 +36,13 @@ pub(crate) struct ChatSession {
     pub pending_uploads: Vec<String>,
     /// Set to true by /clear to prevent a racing polling loop from re-populating history.
     pub cleared: bool,
+    /// Parent session id set by /fork. The next turn forks from it (natively where the
+    /// agent supports it) and the fork placeholder is renamed to the new provider id.
+    pub fork_from: Option<String>,
+    /// Brief set by /compact. The next turn starts a fresh provider session seeded with it.
+    pub compact_brief: Option<String>,
+    /// Set when the provider reported a context overflow; the next turn compacts first.
+    pub needs_compact: bool,
 }
 
 /// Shared state: per-chat sessions + bot settings
@@ -32,14 +51,110 @@ pub(crate) struct SharedData {
     pub settings: BotSettings,
     /// Per-chat cancel tokens for stopping in-progress AI requests
     pub cancel_tokens: HashMap<ChatId, Arc<CancelToken>>,
+    /// Plain messages received while a turn was running (text, message id, sender role).
+    /// Sent as one merged follow-up prompt when the turn ends.
+    pub queued_messages: HashMap<ChatId, Vec<(String, teloxide::types::MessageId, Role)>>,
     /// Message ID of the "Stopping..." message sent by /stop, so the polling loop can update it
     pub stop_message_ids: HashMap<ChatId, teloxide::types::MessageId>,
     /// Per-chat timestamp of the last Telegram API call (for rate limiting)
     pub api_timestamps: HashMap<ChatId, tokio::time::Instant>,
-    /// If set, only messages from this chat ID are allowed (--chat-id parameter)
-    pub allowed_chat_id: Option<i64>,
-    /// Agent type: "claude" or "gemini"
+    /// If non-empty, only messages from these chat IDs are allowed (--chat-id or config `chats`)
+    pub allowed_chat_ids: Vec<i64>,
+    /// Role of allowed-chat members who are not listed users
+    pub chat_role: Role,
+    /// Workspace roots for /start, /down, shell and uploads (None = unrestricted)
+    pub allowed_roots: Option<Vec<PathBuf>>,
+    /// Bot-wide agent from --agent or the config file; chats can override it
     pub agent_type: String,
+    /// Config file the bot was started from (None for command-line bots)
+    pub config_path: Option<PathBuf>,
+}
+
+impl SharedData {
+    /// Current values of everything a reload can change
+    pub fn snapshot(&self) -> reload::Snapshot {
+        reload::Snapshot {
+            chats: self.allowed_chat_ids.clone(),
+            agent: self.agent_type.clone(),
+            chat_role: self.chat_role,
+            allowed_roots: self.allowed_roots.clone(),
+            settings: self.settings.clone(),
+        }
+    }
+
+    /// Replace the reloadable values. Agent sessions and running turns are runtime
+    /// state and stay as they are.
+    pub fn apply_snapshot(&mut self, snapshot: reload::Snapshot) {
+        let chat_state = std::mem::take(&mut self.settings.chat_state);
+        self.allowed_chat_ids = snapshot.chats;
+        self.agent_type = snapshot.agent;
+        self.chat_role = snapshot.chat_role;
+        self.allowed_roots = snapshot.allowed_roots;
+        self.settings = snapshot.settings;
+        self.settings.chat_state = chat_state;
+    }
+
+    /// Role of a user in a chat: the owner and listed users are allowed in any chat and
+    /// members of allowed chats get the chat role. None means the user is not served.
+    pub fn role_of(&self, chat_id: ChatId, user_id: Option<u64>) -> Option<Role> {
+        let chat = if !self.allowed_chat_ids.contains(&chat_id.0) {
+            ChatAccess::NotListed
+        } else if user_id.map(|u| u as i64) == Some(chat_id.0) {
+            ChatAccess::ListedPrivate
+        } else {
+            ChatAccess::Listed
+        };
+        roles::resolve(&self.settings.users, self.settings.owner_user_id, self.chat_role, user_id, chat)
+    }
+
+    /// Settings in effect for a chat: its own settings over the bot-wide defaults
+    pub fn effective_settings(&self, chat_id: ChatId) -> chat_settings::Effective {
+        chat_settings::effective(&self.settings, &self.agent_type, &chat_id.0.to_string())
+    }
+
+    /// Remember the chat's provider session under its current agent (saved with the settings)
+    pub fn remember_session(&mut self, chat_id: ChatId) {
+        let agent = self.effective_settings(chat_id).agent.value;
+        let session_id = self.sessions.get(&chat_id).and_then(|s| s.session_id.clone());
+        self.settings.chat_state.entry(chat_id.0.to_string()).or_default()
+            .set_session(&agent, session_id.as_deref());
+    }
+
+    /// Pick up the provider session the chat's current agent last used in this workspace,
+    /// after an agent switch (call [`Self::remember_session`] before switching).
+    /// Each agent keeps its own session; returns true if one was resumed.
+    pub fn resume_agent_session(&mut self, chat_id: ChatId) -> bool {
+        let agent = self.effective_settings(chat_id).agent.value;
+        let saved = self.settings.chat_state.get(&chat_id.0.to_string()).and_then(|s| s.sessions.get(&agent).cloned());
+        let Some(session) = self.sessions.get_mut(&chat_id) else {
+            return false;
+        };
+        let resumed = saved.as_deref()
+            .and_then(bot_common::load_session_by_id)
+            .filter(|data| session.current_path.as_deref() == Some(data.current_path.as_str()));
+        session.fork_from = None;
+        session.compact_brief = None;
+        session.needs_compact = false;
+        match resumed {
+            Some(data) => {
+                session.session_id = Some(data.session_id);
+                session.history = data.history;
+                true
+            }
+            None => {
+                session.session_id = None;
+                false
+            }
+        }
+    }
+
+    /// The chat's turn ended: clear its running mark and remember the session it used
+    pub fn finish_turn(&mut self, chat_id: ChatId) {
+        self.remember_session(chat_id);
+        if let Some(state) = self.settings.chat_state.get_mut(&chat_id.0.to_string()) {
+            state.running = None;
+        }
+    }
 }
 
 pub(crate) type SharedState = Arc<Mutex<SharedData>>;
@@ -52,53 +167,324 @@ pub fn token_hash(token: &str) -> String {
     bot_common::token_hash(token, None)
 }
 
-/// Resolve a bot token from its hash by searching bot_settings.json
-pub fn resolve_token_by_hash(hash: &str) -> Option<String> {
-    use std::fs;
-    let path = bot_common::bot_settings_path()?;
-    let content = fs::read_to_string(&path).ok()?;
-    let json: serde_json::Value = serde_json::from_str(&content).ok()?;
-    let obj = json.as_object()?;
-    let entry = obj.get(hash)?;
-    entry.get("token").and_then(|v| v.as_str()).map(String::from)
+/// Session origin recorded in saved session files
+pub(crate) fn session_origin(bot: &Bot, chat_id: ChatId) -> SessionOrigin {
+    SessionOrigin {
+        platform: "telegram".to_string(),
+        chat_id: chat_id.0.to_string(),
+        bot_hash: token_hash(bot.token()),
+    }
+}
+
+/// Deliver a file or message for `aemi send` (local socket request)
+async fn handle_local_request(bot: Bot, state: SharedState, request: ipc::Request) -> Result<(), String> {
+    let (ipc::Request::SendFile { chat, .. } | ipc::Request::SendText { chat, .. }) = &request;
+    let chat_id = ChatId(*chat);
+    let allowed = {
+        let data = state.lock().await;
+        if data.allowed_chat_ids.is_empty() {
+            data.sessions.contains_key(&chat_id)
+        } else {
+            data.allowed_chat_ids.contains(&chat_id.0)
+        }
+    };
+    if !allowed {
+        return Err(format!("chat {} is not served by this bot", chat_id.0));
+    }
+
+    let timestamp = chrono::Local::now().format("%H:%M:%S");
+    messages::shared_rate_limit_wait(&state, chat_id).await;
+    match request {
+        ipc::Request::SendFile { path, .. } => {
+            if !path.is_file() {
+                return Err(format!("file not found: {}", path.display()));
+            }
+            // Same rules as /down: allowed roots, and the session directory when confined
+            let (roots, session_dir) = {
+                let data = state.lock().await;
+                let session_dir = data.sessions.get(&chat_id).and_then(|s| s.current_path.clone());
+                (data.allowed_roots.clone(), session_dir)
+            };
+            let path = access::check_download(roots.as_deref(), &path, session_dir.as_deref(), "aemi send")?;
+            println!("  [{timestamp}] ▶ [aemi send] File to chat {}: {}", chat_id.0, path.display());
+            bot.send_document(chat_id, teloxide::types::InputFile::file(path)).await
+                .map_err(|e| e.to_string())?;
+        }
+        ipc::Request::SendText { text, .. } => {
+            println!("  [{timestamp}] ▶ [aemi send] Message to chat {}", chat_id.0);
+            bot.send_message(chat_id, text).await.map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Re-read the config file and bot_settings.json and apply them in one step (SIGHUP or
+/// /reload). Returns what changed; on error nothing is changed.
+pub(crate) async fn reload_bot(state: &SharedState, token: &str) -> Result<Vec<String>, String> {
+    let config_path = state.lock().await.config_path.clone();
+    // The config may run a token helper, so read it off the async runtime
+    let entry = match config_path {
+        Some(path) => {
+            let token = token.to_string();
+            let found = tokio::task::spawn_blocking(move || config::find(&path, Platform::Telegram, &token)).await;
+            Some(found.map_err(|e| e.to_string())??)
+        }
+        None => None,
+    };
+
+    let mut data = state.lock().await;
+    let settings = bot_common::read_bot_settings(&token_hash(token))?;
+    let new = match entry {
+        Some(bot) => reload::snapshot(bot.chats, &bot.agent, bot.options, settings),
+        // Command-line bots keep their chats and agent
+        None => reload::snapshot(data.allowed_chat_ids.clone(), &data.agent_type.clone(), BotOptions::default(), settings),
+    };
+    let changes = reload::changes(&data.snapshot(), &new);
+    data.apply_snapshot(new);
+    Ok(changes)
+}
+
+/// Reload and log the outcome
+async fn reload_and_log(state: &SharedState, token: &str, trigger: &str) -> Result<Vec<String>, String> {
+    let result = reload_bot(state, token).await;
+    let ts = chrono::Local::now().format("%H:%M:%S");
+    match &result {
+        Ok(changes) if changes.is_empty() => println!("  [{ts}] ↻ Reloaded ({trigger}): no changes"),
+        Ok(changes) => println!("  [{ts}] ↻ Reloaded ({trigger}): {}", changes.join("; ")),
+        Err(e) => println!("  [{ts}] ⚠ Reload failed ({trigger}): {e}"),
+    }
+    result
 }
 
 /// Entry point: start the Telegram bot with long polling
-pub async fn run_bot(token: &str, allowed_chat_id: Option<i64>, agent_type: &str) {
+pub async fn run_bot(token: &str, allowed_chat_ids: Vec<i64>, agent_type: &str, options: BotOptions) {
     let bot = Bot::new(token);
-    let bot_settings = bot_common::load_bot_settings(&token_hash(token));
+    let config_path = options.config_path.clone();
+    let startup = reload::snapshot(allowed_chat_ids, agent_type, options, bot_common::load_bot_settings(&token_hash(token)));
+    let reload::Snapshot { chats: allowed_chat_ids, chat_role, allowed_roots, settings: mut bot_settings, .. } = startup;
+
+    // Turns still running when the bot last stopped were cut off
+    let interrupted = chat_state::mark_interrupted(&mut bot_settings.chat_state);
+    if !interrupted.is_empty() {
+        bot_common::save_bot_settings(&token_hash(token), &bot_settings, &[("platform", "telegram")]);
+        println!("  ⚠ Interrupted turns: {} chat(s)", interrupted.len());
+    }
 
-    if let Some(cid) = allowed_chat_id {
-        println!("  ✓ Chat ID restriction: {cid}");
+    if !allowed_chat_ids.is_empty() {
+        let ids: Vec<String> = allowed_chat_ids.iter().map(|id| id.to_string()).collect();
+        println!("  ✓ Chat ID restriction: {} (members: {chat_role})", ids.join(", "));
     } else {
         match bot_settings.owner_user_id {
             Some(owner_id) => println!("  ✓ Owner: {owner_id}"),
             None => println!("  ⚠ No owner registered — first user will be registered as owner"),
         }
     }
+    if !bot_settings.users.is_empty() {
+        println!("  ✓ Users: {}", bot_settings.users.len());
+    }
 
     let state: SharedState = Arc::new(Mutex::new(SharedData {
         sessions: HashMap::new(),
         settings: bot_settings,
         cancel_tokens: HashMap::new(),
         stop_message_ids: HashMap::new(),
+        queued_messages: HashMap::new(),
         api_timestamps: HashMap::new(),
-        allowed_chat_id,
+        allowed_chat_ids,
+        chat_role,
+        allowed_roots,
         agent_type: agent_type.to_string(),
+        config_path,
     }));
 
+    // Retention: prune old sessions, workspaces and logs at startup and periodically.
+    // Sessions and workspaces open in a chat of any bot in this process are never pruned.
+    let prune_state = state.clone();
+    retention::register(Box::new(move || {
+        let state = prune_state.clone();
+        Box::pin(async move {
+            let data = state.lock().await;
+            retention::Protected {
+                session_ids: data.sessions.values().filter_map(|s| s.session_id.clone()).collect(),
+                paths: data.sessions.values().filter_map(|s| s.current_path.clone()).collect(),
+                changed_within: None,
+            }
+        })
+    }));
+
+    // Local socket for `aemi send`, so the CLI never needs the token
+    let socket_path = match ipc::bind(&token_hash(token)) {
+        Ok((listener, path)) => {
+            let (socket_bot, socket_state) = (bot.clone(), state.clone());
+            tokio::spawn(ipc::serve(listener, move |request| {
+                handle_local_request(socket_bot.clone(), socket_state.clone(), request)
+            }));
+            Some(path)
+        }
+        Err(e) => {
+            println!("  ⚠ Local socket unavailable, `aemi send` will not work: {}", e);
+            None
+        }
+    };
+
+    // SIGHUP reloads the config and settings without dropping the connection
+    let (reload_state, reload_token) = (state.clone(), token.to_string());
+    tokio::spawn(async move {
+        let Ok(mut hangup) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) else {
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            let _ = reload_and_log(&reload_state, &reload_token, "SIGHUP").await;
+        }
+    });
+
+    // Tell chats whose turn was interrupted; /retry runs it again
+    let (notice_bot, notice_state) = (bot.clone(), state.clone());
+    tokio::spawn(async move {
+        for (key, turn) in interrupted {
+            let Ok(id) = key.parse::<i64>() else { continue };
+            messages::shared_rate_limit_wait(&notice_state, ChatId(id)).await;
+            let _ = notice_bot.send_message(ChatId(id), chat_state::interrupted_notice(&turn)).await;
+        }
+    });
+
+    // Ctrl-C and SIGTERM drain running turns before the bot disconnects
+    shutdown::listen();
+
     println!("  ✓ Bot connected — Listening for messages");
 
     let shared_state = state.clone();
     let token_owned = token.to_string();
-    teloxide::repl(bot, move |bot: Bot, msg: Message| {
-        let state = shared_state.clone();
-        let token = token_owned.clone();
-        async move {
-            handle_message(bot, msg, state, &token).await
+    let (button_state, button_token) = (state.clone(), token.to_string());
+    let handler = dptree::entry()
+        .branch(Update::filter_message().endpoint(move |bot: Bot, msg: Message| {
+            let state = shared_state.clone();
+            let token = token_owned.clone();
+            async move {
+                handle_message(bot, msg, state, &token).await
+            }
+        }))
+        // Taps on the /resume, /agent and /allowed list buttons
+        .branch(Update::filter_callback_query().endpoint(move |bot: Bot, query: CallbackQuery| {
+            let state = button_state.clone();
+            let token = button_token.clone();
+            async move {
+                handle_callback_query(bot, query, state, &token).await
+            }
+        }));
+    // Other update types are ignored
+    let mut dispatcher = Dispatcher::builder(bot, handler)
+        .default_handler(|_update| Box::pin(async {}))
+        .build();
+
+    let shutdown_token = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        shutdown::stopped().await;
+        if let Ok(stopping) = shutdown_token.shutdown() {
+            stopping.await;
         }
-    })
-    .await;
+    });
+
+    dispatcher.dispatch().await;
+
+    if let Some(path) = socket_path {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Restore the chat's last session from bot_settings.json if it is not in memory
+async fn auto_restore_session(bot: &Bot, chat_id: ChatId, state: &SharedState, user_name: &str) {
+    let mut data = state.lock().await;
+    if !data.sessions.contains_key(&chat_id) {
+        if let Some(last_path) = data.settings.last_sessions.get(&chat_id.0.to_string()).cloned() {
+            let who = format!("chat {}", chat_id.0);
+            if std::path::Path::new(&last_path).is_dir()
+                && access::check(data.allowed_roots.as_deref(), std::path::Path::new(&last_path), "auto-restore", &who).is_ok()
+            {
+                let agent = data.effective_settings(chat_id).agent.value;
+                let agent_session = data.settings.chat_state.get(&chat_id.0.to_string())
+                    .and_then(|s| s.sessions.get(&agent).cloned());
+                let existing = bot_common::restore_session(&last_path, agent_session.as_deref(), &session_origin(bot, chat_id));
+                let session = data.sessions.entry(chat_id).or_insert_with(|| ChatSession {
+                    session_id: None,
+                    current_path: None,
+                    history: Vec::new(),
+                    pending_uploads: Vec::new(),
+                    cleared: false,
+                    fork_from: None,
+                    compact_brief: None,
+                    needs_compact: false,
+                });
+                session.current_path = Some(last_path.clone());
+                if let Some(session_data) = existing {
+                    session.session_id = Some(session_data.session_id);
+                    session.history = session_data.history;
+                }
+                let ts = chrono::Local::now().format("%H:%M:%S");
+                println!("  [{ts}] ↻ [{user_name}] Auto-restored session: {last_path}");
+            }
+        }
+    }
+}
+
+/// Handle a tap on a list button. The tap is checked like the command it stands for:
+/// the sender's role, a shutdown in progress and a running turn all refuse it.
+async fn handle_callback_query(
+    bot: Bot,
+    query: CallbackQuery,
+    state: SharedState,
+    token: &str,
+) -> ResponseResult<()> {
+    let (Some(action), Some(list)) = (query.data.as_deref().and_then(keyboards::Action::parse), query.message.as_ref()) else {
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    };
+    let (chat_id, list_id) = (list.chat().id, list.id());
+    let timestamp = chrono::Local::now().format("%H:%M:%S");
+    let user_id = query.from.id.0;
+    let user_name = format!("{}({user_id})", query.from.first_name);
+
+    let (role, busy, tool_allowed) = {
+        let data = state.lock().await;
+        let tool_allowed = match &action {
+            keyboards::Action::Tool(name) => data.effective_settings(chat_id).allowed_tools.value.contains(name),
+            _ => false,
+        };
+        (data.role_of(chat_id, Some(user_id)), data.cancel_tokens.contains_key(&chat_id), tool_allowed)
+    };
+    let Some(role) = role else {
+        println!("  [{timestamp}] ✗ Rejected button (chat:{}, user:{user_name})", chat_id.0);
+        bot.answer_callback_query(query.id).await?;
+        return Ok(());
+    };
+
+    let command = action.command(tool_allowed);
+    let required = roles::required_role(&command);
+    let refusal = if shutdown::is_shutting_down() {
+        Some("⏻ The bot is shutting down. Tap again once it is back.".to_string())
+    } else if role < required {
+        Some(format!("⛔ This needs the {required} role. You are a {role}."))
+    } else if busy {
+        Some("AI request in progress. Use /stop to cancel.".to_string())
+    } else {
+        None
+    };
+    if let Some(refusal) = refusal {
+        println!("  [{timestamp}] ✗ [{user_name}] {command} (button) — {refusal}");
+        bot.answer_callback_query(query.id).text(refusal).show_alert(true).await?;
+        return Ok(());
+    }
+
+    println!("  [{timestamp}] ◀ [{user_name}] {command} (button)");
+    auto_restore_session(&bot, chat_id, &state, &user_name).await;
+    let notice = commands::handle_button(&bot, chat_id, list_id, &action, &state, token, role == Role::Admin).await?;
+    let answer = bot.answer_callback_query(query.id);
+    if notice.is_empty() {
+        answer.await?;
+    } else {
+        answer.text(notice).await?;
+    }
+    Ok(())
 }
 
 /// Route incoming messages to appropriate handlers
@@ -115,57 +501,52 @@ async fn handle_message(
     let timestamp = chrono::Local::now().format("%H:%M:%S");
     let user_id = msg.from.as_ref().map(|u| u.id.0);
 
-    // Auth check: --chat-id restriction takes priority over imprinting
-    let allowed_cid = {
-        let data = state.lock().await;
-        data.allowed_chat_id
-    };
-
-    if let Some(allowed) = allowed_cid {
-        // --chat-id mode: only allow messages from the specified chat ID
-        if chat_id.0 != allowed {
-            let uid_str = user_id.map(|u| u.to_string()).unwrap_or_else(|| "?".to_string());
-            println!("  [{timestamp}] ✗ Rejected (chat:{}, user:{raw_user_name}/{uid_str}) — allowed chat: {allowed}", chat_id.0);
-            return Ok(());
-        }
-    } else {
-        // Imprinting mode (original behavior)
-        let Some(uid) = user_id else {
-            // No user info (e.g. channel post) → reject
-            return Ok(());
-        };
-        let imprinted = {
-            let mut data = state.lock().await;
-            match data.settings.owner_user_id {
-                None => {
-                    // Imprint: register first user as owner
-                    data.settings.owner_user_id = Some(uid);
-                    bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("token", token)]);
-                    println!("  [{timestamp}] ★ Owner registered: {raw_user_name} (id:{uid})");
-                    true
-                }
-                Some(owner_id) => {
-                    if uid != owner_id {
-                        // Unregistered user → reject silently (log only)
-                        println!("  [{timestamp}] ✗ Rejected: {raw_user_name} (id:{uid})");
-                        return Ok(());
-                    }
-                    false
-                }
+    // Auth: the owner and listed users are allowed in any chat, members of allowed chats
+    // get the chat role, and without a chat restriction the first user becomes the owner
+    let role = {
+        let mut data = state.lock().await;
+        let role = data.role_of(chat_id, user_id);
+        let imprint = data.allowed_chat_ids.is_empty()
+            && data.settings.owner_user_id.is_none()
+            && data.settings.users.is_empty();
+        match (role, user_id) {
+            (None, Some(uid)) if imprint => {
+                // Imprint: register first user as owner
+                data.settings.owner_user_id = Some(uid);
+                bot_common::save_bot_settings(&token_hash(token), &data.settings, &[("platform", "telegram")]);
+                println!("  [{timestamp}] ★ Owner registered: {raw_user_name} (id:{uid})");
+                Some(Role::Admin)
             }
-        };
-        if imprinted {
-            // Owner registration is logged to server console only
-            // No response sent to the user
+            (role, _) => role,
         }
-    }
+    };
+    let Some(role) = role else {
+        // Unknown user or chat → reject silently (log only)
+        let uid_str = user_id.map(|u| u.to_string()).unwrap_or_else(|| "?".to_string());
+        println!("  [{timestamp}] ✗ Rejected (chat:{}, user:{raw_user_name}/{uid_str})", chat_id.0);
+        return Ok(());
+    };
 
     let uid_display = user_id.map(|u| u.to_string()).unwrap_or_else(|| "?".to_string());
     let user_name = format!("{raw_user_name}({uid_display})");
 
+    // Shutting down: no new turns, but /stop can still end a running one early
+    if shutdown::is_shutting_down() && !msg.text().is_some_and(|t| t.starts_with("/stop")) {
+        println!("  [{timestamp}] ✗ [{user_name}] Rejected: shutting down");
+        messages::shared_rate_limit_wait(&state, chat_id).await;
+        bot.send_message(chat_id, "⏻ The bot is shutting down. Send this again once it is back.").await?;
+        return Ok(());
+    }
+
     // Handle file/photo uploads
     if msg.document().is_some() || msg.photo().is_some() {
         let file_hint = if msg.document().is_some() { "document" } else { "photo" };
+        if role < Role::User {
+            println!("  [{timestamp}] ✗ [{user_name}] Upload rejected ({role})");
+            messages::shared_rate_limit_wait(&state, chat_id).await;
+            bot.send_message(chat_id, "⛔ Viewers cannot upload files.").await?;
+            return Ok(());
+        }
         println!("  [{timestamp}] ◀ [{user_name}] Upload: {file_hint}");
         let result = commands::handle_file_upload(&bot, chat_id, &msg, &state).await;
         println!("  [{timestamp}] ▶ [{user_name}] Upload complete");
@@ -179,36 +560,50 @@ async fn handle_message(
     let text = text.to_string();
     let preview = truncate_str(&text, 60);
 
+    let required = roles::required_role(&text);
+    if role < required {
+        println!("  [{timestamp}] ✗ [{user_name}] {preview} — needs {required} role ({role})");
+        messages::shared_rate_limit_wait(&state, chat_id).await;
+        bot.send_message(chat_id, format!("⛔ This needs the {required} role. You are a {role}."))
+            .await?;
+        return Ok(());
+    }
+
     // Auto-restore session from bot_settings.json if not in memory
     if !text.starts_with("/start") {
+        auto_restore_session(&bot, chat_id, &state, &user_name).await;
+    }
+
+    // While an AI request is in progress, plain messages are queued for the next turn
+    // and everything else except /stop and /queue is rejected
+    if !text.starts_with("/stop") && !text.starts_with("/queue") {
         let mut data = state.lock().await;
-        if !data.sessions.contains_key(&chat_id) {
-            if let Some(last_path) = data.settings.last_sessions.get(&chat_id.0.to_string()).cloned() {
-                if std::path::Path::new(&last_path).is_dir() {
-                    let existing = bot_common::load_existing_session(&last_path);
-                    let session = data.sessions.entry(chat_id).or_insert_with(|| ChatSession {
-                        session_id: None,
-                        current_path: None,
-                        history: Vec::new(),
-                        pending_uploads: Vec::new(),
-                        cleared: false,
-                    });
-                    session.current_path = Some(last_path.clone());
-                    if let Some((session_data, _)) = existing {
-                        session.session_id = Some(session_data.session_id.clone());
-                        session.history = session_data.history.clone();
-                    }
-                    let ts = chrono::Local::now().format("%H:%M:%S");
-                    println!("  [{ts}] ↻ [{user_name}] Auto-restored session: {last_path}");
-                }
+        let busy = data.cancel_tokens.contains_key(&chat_id);
+        let queueable = !text.starts_with('/') && !text.starts_with('!');
+        // Messages arriving after a turn ended but before its queue ran join the queue to keep order
+        if queueable && (busy || data.queued_messages.contains_key(&chat_id)) {
+            let queue = data.queued_messages.entry(chat_id).or_default();
+            if queue.len() >= bot_common::MAX_QUEUED_MESSAGES {
+                drop(data);
+                messages::shared_rate_limit_wait(&state, chat_id).await;
+                bot.send_message(chat_id, "Queue is full. Wait for the current turn or use /queue clear.")
+                    .await?;
+                return Ok(());
+            }
+            queue.push((text.clone(), msg.id, role));
+            let position = queue.len();
+            drop(data);
+            println!("  [{timestamp}] ◀ [{user_name}] Queued #{position}: {preview}");
+            if busy {
+                messages::shared_rate_limit_wait(&state, chat_id).await;
+                bot.send_message(chat_id, format!("⏳ Queued (#{}). It will be sent when the current turn finishes. /queue to view or clear.", position))
+                    .await?;
+            } else {
+                chat::run_queued_messages(bot.clone(), chat_id, state.clone());
             }
+            return Ok(());
         }
-    }
-
-    // Block all messages except /stop while an AI request is in progress
-    if !text.starts_with("/stop") {
-        let data = state.lock().await;
-        if data.cancel_tokens.contains_key(&chat_id) {
+        if busy {
             drop(data);
             messages::shared_rate_limit_wait(&state, chat_id).await;
             bot.send_message(chat_id, "AI request in progress. Use /stop to cancel.")
@@ -220,19 +615,64 @@ async fn handle_message(
     if text.starts_with("/stop") {
         println!("  [{timestamp}] ◀ [{user_name}] /stop");
         commands::handle_stop_command(&bot, chat_id, &state).await?;
+    } else if text.starts_with("/queue") {
+        println!("  [{timestamp}] ◀ [{user_name}] /queue {}", text.strip_prefix("/queue").unwrap_or("").trim());
+        commands::handle_queue_command(&bot, chat_id, &text, &state).await?;
     } else if text.starts_with("/help") {
         println!("  [{timestamp}] ◀ [{user_name}] /help");
         commands::handle_help_command(&bot, chat_id, &state).await?;
+    } else if text.starts_with("/templates") {
+        println!("  [{timestamp}] ◀ [{user_name}] /templates");
+        commands::handle_templates_command(&bot, chat_id, &state).await?;
     } else if text.starts_with("/start") {
         println!("  [{timestamp}] ◀ [{user_name}] /start");
         commands::handle_start_command(&bot, chat_id, &text, &state, token).await?;
     } else if text.starts_with("/resume") {
         println!("  [{timestamp}] ◀ [{user_name}] /resume {}", text.strip_prefix("/resume").unwrap_or("").trim());
-        commands::handle_resume_command(&bot, chat_id, &text, &state, token).await?;
+        commands::handle_resume_command(&bot, chat_id, &text, &state, token, role == Role::Admin).await?;
+    } else if text.starts_with("/retry") {
+        println!("  [{timestamp}] ◀ [{user_name}] /retry");
+        commands::handle_retry_command(&bot, chat_id, msg.id, role, &state).await?;
     } else if text.starts_with("/clear") {
         println!("  [{timestamp}] ◀ [{user_name}] /clear");
         commands::handle_clear_command(&bot, chat_id, &state).await?;
         println!("  [{timestamp}] ▶ [{user_name}] Session cleared");
+    } else if text.starts_with("/export") {
+        println!("  [{timestamp}] ◀ [{user_name}] /export {}", text.strip_prefix("/export").unwrap_or("").trim());
+        commands::handle_export_command(&bot, chat_id, &text, &state).await?;
+    } else if text.starts_with("/compact") {
+        println!("  [{timestamp}] ◀ [{user_name}] /compact");
+        commands::handle_compact_command(&bot, chat_id, &state).await?;
+    } else if text.starts_with("/fork") {
+        println!("  [{timestamp}] ◀ [{user_name}] /fork {}", text.strip_prefix("/fork").unwrap_or("").trim());
+        commands::handle_fork_command(&bot, chat_id, &text, &state).await?;
+    } else if text.starts_with("/search") {
+        println!("  [{timestamp}] ◀ [{user_name}] /search {}", text.strip_prefix("/search").unwrap_or("").trim());
+        commands::handle_search_command(&bot, chat_id, &text, &state).await?;
+    } else if text.starts_with("/rename") {
+        println!("  [{timestamp}] ◀ [{user_name}] /rename {}", text.strip_prefix("/rename").unwrap_or("").trim());
+        commands::handle_rename_command(&bot, chat_id, &text, &state).await?;
+    } else if text.starts_with("/tag") {
+        println!("  [{timestamp}] ◀ [{user_name}] /tag {}", text.strip_prefix("/tag").unwrap_or("").trim());
+        commands::handle_tag_command(&bot, chat_id, &text, &state).await?;
+    } else if text.starts_with("/pin") {
+        println!("  [{timestamp}] ◀ [{user_name}] /pin");
+        commands::handle_pin_command(&bot, chat_id, &state).await?;
+    } else if text.starts_with("/diff") {
+        println!("  [{timestamp}] ◀ [{user_name}] /diff");
+        commands::handle_diff_command(&bot, chat_id, &state).await?;
+    } else if text.starts_with("/commit") {
+        println!("  [{timestamp}] ◀ [{user_name}] /commit {}", text.strip_prefix("/commit").unwrap_or("").trim());
+        commands::handle_commit_command(&bot, chat_id, &text, &state).await?;
+    } else if text.starts_with("/branch") {
+        println!("  [{timestamp}] ◀ [{user_name}] /branch {}", text.strip_prefix("/branch").unwrap_or("").trim());
+        commands::handle_branch_command(&bot, chat_id, &text, &state).await?;
+    } else if text.starts_with("/undo") {
+        println!("  [{timestamp}] ◀ [{user_name}] /undo");
+        commands::handle_undo_command(&bot, chat_id, &state).await?;
+    } else if text.starts_with("/checkpoints") {
+        println!("  [{timestamp}] ◀ [{user_name}] /checkpoints {}", text.strip_prefix("/checkpoints").unwrap_or("").trim());
+        commands::handle_checkpoints_command(&bot, chat_id, &text, &state).await?;
     } else if text.starts_with("/pwd") {
         println!("  [{timestamp}] ◀ [{user_name}] /pwd");
         commands::handle_pwd_command(&bot, chat_id, &state).await?;
@@ -245,6 +685,18 @@ async fn handle_message(
     } else if text.starts_with("/availabletools") {
         println!("  [{timestamp}] ◀ [{user_name}] /availabletools");
         commands::handle_availabletools_command(&bot, chat_id, &state).await?;
+    } else if text.starts_with("/settings") {
+        println!("  [{timestamp}] ◀ [{user_name}] /settings {}", text.strip_prefix("/settings").unwrap_or("").trim());
+        commands::handle_settings_command(&bot, chat_id, &text, &state).await?;
+    } else if text.starts_with("/systemprompt") {
+        println!("  [{timestamp}] ◀ [{user_name}] /systemprompt {}", truncate_str(text.strip_prefix("/systemprompt").unwrap_or("").trim(), 60));
+        commands::handle_systemprompt_command(&bot, chat_id, &text, role, &state).await?;
+    } else if text.starts_with("/reload") {
+        println!("  [{timestamp}] ◀ [{user_name}] /reload");
+        commands::handle_reload_command(&bot, chat_id, &state, token).await?;
+    } else if text.starts_with("/users") {
+        println!("  [{timestamp}] ◀ [{user_name}] /users {}", text.strip_prefix("/users").unwrap_or("").trim());
+        commands::handle_users_command(&bot, chat_id, &text, &state, token).await?;
     } else if text.starts_with("/allowedtools") {
         println!("  [{timestamp}] ◀ [{user_name}] /allowedtools");
         commands::handle_allowedtools_command(&bot, chat_id, &state).await?;
@@ -257,7 +709,7 @@ async fn handle_message(
         println!("  [{timestamp}] ▶ [{user_name}] Shell done");
     } else {
         println!("  [{timestamp}] ◀ [{user_name}] {preview}");
-        chat::handle_text_message(&bot, chat_id, &text, msg.id, &state).await?;
+        chat::handle_text_message(&bot, chat_id, &text, msg.id, role, &state, None).await?;
     }
 
     Ok(())
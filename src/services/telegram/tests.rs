@@ -2,7 +2,9 @@ use super::*;
 use super::markdown::*;
 use super::messages::html_escape;
 use crate::services::utils::{floor_char_boundary, truncate_str, normalize_empty_lines};
+use super::keyboards::{self, Action};
 use crate::services::bot_common::{normalize_tool_name, tool_info, risk_badge};
+use teloxide::types::{InlineKeyboardButtonKind, InlineKeyboardMarkup};
 
 // --- token_hash ---
 
@@ -570,3 +572,81 @@ fn test_floor_char_boundary_multibyte() {
     let s = "가나다"; // each 3 bytes
     assert_eq!(floor_char_boundary(s, 4), 3);
 }
+
+// --- keyboards ---
+
+fn callback_data(markup: &InlineKeyboardMarkup) -> Vec<(String, String)> {
+    markup.inline_keyboard.iter().flatten()
+        .map(|b| match &b.kind {
+            InlineKeyboardButtonKind::CallbackData(data) => (b.text.clone(), data.clone()),
+            other => panic!("not a callback button: {:?}", other),
+        })
+        .collect()
+}
+
+fn session_summary(id: &str, title: &str) -> bot_common::SessionSummary {
+    bot_common::SessionSummary {
+        session_id: id.to_string(),
+        current_path: "/work".to_string(),
+        created_at: "2026-01-01 10:00:00".to_string(),
+        history_count: 2,
+        modified: std::time::SystemTime::UNIX_EPOCH,
+        name: None,
+        parent_session_id: None,
+        tags: Vec::new(),
+        pinned: false,
+        title: title.to_string(),
+        platform: None,
+        chat_id: None,
+        bot_hash: None,
+    }
+}
+
+#[test]
+fn test_keyboard_action_roundtrip() {
+    for action in [Action::Resume("3".to_string()), Action::Agent("codex".to_string()), Action::Tool("Bash".to_string())] {
+        assert_eq!(Action::parse(&action.data()), Some(action));
+    }
+    assert_eq!(Action::parse("agent:"), None);
+    assert_eq!(Action::parse("delete:everything"), None);
+    assert_eq!(Action::parse("codex"), None);
+}
+
+#[test]
+fn test_keyboard_action_command() {
+    assert_eq!(Action::Resume("abc".to_string()).command(false), "/resume abc");
+    assert_eq!(Action::Agent("gemini".to_string()).command(false), "/agent gemini");
+    assert_eq!(Action::Tool("Bash".to_string()).command(true), "/allowed -Bash");
+    assert_eq!(Action::Tool("Bash".to_string()).command(false), "/allowed +Bash");
+}
+
+#[test]
+fn test_resume_keyboard_skips_long_ids() {
+    let long_id = "x".repeat(70);
+    let sessions = vec![
+        session_summary("abc-123", "Fix the build"),
+        session_summary(&long_id, "Long one"),
+        session_summary("def-456", "Docs"),
+    ];
+    let buttons = callback_data(&keyboards::resume_keyboard(&sessions, 10));
+    // A list number could name another session by the time it is tapped
+    assert_eq!(buttons, vec![
+        ("1. Fix the build".to_string(), "resume:abc-123".to_string()),
+        ("3. Docs".to_string(), "resume:def-456".to_string()),
+    ]);
+    assert_eq!(keyboards::resume_keyboard(&sessions, 1).inline_keyboard.len(), 1);
+}
+
+#[test]
+fn test_agent_and_tools_keyboards() {
+    let agents = callback_data(&keyboards::agent_keyboard("codex"));
+    assert!(agents.contains(&("✓ codex".to_string(), "agent:codex".to_string())));
+    assert!(agents.contains(&("claude".to_string(), "agent:claude".to_string())));
+
+    let allowed = vec!["Read".to_string(), "mcp__notes".to_string()];
+    let tools = callback_data(&keyboards::tools_keyboard(&allowed));
+    assert!(tools.contains(&("✅ Read".to_string(), "tool:Read".to_string())));
+    assert!(tools.contains(&("⬜ Bash".to_string(), "tool:Bash".to_string())));
+    assert_eq!(tools.last(), Some(&("✅ mcp__notes".to_string(), "tool:mcp__notes".to_string())));
+    assert!(keyboards::tools_keyboard(&allowed).inline_keyboard.iter().all(|row| row.len() <= 3));
+}
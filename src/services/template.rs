@@ -0,0 +1,309 @@
+//! Workspace templates for `/start --template <name>`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::services::agent::kill_process_group;
+
+/// Init scripts are killed after this long
+const INIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long output may keep coming after init.sh exits, from processes it left running
+/// with its stdout or stderr; they are killed after that
+const OUTPUT_GRACE: Duration = Duration::from_secs(2);
+
+/// Lines of init script output included in the /start reply
+const INIT_OUTPUT_LINES: usize = 10;
+
+/// A workspace template: a directory under `~/.aemi/templates/<name>/` with
+/// - `files/`           copied into the new workspace
+/// - `init.sh`          optional, run with bash inside the new workspace after copying
+/// - `prompt.md`        optional, appended to the system prompt for sessions in that workspace
+/// - `description.txt`  optional, first line shown by `/templates`
+///
+/// Which template a workspace came from is recorded in `~/.aemi/workspace_templates.json`,
+/// so the prompt addition survives restarts and `/resume`.
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub path: PathBuf,
+    pub description: Option<String>,
+    pub has_files: bool,
+    pub has_init: bool,
+    pub has_prompt: bool,
+}
+
+/// Result of applying a template to a new workspace
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    pub files_copied: usize,
+    /// Exit code and last lines of output of init.sh, if the template has one
+    pub init: Option<(i32, String)>,
+}
+
+/// Templates directory (~/.aemi/templates)
+pub fn templates_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".aemi").join("templates"))
+}
+
+fn registry_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".aemi").join("workspace_templates.json"))
+}
+
+/// Template names are single path components
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains('/')
+        && !name.contains('\\')
+}
+
+fn load_template(path: PathBuf) -> Option<Template> {
+    if !path.is_dir() {
+        return None;
+    }
+    let name = path.file_name()?.to_string_lossy().to_string();
+    if name.starts_with('.') {
+        return None;
+    }
+    let description = fs::read_to_string(path.join("description.txt")).ok()
+        .and_then(|d| d.lines().map(str::trim).find(|l| !l.is_empty()).map(String::from));
+    Some(Template {
+        name,
+        description,
+        has_files: path.join("files").is_dir(),
+        has_init: path.join("init.sh").is_file(),
+        has_prompt: path.join("prompt.md").is_file(),
+        path,
+    })
+}
+
+fn list_in(dir: &Path) -> Vec<Template> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut templates: Vec<Template> = entries.filter_map(|e| e.ok())
+        .filter_map(|e| load_template(e.path()))
+        .collect();
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+    templates
+}
+
+/// All templates, sorted by name
+pub fn list_templates() -> Vec<Template> {
+    templates_dir().map(|d| list_in(&d)).unwrap_or_default()
+}
+
+/// Look up a template by name
+pub fn find_template(name: &str) -> Option<Template> {
+    if !is_valid_name(name) {
+        return None;
+    }
+    load_template(templates_dir()?.join(name))
+}
+
+/// Copy a directory tree. Symlinks are skipped so a template cannot pull in files from elsewhere.
+fn copy_tree(src: &Path, dest: &Path) -> std::io::Result<usize> {
+    let mut copied = 0;
+    for entry in fs::read_dir(src)?.filter_map(|e| e.ok()) {
+        let from = entry.path();
+        let to = dest.join(entry.file_name());
+        let meta = fs::symlink_metadata(&from)?;
+        if meta.is_dir() {
+            fs::create_dir_all(&to)?;
+            copied += copy_tree(&from, &to)?;
+        } else if meta.is_file() {
+            fs::copy(&from, &to)?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Drain a pipe on a thread so a chatty script cannot block on a full pipe
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = String::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_string(&mut buf);
+        }
+        let _ = tx.send(buf);
+    });
+    rx
+}
+
+/// Run init.sh in the workspace, returning its exit code and the tail of its output.
+/// The script leads its own process group, so a timeout also ends what it started.
+fn run_init(script: &Path, workspace: &Path) -> Result<(i32, String), String> {
+    let mut cmd = Command::new("bash");
+    cmd.arg(script)
+        .current_dir(workspace)
+        .env("AEMI_TEMPLATE_DIR", script.parent().unwrap_or(workspace))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    let mut child = cmd.spawn().map_err(|e| format!("failed to run init.sh: {}", e))?;
+    let (stdout, stderr) = (read_pipe(child.stdout.take()), read_pipe(child.stderr.take()));
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if started.elapsed() > INIT_TIMEOUT => {
+                kill_process_group(child.id());
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(100)),
+            Err(e) => return Err(e.to_string()),
+        }
+    };
+
+    // Background processes still holding the pipes would keep the readers waiting
+    let deadline = Instant::now() + OUTPUT_GRACE;
+    let collect = |pipe: &mpsc::Receiver<String>| {
+        pipe.recv_timeout(deadline.saturating_duration_since(Instant::now())).unwrap_or_else(|_| {
+            kill_process_group(child.id());
+            pipe.recv_timeout(OUTPUT_GRACE).unwrap_or_default()
+        })
+    };
+    let output = format!("{}{}", collect(&stdout), collect(&stderr));
+    let lines: Vec<&str> = output.lines().collect();
+    let tail = lines[lines.len().saturating_sub(INIT_OUTPUT_LINES)..].join("\n");
+    match status {
+        Some(status) => Ok((status.code().unwrap_or(-1), tail)),
+        None => Err(format!("init.sh timed out after {}s", INIT_TIMEOUT.as_secs())),
+    }
+}
+
+/// Copy the template's files into `workspace`, then run its init script
+pub fn apply(template: &Template, workspace: &Path) -> Result<ApplyReport, String> {
+    let mut report = ApplyReport::default();
+    if template.has_files {
+        report.files_copied = copy_tree(&template.path.join("files"), workspace)
+            .map_err(|e| format!("failed to copy template files: {}", e))?;
+    }
+    if template.has_init {
+        report.init = Some(run_init(&template.path.join("init.sh"), workspace)?);
+    }
+    Ok(report)
+}
+
+fn load_registry(path: &Path) -> BTreeMap<String, String> {
+    fs::read_to_string(path).ok()
+        .and_then(|c| serde_json::from_str(&c).ok())
+        .unwrap_or_default()
+}
+
+/// Remember which template a workspace was created from. Entries for workspaces
+/// that no longer exist are dropped.
+pub fn record_workspace(workspace: &str, template: &str) {
+    let Some(path) = registry_path() else { return };
+    let mut registry = load_registry(&path);
+    registry.retain(|dir, _| Path::new(dir).exists());
+    registry.insert(workspace.to_string(), template.to_string());
+    if let Ok(json) = serde_json::to_string_pretty(&registry) {
+        let _ = fs::write(path, json);
+    }
+}
+
+/// System prompt addition for a workspace created from a template (its prompt.md)
+pub fn prompt_for_workspace(workspace: &str) -> Option<String> {
+    let name = load_registry(&registry_path()?).remove(workspace)?;
+    let template = find_template(&name)?;
+    let prompt = fs::read_to_string(template.path.join("prompt.md")).ok()?;
+    let prompt = prompt.trim();
+    if prompt.is_empty() { None } else { Some(prompt.to_string()) }
+}
+
+/// Split `/start` arguments into an optional `--template <name>` and the remaining path
+pub fn parse_start_args(args: &str) -> Result<(Option<String>, String), String> {
+    let mut template = None;
+    let mut rest = Vec::new();
+    let mut words = args.split_whitespace();
+    while let Some(word) = words.next() {
+        if word == "--template" || word == "-t" {
+            match words.next() {
+                Some(name) => template = Some(name.to_string()),
+                None => return Err("Usage: /start --template <name>".to_string()),
+            }
+        } else if let Some(name) = word.strip_prefix("--template=") {
+            template = Some(name.to_string());
+        } else {
+            rest.push(word);
+        }
+    }
+    Ok((template, rest.join(" ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_start_args() {
+        assert_eq!(parse_start_args("").unwrap(), (None, String::new()));
+        assert_eq!(parse_start_args("~/project").unwrap(), (None, "~/project".to_string()));
+        assert_eq!(parse_start_args("--template rust").unwrap(), (Some("rust".to_string()), String::new()));
+        assert_eq!(parse_start_args("--template=py").unwrap(), (Some("py".to_string()), String::new()));
+        assert!(parse_start_args("--template").is_err());
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("rust-cli"));
+        assert!(!is_valid_name("../etc"));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name(""));
+    }
+
+    #[test]
+    fn test_list_and_apply() {
+        let templates = tempfile::tempdir().unwrap();
+        let rust = templates.path().join("rust");
+        fs::create_dir_all(rust.join("files/src")).unwrap();
+        fs::write(rust.join("files/Cargo.toml"), "[package]\n").unwrap();
+        fs::write(rust.join("files/src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(rust.join("init.sh"), "echo ready > init.log\necho done\n").unwrap();
+        fs::write(rust.join("description.txt"), "\nCargo binary project\n").unwrap();
+        fs::create_dir_all(templates.path().join(".hidden")).unwrap();
+        fs::write(templates.path().join("notes.txt"), "not a template").unwrap();
+
+        let list = list_in(templates.path());
+        assert_eq!(list.len(), 1);
+        let t = &list[0];
+        assert_eq!(t.name, "rust");
+        assert_eq!(t.description.as_deref(), Some("Cargo binary project"));
+        assert!(t.has_files && t.has_init && !t.has_prompt);
+
+        let workspace = tempfile::tempdir().unwrap();
+        let report = apply(t, workspace.path()).unwrap();
+        assert_eq!(report.files_copied, 2);
+        assert_eq!(report.init, Some((0, "done".to_string())));
+        assert!(workspace.path().join("src/main.rs").exists());
+        assert_eq!(fs::read_to_string(workspace.path().join("init.log")).unwrap(), "ready\n");
+    }
+
+    #[test]
+    fn test_init_leaving_process_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("init.sh");
+        fs::write(&script, "echo started\nsleep 600 &\n").unwrap();
+        let started = Instant::now();
+        assert_eq!(run_init(&script, dir.path()).unwrap(), (0, "started".to_string()));
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+}